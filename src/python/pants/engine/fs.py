@@ -118,6 +118,25 @@ class Snapshot(datatype([('directory_digest', DirectoryDigest), ('path_stats', t
 FilesContent = Collection.of(FileContent)
 
 
+class SnapshotSubset(datatype([('directory_digest', DirectoryDigest), ('globs', PathGlobs)])):
+  """A request for a Snapshot of the files in a DirectoryDigest which match some PathGlobs.
+
+  Matching happens against the digests in the engine's Store, so no files are materialized.
+  """
+
+
+class DirectoryWithPrefixToStrip(datatype([('directory_digest', DirectoryDigest), 'prefix'])):
+  """A request to strip a prefix from a DirectoryDigest.
+
+  The directory must contain nothing other than the prefix; the resulting DirectoryDigest is that
+  of the directory found at the prefix.
+  """
+
+
+class DirectoryWithPrefixToAdd(datatype([('directory_digest', DirectoryDigest), 'prefix'])):
+  """A request to nest a DirectoryDigest under a prefix, producing a new DirectoryDigest."""
+
+
 # TODO(cosmicexplorer): don't recreate this in python, get this from
 # fs::EMPTY_DIGEST somehow.
 _EMPTY_FINGERPRINT = 'e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855'
@@ -140,4 +159,7 @@ def create_fs_rules():
     RootRule(DirectoryDigest),
     RootRule(PathGlobs),
     RootRule(Snapshot),
+    RootRule(SnapshotSubset),
+    RootRule(DirectoryWithPrefixToStrip),
+    RootRule(DirectoryWithPrefixToAdd),
   ]
@@ -92,11 +92,33 @@ class ExecuteProcessResult(datatype(['stdout', 'stderr', 'output_directory_diges
   Requesting one of these will raise an exception if the exit code is non-zero."""
 
 
-class FallibleExecuteProcessResult(datatype(['stdout', 'stderr', 'exit_code', 'output_directory_digest'])):
+class FallibleExecuteProcessResult(datatype(['stdout',
+                                             'stderr',
+                                             'exit_code',
+                                             'output_directory_digest',
+                                             'kept_sandbox'])):
   """Result of executing a process.
 
+  `kept_sandbox` is the path of the sandbox the process ran in if it was kept (see
+  `--process-execution-keep-sandboxes`), and is otherwise empty. It describes one particular run
+  of the process rather than its result, so it is ignored by equality.
+
   Requesting one of these will not raise an exception if the exit code is non-zero."""
 
+  def _result(self):
+    return (self.stdout, self.stderr, self.exit_code, self.output_directory_digest)
+
+  def __eq__(self, other):
+    if type(self) != type(other):
+      return NotImplemented
+    return self._result() == other._result()
+
+  def __ne__(self, other):
+    return not (self == other)
+
+  def __hash__(self):
+    return hash(self._result())
+
 
 class ProcessExecutionFailure(Exception):
   """Used to denote that a process exited, but was unsuccessful in some way.
@@ -111,14 +133,20 @@ stderr:
 {stderr}
 """
 
-  def __init__(self, exit_code, stdout, stderr, process_description):
+  KEPT_SANDBOX_MSG_FMT = """The sandbox of the process was kept at {sandbox}, and it can be re-run with {sandbox}/__run.sh
+"""
+
+  def __init__(self, exit_code, stdout, stderr, process_description, kept_sandbox=None):
     # These are intentionally "public" members.
     self.exit_code = exit_code
     self.stdout = stdout
     self.stderr = stderr
+    self.kept_sandbox = kept_sandbox
 
     msg = self.MSG_FMT.format(
       desc=process_description, code=exit_code, stdout=stdout, stderr=stderr)
+    if kept_sandbox:
+      msg += self.KEPT_SANDBOX_MSG_FMT.format(sandbox=kept_sandbox)
 
     super(ProcessExecutionFailure, self).__init__(msg)
 
@@ -138,7 +166,8 @@ def fallible_to_exec_result_or_raise(fallible_result, request):
       fallible_result.exit_code,
       fallible_result.stdout,
       fallible_result.stderr,
-      request.description
+      request.description,
+      fallible_result.kept_sandbox
     )
 
 
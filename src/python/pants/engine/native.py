@@ -171,6 +171,9 @@ void tasks_task_begin(Tasks*, Function, TypeConstraint);
 void tasks_add_get(Tasks*, TypeConstraint, TypeId);
 void tasks_add_select(Tasks*, TypeConstraint);
 void tasks_add_select_variant(Tasks*, TypeConstraint, Buffer);
+void tasks_add_select_union(Tasks*, TypeConstraint);
+void tasks_set_priority(Tasks*, int64_t);
+void tasks_set_declares_other_products(Tasks*);
 void tasks_task_end(Tasks*);
 void tasks_singleton_add(Tasks*, Value, TypeConstraint);
 void tasks_destroy(Tasks*);
@@ -198,6 +201,9 @@ Scheduler* scheduler_create(Tasks*,
                             TypeConstraint,
                             TypeConstraint,
                             TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
                             TypeId,
                             TypeId,
                             Buffer,
@@ -209,9 +215,18 @@ Scheduler* scheduler_create(Tasks*,
                             uint64_t,
                             uint64_t,
                             uint64_t,
+                            uint64_t,
+                            _Bool,
+                            Buffer,
+                            Buffer,
+                            _Bool,
+                            uint64_t,
+                            uint64_t,
                             uint64_t);
 void scheduler_pre_fork(Scheduler*);
 Value scheduler_metrics(Scheduler*, Session*);
+Value scheduler_critical_path(Scheduler*, Session*);
+Value scheduler_progress(Scheduler*, Session*);
 RawNodes* scheduler_execute(Scheduler*, Session*, ExecutionRequest*);
 void scheduler_destroy(Scheduler*);
 
@@ -222,20 +237,33 @@ ExecutionRequest* execution_request_create(void);
 void execution_request_destroy(ExecutionRequest*);
 
 uint64_t graph_len(Scheduler*);
+_Bool graph_await_watcher_invalidation(Scheduler*, uint64_t, uint64_t);
 uint64_t graph_invalidate(Scheduler*, BufferBuffer);
+PyResult graph_invalidate_globs(Scheduler*, Value);
+PyResult graph_invalidate_node_type(Scheduler*, Buffer);
+PyResult graph_persist(Scheduler*, char*);
+PyResult graph_load_persisted(Scheduler*, char*);
 PyResult graph_visualize(Scheduler*, Session*, char*);
+PyResult graph_export_json(Scheduler*, Session*, char*, _Bool);
+PyResult graph_export_chrome_trace(Scheduler*, Session*, char*, _Bool);
 void graph_trace(Scheduler*, ExecutionRequest*, char*);
 
 PyResult  execution_add_root_select(Scheduler*, ExecutionRequest*, Key, TypeConstraint);
 
 PyResult capture_snapshots(Scheduler*, Value);
 
-PyResult merge_directories(Scheduler*, Value);
+PyResult merge_directories(Scheduler*, Value, Buffer);
 
 Value validator_run(Scheduler*);
+Value validator_diagnostics(Scheduler*);
+Value validator_ambiguities(Scheduler*);
 
 void rule_graph_visualize(Scheduler*, TypeIdBuffer, char*);
 void rule_subgraph_visualize(Scheduler*, TypeId, TypeConstraint, char*);
+PyResult rule_graph_export_json(Scheduler*, TypeIdBuffer, char*);
+Value rule_graph_rule_paths(Scheduler*, TypeId, TypeConstraint, uint64_t);
+Value rule_graph_unused_rules(Scheduler*);
+Value rule_graph_reached_intrinsics(Scheduler*);
 
 void nodes_destroy(RawNodes*);
 
@@ -737,6 +765,9 @@ class Native(object):
                     constraint_path_globs,
                     constraint_directory_digest,
                     constraint_snapshot,
+                    constraint_snapshot_subset,
+                    constraint_directory_with_prefix_to_strip,
+                    constraint_directory_with_prefix_to_add,
                     constraint_files_content,
                     constraint_dir,
                     constraint_file,
@@ -770,6 +801,9 @@ class Native(object):
         tc(constraint_path_globs),
         tc(constraint_directory_digest),
         tc(constraint_snapshot),
+        tc(constraint_snapshot_subset),
+        tc(constraint_directory_with_prefix_to_strip),
+        tc(constraint_directory_with_prefix_to_add),
         tc(constraint_files_content),
         tc(constraint_dir),
         tc(constraint_file),
@@ -793,6 +827,13 @@ class Native(object):
         execution_options.remote_store_chunk_bytes,
         execution_options.remote_store_chunk_upload_timeout_seconds,
         execution_options.process_execution_parallelism,
+        execution_options.process_execution_reuse_workdirs,
+        self.context.utf8_buf(execution_options.process_execution_keep_sandboxes),
+        self.context.utf8_buf(execution_options.process_execution_file_cache),
+        execution_options.watch_filesystem,
+        execution_options.graph_max_entries,
+        execution_options.graph_max_memory_mb,
+        execution_options.rule_graph_parallelism,
       )
     return self.gc(scheduler, self.lib.scheduler_destroy)
 
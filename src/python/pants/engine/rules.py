@@ -33,13 +33,16 @@ class _RuleVisitor(ast.NodeVisitor):
     self.gets.append(Get.extract_constraints(node))
 
 
-def rule(output_type, input_selectors):
+def rule(output_type, input_selectors, priority=None):
   """A @decorator that declares that a particular static function may be used as a TaskRule.
 
   :param Constraint output_type: The return/output type for the Rule. This may be either a
     concrete Python type, or an instance of `Exactly` representing a union of multiple types.
   :param list input_selectors: A list of Selector instances that matches the number of arguments
     to the @decorated function.
+  :param int priority: If set, the Rule is preferred over other Rules with lower (or undeclared)
+    priorities that compute the same product for a subject. Without a priority, multiple Rules that
+    could compute the same product for a subject are rejected as ambiguous.
   """
 
   def wrapper(func):
@@ -64,7 +67,8 @@ def rule(output_type, input_selectors):
         rule_visitor.visit(node)
         gets.update(Get(resolve_type(p), resolve_type(s)) for p, s in rule_visitor.gets)
 
-    func._rule = TaskRule(output_type, input_selectors, func, input_gets=list(gets))
+    func._rule = TaskRule(output_type, input_selectors, func, input_gets=list(gets),
+                          priority=priority)
     return func
   return wrapper
 
@@ -85,13 +89,14 @@ class Rule(AbstractClass):
     """Collection of input selectors."""
 
 
-class TaskRule(datatype(['output_constraint', 'input_selectors', 'input_gets', 'func']), Rule):
+class TaskRule(datatype(['output_constraint', 'input_selectors', 'input_gets', 'func', 'priority']),
+               Rule):
   """A Rule that runs a task function when all of its input selectors are satisfied.
 
   TODO: Make input_gets non-optional when more/all rules are using them.
   """
 
-  def __new__(cls, output_type, input_selectors, func, input_gets=None):
+  def __new__(cls, output_type, input_selectors, func, input_gets=None, priority=None):
     # Validate result type.
     if isinstance(output_type, Exactly):
       constraint = output_type
@@ -112,8 +117,18 @@ class TaskRule(datatype(['output_constraint', 'input_selectors', 'input_gets', '
       raise TypeError("Expected a list of Gets for rule `{}`, got: {}".format(
         func.__name__, type(input_gets)))
 
+    # Validate priority.
+    if priority is not None and not isinstance(priority, int):
+      raise TypeError("Expected an int priority for rule `{}`, got: {}".format(
+        func.__name__, type(priority)))
+
     # Create.
-    return super(TaskRule, cls).__new__(cls, constraint, tuple(input_selectors), tuple(input_gets), func)
+    return super(TaskRule, cls).__new__(cls,
+                                        constraint,
+                                        tuple(input_selectors),
+                                        tuple(input_gets),
+                                        func,
+                                        priority)
 
   def __str__(self):
     return '({}, {!r}, {})'.format(type_or_constraint_repr(self.output_constraint),
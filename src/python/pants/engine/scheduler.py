@@ -14,13 +14,14 @@ from types import GeneratorType
 from pants.base.exceptions import TaskError
 from pants.base.project_tree import Dir, File, Link
 from pants.build_graph.address import Address
-from pants.engine.fs import (DirectoryDigest, FileContent, FilesContent, Path, PathGlobs,
-                             PathGlobsAndRoot, Snapshot)
+from pants.engine.fs import (DirectoryDigest, DirectoryWithPrefixToAdd,
+                             DirectoryWithPrefixToStrip, FileContent, FilesContent, Path,
+                             PathGlobs, PathGlobsAndRoot, Snapshot, SnapshotSubset)
 from pants.engine.isolated_process import ExecuteProcessRequest, FallibleExecuteProcessResult
 from pants.engine.native import Function, TypeConstraint, TypeId
 from pants.engine.nodes import Return, State, Throw
 from pants.engine.rules import RuleIndex, SingletonRule, TaskRule
-from pants.engine.selectors import Select, SelectVariant, constraint_for
+from pants.engine.selectors import Select, SelectUnion, SelectVariant, constraint_for
 from pants.engine.struct import HasProducts, Variants
 from pants.util.contextutil import temporary_file_path
 from pants.util.objects import Collection, SubclassesOf, datatype
@@ -67,10 +68,78 @@ class ExecutionResult(datatype(['error', 'root_products'])):
     return cls(error=error, root_products=None)
 
 
+class ExecutionProgress(datatype(['queued', 'running', 'completed', 'bytes_uploaded',
+                                   'bytes_downloaded', 'running_processes'])):
+  """A snapshot of the progress of the executions in a SchedulerSession.
+
+  To poll for progress while a SchedulerSession is executing, see `SchedulerSession.progress`.
+  The queued and running counts are for the Scheduler as a whole, while the completed count and
+  the bytes transferred are only those since the SchedulerSession was created.
+
+  :param running_processes: The running processes, longest running first.
+  :type running_processes: tuple of tuples of process description and elapsed time in seconds.
+  """
+
+  def lines(self):
+    """Renders this progress as lines of text, suitable for a live multi-line display."""
+    summary = '{} running, {} queued, {} completed nodes; {} bytes uploaded, {} downloaded'.format(
+      self.running, self.queued, self.completed, self.bytes_uploaded, self.bytes_downloaded)
+    return [summary] + ['  {:6.1f}s {}'.format(elapsed, description)
+                        for description, elapsed in self.running_processes]
+
+
 class ExecutionError(Exception):
   pass
 
 
+class RuleDiagnostic(datatype(['subject_type', 'product', 'reason', 'rejected_candidates',
+                               'suggestions'])):
+  """A reason that a rule could not be used for a particular subject type.
+
+  :param product: The product that could not be computed, or None.
+  :param rejected_candidates: Rules that could have computed the product, but were unusable.
+  :type rejected_candidates: tuple of tuples of rule description and tuple of reasons.
+  :param suggestions: If no rule could compute the product, known types with similar names.
+  """
+
+
+class RuleError(datatype(['rule', 'diagnostics'])):
+  """The reasons that a rule could not be used.
+
+  :param diagnostics: A tuple of :class:`RuleDiagnostic`.
+  """
+
+
+class RuleAmbiguity(datatype(['subject_type', 'product', 'candidates'])):
+  """A product for a subject type that could be computed by multiple rules.
+
+  :param candidates: A tuple of descriptions of the rules, none of which declared a higher priority
+    than the others.
+  """
+
+
+class RulePaths(datatype(['paths', 'truncated'])):
+  """The paths through the rule graph by which a product could be computed for a subject.
+
+  :param paths: A tuple of tuples of entry descriptions.
+  :param truncated: True if there were too many paths to explore, in which case `paths` is
+    incomplete.
+  """
+
+
+class RuleGraphValidationError(ValueError):
+  """Indicates that the rule graph contained rules that could not be used, or ambiguous rules."""
+
+  def __init__(self, message, rule_errors, ambiguities):
+    """
+    :param rule_errors: A tuple of :class:`RuleError`.
+    :param ambiguities: A tuple of :class:`RuleAmbiguity`.
+    """
+    super(RuleGraphValidationError, self).__init__(message)
+    self.rule_errors = rule_errors
+    self.ambiguities = ambiguities
+
+
 class Scheduler(object):
   def __init__(
     self,
@@ -134,6 +203,9 @@ class Scheduler(object):
       constraint_for(PathGlobs),
       constraint_for(DirectoryDigest),
       constraint_for(Snapshot),
+      constraint_for(SnapshotSubset),
+      constraint_for(DirectoryWithPrefixToStrip),
+      constraint_for(DirectoryWithPrefixToAdd),
       constraint_for(FilesContent),
       constraint_for(Dir),
       constraint_for(File),
@@ -166,7 +238,34 @@ class Scheduler(object):
     value = self._from_value(raw_value)
 
     if isinstance(value, Exception):
-      raise ValueError(str(value))
+      raise RuleGraphValidationError(str(value),
+                                     self.rule_graph_diagnostics(),
+                                     self.rule_graph_ambiguities())
+
+  def rule_graph_diagnostics(self):
+    """Returns the reasons that rules in the rule graph could not be used.
+
+    :returns: A tuple of :class:`RuleError`, sorted by rule.
+    """
+    def diagnostic(subject_type, product, reason, rejected_candidates, suggestions):
+      return RuleDiagnostic(subject_type=subject_type,
+                            product=product or None,
+                            reason=reason,
+                            rejected_candidates=rejected_candidates,
+                            suggestions=suggestions)
+
+    errors_val = self._from_value(self._native.lib.validator_diagnostics(self._scheduler))
+    return tuple(RuleError(rule=rule, diagnostics=tuple(diagnostic(*d) for d in diagnostics))
+                 for rule, diagnostics in errors_val)
+
+  def rule_graph_ambiguities(self):
+    """Returns the products which could be computed by multiple rules with the same priority.
+
+    :returns: A tuple of :class:`RuleAmbiguity`, sorted by product and then subject type.
+    """
+    ambiguities_val = self._from_value(self._native.lib.validator_ambiguities(self._scheduler))
+    return tuple(RuleAmbiguity(subject_type=subject_type, product=product, candidates=candidates)
+                 for subject_type, product, candidates in ambiguities_val)
 
   def _to_value(self, obj):
     return self._native.context.to_value(obj)
@@ -219,7 +318,10 @@ class Scheduler(object):
         if type(rule) is SingletonRule:
           self._register_singleton(output_constraint, rule)
         elif type(rule) is TaskRule:
-          self._register_task(output_constraint, rule)
+          # A rule which declares multiple product types is registered for each of them, but will
+          # only produce one.
+          declares_other_products = constraint_for(product_type) != rule.output_constraint
+          self._register_task(output_constraint, rule, declares_other_products)
         else:
           raise ValueError('Unexpected Rule type: {}'.format(rule))
 
@@ -232,7 +334,7 @@ class Scheduler(object):
                                          self._to_value(rule.value),
                                          output_constraint)
 
-  def _register_task(self, output_constraint, rule):
+  def _register_task(self, output_constraint, rule, declares_other_products):
     """Register the given TaskRule with the native scheduler."""
     func = rule.func
     self._native.lib.tasks_task_begin(self._tasks, Function(self._to_key(func)), output_constraint)
@@ -241,6 +343,8 @@ class Scheduler(object):
       product_constraint = self._to_constraint(selector.product)
       if selector_type is Select:
         self._native.lib.tasks_add_select(self._tasks, product_constraint)
+      elif selector_type is SelectUnion:
+        self._native.lib.tasks_add_select_union(self._tasks, product_constraint)
       elif selector_type is SelectVariant:
         key_buf = self._to_utf8_buf(selector.variant_key)
         self._native.lib.tasks_add_select_variant(self._tasks,
@@ -252,12 +356,27 @@ class Scheduler(object):
       self._native.lib.tasks_add_get(self._tasks,
                                      self._to_constraint(get.product),
                                      TypeId(self._to_id(get.subject)))
+    if rule.priority is not None:
+      self._native.lib.tasks_set_priority(self._tasks, rule.priority)
+    if declares_other_products:
+      self._native.lib.tasks_set_declares_other_products(self._tasks)
     self._native.lib.tasks_task_end(self._tasks)
 
   def visualize_graph_to_file(self, session, filename):
     res = self._native.lib.graph_visualize(self._scheduler, session, bytes(filename))
     self._raise_or_return(res)
 
+  def export_graph_json(self, session, filename, all_nodes=False):
+    res = self._native.lib.graph_export_json(self._scheduler, session, bytes(filename), all_nodes)
+    return self._raise_or_return(res)
+
+  def export_chrome_trace(self, session, filename, all_nodes=False):
+    res = self._native.lib.graph_export_chrome_trace(self._scheduler,
+                                                     session,
+                                                     bytes(filename),
+                                                     all_nodes)
+    return self._raise_or_return(res)
+
   def visualize_rule_graph_to_file(self, filename):
     self._native.lib.rule_graph_visualize(
       self._scheduler,
@@ -285,6 +404,43 @@ class Scheduler(object):
         for line in fd.readlines():
           yield line.rstrip()
 
+  def export_rule_graph_json(self, filename):
+    """Writes the rule graph to the given file as a JSON object.
+
+    The object contains the `root_subject_types`, the `entries` of the graph (each with its
+    `entry`, `kind` and `dependencies`), the `reached_intrinsics`, and the `unused_rules`.
+    """
+    res = self._native.lib.rule_graph_export_json(self._scheduler,
+                                                  self._root_type_ids(),
+                                                  bytes(filename))
+    self._raise_or_return(res)
+
+  def rule_paths(self, subject_type, product_type, max_paths=10000):
+    """Returns the paths through the rule graph by which a product could be computed for a subject.
+
+    Each path begins with an entry that provides the product, and follows dependencies down to an
+    entry that has none. Because the number of paths can be exponential in the size of the rule
+    graph, at most `max_paths` paths are explored.
+
+    :rtype: :class:`RulePaths`
+    """
+    subject_type_id = TypeId(self._to_id(subject_type))
+    product_type_id = TypeConstraint(self._to_key(constraint_for(product_type)))
+    paths, truncated = self._from_value(
+      self._native.lib.rule_graph_rule_paths(self._scheduler,
+                                             subject_type_id,
+                                             product_type_id,
+                                             max_paths))
+    return RulePaths(paths, bool(truncated))
+
+  def unused_rules(self):
+    """Returns the descriptions of the rules that are not used in the rule graph, sorted."""
+    return self._from_value(self._native.lib.rule_graph_unused_rules(self._scheduler))
+
+  def reached_intrinsics(self):
+    """Returns the descriptions of the intrinsics that are reached in the rule graph, sorted."""
+    return self._from_value(self._native.lib.rule_graph_reached_intrinsics(self._scheduler))
+
   def invalidate_files(self, direct_filenames):
     # NB: Watchman no longer triggers events when children are created/deleted under a directory,
     # so we always need to invalidate the direct parent as well.
@@ -295,9 +451,67 @@ class Scheduler(object):
     logger.info('invalidated %d nodes for: %s', invalidated, filenames)
     return invalidated
 
+  def invalidate_globs(self, path_globs):
+    """Invalidates filesystem nodes for paths matched by the given PathGlobs.
+
+    As with `invalidate_files`, the listings of the directories which might contain matched paths
+    are invalidated as well.
+
+    :returns: A dict from node type name to a tuple of the (cleared, dirtied) counts of nodes of
+              that type.
+    """
+    res = self._native.lib.graph_invalidate_globs(self._scheduler, self._to_value(path_globs))
+    counts = self._invalidation_counts(res)
+    logger.info('invalidated %d nodes for: %s', self._invalidated_total(counts), path_globs)
+    return counts
+
+  def invalidate_node_type(self, type_name):
+    """Invalidates all nodes of the given type (e.g. 'ExecuteProcess').
+
+    :returns: A dict like the one returned by `invalidate_globs`.
+    """
+    res = self._native.lib.graph_invalidate_node_type(self._scheduler,
+                                                      self._native.context.utf8_buf(type_name))
+    counts = self._invalidation_counts(res)
+    logger.info('invalidated %d nodes for type: %s', self._invalidated_total(counts), type_name)
+    return counts
+
+  def _invalidation_counts(self, res):
+    return {type_name.decode('utf-8'): (cleared, dirtied)
+            for type_name, cleared, dirtied in self._raise_or_return(res)}
+
+  @staticmethod
+  def _invalidated_total(counts):
+    return sum(cleared + dirtied for cleared, dirtied in counts.values())
+
   def graph_len(self):
     return self._native.lib.graph_len(self._scheduler)
 
+  def await_watcher_invalidation(self, count, timeout_secs):
+    """Waits until the filesystem watcher has applied more than `count` invalidations.
+
+    :param int count: A previous value of the `watcher_invalidation_count` metric.
+    :param float timeout_secs: The maximum number of seconds to wait.
+    :returns: True if an invalidation was applied, or False if the timeout expired (or the
+              filesystem is not watched).
+    """
+    return self._native.lib.graph_await_watcher_invalidation(self._scheduler,
+                                                             count,
+                                                             int(timeout_secs * 1000))
+
+  def persist_graph(self, filename):
+    """Writes persistable Node results to the given file, and returns the number written."""
+    res = self._native.lib.graph_persist(self._scheduler, bytes(filename))
+    return self._raise_or_return(res)
+
+  def load_persisted_graph(self, filename):
+    """Loads Node results written by `persist_graph`, and returns the number loaded.
+
+    Loaded results are validated against the filesystem when they are first requested.
+    """
+    res = self._native.lib.graph_load_persisted(self._scheduler, bytes(filename))
+    return self._raise_or_return(res)
+
   def add_root_selection(self, execution_request, subject, product):
     res = self._native.lib.execution_add_root_select(self._scheduler,
                                                      execution_request,
@@ -312,6 +526,19 @@ class Scheduler(object):
     metrics_val = self._native.lib.scheduler_metrics(self._scheduler, session)
     return {k: v for k, v in self._from_value(metrics_val)}
 
+  def _progress(self, session):
+    counts_val, running_processes_val = self._from_value(
+      self._native.lib.scheduler_progress(self._scheduler, session))
+    counts = {k: v for k, v in counts_val}
+    running_processes = tuple((description, elapsed_micros / 1000000.0)
+                              for description, elapsed_micros in running_processes_val)
+    return ExecutionProgress(running_processes=running_processes, **counts)
+
+  def _critical_path(self, session):
+    critical_path_val = self._native.lib.scheduler_critical_path(self._scheduler, session)
+    return [(node, self_time_micros / 1000000.0)
+            for node, self_time_micros in self._from_value(critical_path_val)]
+
   def pre_fork(self):
     self._native.lib.scheduler_pre_fork(self._scheduler)
 
@@ -347,15 +574,18 @@ class Scheduler(object):
     )
     return self._raise_or_return(result)
 
-  def merge_directories(self, directory_digests):
+  def merge_directories(self, directory_digests, merge_policy='error'):
     """Merges any number of directories.
 
     :param directory_digests: Tuple of DirectoryDigests.
+    :param merge_policy: How to resolve a path which is present in more than one directory: one of
+                         'error', 'allow_identical', 'first_wins' or 'last_wins'.
     :return: A DirectoryDigest.
     """
     result = self._native.lib.merge_directories(
       self._scheduler,
       self._to_value(_DirectoryDigests(directory_digests)),
+      self._to_utf8_buf(merge_policy),
     )
     return self._raise_or_return(result)
 
@@ -391,6 +621,15 @@ class SchedulerSession(object):
   def graph_len(self):
     return self._scheduler.graph_len()
 
+  def await_watcher_invalidation(self, count, timeout_secs):
+    return self._scheduler.await_watcher_invalidation(count, timeout_secs)
+
+  def persist_graph(self, filename):
+    return self._scheduler.persist_graph(filename)
+
+  def load_persisted_graph(self, filename):
+    return self._scheduler.load_persisted_graph(filename)
+
   def trace(self, execution_request):
     """Yields a stringified 'stacktrace' starting from the scheduler's roots."""
     for line in self._scheduler.graph_trace(execution_request.native):
@@ -403,6 +642,35 @@ class SchedulerSession(object):
     """
     self._scheduler.visualize_graph_to_file(self._session, filename)
 
+  def export_graph_json(self, filename, all_nodes=False):
+    """Export the graph as JSON lines: one JSON object per node.
+
+    Each object contains the node's `id`, `type`, formatted `node` key and `state`, the `outcome`,
+    `result`, `generation` and `duration_secs` of its most recent run, and the ids of its
+    `dependencies`.
+
+    :param str filename: The filename to output the JSON lines to.
+    :param bool all_nodes: True to export all nodes in the graph, rather than only those reachable
+                           from the roots of this session.
+    :returns: The number of nodes that were exported.
+    """
+    return self._scheduler.export_graph_json(self._session, filename, all_nodes=all_nodes)
+
+  def export_chrome_trace(self, filename, all_nodes=False):
+    """Export the timings of the most recent runs of nodes in the Chrome trace-event format.
+
+    The resulting file can be opened in `chrome://tracing` (or another trace viewer). Each node is
+    rendered as a span below the spans of the nodes that requested it, and process executions are
+    rendered in lanes of their own. The `args` of each span record whether the node `completed`,
+    and the number of `runs` that it has started.
+
+    :param str filename: The filename to output the trace to.
+    :param bool all_nodes: True to export all nodes in the graph, rather than only those reachable
+                           from the roots of this session.
+    :returns: The number of nodes that were exported.
+    """
+    return self._scheduler.export_chrome_trace(self._session, filename, all_nodes=all_nodes)
+
   def visualize_rule_graph_to_file(self, filename):
     self._scheduler.visualize_rule_graph_to_file(filename)
 
@@ -435,6 +703,18 @@ class SchedulerSession(object):
     self._maybe_visualize()
     return invalidated
 
+  def invalidate_globs(self, path_globs):
+    """Calls `Scheduler.invalidate_globs()` against an internal product Graph instance."""
+    counts = self._scheduler.invalidate_globs(path_globs)
+    self._maybe_visualize()
+    return counts
+
+  def invalidate_node_type(self, type_name):
+    """Calls `Scheduler.invalidate_node_type()` against an internal product Graph instance."""
+    counts = self._scheduler.invalidate_node_type(type_name)
+    self._maybe_visualize()
+    return counts
+
   def node_count(self):
     return self._scheduler.graph_len()
 
@@ -442,6 +722,29 @@ class SchedulerSession(object):
     """Returns metrics for this SchedulerSession as a dict of metric name to metric value."""
     return self._scheduler._metrics(self._session)
 
+  def progress(self):
+    """Returns the progress of the executions in this SchedulerSession.
+
+    This may be called from another thread while the session is executing, in order to render a
+    live progress display.
+
+    :rtype: :class:`ExecutionProgress`
+    """
+    return self._scheduler._progress(self._session)
+
+  def critical_path(self):
+    """Returns the critical path through the nodes executed for the roots of this session.
+
+    The path begins at the root that ran for the longest, and follows the dependency that each
+    node was waiting on when it completed. Each node is only attributed the portion of its run
+    that overlapped with the run of the node before it in the path (a dependency may have been
+    started earlier by some other node), so the self-times of the nodes in the path sum to the
+    duration of the root.
+
+    :returns: A list of tuples of node description and self-time in seconds.
+    """
+    return self._scheduler._critical_path(self._session)
+
   def pre_fork(self):
     self._scheduler.pre_fork()
 
@@ -551,8 +854,8 @@ class SchedulerSession(object):
     """
     return self._scheduler.capture_snapshots(path_globs_and_roots)
 
-  def merge_directories(self, directory_digests):
-    return self._scheduler.merge_directories(directory_digests)
+  def merge_directories(self, directory_digests, merge_policy='error'):
+    return self._scheduler.merge_directories(directory_digests, merge_policy=merge_policy)
 
   def lease_files_in_graph(self):
     self._scheduler.lease_files_in_graph()
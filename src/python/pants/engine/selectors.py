@@ -112,6 +112,20 @@ class Select(datatype(['product', 'optional']), Selector):
                              ', optional=True' if self.optional else '')
 
 
+class SelectUnion(datatype(['product']), Selector):
+  """Selects a tuple of the given Product from every rule that can provide it for the Subject.
+
+  This allows rules to consume products that are contributed by rules that they do not know about
+  (for example, the results of all installed linters). Rules that do not produce a value for the
+  Subject are skipped, so the tuple may be empty. A Subject which is itself an instance of the
+  Product is included first.
+  """
+  optional = False
+
+  def __repr__(self):
+    return '{}({})'.format(type(self).__name__, type_or_constraint_repr(self.product))
+
+
 class SelectVariant(datatype(['product', 'variant_key']), Selector):
   """Selects the matching Product and variant name for the Subject provided to the constructor.
 
@@ -67,6 +67,13 @@ class ExecutionOptions(datatype([
   'remote_store_chunk_bytes',
   'remote_store_chunk_upload_timeout_seconds',
   'process_execution_parallelism',
+  'process_execution_reuse_workdirs',
+  'process_execution_keep_sandboxes',
+  'process_execution_file_cache',
+  'watch_filesystem',
+  'graph_max_entries',
+  'graph_max_memory_mb',
+  'rule_graph_parallelism',
 ])):
   """A collection of all options related to (remote) execution of processes.
 
@@ -83,6 +90,13 @@ class ExecutionOptions(datatype([
       remote_store_chunk_bytes=bootstrap_options.remote_store_chunk_bytes,
       remote_store_chunk_upload_timeout_seconds=bootstrap_options.remote_store_chunk_upload_timeout_seconds,
       process_execution_parallelism=bootstrap_options.process_execution_parallelism,
+      process_execution_reuse_workdirs=bootstrap_options.process_execution_reuse_workdirs,
+      process_execution_keep_sandboxes=bootstrap_options.process_execution_keep_sandboxes,
+      process_execution_file_cache=bootstrap_options.process_execution_file_cache,
+      watch_filesystem=bootstrap_options.watch_filesystem,
+      graph_max_entries=bootstrap_options.graph_max_entries,
+      graph_max_memory_mb=bootstrap_options.graph_max_memory_mb,
+      rule_graph_parallelism=bootstrap_options.rule_graph_parallelism,
     )
 
 
@@ -93,6 +107,13 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_store_chunk_bytes=1024*1024,
     remote_store_chunk_upload_timeout_seconds=60,
     process_execution_parallelism=multiprocessing.cpu_count()*2,
+    process_execution_reuse_workdirs=False,
+    process_execution_keep_sandboxes='never',
+    process_execution_file_cache='off',
+    watch_filesystem=False,
+    graph_max_entries=0,
+    graph_max_memory_mb=0,
+    rule_graph_parallelism=8,
   )
 
 
@@ -275,6 +296,12 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              help='The number of workers to use for the filesystem event service executor pool.')
     register('--pantsd-invalidation-globs', advanced=True, type=list, fromfile=True, default=[],
              help='Filesystem events matching any of these globs will trigger a daemon restart.')
+    register('--pantsd-persist-graph', advanced=True, type=bool, default=False,
+             help='Persist the results of filesystem operations and processes in the product '
+                  'graph periodically and when pantsd exits, and reuse them (after validating them '
+                  'against the filesystem) when it restarts. Validation re-reads and re-hashes '
+                  'every file that a persisted result depends on, so this saves time when the '
+                  'processes that are skipped are slower than re-hashing their inputs.')
 
     # Watchman options.
     register('--watchman-version', advanced=True, default='4.9.0-pants1', help='Watchman version.')
@@ -315,6 +342,39 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--process-execution-parallelism', type=int, default=multiprocessing.cpu_count(),
              advanced=True,
              help='Number of concurrent processes that may be executed either locally and remotely.')
+    register('--process-execution-reuse-workdirs', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_reuse_workdirs,
+             help='Whether to keep the working directories of locally executed processes, and '
+                  'reuse them for later processes by syncing only the inputs which changed. '
+                  'A working directory in which a process modified its inputs is discarded.')
+    register('--process-execution-keep-sandboxes', choices=['never', 'on_failure', 'always'],
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_keep_sandboxes, advanced=True,
+             help='Which sandboxes of locally executed processes to keep for debugging. A kept '
+                  'sandbox contains a __run.sh script which re-runs the process.')
+    register('--process-execution-file-cache', choices=['off', 'reflink', 'reflink_or_hardlink'],
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_file_cache, advanced=True,
+             help='Whether to materialize the inputs of locally executed processes by linking '
+                  'them from an on-disk cache, rather than writing out their content. The cache '
+                  'holds an extra copy of each input. Hardlinked inputs share their content with '
+                  'the cache, so only use reflink_or_hardlink if processes never modify (or '
+                  'change the permissions of) their inputs.')
+    register('--watch-filesystem', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.watch_filesystem,
+             help='Whether the engine should watch the directories that it reads for changes, '
+                  'and invalidate its caches of them directly. Ignored paths are not watched.')
+    register('--graph-max-entries', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.graph_max_entries,
+             help='The maximum number of nodes to keep in the engine\'s graph between runs. When '
+                  'exceeded, the least recently used nodes are evicted. 0 means unlimited.')
+    register('--graph-max-memory-mb', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.graph_max_memory_mb,
+             help='The maximum estimated size (in megabytes) of the engine\'s graph between runs. '
+                  'When exceeded, the least recently used nodes are evicted. The estimate does '
+                  'not include the sizes of python objects. 0 means unlimited.')
+    register('--rule-graph-parallelism', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.rule_graph_parallelism,
+             help='The maximum number of threads used to construct the engine\'s rule graph. 1 '
+                  'constructs it serially.')
 
   @classmethod
   def register_options(cls, register):
@@ -179,12 +179,18 @@ class PantsDaemon(FingerprintedProcessManager):
           'pantsd processes.'
         )
 
+      if bootstrap_options.pantsd_persist_graph:
+        graph_persistence_path = os.path.join(bootstrap_options.pants_workdir, 'pantsd', 'graph')
+      else:
+        graph_persistence_path = None
+
       scheduler_service = SchedulerService(
         fs_event_service,
         legacy_graph_scheduler,
         build_root,
         bootstrap_options.pantsd_invalidation_globs,
         pidfile,
+        graph_persistence_path,
       )
 
       pailgun_service = PailgunService(
@@ -9,10 +9,12 @@ import logging
 import os
 import Queue
 import threading
+import time
 
 from twitter.common.dirutil import Fileset
 
 from pants.pantsd.service.pants_service import PantsService
+from pants.util.dirutil import safe_mkdir
 
 
 class SchedulerService(PantsService):
@@ -24,6 +26,8 @@ class SchedulerService(PantsService):
   """
 
   QUEUE_SIZE = 64
+  # The minimum interval between persisting the product graph after runs have warmed it.
+  PERSIST_INTERVAL_SECONDS = 60
 
   def __init__(
     self,
@@ -32,6 +36,7 @@ class SchedulerService(PantsService):
     build_root,
     invalidation_globs,
     pantsd_pidfile,
+    graph_persistence_path=None,
   ):
     """
     :param FSEventService fs_event_service: An unstarted FSEventService instance for setting up
@@ -41,6 +46,8 @@ class SchedulerService(PantsService):
     :param str build_root: The current build root.
     :param list invalidation_globs: A list of `globs` that when encountered in filesystem event
                                     subscriptions will tear down the daemon.
+    :param str graph_persistence_path: If set, a file to persist the product graph to periodically
+                                       and on exit, and to load it from on startup.
     """
     super(SchedulerService, self).__init__()
     self._fs_event_service = fs_event_service
@@ -48,6 +55,9 @@ class SchedulerService(PantsService):
     self._invalidation_globs = invalidation_globs
     self._build_root = build_root
     self._pantsd_pidfile = pantsd_pidfile
+    self._graph_persistence_path = graph_persistence_path
+    self._graph_dirty = False
+    self._last_persisted = 0
 
     self._scheduler = legacy_graph_scheduler.scheduler
     self._logger = logging.getLogger(__name__)
@@ -77,6 +87,31 @@ class SchedulerService(PantsService):
     if self._pantsd_pidfile:
       self._fs_event_service.register_pidfile_handler(self._pantsd_pidfile, self._enqueue_fs_event)
 
+    if self._graph_persistence_path and os.path.exists(self._graph_persistence_path):
+      try:
+        loaded = self._scheduler.load_persisted_graph(self._graph_persistence_path)
+        self._logger.info('loaded {} persisted graph entries'.format(loaded))
+      except Exception as e:
+        self._logger.warning('failed to load persisted graph: {!r}'.format(e))
+
+  def _maybe_persist_graph(self):
+    """Persists the product graph if a run has warmed it since it was last persisted."""
+    if self._graph_dirty and time.time() - self._last_persisted >= self.PERSIST_INTERVAL_SECONDS:
+      self._persist_graph()
+
+  def _persist_graph(self):
+    if not self._graph_persistence_path:
+      return
+    self._graph_dirty = False
+    self._last_persisted = time.time()
+    try:
+      safe_mkdir(os.path.dirname(self._graph_persistence_path))
+      with self.fork_lock:
+        persisted = self._scheduler.persist_graph(self._graph_persistence_path)
+      self._logger.info('persisted {} graph entries'.format(persisted))
+    except Exception as e:
+      self._logger.warning('failed to persist graph: {!r}'.format(e))
+
   def _enqueue_fs_event(self, event):
     """Watchman filesystem event handler for BUILD/requirements.txt updates. Called via a thread."""
     self._logger.info('enqueuing {} changes for subscription {}'
@@ -179,9 +214,12 @@ class SchedulerService(PantsService):
         tags=tuple(options.for_global_scope().tag) if options.for_global_scope().tag else tuple()
       )
       session.warm_product_graph(target_roots)
+      self._graph_dirty = True
       return session, target_roots
 
   def run(self):
     """Main service entrypoint."""
     while not self.is_killed:
       self._process_event_queue()
+      self._maybe_persist_graph()
+    self._persist_graph()
@@ -120,6 +120,27 @@ to this directory.",
               .arg(Arg::with_name("size_bytes").required(true).takes_value(
                 true,
               )),
+          )
+          .subcommand(
+            SubCommand::with_name("diff")
+              .about(
+                "Output the files and symlinks which differ between two Directories addressed by \
+fingerprint. Each line is one of `added <path> <entry>`, `removed <path> <entry>` or `modified \
+<path> <before entry> <after entry>`, where each entry is either `file <fingerprint> <size_bytes> \
+<is_executable>` or `symlink <target>`.",
+              )
+              .arg(Arg::with_name("before_fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("before_size_bytes").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("after_fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("after_size_bytes").required(true).takes_value(
+                true,
+              )),
           ),
       )
       .subcommand(
@@ -324,6 +345,46 @@ fn execute(top_match: clap::ArgMatches) -> Result<(), ExitError> {
           )),
         }
       }
+      ("diff", Some(args)) => {
+        let before = Digest(
+          Fingerprint::from_hex_string(args.value_of("before_fingerprint").unwrap())?,
+          args
+            .value_of("before_size_bytes")
+            .unwrap()
+            .parse::<usize>()
+            .expect("before_size_bytes must be a non-negative number"),
+        );
+        let after = Digest(
+          Fingerprint::from_hex_string(args.value_of("after_fingerprint").unwrap())?,
+          args
+            .value_of("after_size_bytes")
+            .unwrap()
+            .parse::<usize>()
+            .expect("after_size_bytes must be a non-negative number"),
+        );
+        let diff = store
+          .diff_directories(before, after)
+          .wait()?
+          .ok_or_else(|| {
+            ExitError(
+              format!(
+                "Directory with digest {:?} or {:?} (or one of their sub-directories) not found",
+                before, after
+              ),
+              ExitCode::NotFound,
+            )
+          })?;
+        for (path, entry) in diff.added {
+          println!("added {} {}", path.display(), entry);
+        }
+        for (path, entry) in diff.removed {
+          println!("removed {} {}", path.display(), entry);
+        }
+        for (path, before, after) in diff.modified {
+          println!("modified {} {} {}", path.display(), before, after);
+        }
+        Ok(())
+      }
       (_, _) => unimplemented!(),
     },
     ("cat", Some(args)) => {
@@ -0,0 +1,603 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use boxfuture::{BoxFuture, Boxable};
+use hashing::Digest;
+use libc;
+use tempfile;
+
+use pool::ResettablePool;
+
+///
+/// How (and whether) files are materialized via a FileCache.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileCacheMode {
+  // Files are materialized by writing out their content, without a cache.
+  Off,
+  // Files are reflinked from the cache where the destination filesystem supports it, and their
+  // content is written out otherwise.
+  Reflink,
+  // As for Reflink, but files are hardlinked from the cache where reflinks are unsupported.
+  // Hardlinked files share their inode with the cache, so they must never be modified (or have
+  // their permissions changed) in place.
+  ReflinkOrHardlink,
+}
+
+impl FileCacheMode {
+  pub fn create(mode: &str) -> Result<FileCacheMode, String> {
+    match mode {
+      "off" => Ok(FileCacheMode::Off),
+      "reflink" => Ok(FileCacheMode::Reflink),
+      "reflink_or_hardlink" => Ok(FileCacheMode::ReflinkOrHardlink),
+      _ => Err(format!("Unrecognized file cache mode: {}.", mode)),
+    }
+  }
+}
+
+///
+/// An on-disk cache of immutable files, keyed by Digest and executable bit, from which files can
+/// be materialized without writing out their content again.
+///
+/// A file is materialized from the cache by (in order of preference):
+///  1. Reflinking (copy-on-write cloning) it, where the filesystem supports it. The result is an
+///     independent, writable file which shares its blocks with the cached copy.
+///  2. Hardlinking it, if the FileCacheMode allows it.
+///  3. Writing out its content, bypassing the cache.
+///
+/// Which of these works is a property of the filesystem of the destination, so it is recorded
+/// per device the first time that a link fails, rather than being retried for every file. In
+/// particular, destinations on a different device than the cache can never be linked to, so
+/// their files are written out directly, without also being copied into the cache.
+///
+/// Files are written into the cache under a temporary name, made read-only, and then atomically
+/// renamed into place, so a cached file is always complete and is never modified.
+///
+#[derive(Clone)]
+pub struct FileCache {
+  root: PathBuf,
+  root_device: u64,
+  pool: Arc<ResettablePool>,
+  allow_hardlinks: bool,
+  // The known LinkSupport of destination devices, which are assumed to support reflinks until
+  // one fails.
+  link_support: Arc<Mutex<HashMap<u64, LinkSupport>>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LinkSupport {
+  Reflink,
+  Hardlink,
+  None,
+}
+
+// The outcome of attempting to link a cached file to a destination.
+enum LinkResult {
+  Linked,
+  // The cached file did not exist (possibly because it was garbage collected concurrently).
+  SourceMissing,
+  // Linking is not supported for the destination: its content must be written out instead.
+  Unsupported,
+}
+
+impl FileCache {
+  pub fn new<P: AsRef<Path>>(
+    root: P,
+    pool: Arc<ResettablePool>,
+    mode: FileCacheMode,
+  ) -> Result<FileCache, String> {
+    let root = root.as_ref().to_owned();
+    super::safe_create_dir_all(&root)?;
+    let root_device = fs::metadata(&root)
+      .map_err(|e| format!("Error reading metadata of file cache {:?}: {:?}", root, e))?
+      .dev();
+    Ok(FileCache {
+      root,
+      root_device,
+      pool,
+      allow_hardlinks: mode == FileCacheMode::ReflinkOrHardlink,
+      link_support: Arc::new(Mutex::new(HashMap::new())),
+    })
+  }
+
+  ///
+  /// Materializes the file with the given Digest at `destination` if it is present in the cache
+  /// and can be linked there, returning false otherwise.
+  ///
+  pub fn materialize_if_cached(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+    destination: PathBuf,
+  ) -> BoxFuture<bool, String> {
+    let file_cache = self.clone();
+    self
+      .pool
+      .spawn_fn(move || -> Result<bool, String> {
+        let cached_path = file_cache.path_for(digest, is_executable);
+        match file_cache.link(&cached_path, &destination, is_executable)? {
+          LinkResult::Linked => Ok(true),
+          LinkResult::SourceMissing | LinkResult::Unsupported => Ok(false),
+        }
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Adds the given content to the cache (if it is not already present), and materializes it at
+  /// `destination`. If the destination cannot be linked to, the content is written out directly
+  /// instead, and not added to the cache. This blocks on disk IO, so is expected to be called from
+  /// a pool thread (e.g. from within `Store::load_file_bytes_with`).
+  ///
+  pub fn insert_and_materialize(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+    bytes: &[u8],
+    destination: &Path,
+  ) -> Result<(), String> {
+    let cached_path = self.path_for(digest, is_executable);
+    // The cached file might be garbage collected between being inserted and being linked, so
+    // re-insert it once if it goes missing.
+    for _ in 0..2 {
+      if self.destination_link_support(destination)? == LinkSupport::None {
+        break;
+      }
+      if !cached_path.exists() {
+        self.insert(&cached_path, is_executable, bytes)?;
+      }
+      match self.link(&cached_path, destination, is_executable)? {
+        LinkResult::Linked => return Ok(()),
+        LinkResult::SourceMissing => continue,
+        LinkResult::Unsupported => break,
+      }
+    }
+    write_file(destination, bytes, is_executable)
+  }
+
+  ///
+  /// Removes any cached files which are not currently hardlinked into a materialized directory.
+  ///
+  /// Reflinked files are independent of the cached copy, so they are unaffected. Concurrent
+  /// materializations which find that a cached file has been removed re-populate it.
+  ///
+  pub fn garbage_collect(&self) -> Result<(), String> {
+    let shards = fs::read_dir(&self.root)
+      .map_err(|e| format!("Error listing file cache {:?}: {:?}", self.root, e))?;
+    for shard in shards {
+      let shard = shard.map_err(|e| format!("Error listing file cache {:?}: {:?}", self.root, e))?;
+      let entries = fs::read_dir(shard.path())
+        .map_err(|e| format!("Error listing file cache shard {:?}: {:?}", shard.path(), e))?;
+      for entry in entries {
+        let path = entry
+          .map_err(|e| format!("Error listing file cache shard {:?}: {:?}", shard.path(), e))?
+          .path();
+        let in_use = fs::symlink_metadata(&path)
+          .map(|metadata| metadata.nlink() > 1)
+          .unwrap_or(true);
+        if !in_use {
+          // Another process may be racing to remove the same file, so ignore NotFound.
+          match fs::remove_file(&path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("Error removing cached file {:?}: {:?}", path, e)),
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn path_for(&self, digest: Digest, is_executable: bool) -> PathBuf {
+    let hex = digest.0.to_hex();
+    self.root.join(&hex[0..2]).join(format!(
+      "{}-{}{}",
+      hex,
+      digest.1,
+      if is_executable { "-x" } else { "" }
+    ))
+  }
+
+  fn insert(&self, cached_path: &Path, is_executable: bool, bytes: &[u8]) -> Result<(), String> {
+    let shard = cached_path.parent().unwrap();
+    super::safe_create_dir_all(shard)?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(shard)
+      .map_err(|e| format!("Error creating temporary file in {:?}: {:?}", shard, e))?;
+    temp_file
+      .write_all(bytes)
+      .and_then(|()| {
+        let mut permissions = temp_file.as_file().metadata()?.permissions();
+        permissions.set_mode(if is_executable { 0o555 } else { 0o444 });
+        temp_file.as_file().set_permissions(permissions)
+      })
+      .map_err(|e| format!("Error writing cached file {:?}: {:?}", cached_path, e))?;
+    // If another thread or process won the race to insert this file, its content is identical.
+    temp_file
+      .persist(cached_path)
+      .map(|_| ())
+      .map_err(|e| format!("Error persisting cached file {:?}: {:?}", cached_path, e))
+  }
+
+  fn destination_device(destination: &Path) -> Result<u64, String> {
+    let parent = destination.parent().unwrap_or_else(|| Path::new("/"));
+    fs::metadata(parent)
+      .map(|metadata| metadata.dev())
+      .map_err(|e| format!("Error reading metadata of {:?}: {:?}", parent, e))
+  }
+
+  fn destination_link_support(&self, destination: &Path) -> Result<LinkSupport, String> {
+    let device = FileCache::destination_device(destination)?;
+    if device != self.root_device {
+      return Ok(LinkSupport::None);
+    }
+    Ok(
+      self
+        .link_support
+        .lock()
+        .unwrap()
+        .get(&device)
+        .cloned()
+        .unwrap_or(LinkSupport::Reflink),
+    )
+  }
+
+  fn downgrade_link_support(&self, destination: &Path, support: LinkSupport) {
+    if let Ok(device) = FileCache::destination_device(destination) {
+      self.link_support.lock().unwrap().insert(device, support);
+    }
+  }
+
+  fn link(
+    &self,
+    cached_path: &Path,
+    destination: &Path,
+    is_executable: bool,
+  ) -> Result<LinkResult, String> {
+    let mut support = self.destination_link_support(destination)?;
+    if support == LinkSupport::Reflink {
+      match reflink(cached_path, destination, is_executable) {
+        Ok(()) => return Ok(LinkResult::Linked),
+        Err(ReflinkError::SourceMissing) => return Ok(LinkResult::SourceMissing),
+        Err(ReflinkError::Unsupported) => {
+          support = if self.allow_hardlinks {
+            LinkSupport::Hardlink
+          } else {
+            LinkSupport::None
+          };
+          self.downgrade_link_support(destination, support);
+        }
+        Err(ReflinkError::Failed) => {
+          if !self.allow_hardlinks {
+            return Ok(LinkResult::Unsupported);
+          }
+          support = LinkSupport::Hardlink;
+        }
+      }
+    }
+    if support != LinkSupport::Hardlink {
+      return Ok(LinkResult::Unsupported);
+    }
+    match fs::hard_link(cached_path, destination) {
+      Ok(()) => Ok(LinkResult::Linked),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound && !cached_path.exists() => {
+        Ok(LinkResult::SourceMissing)
+      }
+      Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+        self.downgrade_link_support(destination, LinkSupport::None);
+        Ok(LinkResult::Unsupported)
+      }
+      Err(_) => Ok(LinkResult::Unsupported),
+    }
+  }
+}
+
+///
+/// Writes the given content to a new file at `destination`.
+///
+pub fn write_file(destination: &Path, bytes: &[u8], is_executable: bool) -> Result<(), String> {
+  OpenOptions::new()
+    .create(true)
+    .write(true)
+    .mode(if is_executable { 0o755 } else { 0o644 })
+    .open(destination)
+    .and_then(|mut f| f.write_all(bytes))
+    .map_err(|e| format!("Error writing file {:?}: {:?}", destination, e))
+}
+
+enum ReflinkError {
+  // The cached file does not exist.
+  SourceMissing,
+  // The destination filesystem (or platform) does not support reflinks from the cache, so there
+  // is no point in retrying for other files.
+  Unsupported,
+  // Reflinking this particular file failed, but others might succeed.
+  Failed,
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path, is_executable: bool) -> Result<(), ReflinkError> {
+  use std::os::unix::io::AsRawFd;
+
+  // From linux/fs.h: _IOW(0x94, 9, int).
+  const FICLONE: libc::c_ulong = 0x4004_9409;
+
+  // Once the source is open, it can be read even if it is garbage collected concurrently.
+  let source_file = fs::File::open(source).map_err(|e| {
+    if e.kind() == io::ErrorKind::NotFound {
+      ReflinkError::SourceMissing
+    } else {
+      ReflinkError::Failed
+    }
+  })?;
+  let destination_file = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(if is_executable { 0o755 } else { 0o644 })
+    .open(destination)
+    .map_err(|_| ReflinkError::Failed)?;
+  let result = unsafe {
+    libc::ioctl(
+      destination_file.as_raw_fd(),
+      FICLONE,
+      source_file.as_raw_fd(),
+    )
+  };
+  if result == 0 {
+    return Ok(());
+  }
+  let error = io::Error::last_os_error();
+  drop(destination_file);
+  let _ = fs::remove_file(destination);
+  match error.raw_os_error() {
+    Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) | Some(libc::EXDEV) => {
+      Err(ReflinkError::Unsupported)
+    }
+    _ => Err(ReflinkError::Failed),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path, _is_executable: bool) -> Result<(), ReflinkError> {
+  Err(ReflinkError::Unsupported)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{FileCache, FileCacheMode, LinkSupport};
+  use futures::Future;
+  use hashing::Digest;
+  use pool::ResettablePool;
+  use std::fs;
+  use std::io::{Read, Write};
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
+  use std::path::Path;
+  use std::sync::Arc;
+  use tempfile::TempDir;
+  use testutil::data::TestData;
+
+  fn contents(path: &Path) -> String {
+    let mut contents = String::new();
+    fs::File::open(path)
+      .and_then(|mut f| f.read_to_string(&mut contents))
+      .expect("Error reading file");
+    contents
+  }
+
+  fn new_cache(root: &Path, mode: FileCacheMode) -> FileCache {
+    FileCache::new(
+      root,
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      mode,
+    ).unwrap()
+  }
+
+  fn cached_file_count(root: &Path) -> usize {
+    fs::read_dir(root)
+      .unwrap()
+      .flat_map(|shard| fs::read_dir(shard.unwrap().path()).unwrap())
+      .count()
+  }
+
+  fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+      .expect("Getting metadata")
+      .permissions()
+      .mode() & 0o100 == 0o100
+  }
+
+  #[test]
+  fn materialize_if_cached_miss() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path(), FileCacheMode::ReflinkOrHardlink);
+
+    let destination = materialize_dir.path().join("roland");
+    assert_eq!(
+      cache
+        .materialize_if_cached(TestData::roland().digest(), false, destination.clone())
+        .wait(),
+      Ok(false)
+    );
+    assert!(!destination.exists());
+  }
+
+  #[test]
+  fn insert_then_materialize_from_cache() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path(), FileCacheMode::ReflinkOrHardlink);
+    let roland = TestData::roland();
+
+    let first = materialize_dir.path().join("first");
+    cache
+      .insert_and_materialize(roland.digest(), true, &roland.bytes(), &first)
+      .expect("Error inserting");
+    let second = materialize_dir.path().join("second");
+    assert_eq!(
+      cache
+        .materialize_if_cached(roland.digest(), true, second.clone())
+        .wait(),
+      Ok(true)
+    );
+
+    for path in vec![first, second] {
+      assert_eq!(contents(&path), roland.string());
+      assert!(is_executable(&path));
+    }
+    // Executability is part of the key, because hardlinks share their permissions.
+    assert_eq!(
+      cache
+        .materialize_if_cached(roland.digest(), false, materialize_dir.path().join("third"))
+        .wait(),
+      Ok(false)
+    );
+  }
+
+  #[test]
+  fn garbage_collect_keeps_linked_files() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path(), FileCacheMode::ReflinkOrHardlink);
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+
+    let roland_path = materialize_dir.path().join("roland");
+    let catnip_path = materialize_dir.path().join("catnip");
+    cache
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &roland_path)
+      .unwrap();
+    cache
+      .insert_and_materialize(catnip.digest(), false, &catnip.bytes(), &catnip_path)
+      .unwrap();
+    fs::remove_file(&catnip_path).unwrap();
+
+    cache.garbage_collect().expect("Error collecting garbage");
+
+    let other_dir = TempDir::new().unwrap();
+    let cached = |digest: Digest| {
+      cache
+        .materialize_if_cached(digest, false, other_dir.path().join(digest.0.to_hex()))
+        .wait()
+        .unwrap()
+    };
+    assert!(!cached(catnip.digest()));
+    // Whether roland survives depends on whether it was hardlinked or reflinked, but it must
+    // still be readable where it was materialized.
+    assert_eq!(contents(&roland_path), roland.string());
+  }
+
+  #[test]
+  fn materialize_after_garbage_collection_repopulates() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path(), FileCacheMode::ReflinkOrHardlink);
+    let roland = TestData::roland();
+
+    let first = materialize_dir.path().join("first");
+    cache
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &first)
+      .unwrap();
+    fs::remove_file(&first).unwrap();
+    cache.garbage_collect().expect("Error collecting garbage");
+
+    // A collected file is a cache miss rather than an error, and is re-inserted on demand.
+    let second = materialize_dir.path().join("second");
+    assert_eq!(
+      cache
+        .materialize_if_cached(roland.digest(), false, second.clone())
+        .wait(),
+      Ok(false)
+    );
+    cache
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &second)
+      .unwrap();
+    assert_eq!(contents(&second), roland.string());
+  }
+
+  #[test]
+  fn reflink_mode_materializes_independent_files() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path(), FileCacheMode::Reflink);
+    let roland = TestData::roland();
+
+    let first = materialize_dir.path().join("first");
+    cache
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &first)
+      .unwrap();
+    // Without hardlinks, materialized files never share an inode with the cache, so they can be
+    // modified without affecting later materializations.
+    assert_eq!(fs::metadata(&first).unwrap().nlink(), 1);
+    fs::File::create(&first)
+      .and_then(|mut f| f.write_all(b"modified"))
+      .unwrap();
+
+    let second = materialize_dir.path().join("second");
+    cache
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &second)
+      .unwrap();
+    assert_eq!(contents(&second), roland.string());
+  }
+
+  #[test]
+  fn unlinkable_destinations_bypass_the_cache() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let cache = new_cache(cache_dir.path(), FileCacheMode::ReflinkOrHardlink);
+    let roland = TestData::roland();
+
+    let destination = materialize_dir.path().join("roland");
+    // As if a link to the destination's device had failed with EXDEV.
+    cache.downgrade_link_support(&destination, LinkSupport::None);
+    cache
+      .insert_and_materialize(roland.digest(), true, &roland.bytes(), &destination)
+      .unwrap();
+
+    assert_eq!(contents(&destination), roland.string());
+    assert!(is_executable(&destination));
+    assert_eq!(cached_file_count(cache_dir.path()), 0);
+  }
+
+  #[test]
+  fn destinations_on_other_devices_bypass_the_cache() {
+    let cache_dir = TempDir::new().unwrap();
+    let materialize_dir = TempDir::new().unwrap();
+    let same_device = new_cache(cache_dir.path(), FileCacheMode::ReflinkOrHardlink);
+    // As if the cache were on a different device than the destination.
+    let cache = FileCache {
+      root_device: same_device.root_device.wrapping_add(1),
+      ..same_device.clone()
+    };
+    let roland = TestData::roland();
+
+    let destination = materialize_dir.path().join("roland");
+    cache
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &destination)
+      .unwrap();
+    assert_eq!(contents(&destination), roland.string());
+    assert_eq!(fs::metadata(&destination).unwrap().nlink(), 1);
+    assert_eq!(cached_file_count(cache_dir.path()), 0);
+    assert_eq!(
+      cache
+        .materialize_if_cached(roland.digest(), false, materialize_dir.path().join("again"))
+        .wait(),
+      Ok(false)
+    );
+
+    // A device mismatch isn't recorded as a lack of link support, so destinations on the cache's
+    // own device are still linked.
+    let linked = materialize_dir.path().join("linked");
+    same_device
+      .insert_and_materialize(roland.digest(), false, &roland.bytes(), &linked)
+      .unwrap();
+    assert_eq!(contents(&linked), roland.string());
+    assert_eq!(cached_file_count(cache_dir.path()), 1);
+  }
+}
@@ -348,7 +348,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
                   PathGlob::parse_globs(stat, path, &remainder)
                     .map_err(|e| Self::mk_error(e.as_str()))
                 ),
-              PathStat::File { .. } => None,
+              PathStat::File { .. } | PathStat::Link { .. } => None,
             })
             .collect::<Result<Vec<_>, E>>()
         })
@@ -392,6 +392,7 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
         path_stats.pop().map(|ps| match ps {
           PathStat::Dir { stat, .. } => PathStat::dir(symbolic_path, stat),
           PathStat::File { stat, .. } => PathStat::file(symbolic_path, stat),
+          PathStat::Link { stat, target, .. } => PathStat::link(symbolic_path, stat, target),
         })
       })
       .to_boxed()
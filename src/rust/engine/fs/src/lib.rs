@@ -1,15 +1,19 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+mod file_cache;
+pub use file_cache::FileCacheMode;
 mod glob_matching;
 pub use glob_matching::GlobMatching;
 mod snapshot;
-pub use snapshot::{OneOffStoreFileByDigest, Snapshot, StoreFileByDigest, EMPTY_DIGEST,
-                   EMPTY_FINGERPRINT};
+pub use snapshot::{MergePolicy, OneOffStoreFileByDigest, Snapshot, StoreFileByDigest,
+                   EMPTY_DIGEST, EMPTY_FINGERPRINT};
 mod store;
-pub use store::Store;
+pub use store::{DiffEntry, DirectoryDiff, Store, TransferCounts};
 mod pool;
 pub use pool::ResettablePool;
+mod watch;
+pub use watch::{Invalidation, InvalidationWatcher};
 
 extern crate bazel_protos;
 #[macro_use]
@@ -28,11 +32,13 @@ extern crate indexmap;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate lmdb;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
 extern crate mock;
+extern crate notify;
 extern crate protobuf;
 extern crate resettable;
 extern crate sha2;
@@ -45,6 +51,7 @@ use std::io::{self, Read};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, fs};
 
 use bytes::Bytes;
@@ -97,6 +104,15 @@ pub enum PathStat {
     // The canonical Stat that underlies the Path.
     stat: File,
   },
+  Link {
+    // The symbolic name of some filesystem Path, which is context specific.
+    path: PathBuf,
+    // The Link itself: unlike Dirs and Files, Links are only represented as PathStats when they
+    // are being preserved rather than expanded (for example, when capturing process outputs).
+    stat: Link,
+    // The literal (uncanonicalized) destination of the Link.
+    target: PathBuf,
+  },
 }
 
 impl PathStat {
@@ -114,10 +130,19 @@ impl PathStat {
     }
   }
 
+  pub fn link(path: PathBuf, stat: Link, target: PathBuf) -> PathStat {
+    PathStat::Link {
+      path: path,
+      stat: stat,
+      target: target,
+    }
+  }
+
   pub fn path(&self) -> &Path {
     match self {
       &PathStat::Dir { ref path, .. } => path.as_path(),
       &PathStat::File { ref path, .. } => path.as_path(),
+      &PathStat::Link { ref path, .. } => path.as_path(),
     }
   }
 }
@@ -160,7 +185,11 @@ impl GitignoreStyleExcludes {
       &Stat::Dir(_) => true,
       _ => false,
     };
-    match self.gitignore.matched(stat.path(), is_dir) {
+    self.is_ignored_path(stat.path(), is_dir)
+  }
+
+  fn is_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
+    match self.gitignore.matched(path, is_dir) {
       ignore::Match::None | ignore::Match::Whitelist(_) => false,
       ignore::Match::Ignore(_) => true,
     }
@@ -302,6 +331,91 @@ impl PathGlob {
     PathGlob::parse_globs(canonical_dir, symbolic_path, &parts)
   }
 
+  ///
+  /// Returns true if the given path (relative to the root) would be matched by this PathGlob,
+  /// without consulting the filesystem. Because links are not expanded, a path will only match
+  /// if it is matched via its canonical (rather than symbolic) name.
+  ///
+  fn matches(&self, path: &Path) -> bool {
+    match self {
+      &PathGlob::Wildcard {
+        ref canonical_dir,
+        ref wildcard,
+        ..
+      } => {
+        path.parent() == Some(canonical_dir.0.as_path())
+          && path
+            .file_name()
+            .map(|file_name| wildcard.matches_path(Path::new(file_name)))
+            .unwrap_or(false)
+      }
+      &PathGlob::DirWildcard {
+        ref canonical_dir,
+        ref symbolic_path,
+        ref wildcard,
+        ref remainder,
+      } => {
+        let mut components = match path.strip_prefix(&canonical_dir.0) {
+          Ok(relative_path) => relative_path.components(),
+          Err(_) => return false,
+        };
+        let dir_name = match components.next() {
+          Some(Component::Normal(dir_name)) => dir_name,
+          _ => return false,
+        };
+        if components.next().is_none() || !wildcard.matches_path(Path::new(dir_name)) {
+          return false;
+        }
+        // Expand the remainder within the matched directory, as glob expansion would.
+        PathGlob::parse_globs(
+          Dir(canonical_dir.0.join(dir_name)),
+          symbolic_path.join(dir_name),
+          remainder,
+        ).map(|globs| globs.iter().any(|glob| glob.matches(path)))
+          .unwrap_or(false)
+      }
+    }
+  }
+
+  ///
+  /// Returns true if this PathGlob might match a path directly inside the given directory
+  /// (relative to the root), without consulting the filesystem: see `matches`.
+  ///
+  fn matches_in_dir(&self, dir: &Path) -> bool {
+    match self {
+      &PathGlob::Wildcard {
+        ref canonical_dir, ..
+      } => canonical_dir.0.as_path() == dir,
+      &PathGlob::DirWildcard {
+        ref canonical_dir,
+        ref symbolic_path,
+        ref wildcard,
+        ref remainder,
+      } => {
+        let mut components = match dir.strip_prefix(&canonical_dir.0) {
+          Ok(relative_dir) => relative_dir.components(),
+          Err(_) => return false,
+        };
+        let dir_name = match components.next() {
+          Some(Component::Normal(dir_name)) => dir_name,
+          // The wildcard matches directories directly inside of the canonical_dir, so (re)creating
+          // one of them changes the listing of the canonical_dir.
+          None => return true,
+          _ => return false,
+        };
+        if !wildcard.matches_path(Path::new(dir_name)) {
+          return false;
+        }
+        PathGlob::parse_globs(
+          Dir(canonical_dir.0.join(dir_name)),
+          symbolic_path.join(dir_name),
+          remainder,
+        ).map(|globs| globs.iter().any(|glob| glob.matches_in_dir(dir)))
+          .unwrap_or(false)
+      }
+    }
+  }
+
   ///
   /// Given a filespec as Patterns, create a series of PathGlob objects.
   ///
@@ -449,6 +563,33 @@ impl PathGlobs {
     })
   }
 
+  ///
+  /// Returns true if the given path (relative to the root) would be matched by these PathGlobs,
+  /// without consulting the filesystem: see `PathGlob::matches`.
+  ///
+  /// Since it is not known whether the path is a directory, exclude patterns which apply only to
+  /// directories are not applied.
+  ///
+  pub fn matches(&self, path: &Path) -> bool {
+    self
+      .include
+      .iter()
+      .any(|entry| entry.globs.iter().any(|glob| glob.matches(path)))
+      && !self.exclude.is_ignored_path(path, false)
+  }
+
+  ///
+  /// Returns true if these PathGlobs might match a path directly inside the given directory
+  /// (relative to the root), and so if the listing of the directory might change when the paths
+  /// that they match do. Excludes are not applied, since the names of the paths are not known.
+  ///
+  pub fn matches_in_dir(&self, dir: &Path) -> bool {
+    self
+      .include
+      .iter()
+      .any(|entry| entry.globs.iter().any(|glob| glob.matches_in_dir(dir)))
+  }
+
   pub fn from_globs(include: Vec<PathGlob>) -> Result<PathGlobs, String> {
     let include = include
       .into_iter()
@@ -481,6 +622,7 @@ pub struct PosixFS {
   root: Dir,
   pool: Arc<ResettablePool>,
   ignore: Arc<GitignoreStyleExcludes>,
+  watcher: Option<Arc<InvalidationWatcher>>,
 }
 
 impl PosixFS {
@@ -516,9 +658,75 @@ impl PosixFS {
       root: canonical_root,
       pool: pool,
       ignore: ignore,
+      watcher: None,
     })
   }
 
+  ///
+  /// Watches each directory that is scanned by this PosixFS, and calls `invalidate` with the
+  /// (relative, non-ignored) paths that change within them: see `InvalidationWatcher`.
+  ///
+  pub fn with_watcher<F>(mut self, debounce: Duration, invalidate: F) -> Result<PosixFS, String>
+  where
+    F: Fn(Invalidation) + Send + 'static,
+  {
+    let watcher =
+      InvalidationWatcher::new(&self.root.0, self.ignore.clone(), debounce, invalidate)?;
+    self.watcher = Some(Arc::new(watcher));
+    Ok(self)
+  }
+
+  ///
+  /// Pauses the watcher (if any) before forking: see `InvalidationWatcher::pause_prefork`.
+  ///
+  pub fn pause_watcher_prefork(&self) {
+    if let Some(ref watcher) = self.watcher {
+      watcher.pause_prefork();
+    }
+  }
+
+  ///
+  /// Resumes the watcher (if any) after `pause_watcher_prefork`.
+  ///
+  pub fn resume_watcher(&self) {
+    if let Some(ref watcher) = self.watcher {
+      watcher.resume();
+    }
+  }
+
+  ///
+  /// Applies the invalidations for changes that the watcher (if any) has been notified of: see
+  /// `InvalidationWatcher::flush`.
+  ///
+  pub fn flush_watcher(&self) {
+    if let Some(ref watcher) = self.watcher {
+      watcher.flush();
+    }
+  }
+
+  ///
+  /// The number of invalidations that the watcher has applied, or 0 if there is no watcher.
+  ///
+  pub fn watcher_invalidation_count(&self) -> u64 {
+    self
+      .watcher
+      .as_ref()
+      .map(|watcher| watcher.invalidation_count())
+      .unwrap_or(0)
+  }
+
+  ///
+  /// Waits for the watcher to apply an invalidation: see `InvalidationWatcher::await_invalidation`.
+  /// Returns false if there is no watcher.
+  ///
+  pub fn await_watcher_invalidation(&self, count: u64, timeout: Duration) -> bool {
+    self
+      .watcher
+      .as_ref()
+      .map(|watcher| watcher.await_invalidation(count, timeout))
+      .unwrap_or(false)
+  }
+
   fn scandir_sync(root: PathBuf, dir_relative_to_root: Dir) -> Result<Vec<Stat>, io::Error> {
     let dir_abs = root.join(&dir_relative_to_root.0);
     let mut stats: Vec<Stat> = dir_abs
@@ -651,11 +859,66 @@ impl PosixFS {
   pub fn scandir(&self, dir: &Dir) -> BoxFuture<Vec<Stat>, io::Error> {
     let dir = dir.to_owned();
     let root = self.root.0.clone();
+    let watcher = self.watcher.clone();
     self
       .pool
-      .spawn_fn(move || PosixFS::scandir_sync(root, dir))
+      .spawn_fn(move || {
+        // Watch before scanning, so that no changes are missed in between. A failure to watch
+        // does not fail the scan: instead the watcher invalidates everything before each run.
+        if let Some(ref watcher) = watcher {
+          if let Err(e) = watcher.watch(&dir) {
+            warn!("{}", e);
+          }
+        }
+        PosixFS::scandir_sync(root, dir).map(|stats| {
+          if let Some(ref watcher) = watcher {
+            watcher.listed(&stats);
+          }
+          stats
+        })
+      })
       .to_boxed()
   }
+
+  ///
+  /// Returns PathStats for the given path and everything beneath it, without following symlinks:
+  /// Links are returned as PathStat::Link with their literal targets, and Dirs (including empty
+  /// ones) are returned alongside their contents. Returns None if the path does not exist.
+  ///
+  /// Unlike glob expansion, this preserves the on-disk layout exactly, which is what we want when
+  /// capturing the outputs of a process.
+  ///
+  pub fn walk_preserving_links(
+    &self,
+    relative_path: PathBuf,
+  ) -> BoxFuture<Option<Vec<PathStat>>, io::Error> {
+    let root = self.root.0.clone();
+    self
+      .pool
+      .spawn_fn(move || match PosixFS::stat_path(relative_path, &root) {
+        Ok(stat) => PosixFS::walk_preserving_links_sync(&root, stat).map(Some),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+      })
+      .to_boxed()
+  }
+
+  fn walk_preserving_links_sync(root: &Path, stat: Stat) -> Result<Vec<PathStat>, io::Error> {
+    match stat {
+      Stat::File(file) => Ok(vec![PathStat::file(file.path.clone(), file)]),
+      Stat::Link(link) => {
+        let target = root.join(&link.0).read_link()?;
+        Ok(vec![PathStat::link(link.0.clone(), link, target)])
+      }
+      Stat::Dir(dir) => {
+        let mut path_stats = vec![PathStat::dir(dir.0.clone(), dir.clone())];
+        for child in PosixFS::scandir_sync(root.to_owned(), dir)? {
+          path_stats.extend(PosixFS::walk_preserving_links_sync(root, child)?);
+        }
+        Ok(path_stats)
+      }
+    }
+  }
 }
 
 impl VFS<io::Error> for Arc<PosixFS> {
@@ -782,11 +1045,15 @@ mod posixfs_test {
   extern crate testutil;
 
   use self::testutil::make_file;
-  use super::{Dir, File, Link, PathStat, PathStatGetter, PosixFS, ResettablePool, Stat};
+  use super::{Dir, File, Invalidation, Link, PathGlobs, PathStat, PathStatGetter, PosixFS,
+              ResettablePool, Stat, StrictGlobMatching};
   use futures::Future;
   use std;
+  use std::collections::HashSet;
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
+  use std::sync::mpsc::{self, Receiver};
+  use std::time::Duration;
 
   #[test]
   fn is_executable_false() {
@@ -1058,6 +1325,140 @@ mod posixfs_test {
     assert_eq!(v, path_stats);
   }
 
+  #[test]
+  fn walk_preserving_links() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root_path = dir.path();
+
+    // File tree:
+    // outputs
+    // outputs/empty_dir
+    // outputs/regular_file
+    // outputs/symlink -> regular_file
+    // outputs/symlink_to_nothing -> doesnotexist
+
+    let outputs = PathBuf::from("outputs");
+    std::fs::create_dir_all(&root_path.join(&outputs).join("empty_dir")).unwrap();
+    make_file(&root_path.join(&outputs).join("regular_file"), &[], 0o600);
+    std::os::unix::fs::symlink("regular_file", &root_path.join(&outputs).join("symlink")).unwrap();
+    std::os::unix::fs::symlink(
+      "doesnotexist",
+      &root_path.join(&outputs).join("symlink_to_nothing"),
+    ).unwrap();
+
+    let posix_fs = new_posixfs(&root_path);
+    assert_eq!(
+      posix_fs
+        .walk_preserving_links(outputs.clone())
+        .wait()
+        .unwrap(),
+      Some(vec![
+        PathStat::dir(outputs.clone(), Dir(outputs.clone())),
+        PathStat::dir(
+          outputs.join("empty_dir"),
+          Dir(outputs.join("empty_dir")),
+        ),
+        PathStat::file(
+          outputs.join("regular_file"),
+          File {
+            path: outputs.join("regular_file"),
+            is_executable: false,
+          },
+        ),
+        PathStat::link(
+          outputs.join("symlink"),
+          Link(outputs.join("symlink")),
+          PathBuf::from("regular_file"),
+        ),
+        PathStat::link(
+          outputs.join("symlink_to_nothing"),
+          Link(outputs.join("symlink_to_nothing")),
+          PathBuf::from("doesnotexist"),
+        ),
+      ])
+    );
+    assert_eq!(
+      posix_fs
+        .walk_preserving_links(PathBuf::from("doesnotexist"))
+        .wait()
+        .unwrap(),
+      None
+    );
+  }
+
+  #[test]
+  fn path_globs_matches() {
+    let path_globs = PathGlobs::create(
+      &["src/**/*.rs".to_string(), "*.toml".to_string()],
+      &["src/ignored/**".to_string()],
+      StrictGlobMatching::Ignore,
+    ).unwrap();
+    assert!(path_globs.matches(Path::new("Cargo.toml")));
+    assert!(path_globs.matches(Path::new("src/lib.rs")));
+    assert!(path_globs.matches(Path::new("src/a/b/lib.rs")));
+    assert!(!path_globs.matches(Path::new("src")));
+    assert!(!path_globs.matches(Path::new("src/Cargo.toml")));
+    assert!(!path_globs.matches(Path::new("src/a/lib.py")));
+    assert!(!path_globs.matches(Path::new("src/ignored/lib.rs")));
+  }
+
+  #[test]
+  fn path_globs_matches_in_dir() {
+    let path_globs = PathGlobs::create(
+      &["src/**".to_string(), "*.toml".to_string()],
+      &[],
+      StrictGlobMatching::Ignore,
+    ).unwrap();
+    assert!(path_globs.matches_in_dir(Path::new("")));
+    assert!(path_globs.matches_in_dir(Path::new("src")));
+    assert!(path_globs.matches_in_dir(Path::new("src/a/b")));
+    assert!(!path_globs.matches_in_dir(Path::new("test")));
+    assert!(!path_globs.matches_in_dir(Path::new("test/src")));
+
+    let path_globs = PathGlobs::create(
+      &["src/*/BUILD".to_string()],
+      &[],
+      StrictGlobMatching::Ignore,
+    ).unwrap();
+    assert!(path_globs.matches_in_dir(Path::new("src")));
+    assert!(path_globs.matches_in_dir(Path::new("src/a")));
+    assert!(!path_globs.matches_in_dir(Path::new("src/a/b")));
+  }
+
+  #[test]
+  fn watcher_reports_changes_in_scanned_dirs() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir(dir.path().join("enclosure")).unwrap();
+    let (posix_fs, invalidations) = new_watched_posixfs(&dir.path(), vec![]);
+    posix_fs
+      .scandir(&Dir(PathBuf::from("enclosure")))
+      .wait()
+      .unwrap();
+
+    // Creating a file invalidates both the file and the listing of its directory.
+    make_file(&dir.path().join("enclosure/marmoset"), &[], 0o600);
+    let invalidated = await_invalidated(&invalidations, "enclosure/marmoset");
+    assert!(invalidated.contains(Path::new("enclosure")));
+  }
+
+  #[test]
+  fn watcher_ignores_removed_dirs() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("enclosure/ignored")).unwrap();
+    make_file(&dir.path().join("enclosure/marmoset"), &[], 0o600);
+    let (posix_fs, invalidations) = new_watched_posixfs(&dir.path(), vec!["ignored/".to_owned()]);
+    posix_fs
+      .scandir(&Dir(PathBuf::from("enclosure")))
+      .wait()
+      .unwrap();
+
+    // The directory-only ignore pattern applies to the directory even once it has been removed.
+    std::fs::remove_dir(dir.path().join("enclosure/ignored")).unwrap();
+    std::fs::remove_file(dir.path().join("enclosure/marmoset")).unwrap();
+    let invalidated = await_invalidated(&invalidations, "enclosure/marmoset");
+    assert!(!invalidated.contains(Path::new("enclosure/ignored")));
+  }
+
   fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
     let fs = new_posixfs(path);
     let stats = fs.scandir(&Dir(PathBuf::from("."))).wait().unwrap();
@@ -1077,4 +1478,36 @@ mod posixfs_test {
       vec![],
     ).unwrap()
   }
+
+  fn new_watched_posixfs<P: AsRef<Path>>(
+    dir: P,
+    ignore_patterns: Vec<String>,
+  ) -> (PosixFS, Receiver<Invalidation>) {
+    let (sender, receiver) = mpsc::channel();
+    let posix_fs = PosixFS::new(
+      dir.as_ref(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      ignore_patterns,
+    ).unwrap()
+      .with_watcher(Duration::from_millis(10), move |invalidation| {
+        // The receiver is dropped at the end of the test, while the watcher may still be running.
+        let _ = sender.send(invalidation);
+      })
+      .unwrap();
+    (posix_fs, receiver)
+  }
+
+  ///
+  /// Waits until the given path has been invalidated, and returns all paths invalidated until then.
+  ///
+  fn await_invalidated(invalidations: &Receiver<Invalidation>, path: &str) -> HashSet<PathBuf> {
+    let mut invalidated = HashSet::new();
+    while !invalidated.contains(Path::new(path)) {
+      match invalidations.recv_timeout(Duration::from_secs(10)) {
+        Ok(Invalidation::Paths(paths)) => invalidated.extend(paths),
+        other => panic!("Expected {} to be invalidated, got {:?}", path, other),
+      }
+    }
+    invalidated
+  }
 }
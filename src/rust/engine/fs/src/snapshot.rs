@@ -3,16 +3,17 @@
 
 use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
-use futures::Future;
+use futures::{stream, Future, Stream};
 use futures::future::{self, join_all};
 use hashing::{Digest, Fingerprint};
 use indexmap::{self, IndexMap};
 use itertools::Itertools;
-use {File, PathStat, PosixFS, Store};
+use {Dir, File, GlobMatching, Link, PathGlobs, PathStat, PosixFS, Stat, Store, VFS};
 use protobuf;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 pub const EMPTY_FINGERPRINT: Fingerprint = Fingerprint([
@@ -75,6 +76,7 @@ impl Snapshot {
       Vec::new();
     let mut dir_futures: Vec<BoxFuture<bazel_protos::remote_execution::DirectoryNode, String>> =
       Vec::new();
+    let mut symlinks: Vec<bazel_protos::remote_execution::SymlinkNode> = Vec::new();
 
     for (first_component, group) in &path_stats
       .iter()
@@ -83,8 +85,8 @@ impl Snapshot {
     {
       let mut path_group: Vec<PathStat> = group.collect();
       if path_group.len() == 1 && path_group.get(0).unwrap().path().components().count() == 1 {
-        // Exactly one entry with exactly one component indicates either a file in this directory,
-        // or an empty directory.
+        // Exactly one entry with exactly one component indicates either a file or a symlink in
+        // this directory, or an empty directory.
         // If the child is a non-empty directory, or a file therein, there must be multiple
         // PathStats with that prefix component, and we will handle that in the recursive
         // save_directory call.
@@ -121,6 +123,15 @@ impl Snapshot {
                 .to_boxed(),
             );
           }
+          PathStat::Link { target, .. } => {
+            let symlink_node = try_future!(osstring_as_utf8(first_component).and_then(|name| {
+              let mut symlink_node = bazel_protos::remote_execution::SymlinkNode::new();
+              symlink_node.set_name(name);
+              symlink_node.set_target(osstring_as_utf8(target.into_os_string())?);
+              Ok(symlink_node)
+            }));
+            symlinks.push(symlink_node);
+          }
         }
       } else {
         dir_futures.push(
@@ -145,6 +156,7 @@ impl Snapshot {
         let mut directory = bazel_protos::remote_execution::Directory::new();
         directory.set_directories(protobuf::RepeatedField::from_vec(dirs));
         directory.set_files(protobuf::RepeatedField::from_vec(files));
+        directory.set_symlinks(protobuf::RepeatedField::from_vec(symlinks));
         store.record_directory(&directory, true)
       })
       .to_boxed()
@@ -199,11 +211,56 @@ impl Snapshot {
   /// output Directory Digest. Fails for collisions.
   ///
   pub fn merge_directories(store: Store, dir_digests: Vec<Digest>) -> BoxFuture<Digest, String> {
+    Self::merge_directories_with_policy(store, dir_digests, MergePolicy::Error)
+  }
+
+  ///
+  /// Given Digest(s) representing Directory instances, merge them recursively into a single
+  /// output Directory Digest, resolving any paths which are present in more than one of them
+  /// according to the given MergePolicy.
+  ///
+  /// Directories which are present in more than one input are always merged recursively. If the
+  /// policy does not resolve a collision, the error lists every colliding path in the tree.
+  ///
+  pub fn merge_directories_with_policy(
+    store: Store,
+    dir_digests: Vec<Digest>,
+    policy: MergePolicy,
+  ) -> BoxFuture<Digest, String> {
+    Self::merge_directories_recursive(store, PathBuf::new(), dir_digests, policy)
+      .and_then(move |(digest, conflicts)| {
+        if conflicts.is_empty() {
+          return Ok(digest);
+        }
+        Err(format!(
+          "Can only merge Directories with no {}, but found {} conflicting path(s):\n  {}",
+          match policy {
+            MergePolicy::AllowIdentical => "conflicting duplicates",
+            _ => "duplicates",
+          },
+          conflicts.len(),
+          conflicts.join("\n  ")
+        ))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Merges the given Directories, returning the merged Digest along with a description of each
+  /// collision which the policy did not resolve. Colliding entries which are not resolved are
+  /// left out of the merged Directory: callers should not use it if any collisions are returned.
+  ///
+  fn merge_directories_recursive(
+    store: Store,
+    parent_path: PathBuf,
+    dir_digests: Vec<Digest>,
+    policy: MergePolicy,
+  ) -> BoxFuture<(Digest, Vec<String>), String> {
     if dir_digests.is_empty() {
-      return future::ok(EMPTY_DIGEST).to_boxed();
+      return future::ok((EMPTY_DIGEST, vec![])).to_boxed();
     } else if dir_digests.len() == 1 {
       let mut dir_digests = dir_digests;
-      return future::ok(dir_digests.pop().unwrap()).to_boxed();
+      return future::ok((dir_digests.pop().unwrap(), vec![])).to_boxed();
     }
 
     let directories = dir_digests
@@ -218,78 +275,416 @@ impl Snapshot {
       })
       .collect::<Vec<_>>();
     join_all(directories)
-      .and_then(move |mut directories| {
-        let mut out_dir = bazel_protos::remote_execution::Directory::new();
-
-        // Merge FileNodes.
-        out_dir.set_files(protobuf::RepeatedField::from_vec(
-          directories
-            .iter_mut()
-            .map(|directory| directory.take_files().into_iter())
-            .flatten()
-            .collect(),
-        ));
-        out_dir.mut_files().sort_by(|a, b| a.name.cmp(&b.name));
-        let unique_count = out_dir
-          .mut_files()
-          .iter()
-          .map(|v| v.get_name())
-          .dedup()
-          .count();
-        if unique_count != out_dir.get_files().len() {
-          let groups = out_dir
-            .get_files()
-            .iter()
-            .group_by(|f| f.get_name().to_owned());
-          for (file_name, group) in groups.into_iter() {
-            if group.count() > 1 {
-              return future::err(format!(
-                "Can only merge Directories with no duplicates, but found duplicate files: {}",
-                file_name
-              )).to_boxed();
-            }
+      .and_then(move |directories| {
+        // Collect the entries for each name, in the order of the Directories they came from.
+        let mut entries_by_name: BTreeMap<String, Vec<MergeEntry>> = BTreeMap::new();
+        for mut directory in directories {
+          for file_node in directory.take_files().into_iter() {
+            entries_by_name
+              .entry(file_node.get_name().to_owned())
+              .or_insert_with(Vec::new)
+              .push(MergeEntry::File(file_node));
+          }
+          for symlink_node in directory.take_symlinks().into_iter() {
+            entries_by_name
+              .entry(symlink_node.get_name().to_owned())
+              .or_insert_with(Vec::new)
+              .push(MergeEntry::Symlink(symlink_node));
+          }
+          for directory_node in directory.take_directories().into_iter() {
+            entries_by_name
+              .entry(directory_node.get_name().to_owned())
+              .or_insert_with(Vec::new)
+              .push(MergeEntry::Directory(directory_node));
           }
         }
 
-        // Group and recurse for DirectoryNodes.
-        let sorted_child_directories = {
-          let mut merged_directories = directories
-            .iter_mut()
-            .map(|directory| directory.take_directories().into_iter())
-            .flatten()
-            .collect::<Vec<_>>();
-          merged_directories.sort_by(|a, b| a.name.cmp(&b.name));
-          merged_directories
-        };
-        let store2 = store.clone();
-        join_all(
-          sorted_child_directories
-            .into_iter()
-            .group_by(|d| d.name.clone())
-            .into_iter()
-            .map(move |(child_name, group)| {
-              let store2 = store2.clone();
-              let digests_result = group
-                .map(|d| d.get_digest().into())
-                .collect::<Result<Vec<_>, String>>();
+        let mut out_dir = bazel_protos::remote_execution::Directory::new();
+        let mut conflicts = vec![];
+        let mut child_futures = vec![];
+        for (name, mut entries) in entries_by_name {
+          let path = parent_path.join(&name);
+          let winner = if entries.len() == 1 {
+            entries.pop()
+          } else if entries.iter().all(|entry| entry.is_directory()) {
+            // Only directories share this name, so merge them.
+            let digests_result = entries
+              .iter()
+              .map(|entry| entry.digest())
+              .collect::<Result<Vec<_>, String>>();
+            let store = store.clone();
+            child_futures.push(
               future::done(digests_result)
-                .and_then(move |digests| Self::merge_directories(store2.clone(), digests))
-                .map(move |merged_digest| {
+                .and_then(move |digests| {
+                  Self::merge_directories_recursive(store, path, digests, policy)
+                })
+                .map(move |(merged_digest, child_conflicts)| {
                   let mut child_dir = bazel_protos::remote_execution::DirectoryNode::new();
-                  child_dir.set_name(child_name);
+                  child_dir.set_name(name);
                   child_dir.set_digest((&merged_digest).into());
-                  child_dir
+                  (child_dir, child_conflicts)
                 })
-            })
-            .collect::<Vec<_>>(),
-        ).and_then(move |child_directories| {
-          out_dir.set_directories(protobuf::RepeatedField::from_vec(child_directories));
-          store.record_directory(&out_dir, true)
-        })
+                .to_boxed(),
+            );
+            continue;
+          } else {
+            let all_identical = entries.iter().all(|entry| entry == &entries[0]);
+            match policy {
+              MergePolicy::AllowIdentical if all_identical => entries.pop(),
+              MergePolicy::FirstWins => Some(entries.remove(0)),
+              MergePolicy::LastWins => entries.pop(),
+              MergePolicy::Error | MergePolicy::AllowIdentical => {
+                conflicts.push(format!(
+                  "{}: {}",
+                  path.display(),
+                  entries
+                    .iter()
+                    .map(|entry| entry.to_string())
+                    .join(", ")
+                ));
+                None
+              }
+            }
+          };
+          match winner {
+            Some(MergeEntry::File(file_node)) => out_dir.mut_files().push(file_node),
+            Some(MergeEntry::Symlink(symlink_node)) => out_dir.mut_symlinks().push(symlink_node),
+            Some(MergeEntry::Directory(directory_node)) => {
+              child_futures.push(future::ok((directory_node, vec![])).to_boxed())
+            }
+            None => (),
+          }
+        }
+
+        join_all(child_futures)
+          .and_then(move |children| {
+            let mut child_directories = Vec::with_capacity(children.len());
+            for (child_dir, child_conflicts) in children {
+              child_directories.push(child_dir);
+              conflicts.extend(child_conflicts);
+            }
+            out_dir.set_directories(protobuf::RepeatedField::from_vec(child_directories));
+            store
+              .record_directory(&out_dir, true)
+              .map(move |digest| (digest, conflicts))
+          })
           .to_boxed() as BoxFuture<_, _>
       })
       .to_boxed()
   }
+
+  ///
+  /// Returns a Snapshot of the entries of the given Directory which match the given PathGlobs.
+  ///
+  /// Globs are matched against the Directory protos in the Store, so no files are read or
+  /// materialized.
+  ///
+  pub fn subset(
+    store: Store,
+    digest: Digest,
+    path_globs: PathGlobs,
+  ) -> BoxFuture<Snapshot, String> {
+    StoredDirectories::load(store.clone(), digest)
+      .and_then(move |stored_directories| {
+        stored_directories
+          .expand(path_globs)
+          .and_then(move |path_stats| {
+            Snapshot::from_path_stats(store, stored_directories, path_stats)
+          })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Given the digest of a Directory which contains nothing but the given prefix, returns the
+  /// digest of the Directory found at that prefix.
+  ///
+  /// Fails if any other entries exist alongside the prefix, as they would otherwise be silently
+  /// dropped.
+  ///
+  pub fn strip_prefix(store: Store, digest: Digest, prefix: PathBuf) -> BoxFuture<Digest, String> {
+    let components = try_future!(relative_path_components(&prefix));
+    let root_digest = digest;
+    stream::iter_ok::<_, String>(components)
+      .fold(digest, move |digest, component| {
+        let prefix = prefix.clone();
+        store
+          .load_directory(digest)
+          .and_then(move |maybe_directory| -> Result<Digest, String> {
+            let directory = maybe_directory
+              .ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))?;
+            let mut entries = directory
+              .get_files()
+              .iter()
+              .map(|file_node| file_node.get_name())
+              .chain(directory.get_directories().iter().map(|dir_node| dir_node.get_name()))
+              .chain(directory.get_symlinks().iter().map(|symlink| symlink.get_name()))
+              .filter(|name| *name != component)
+              .peekable();
+            if entries.peek().is_some() {
+              return Err(format!(
+                "Cannot strip prefix {:?} from Directory {:?}: it contained entries other than \
+                 {:?}: {}",
+                prefix,
+                root_digest,
+                component,
+                entries.join(", ")
+              ));
+            }
+            match directory
+              .get_directories()
+              .iter()
+              .find(|dir_node| dir_node.get_name() == component)
+            {
+              Some(dir_node) => dir_node.get_digest().into(),
+              None => Err(format!(
+                "Cannot strip prefix {:?} from Directory {:?}: it did not contain a directory \
+                 named {:?}",
+                prefix, root_digest, component
+              )),
+            }
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the digest of a Directory which contains the given Directory at the given prefix.
+  ///
+  pub fn add_prefix(store: Store, digest: Digest, prefix: PathBuf) -> BoxFuture<Digest, String> {
+    let components = try_future!(relative_path_components(&prefix));
+    // Wrap the Directory once for each component of the prefix, innermost first.
+    stream::iter_ok::<_, String>(components.into_iter().rev())
+      .fold(digest, move |digest, component| {
+        let mut directory = bazel_protos::remote_execution::Directory::new();
+        directory.mut_directories().push({
+          let mut dir_node = bazel_protos::remote_execution::DirectoryNode::new();
+          dir_node.set_name(component);
+          dir_node.set_digest((&digest).into());
+          dir_node
+        });
+        store.record_directory(&directory, true)
+      })
+      .to_boxed()
+  }
+}
+
+///
+/// The Directory protos of a tree which is already present in the Store, keyed by their paths
+/// relative to the root of the tree.
+///
+/// Acts as a VFS so that globs can be matched against it, and as a StoreFileByDigest which knows
+/// the digest of every file in the tree, so that matches can be ingested back into the Store
+/// without reading any file content.
+///
+#[derive(Clone)]
+struct StoredDirectories {
+  directories: Arc<HashMap<PathBuf, bazel_protos::remote_execution::Directory>>,
+}
+
+impl StoredDirectories {
+  fn load(store: Store, digest: Digest) -> BoxFuture<StoredDirectories, String> {
+    StoredDirectories::load_recursive(store, PathBuf::new(), digest)
+      .map(|directories| StoredDirectories {
+        directories: Arc::new(directories.into_iter().collect()),
+      })
+      .to_boxed()
+  }
+
+  fn load_recursive(
+    store: Store,
+    path: PathBuf,
+    digest: Digest,
+  ) -> BoxFuture<Vec<(PathBuf, bazel_protos::remote_execution::Directory)>, String> {
+    store
+      .load_directory(digest)
+      .and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))
+      })
+      .and_then(move |directory| {
+        let child_futures = directory
+          .get_directories()
+          .iter()
+          .map(|dir_node| {
+            let store = store.clone();
+            let child_path = path.join(dir_node.get_name());
+            let child_digest: Result<Digest, String> = dir_node.get_digest().into();
+            future::result(child_digest)
+              .and_then(move |child_digest| {
+                StoredDirectories::load_recursive(store, child_path, child_digest)
+              })
+              .to_boxed()
+          })
+          .collect::<Vec<_>>();
+        join_all(child_futures).map(move |children| {
+          let mut directories = vec![(path, directory)];
+          directories.extend(children.into_iter().flat_map(|child| child));
+          directories
+        })
+      })
+      .to_boxed()
+  }
+
+  fn parent_of(&self, path: &Path) -> Result<&bazel_protos::remote_execution::Directory, String> {
+    path
+      .parent()
+      .and_then(|parent| self.directories.get(parent))
+      .ok_or_else(|| format!("Parent directory of {:?} did not exist", path))
+  }
+
+  fn name_of(path: &Path) -> Result<&str, String> {
+    path
+      .file_name()
+      .and_then(|name| name.to_str())
+      .ok_or_else(|| format!("{:?} did not have a UTF8 file name", path))
+  }
+}
+
+impl VFS<String> for StoredDirectories {
+  fn read_link(&self, link: Link) -> BoxFuture<PathBuf, String> {
+    let parent = try_future!(self.parent_of(&link.0));
+    let name = try_future!(StoredDirectories::name_of(&link.0));
+    future::result(
+      parent
+        .get_symlinks()
+        .iter()
+        .find(|symlink| symlink.get_name() == name)
+        .map(|symlink| PathBuf::from(symlink.get_target()))
+        .ok_or_else(|| format!("Symlink {:?} did not exist", link.0)),
+    ).to_boxed()
+  }
+
+  fn scandir(&self, dir: Dir) -> BoxFuture<Vec<Stat>, String> {
+    let directory = match self.directories.get(&dir.0) {
+      Some(directory) => directory,
+      None => return future::err(format!("Directory {:?} did not exist", dir.0)).to_boxed(),
+    };
+    let mut stats = directory
+      .get_directories()
+      .iter()
+      .map(|dir_node| Stat::Dir(Dir(dir.0.join(dir_node.get_name()))))
+      .chain(directory.get_files().iter().map(|file_node| {
+        Stat::File(File {
+          path: dir.0.join(file_node.get_name()),
+          is_executable: file_node.get_is_executable(),
+        })
+      }))
+      .chain(
+        directory
+          .get_symlinks()
+          .iter()
+          .map(|symlink| Stat::Link(Link(dir.0.join(symlink.get_name())))),
+      )
+      .collect::<Vec<_>>();
+    stats.sort_by(|s1, s2| s1.path().cmp(s2.path()));
+    future::ok(stats).to_boxed()
+  }
+
+  fn is_ignored(&self, _stat: &Stat) -> bool {
+    false
+  }
+
+  fn mk_error(msg: &str) -> String {
+    msg.to_owned()
+  }
+}
+
+impl StoreFileByDigest<String> for StoredDirectories {
+  fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+    // Every file in the tree is already in the Store, so we only need to look up its digest.
+    let parent = try_future!(self.parent_of(&file.path));
+    let name = try_future!(StoredDirectories::name_of(&file.path));
+    future::result(
+      parent
+        .get_files()
+        .iter()
+        .find(|file_node| file_node.get_name() == name)
+        .ok_or_else(|| format!("File {:?} did not exist", file.path))
+        .and_then(|file_node| file_node.get_digest().into()),
+    ).to_boxed()
+  }
+}
+
+///
+/// How to resolve a path which is present in more than one of a set of Directories being merged.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MergePolicy {
+  // Fail if any path other than a directory is present more than once.
+  Error,
+  // Allow a path to be present more than once, as long as every copy is identical.
+  AllowIdentical,
+  // Use the entry from the first Directory which contains the path.
+  FirstWins,
+  // Use the entry from the last Directory which contains the path.
+  LastWins,
+}
+
+impl MergePolicy {
+  pub fn create(policy: &str) -> Result<MergePolicy, String> {
+    match policy {
+      "error" => Ok(MergePolicy::Error),
+      "allow_identical" => Ok(MergePolicy::AllowIdentical),
+      "first_wins" => Ok(MergePolicy::FirstWins),
+      "last_wins" => Ok(MergePolicy::LastWins),
+      _ => Err(format!("Unrecognized merge policy: {}.", policy)),
+    }
+  }
+}
+
+#[derive(Clone, PartialEq)]
+enum MergeEntry {
+  File(bazel_protos::remote_execution::FileNode),
+  Symlink(bazel_protos::remote_execution::SymlinkNode),
+  Directory(bazel_protos::remote_execution::DirectoryNode),
+}
+
+impl MergeEntry {
+  fn is_directory(&self) -> bool {
+    match self {
+      &MergeEntry::Directory(_) => true,
+      _ => false,
+    }
+  }
+
+  fn digest(&self) -> Result<Digest, String> {
+    match self {
+      &MergeEntry::File(ref file_node) => file_node.get_digest().into(),
+      &MergeEntry::Directory(ref directory_node) => directory_node.get_digest().into(),
+      &MergeEntry::Symlink(ref symlink_node) => Err(format!(
+        "Symlink {} does not have a digest",
+        symlink_node.get_name()
+      )),
+    }
+  }
+}
+
+impl fmt::Display for MergeEntry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &MergeEntry::File(ref file_node) => write!(
+        f,
+        "file {}:{}{}",
+        file_node.get_digest().get_hash(),
+        file_node.get_digest().get_size_bytes(),
+        if file_node.get_is_executable() {
+          " (executable)"
+        } else {
+          ""
+        }
+      ),
+      &MergeEntry::Symlink(ref symlink_node) => {
+        write!(f, "symlink -> {}", symlink_node.get_target())
+      }
+      &MergeEntry::Directory(ref directory_node) => write!(
+        f,
+        "directory {}:{}",
+        directory_node.get_digest().get_hash(),
+        directory_node.get_digest().get_size_bytes()
+      ),
+    }
+  }
 }
 
 impl fmt::Debug for Snapshot {
@@ -319,11 +714,29 @@ fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
           path: path.iter().skip(1).collect(),
           stat: stat,
         },
+        PathStat::Link { path, stat, target } => PathStat::Link {
+          path: path.iter().skip(1).collect(),
+          stat: stat,
+          target: target,
+        },
       })
     })
     .collect()
 }
 
+fn relative_path_components(path: &Path) -> Result<Vec<String>, String> {
+  path
+    .components()
+    .map(|component| match component {
+      Component::Normal(name) => osstring_as_utf8(name.to_owned()),
+      _ => Err(format!(
+        "Prefix {:?} must be a relative path with no `.` or `..` components",
+        path
+      )),
+    })
+    .collect()
+}
+
 fn osstring_as_utf8(path: OsString) -> Result<String, String> {
   path
     .into_string()
@@ -370,14 +783,15 @@ mod tests {
   extern crate tempfile;
   extern crate testutil;
 
+  use bazel_protos;
   use futures::future::Future;
   use hashing::{Digest, Fingerprint};
   use self::testutil::make_file;
-  use self::testutil::data::TestDirectory;
+  use self::testutil::data::{TestData, TestDirectory};
 
-  use super::OneOffStoreFileByDigest;
-  use super::super::{Dir, File, GlobMatching, Path, PathGlobs, PathStat, PosixFS, ResettablePool,
-                     Snapshot, Store, StrictGlobMatching, VFS};
+  use super::{MergePolicy, OneOffStoreFileByDigest};
+  use super::super::{Dir, File, GlobMatching, Link, Path, PathGlobs, PathStat, PosixFS,
+                     ResettablePool, Snapshot, Store, StrictGlobMatching, VFS};
 
   use std;
   use std::path::PathBuf;
@@ -544,6 +958,264 @@ mod tests {
     );
   }
 
+  #[test]
+  fn snapshot_symlinks_and_empty_directories() {
+    let (store, tempdir, _, digester) = setup();
+
+    let cats = make_dir_stat(tempdir.path(), &PathBuf::from("cats"));
+    let roland = make_file_stat(
+      tempdir.path(),
+      &PathBuf::from("cats").join("roland"),
+      STR.as_bytes(),
+      false,
+    );
+    let dogs = make_dir_stat(tempdir.path(), &PathBuf::from("dogs"));
+    let roland_link = PathStat::link(
+      PathBuf::from("roland"),
+      Link(PathBuf::from("roland")),
+      PathBuf::from("cats").join("roland"),
+    );
+
+    assert_eq!(
+      Snapshot::digest_from_path_stats(store, digester, vec![roland_link, dogs, roland, cats])
+        .wait(),
+      Ok(TestDirectory::with_symlink_and_empty_directory().digest())
+    );
+  }
+
+  #[test]
+  fn merge_directories_symlink_clashing_with_file() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_roland_symlink = TestDirectory::with_symlink_and_empty_directory();
+
+    store
+      .record_directory(&containing_roland.directory(), false)
+      .wait()
+      .expect("Storing roland directory");
+    store
+      .record_directory(&containing_roland_symlink.directory(), false)
+      .wait()
+      .expect("Storing roland symlink directory");
+
+    let err = Snapshot::merge_directories(
+      store,
+      vec![containing_roland.digest(), containing_roland_symlink.digest()],
+    ).wait()
+      .expect_err("Want error merging");
+
+    assert!(
+      err.contains("roland") && err.contains("symlink"),
+      "Want error message to contain roland and symlink but was: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn merge_directories_lists_all_conflicts() {
+    let (store, _, _, _) = setup();
+
+    let recursive = TestDirectory::recursive();
+    let containing_wrong_roland = TestDirectory::containing_wrong_roland();
+    let mut clashing = bazel_protos::remote_execution::Directory::new();
+    clashing.mut_directories().push({
+      let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+      subdir.set_name("cats".to_string());
+      subdir.set_digest((&containing_wrong_roland.digest()).into());
+      subdir
+    });
+    clashing.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("treats".to_string());
+      file.set_digest((&TestData::roland().digest()).into());
+      file.set_is_executable(false);
+      file
+    });
+
+    for directory in vec![
+      recursive.directory(),
+      TestDirectory::containing_roland().directory(),
+      containing_wrong_roland.directory(),
+    ] {
+      store
+        .record_directory(&directory, false)
+        .wait()
+        .expect("Storing directory");
+    }
+    let clashing_digest = store
+      .record_directory(&clashing, false)
+      .wait()
+      .expect("Storing clashing directory");
+
+    let err = Snapshot::merge_directories(store, vec![recursive.digest(), clashing_digest])
+      .wait()
+      .expect_err("Want error merging");
+
+    assert!(
+      err.contains("2 conflicting path(s)") && err.contains("cats/roland")
+        && err.contains("treats"),
+      "Want error message to list both conflicts but was: {}",
+      err
+    );
+    assert!(
+      err.contains(&TestData::catnip().fingerprint().to_hex()),
+      "Want error message to contain the conflicting digests but was: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn merge_directories_allow_identical() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_wrong_roland = TestDirectory::containing_wrong_roland();
+    store
+      .record_directory(&containing_roland.directory(), false)
+      .wait()
+      .expect("Storing roland directory");
+    store
+      .record_directory(&containing_wrong_roland.directory(), false)
+      .wait()
+      .expect("Storing wrong roland directory");
+
+    assert_eq!(
+      Snapshot::merge_directories_with_policy(
+        store.clone(),
+        vec![containing_roland.digest(), containing_roland.digest()],
+        MergePolicy::AllowIdentical,
+      ).wait(),
+      Ok(containing_roland.digest())
+    );
+    Snapshot::merge_directories(
+      store.clone(),
+      vec![containing_roland.digest(), containing_roland.digest()],
+    ).wait()
+      .expect_err("Want error merging identical files without AllowIdentical");
+    Snapshot::merge_directories_with_policy(
+      store,
+      vec![containing_roland.digest(), containing_wrong_roland.digest()],
+      MergePolicy::AllowIdentical,
+    ).wait()
+      .expect_err("Want error merging different files");
+  }
+
+  #[test]
+  fn merge_directories_first_and_last_wins() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_wrong_roland = TestDirectory::containing_wrong_roland();
+    store
+      .record_directory(&containing_roland.directory(), false)
+      .wait()
+      .expect("Storing roland directory");
+    store
+      .record_directory(&containing_wrong_roland.directory(), false)
+      .wait()
+      .expect("Storing wrong roland directory");
+
+    let digests = vec![containing_roland.digest(), containing_wrong_roland.digest()];
+    assert_eq!(
+      Snapshot::merge_directories_with_policy(
+        store.clone(),
+        digests.clone(),
+        MergePolicy::FirstWins,
+      ).wait(),
+      Ok(containing_roland.digest())
+    );
+    assert_eq!(
+      Snapshot::merge_directories_with_policy(store, digests, MergePolicy::LastWins).wait(),
+      Ok(containing_wrong_roland.digest())
+    );
+  }
+
+  #[test]
+  fn subset_by_globs() {
+    let (store, _, _, _) = setup();
+
+    let recursive = TestDirectory::recursive();
+    store
+      .record_directory(&recursive.directory(), false)
+      .wait()
+      .expect("Storing recursive directory");
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .expect("Storing roland directory");
+
+    let subset = |globs: &[&str]| {
+      Snapshot::subset(
+        store.clone(),
+        recursive.digest(),
+        PathGlobs::create(
+          &globs.iter().map(|glob| glob.to_string()).collect::<Vec<_>>(),
+          &[],
+          StrictGlobMatching::Ignore,
+        ).unwrap(),
+      ).wait()
+        .map(|snapshot| snapshot.digest)
+    };
+
+    assert_eq!(
+      subset(&["treats"]),
+      Ok(TestDirectory::containing_treats().digest())
+    );
+    assert_eq!(subset(&["cats/*"]), Ok(TestDirectory::nested().digest()));
+    assert_eq!(subset(&["**"]), Ok(recursive.digest()));
+  }
+
+  #[test]
+  fn strip_prefix() {
+    let (store, _, _, _) = setup();
+
+    let nested = TestDirectory::nested();
+    store
+      .record_directory(&nested.directory(), false)
+      .wait()
+      .expect("Storing nested directory");
+
+    assert_eq!(
+      Snapshot::strip_prefix(store, nested.digest(), PathBuf::from("cats")).wait(),
+      Ok(TestDirectory::containing_roland().digest())
+    );
+  }
+
+  #[test]
+  fn strip_prefix_with_other_entries() {
+    let (store, _, _, _) = setup();
+
+    let recursive = TestDirectory::recursive();
+    store
+      .record_directory(&recursive.directory(), false)
+      .wait()
+      .expect("Storing recursive directory");
+
+    let err = Snapshot::strip_prefix(store, recursive.digest(), PathBuf::from("cats"))
+      .wait()
+      .expect_err("Want error stripping prefix with siblings");
+    assert!(
+      err.contains("treats"),
+      "Error message should mention the unexpected entry: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn add_prefix() {
+    let (store, _, _, _) = setup();
+
+    assert_eq!(
+      Snapshot::add_prefix(
+        store,
+        TestDirectory::containing_roland().digest(),
+        PathBuf::from("cats"),
+      ).wait(),
+      Ok(TestDirectory::nested().digest())
+    );
+  }
+
   #[test]
   fn snapshot_merge_two_files() {
     let (store, tempdir, _, digester) = setup();
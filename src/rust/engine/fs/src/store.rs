@@ -1,4 +1,4 @@
-use FileContent;
+use {FileContent, EMPTY_FINGERPRINT};
 
 use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
@@ -6,14 +6,15 @@ use bytes::Bytes;
 use futures::{future, Future};
 use hashing::Digest;
 use protobuf::core::Message;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
+use std;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use file_cache::{self, FileCache, FileCacheMode};
 use pool::ResettablePool;
 
 // This is the maximum size any particular local LMDB store file is allowed to grow to.
@@ -25,6 +26,27 @@ const MAX_LOCAL_STORE_SIZE_BYTES: usize = 1024 * 1024 * 1024 * 1024 / 10;
 // after garbage collection. We almost certainly want to make this configurable.
 const LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
 
+///
+/// The number of bytes that a Store has transferred to and from its remote (if any).
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TransferCounts {
+  pub uploaded: usize,
+  pub downloaded: usize,
+}
+
+impl TransferCounts {
+  ///
+  /// Returns the number of bytes transferred since the given (earlier) counts were taken.
+  ///
+  pub fn since(&self, earlier: &TransferCounts) -> TransferCounts {
+    TransferCounts {
+      uploaded: self.uploaded.saturating_sub(earlier.uploaded),
+      downloaded: self.downloaded.saturating_sub(earlier.downloaded),
+    }
+  }
+}
+
 ///
 /// A content-addressed store of file contents, and Directories.
 ///
@@ -37,10 +59,15 @@ const LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
 /// It can also write back to a remote gRPC server, but will only do so when explicitly instructed
 /// to do so.
 ///
+/// Files can optionally be materialized via an immutable on-disk FileCache (see
+/// `with_file_cache`), so that materializing the same file repeatedly doesn't re-write its
+/// content.
+///
 #[derive(Clone)]
 pub struct Store {
   local: local::ByteStore,
   remote: Option<remote::ByteStore>,
+  file_cache: Option<FileCache>,
 }
 
 // Note that Store doesn't implement ByteStore because it operates at a higher level of abstraction,
@@ -55,6 +82,7 @@ impl Store {
     Ok(Store {
       local: local::ByteStore::new(path, pool)?,
       remote: None,
+      file_cache: None,
     })
   }
 
@@ -78,9 +106,29 @@ impl Store {
         chunk_size_bytes,
         timeout,
       )),
+      file_cache: None,
     })
   }
 
+  ///
+  /// Materialize files via a FileCache rooted at the given path, unless the mode is Off.
+  ///
+  /// The cache holds a second copy of each materialized file, so it costs disk space, and in
+  /// FileCacheMode::ReflinkOrHardlink, materialized files must not be modified in place.
+  ///
+  pub fn with_file_cache<P: AsRef<Path>>(
+    self,
+    root: P,
+    pool: Arc<ResettablePool>,
+    mode: FileCacheMode,
+  ) -> Result<Store, String> {
+    let file_cache = match mode {
+      FileCacheMode::Off => None,
+      _ => Some(FileCache::new(root, pool, mode)?),
+    };
+    Ok(Store { file_cache, ..self })
+  }
+
   ///
   /// LMDB Environments aren't safe to be re-used after forking, so we need to drop them before
   /// forking and re-create them afterwards.
@@ -96,6 +144,17 @@ impl Store {
     }
   }
 
+  ///
+  /// Returns the number of bytes that have been transferred to and from the remote store.
+  ///
+  pub fn transfer_counts(&self) -> TransferCounts {
+    self
+      .remote
+      .as_ref()
+      .map(|remote| remote.transfer_counts())
+      .unwrap_or_default()
+  }
+
   pub fn store_file_bytes(&self, bytes: Bytes, initial_lease: bool) -> BoxFuture<Digest, String> {
     let len = bytes.len();
     self
@@ -332,7 +391,10 @@ impl Store {
       }
       Err(err) => return Err(format!("Garbage collection failed: {:?}", err)),
     };
-    Ok(())
+    match self.file_cache {
+      Some(ref file_cache) => file_cache.garbage_collect(),
+      None => Ok(()),
+    }
   }
 
   ///
@@ -369,6 +431,39 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Returns true if the Directory with the given digest, and everything that it transitively
+  /// contains, is available. Without a remote store, that means present in the local store (e.g.
+  /// not garbage collected since the digest was recorded).
+  ///
+  pub fn contains_directory_recursive(&self, digest: Digest) -> BoxFuture<bool, String> {
+    let local = self.local.clone();
+    let has_remote = self.remote.is_some();
+    self
+      .expand_directory(digest)
+      .then(move |expanded| -> Result<bool, String> {
+        let entries = match expanded {
+          Ok(entries) => entries,
+          // A Directory which couldn't be loaded is missing.
+          Err(_) => return Ok(false),
+        };
+        if has_remote {
+          // Missing files will be fetched from the remote store when they are needed.
+          return Ok(true);
+        }
+        for (digest, entry_type) in entries {
+          // Directories were loaded by `expand_directory`, so only files need to be checked.
+          if entry_type == EntryType::File && digest.0 != EMPTY_FINGERPRINT
+            && local.entry_type(&digest.0)? != Some(EntryType::File)
+          {
+            return Ok(false);
+          }
+        }
+        Ok(true)
+      })
+      .to_boxed()
+  }
+
   fn expand_directory_helper(
     &self,
     digest: Digest,
@@ -443,28 +538,144 @@ impl Store {
             store.materialize_directory(path, digest)
           })
           .collect::<Vec<_>>();
+        let symlink_futures = directory
+          .get_symlinks()
+          .iter()
+          .map(|symlink_node| {
+            let path = destination.join(symlink_node.get_name());
+            store.materialize_symlink(path, symlink_node.get_target().to_owned())
+          })
+          .collect::<Vec<_>>();
         future::join_all(file_futures)
           .join(future::join_all(directory_futures))
+          .join(future::join_all(symlink_futures))
           .map(|_| ())
       })
       .to_boxed()
   }
 
+  ///
+  /// Updates `destination`, which must contain exactly the materialized Directory with digest
+  /// `current`, so that it contains the Directory with digest `target` instead. Only entries which
+  /// differ between the two trees are touched, and subtrees whose digests are equal are skipped
+  /// without being loaded.
+  ///
+  pub fn sync_directory(
+    &self,
+    destination: PathBuf,
+    current: Digest,
+    target: Digest,
+  ) -> BoxFuture<(), String> {
+    if current == target {
+      return future::ok(()).to_boxed();
+    }
+    let store = self.clone();
+    self
+      .load_directory_or_empty(Some(current))
+      .join(self.load_directory_or_empty(Some(target)))
+      .and_then(move |(current_dir, target_dir)| {
+        let current_files = try_future!(file_digests_by_name(&current_dir));
+        let target_files = try_future!(file_digests_by_name(&target_dir));
+        let current_dirs = try_future!(directory_digests_by_name(&current_dir));
+        let target_dirs = try_future!(directory_digests_by_name(&target_dir));
+        let current_links = symlink_targets_by_name(&current_dir);
+        let target_links = symlink_targets_by_name(&target_dir);
+
+        // Remove stale entries first, so that a name which changes kind (e.g. from a file to a
+        // directory) is free to be re-materialized.
+        for (name, file) in &current_files {
+          if target_files.get(name) != Some(file) {
+            try_future!(remove_path(&destination.join(name), false));
+          }
+        }
+        for (name, link_target) in &current_links {
+          if target_links.get(name) != Some(link_target) {
+            try_future!(remove_path(&destination.join(name), false));
+          }
+        }
+        for name in current_dirs.keys() {
+          if !target_dirs.contains_key(name) {
+            try_future!(remove_path(&destination.join(name), true));
+          }
+        }
+
+        let file_futures = target_files
+          .iter()
+          .filter(|&(name, file)| current_files.get(name) != Some(file))
+          .map(|(name, &(digest, is_executable))| {
+            store.materialize_file(destination.join(name), digest, is_executable)
+          })
+          .collect::<Vec<_>>();
+        let symlink_futures = target_links
+          .iter()
+          .filter(|&(name, link_target)| current_links.get(name) != Some(link_target))
+          .map(|(name, link_target)| {
+            store.materialize_symlink(destination.join(name), link_target.clone())
+          })
+          .collect::<Vec<_>>();
+        let directory_futures = target_dirs
+          .iter()
+          .map(|(name, &digest)| match current_dirs.get(name) {
+            Some(&current_digest) => {
+              store.sync_directory(destination.join(name), current_digest, digest)
+            }
+            None => store.materialize_directory(destination.join(name), digest),
+          })
+          .collect::<Vec<_>>();
+        future::join_all(file_futures)
+          .join(future::join_all(symlink_futures))
+          .join(future::join_all(directory_futures))
+          .map(|_| ())
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn materialize_symlink(&self, destination: PathBuf, target: String) -> BoxFuture<(), String> {
+    future::result(symlink(&target, &destination).map_err(|e| {
+      format!(
+        "Error creating symlink {:?} -> {:?}: {:?}",
+        destination, target, e
+      )
+    })).to_boxed()
+  }
+
   fn materialize_file(
     &self,
     destination: PathBuf,
     digest: Digest,
     is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    let file_cache = match self.file_cache {
+      Some(ref file_cache) => file_cache.clone(),
+      None => return self.write_file(destination, digest, is_executable, None),
+    };
+    let store = self.clone();
+    file_cache
+      .materialize_if_cached(digest, is_executable, destination.clone())
+      .and_then(move |was_cached| {
+        if was_cached {
+          future::ok(()).to_boxed()
+        } else {
+          store.write_file(destination, digest, is_executable, Some(file_cache))
+        }
+      })
+      .to_boxed()
+  }
+
+  fn write_file(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+    cache: Option<FileCache>,
   ) -> BoxFuture<(), String> {
     self
-      .load_file_bytes_with(digest, move |bytes| {
-        OpenOptions::new()
-          .create(true)
-          .write(true)
-          .mode(if is_executable { 0o755 } else { 0o644 })
-          .open(&destination)
-          .and_then(|mut f| f.write_all(&bytes))
-          .map_err(|e| format!("Error writing file {:?}: {:?}", destination, e))
+      .load_file_bytes_with(digest, move |bytes| match cache {
+        Some(ref cache) => {
+          cache.insert_and_materialize(digest, is_executable, &bytes, &destination)
+        }
+        None => file_cache::write_file(&destination, &bytes, is_executable),
       })
       .map_err(|e| e.into())
       .and_then(move |write_result| match write_result {
@@ -475,6 +686,134 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Computes which files and symlinks differ between the Directory with digest `before` and the
+  /// Directory with digest `after`. Subtrees which have the same digest on both sides are skipped
+  /// without being loaded.
+  ///
+  /// Returns None if either Directory (or any of the sub-directories which needed to be loaded)
+  /// is not in the Store.
+  ///
+  pub fn diff_directories(
+    &self,
+    before: Digest,
+    after: Digest,
+  ) -> BoxFuture<Option<DirectoryDiff>, String> {
+    self
+      .diff_directories_helper(Some(before), Some(after), PathBuf::new())
+      .map(|maybe_diff| {
+        maybe_diff.map(|mut diff| {
+          diff.added.sort_by(|l, r| l.0.cmp(&r.0));
+          diff.removed.sort_by(|l, r| l.0.cmp(&r.0));
+          diff.modified.sort_by(|l, r| l.0.cmp(&r.0));
+          diff
+        })
+      })
+      .to_boxed()
+  }
+
+  // A missing digest stands for a directory which does not exist on that side of the diff.
+  fn diff_directories_helper(
+    &self,
+    before: Option<Digest>,
+    after: Option<Digest>,
+    path_so_far: PathBuf,
+  ) -> BoxFuture<Option<DirectoryDiff>, String> {
+    if before == after {
+      return future::ok(Some(DirectoryDiff::default())).to_boxed();
+    }
+    let store = self.clone();
+    self
+      .load_directory_if_present(before)
+      .join(self.load_directory_if_present(after))
+      .and_then(move |maybe_dirs| {
+        let (before_dir, after_dir) = match maybe_dirs {
+          (Some(before_dir), Some(after_dir)) => (before_dir, after_dir),
+          _ => return future::ok(None).to_boxed(),
+        };
+        let before_entries = try_future!(diff_entries_by_name(&before_dir));
+        let after_entries = try_future!(diff_entries_by_name(&after_dir));
+        let mut diff = DirectoryDiff::default();
+        for (name, before_entry) in &before_entries {
+          let path = path_so_far.join(name);
+          match after_entries.get(name) {
+            Some(after_entry) if after_entry != before_entry => {
+              diff
+                .modified
+                .push((path, before_entry.clone(), after_entry.clone()))
+            }
+            Some(_) => (),
+            None => diff.removed.push((path, before_entry.clone())),
+          }
+        }
+        for (name, after_entry) in &after_entries {
+          if !before_entries.contains_key(name) {
+            diff
+              .added
+              .push((path_so_far.join(name), after_entry.clone()));
+          }
+        }
+
+        let before_dirs = try_future!(directory_digests_by_name(&before_dir));
+        let after_dirs = try_future!(directory_digests_by_name(&after_dir));
+        let child_futures = before_dirs
+          .keys()
+          .chain(after_dirs.keys().filter(|name| !before_dirs.contains_key(*name)))
+          .map(|name| {
+            store.diff_directories_helper(
+              before_dirs.get(name).cloned(),
+              after_dirs.get(name).cloned(),
+              path_so_far.join(name),
+            )
+          })
+          .collect::<Vec<_>>();
+        future::join_all(child_futures)
+          .map(move |child_diffs| {
+            for child_diff in child_diffs {
+              let child_diff = match child_diff {
+                Some(child_diff) => child_diff,
+                None => return None,
+              };
+              diff.added.extend(child_diff.added);
+              diff.removed.extend(child_diff.removed);
+              diff.modified.extend(child_diff.modified);
+            }
+            Some(diff)
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Loads the Directory with the given digest if it is in the Store, or an empty Directory if no
+  /// digest is given.
+  ///
+  fn load_directory_if_present(
+    &self,
+    maybe_digest: Option<Digest>,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
+    match maybe_digest {
+      Some(digest) => self.load_directory(digest),
+      None => future::ok(Some(bazel_protos::remote_execution::Directory::new())).to_boxed(),
+    }
+  }
+
+  fn load_directory_or_empty(
+    &self,
+    maybe_digest: Option<Digest>,
+  ) -> BoxFuture<bazel_protos::remote_execution::Directory, String> {
+    match maybe_digest {
+      Some(digest) => self
+        .load_directory(digest)
+        .and_then(move |maybe_dir| {
+          maybe_dir.ok_or_else(|| format!("Could not find directory with digest {:?}", digest))
+        })
+        .to_boxed(),
+      None => future::ok(bazel_protos::remote_execution::Directory::new()).to_boxed(),
+    }
+  }
+
   // Returns files sorted by their path.
   pub fn contents_for_directory(
     &self,
@@ -551,6 +890,132 @@ impl Store {
   }
 }
 
+///
+/// The files and symlinks which differ between two Directories, each sorted by path.
+///
+/// Entries which moved between paths are reported as removed from one and added at the other.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirectoryDiff {
+  // Entries only present in the after Directory.
+  pub added: Vec<(PathBuf, DiffEntry)>,
+  // Entries only present in the before Directory.
+  pub removed: Vec<(PathBuf, DiffEntry)>,
+  // Entries present in both Directories with different content, executable bits, targets or
+  // kinds, with their before and after values.
+  pub modified: Vec<(PathBuf, DiffEntry, DiffEntry)>,
+}
+
+///
+/// A non-directory entry of a Directory, as compared by `Store::diff_directories`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DiffEntry {
+  File { digest: Digest, is_executable: bool },
+  Symlink { target: String },
+}
+
+impl DirectoryDiff {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+  }
+}
+
+impl fmt::Display for DiffEntry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &DiffEntry::File {
+        ref digest,
+        is_executable,
+      } => write!(f, "file {} {} {}", digest.0, digest.1, is_executable),
+      &DiffEntry::Symlink { ref target } => write!(f, "symlink {}", target),
+    }
+  }
+}
+
+fn file_digests_by_name(
+  directory: &bazel_protos::remote_execution::Directory,
+) -> Result<BTreeMap<String, (Digest, bool)>, String> {
+  directory
+    .get_files()
+    .iter()
+    .map(|file_node| {
+      let digest: Result<Digest, String> = file_node.get_digest().into();
+      digest.map(|digest| {
+        (
+          file_node.get_name().to_owned(),
+          (digest, file_node.get_is_executable()),
+        )
+      })
+    })
+    .collect()
+}
+
+fn diff_entries_by_name(
+  directory: &bazel_protos::remote_execution::Directory,
+) -> Result<BTreeMap<String, DiffEntry>, String> {
+  let mut entries: BTreeMap<String, DiffEntry> = file_digests_by_name(directory)?
+    .into_iter()
+    .map(|(name, (digest, is_executable))| {
+      (
+        name,
+        DiffEntry::File {
+          digest: digest,
+          is_executable: is_executable,
+        },
+      )
+    })
+    .collect();
+  entries.extend(
+    symlink_targets_by_name(directory)
+      .into_iter()
+      .map(|(name, target)| (name, DiffEntry::Symlink { target: target })),
+  );
+  Ok(entries)
+}
+
+fn directory_digests_by_name(
+  directory: &bazel_protos::remote_execution::Directory,
+) -> Result<BTreeMap<String, Digest>, String> {
+  directory
+    .get_directories()
+    .iter()
+    .map(|dir_node| {
+      let digest: Result<Digest, String> = dir_node.get_digest().into();
+      digest.map(|digest| (dir_node.get_name().to_owned(), digest))
+    })
+    .collect()
+}
+
+fn symlink_targets_by_name(
+  directory: &bazel_protos::remote_execution::Directory,
+) -> BTreeMap<String, String> {
+  directory
+    .get_symlinks()
+    .iter()
+    .map(|symlink_node| {
+      (
+        symlink_node.get_name().to_owned(),
+        symlink_node.get_target().to_owned(),
+      )
+    })
+    .collect()
+}
+
+// Paths which have already been removed (e.g. by the process which last used them) are ignored.
+fn remove_path(path: &Path, is_dir: bool) -> Result<(), String> {
+  let result = if is_dir {
+    std::fs::remove_dir_all(path)
+  } else {
+    std::fs::remove_file(path)
+  };
+  match result {
+    Ok(()) => Ok(()),
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    Err(e) => Err(format!("Error removing {:?}: {:?}", path, e)),
+  }
+}
+
 // Only public for testing.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub enum EntryType {
@@ -1516,7 +1981,7 @@ mod local {
 }
 
 mod remote {
-  use super::EntryType;
+  use super::{EntryType, TransferCounts};
 
   use bazel_protos;
   use boxfuture::{BoxFuture, Boxable};
@@ -1530,6 +1995,7 @@ mod remote {
   use std::cmp::min;
   use std::collections::HashSet;
   use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
   use std::time::Duration;
 
   #[derive(Clone)]
@@ -1541,6 +2007,9 @@ mod remote {
     upload_timeout: Duration,
     env: Resettable<Arc<grpcio::Environment>>,
     channel: Resettable<grpcio::Channel>,
+    // Shared between clones, so that all users of the remote are counted.
+    bytes_uploaded: Arc<AtomicUsize>,
+    bytes_downloaded: Arc<AtomicUsize>,
   }
 
   impl ByteStore {
@@ -1573,6 +2042,15 @@ mod remote {
         upload_timeout,
         env,
         channel,
+        bytes_uploaded: Arc::new(AtomicUsize::new(0)),
+        bytes_downloaded: Arc::new(AtomicUsize::new(0)),
+      }
+    }
+
+    pub fn transfer_counts(&self) -> TransferCounts {
+      TransferCounts {
+        uploaded: self.bytes_uploaded.load(Ordering::SeqCst),
+        downloaded: self.bytes_downloaded.load(Ordering::SeqCst),
       }
     }
 
@@ -1606,6 +2084,7 @@ mod remote {
         )).to_boxed(),
         Ok((sender, receiver)) => {
           let chunk_size_bytes = self.chunk_size_bytes;
+          let bytes_uploaded = self.bytes_uploaded.clone();
           let stream =
             futures::stream::unfold::<_, _, futures::future::FutureResult<_, grpcio::Error>, _>(
               (0 as usize, false),
@@ -1651,6 +2130,7 @@ mod remote {
                   received.get_committed_size()
                 ))
               } else {
+                bytes_uploaded.fetch_add(len, Ordering::SeqCst);
                 Ok(Digest(fingerprint, len))
               }
             })
@@ -1674,11 +2154,13 @@ mod remote {
         req
       }) {
         Ok(stream) => {
+          let bytes_downloaded = self.bytes_downloaded.clone();
           // We shouldn't have to pass around the client here, it's a workaround for
           // https://github.com/pingcap/grpc-rs/issues/123
           future::ok(self.byte_stream_client.get())
             .join(
               stream.fold(BytesMut::with_capacity(digest.1), move |mut bytes, r| {
+                bytes_downloaded.fetch_add(r.data.len(), Ordering::SeqCst);
                 bytes.extend_from_slice(&r.data);
                 future::ok::<_, grpcio::Error>(bytes)
               }),
@@ -1738,7 +2220,7 @@ mod remote {
     extern crate tempfile;
 
     use super::ByteStore;
-    use super::super::EntryType;
+    use super::super::{EntryType, TransferCounts};
     use bytes::Bytes;
     use futures::Future;
     use hashing::Digest;
@@ -1879,6 +2361,26 @@ mod remote {
       assert_eq!(blobs.get(&testdata.fingerprint()), Some(&testdata.bytes()));
     }
 
+    #[test]
+    fn counts_transferred_bytes() {
+      let testdata = TestData::roland();
+      let cas = StubCAS::empty();
+
+      let store = new_byte_store(&cas);
+      store.store_bytes(testdata.bytes()).wait().unwrap();
+      assert_eq!(
+        load_file_bytes(&store, testdata.digest()),
+        Ok(Some(testdata.bytes()))
+      );
+      assert_eq!(
+        store.transfer_counts(),
+        TransferCounts {
+          uploaded: testdata.len(),
+          downloaded: testdata.len(),
+        }
+      );
+    }
+
     #[test]
     fn write_file_multiple_chunks() {
       let cas = StubCAS::empty();
@@ -2036,11 +2538,12 @@ mod remote {
 
 #[cfg(test)]
 mod tests {
-  use super::{local, EntryType, FileContent, Store};
+  use super::{local, DiffEntry, DirectoryDiff, EntryType, FileContent, Store};
 
   use bazel_protos;
   use bytes::Bytes;
   use digest::{Digest as DigestTrait, FixedOutput};
+  use file_cache::FileCacheMode;
   use futures::Future;
   use hashing::{Digest, Fingerprint};
   use mock::StubCAS;
@@ -2500,6 +3003,30 @@ mod tests {
     );
   }
 
+  #[test]
+  fn contains_directory_recursive() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+    let testdir = TestDirectory::containing_roland();
+
+    let contains = |digest| store.contains_directory_recursive(digest).wait();
+    assert_eq!(contains(TestDirectory::empty().digest()), Ok(true));
+    assert_eq!(contains(testdir.digest()), Ok(false));
+
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory locally");
+    // The Directory is present, but the file that it contains is not.
+    assert_eq!(contains(testdir.digest()), Ok(false));
+
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+    assert_eq!(contains(testdir.digest()), Ok(true));
+  }
+
   #[test]
   fn uploads_files() {
     let dir = TempDir::new().unwrap();
@@ -2788,6 +3315,41 @@ mod tests {
     assert!(is_executable(&file));
   }
 
+  #[test]
+  fn materialize_file_twice_uses_file_cache() {
+    let materialize_dir = TempDir::new().unwrap();
+    let first = materialize_dir.path().join("first");
+    let second = materialize_dir.path().join("second");
+
+    let testdata = TestData::roland();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path())
+      .with_file_cache(
+        store_dir.path().join("immutable_files"),
+        Arc::new(ResettablePool::new("test-pool-".to_string())),
+        FileCacheMode::ReflinkOrHardlink,
+      )
+      .unwrap();
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error saving bytes");
+    for file in vec![&first, &second] {
+      store
+        .materialize_file(file.clone(), testdata.digest(), false)
+        .wait()
+        .expect("Error materializing file");
+      assert_eq!(file_contents(file), testdata.bytes());
+    }
+
+    let cached_files = std::fs::read_dir(store_dir.path().join("immutable_files"))
+      .unwrap()
+      .flat_map(|shard| std::fs::read_dir(shard.unwrap().path()).unwrap())
+      .count();
+    assert_eq!(cached_files, 1);
+  }
+
   #[test]
   fn materialize_missing_directory() {
     let materialize_dir = TempDir::new().unwrap();
@@ -2854,6 +3416,70 @@ mod tests {
     );
   }
 
+  #[test]
+  fn sync_directory() {
+    use std::os::unix::fs::MetadataExt;
+
+    let materialize_dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let recursive_testdir = TestDirectory::recursive();
+    let flat_testdir = TestDirectory::containing_roland_and_treats();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    for directory in vec![
+      TestDirectory::containing_roland(),
+      TestDirectory::recursive(),
+      TestDirectory::containing_roland_and_treats(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+    store
+      .store_file_bytes(catnip.bytes(), false)
+      .wait()
+      .expect("Error saving catnip file bytes");
+
+    store
+      .materialize_directory(
+        materialize_dir.path().to_owned(),
+        recursive_testdir.digest(),
+      )
+      .wait()
+      .expect("Error materializing");
+    let treats_inode = |path: &Path| std::fs::metadata(path.join("treats")).unwrap().ino();
+    let inode_before = treats_inode(materialize_dir.path());
+
+    store
+      .sync_directory(
+        materialize_dir.path().to_owned(),
+        recursive_testdir.digest(),
+        flat_testdir.digest(),
+      )
+      .wait()
+      .expect("Error syncing");
+
+    assert_eq!(list_dir(materialize_dir.path()), vec!["roland", "treats"]);
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("roland")),
+      roland.bytes()
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("treats")),
+      catnip.bytes()
+    );
+    // Unchanged files are left alone.
+    assert_eq!(treats_inode(materialize_dir.path()), inode_before);
+  }
+
   #[test]
   fn materialize_directory_executable() {
     let materialize_dir = TempDir::new().unwrap();
@@ -2890,6 +3516,249 @@ mod tests {
     assert!(!is_executable(&materialize_dir.path().join("food")));
   }
 
+  #[test]
+  fn materialize_directory_symlinks_and_empty_directories() {
+    let materialize_dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    let symlink_testdir = TestDirectory::with_symlink_and_empty_directory();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&symlink_testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory with symlink");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+
+    store
+      .materialize_directory(materialize_dir.path().to_owned(), symlink_testdir.digest())
+      .wait()
+      .expect("Error materializing");
+
+    assert_eq!(
+      list_dir(materialize_dir.path()),
+      vec!["cats", "dogs", "roland"]
+    );
+    assert_eq!(
+      list_dir(&materialize_dir.path().join("dogs")),
+      Vec::<String>::new()
+    );
+    assert_eq!(
+      std::fs::read_link(materialize_dir.path().join("roland")).unwrap(),
+      PathBuf::from("cats").join("roland")
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("roland")),
+      roland.bytes()
+    );
+  }
+
+  #[test]
+  fn diff_directories_skips_identical_digests() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+
+    // The Directory is not in the Store, so this would fail if it were loaded.
+    let digest = TestDirectory::recursive().digest();
+    assert_eq!(
+      store.diff_directories(digest, digest).wait(),
+      Ok(Some(DirectoryDiff::default()))
+    );
+  }
+
+  #[test]
+  fn diff_directories_missing() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::containing_roland().digest(),
+          TestDirectory::recursive().digest(),
+        )
+        .wait(),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn diff_directories() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    for directory in vec![
+      TestDirectory::recursive(),
+      TestDirectory::containing_roland(),
+      TestDirectory::containing_roland_and_treats(),
+      TestDirectory::containing_wrong_roland(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::recursive().digest(),
+          TestDirectory::containing_roland_and_treats().digest(),
+        )
+        .wait(),
+      Ok(Some(DirectoryDiff {
+        added: vec![(PathBuf::from("roland"), file_entry(TestData::roland(), false))],
+        removed: vec![(
+          PathBuf::from("cats").join("roland"),
+          file_entry(TestData::roland(), false),
+        )],
+        modified: vec![],
+      }))
+    );
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::containing_roland().digest(),
+          TestDirectory::containing_wrong_roland().digest(),
+        )
+        .wait(),
+      Ok(Some(DirectoryDiff {
+        added: vec![],
+        removed: vec![],
+        modified: vec![(
+          PathBuf::from("roland"),
+          file_entry(TestData::roland(), false),
+          file_entry(TestData::catnip(), false),
+        )],
+      }))
+    );
+  }
+
+  #[test]
+  fn diff_directories_compares_symlinks_and_executable_bits() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    let mut executable_roland = TestDirectory::containing_roland().directory();
+    executable_roland.mut_files()[0].set_is_executable(true);
+    let mut retargeted_symlink = TestDirectory::with_symlink().directory();
+    retargeted_symlink.mut_symlinks()[0].set_target("dogs/roland".to_string());
+    let executable_roland_digest = store
+      .record_directory(&executable_roland, false)
+      .wait()
+      .expect("Error saving Directory");
+    let retargeted_symlink_digest = store
+      .record_directory(&retargeted_symlink, false)
+      .wait()
+      .expect("Error saving Directory");
+    for directory in vec![
+      TestDirectory::containing_roland(),
+      TestDirectory::nested(),
+      TestDirectory::with_symlink(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::containing_roland().digest(),
+          executable_roland_digest,
+        )
+        .wait(),
+      Ok(Some(DirectoryDiff {
+        added: vec![],
+        removed: vec![],
+        modified: vec![(
+          PathBuf::from("roland"),
+          file_entry(TestData::roland(), false),
+          file_entry(TestData::roland(), true),
+        )],
+      }))
+    );
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::nested().digest(),
+          TestDirectory::with_symlink().digest(),
+        )
+        .wait(),
+      Ok(Some(DirectoryDiff {
+        added: vec![(PathBuf::from("roland"), symlink_entry("cats/roland"))],
+        removed: vec![],
+        modified: vec![],
+      }))
+    );
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::with_symlink().digest(),
+          retargeted_symlink_digest,
+        )
+        .wait(),
+      Ok(Some(DirectoryDiff {
+        added: vec![],
+        removed: vec![],
+        modified: vec![(
+          PathBuf::from("roland"),
+          symlink_entry("cats/roland"),
+          symlink_entry("dogs/roland"),
+        )],
+      }))
+    );
+
+    assert_eq!(
+      store
+        .diff_directories(
+          TestDirectory::containing_roland().digest(),
+          TestDirectory::with_symlink().digest(),
+        )
+        .wait(),
+      Ok(Some(DirectoryDiff {
+        added: vec![(
+          PathBuf::from("cats").join("roland"),
+          file_entry(TestData::roland(), false),
+        )],
+        removed: vec![],
+        modified: vec![(
+          PathBuf::from("roland"),
+          file_entry(TestData::roland(), false),
+          symlink_entry("cats/roland"),
+        )],
+      }))
+    );
+  }
+
+  fn file_entry(data: TestData, is_executable: bool) -> DiffEntry {
+    DiffEntry::File {
+      digest: data.digest(),
+      is_executable: is_executable,
+    }
+  }
+
+  fn symlink_entry(target: &str) -> DiffEntry {
+    DiffEntry::Symlink {
+      target: target.to_string(),
+    }
+  }
+
   #[test]
   fn works_after_reset_prefork() {
     let dir = TempDir::new().unwrap();
@@ -0,0 +1,426 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use notify::{self, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use {Dir, GitignoreStyleExcludes, Stat};
+
+///
+/// A change reported by an `InvalidationWatcher`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Invalidation {
+  /// The given paths (relative to the root of the PosixFS) changed.
+  Paths(HashSet<PathBuf>),
+  /// Events were lost (because the OS queue overflowed, for example), so any path beneath a
+  /// watched directory might have changed.
+  All,
+}
+
+///
+/// Watches the directories that a PosixFS has scanned for changes (using inotify on Linux), and
+/// reports the changed paths to an `invalidate` function.
+///
+/// Events are debounced, so a burst of changes to a path (as when an editor saves a file) is
+/// reported once. The reported paths are relative to the root of the PosixFS, and always
+/// include the parent directory of each changed path, since creating or deleting a file changes
+/// the listing of its parent. Paths matching the ignore patterns of the PosixFS are not reported.
+///
+/// Events are processed (and `invalidate` is called) on a background thread, which must be paused
+/// with `pause_prefork` before the process forks: see that method. Events which have been
+/// delivered to that thread can be applied synchronously with `flush`.
+///
+/// If a directory cannot be watched (because the OS limit on watches was reached, for example),
+/// the watcher can no longer detect all changes, and so every `flush` invalidates everything.
+///
+pub struct InvalidationWatcher {
+  watcher: Mutex<RecommendedWatcher>,
+  // A sender for the channel that the watcher delivers events to, used to send flush markers.
+  sender: Mutex<Sender<DebouncedEvent>>,
+  // The counts of invalidations and of flush markers that the event thread has applied, and the
+  // count of flush markers that have been sent.
+  applied: Arc<(Mutex<Applied>, Condvar)>,
+  // Whether some directory could not be watched.
+  unwatched: AtomicBool,
+  // The directories (relative to the root) that are currently watched.
+  watched: Arc<Mutex<HashSet<PathBuf>>>,
+  // The directories (relative to the root) that have appeared in the listings of watched
+  // directories. Once a path has been deleted it is no longer possible to stat it, so this is
+  // used to determine whether it was a directory.
+  listed_dirs: Arc<Mutex<HashSet<PathBuf>>>,
+  // Whether the processing of events is paused.
+  paused: Arc<(Mutex<bool>, Condvar)>,
+  root: PathBuf,
+  // The process which started the watcher. A forked child does not inherit its threads.
+  pid: libc::pid_t,
+}
+
+#[derive(Default)]
+struct Applied {
+  invalidations: u64,
+  flushes: u64,
+  flushes_sent: u64,
+}
+
+///
+/// The event that `flush` sends through the event channel. The watcher only reports absolute
+/// paths, so it never sends a notice for the empty path itself.
+///
+fn flush_marker() -> DebouncedEvent {
+  DebouncedEvent::NoticeWrite(PathBuf::new())
+}
+
+impl InvalidationWatcher {
+  pub fn new<F>(
+    root: &Path,
+    ignore: Arc<GitignoreStyleExcludes>,
+    debounce: Duration,
+    invalidate: F,
+  ) -> Result<InvalidationWatcher, String>
+  where
+    F: Fn(Invalidation) + Send + 'static,
+  {
+    let (sender, receiver) = mpsc::channel();
+    let watcher = notify::watcher(sender.clone(), debounce)
+      .map_err(|e| format!("Failed to start filesystem watcher: {}", e))?;
+    let watched = Arc::new(Mutex::new(HashSet::new()));
+    let listed_dirs = Arc::new(Mutex::new(HashSet::new()));
+    let paused = Arc::new((Mutex::new(false), Condvar::new()));
+    let applied = Arc::new((Mutex::new(Applied::default()), Condvar::new()));
+
+    // The thread exits when the watcher (which owns the senders for the channel) is dropped.
+    let thread_root = root.to_owned();
+    let thread_watched = watched.clone();
+    let thread_listed_dirs = listed_dirs.clone();
+    let thread_paused = paused.clone();
+    let thread_applied = applied.clone();
+    thread::Builder::new()
+      .name("fs-watcher".to_string())
+      .spawn(move || {
+        InvalidationWatcher::process_events(
+          &thread_root,
+          &ignore,
+          &thread_watched,
+          &thread_listed_dirs,
+          &thread_paused,
+          &thread_applied,
+          receiver,
+          invalidate,
+        )
+      })
+      .map_err(|e| format!("Failed to start filesystem watcher thread: {}", e))?;
+
+    Ok(InvalidationWatcher {
+      watcher: Mutex::new(watcher),
+      sender: Mutex::new(sender),
+      applied: applied,
+      unwatched: AtomicBool::new(false),
+      watched: watched,
+      listed_dirs: listed_dirs,
+      paused: paused,
+      root: root.to_owned(),
+      pid: unsafe { libc::getpid() },
+    })
+  }
+
+  ///
+  /// Begins watching the given directory (relative to the root), if it is not already watched.
+  ///
+  /// Directories should be watched before they are scanned, so that changes which occur
+  /// between scanning and watching are not missed.
+  ///
+  /// If an existing directory cannot be watched, the watcher is marked unwatched (so that each
+  /// subsequent `flush` invalidates everything), and an error is returned. A directory which does
+  /// not exist is not an error here: scanning it will fail in its own right.
+  ///
+  pub fn watch(&self, dir: &Dir) -> Result<(), String> {
+    if !self.is_owner() {
+      // The threads that service the watcher do not exist in a forked child, so registering a
+      // watch would block forever. A child's Graph is never invalidated by the watcher anyway.
+      return Ok(());
+    }
+    if self.unwatched.load(Ordering::SeqCst) {
+      // Everything is invalidated before each run anyway.
+      return Ok(());
+    }
+    if !self.watched.lock().unwrap().insert(dir.0.clone()) {
+      return Ok(());
+    }
+    let path = self.root.join(&dir.0);
+    let res = self
+      .watcher
+      .lock()
+      .unwrap()
+      .watch(&path, RecursiveMode::NonRecursive);
+    match res {
+      Ok(()) => Ok(()),
+      Err(e) => {
+        self.watched.lock().unwrap().remove(&dir.0);
+        if fs::symlink_metadata(&path).is_err() {
+          return Ok(());
+        }
+        self.mark_unwatched();
+        Err(format!(
+          "Failed to watch {:?}: {}. Changes to the filesystem can no longer be detected, so all \
+           filesystem nodes will be invalidated before each run.",
+          path, e
+        ))
+      }
+    }
+  }
+
+  ///
+  /// Records the directories in the listing of a watched directory.
+  ///
+  pub fn listed(&self, stats: &[Stat]) {
+    let mut listed_dirs = self.listed_dirs.lock().unwrap();
+    for stat in stats {
+      if let &Stat::Dir(ref dir) = stat {
+        listed_dirs.insert(dir.0.clone());
+      }
+    }
+  }
+
+  ///
+  /// Pauses the processing of events (which are queued meanwhile), waiting for any invalidation
+  /// that is in progress to complete.
+  ///
+  /// Must be called before forking, so that a forked child does not inherit locks (in
+  /// particular, the lock of the Graph being invalidated) that were held by the event thread.
+  ///
+  pub fn pause_prefork(&self) {
+    let &(ref paused, _) = &*self.paused;
+    *paused.lock().unwrap() = true;
+  }
+
+  ///
+  /// Resumes the processing of events after `pause_prefork`, beginning with any that were queued
+  /// while paused.
+  ///
+  pub fn resume(&self) {
+    let &(ref paused, ref condvar) = &*self.paused;
+    *paused.lock().unwrap() = false;
+    condvar.notify_all();
+  }
+
+  ///
+  /// Applies the invalidations for all events that have been delivered to the event thread,
+  /// waiting until it has done so. If the watcher is unwatched, everything is invalidated.
+  ///
+  /// Events for changes which are still being debounced have not yet been delivered, and so are
+  /// not applied. Must not be called while the watcher is paused.
+  ///
+  pub fn flush(&self) {
+    if !self.is_owner() {
+      return;
+    }
+    let &(ref applied, ref condvar) = &*self.applied;
+    let flush = {
+      let sender = self.sender.lock().unwrap();
+      if self.unwatched.load(Ordering::SeqCst) && sender.send(DebouncedEvent::Rescan).is_err() {
+        return;
+      }
+      if sender.send(flush_marker()).is_err() {
+        // The event thread has exited.
+        return;
+      }
+      // Incremented while holding the sender, so that markers are counted in the order sent.
+      let mut applied = applied.lock().unwrap();
+      applied.flushes_sent += 1;
+      applied.flushes_sent
+    };
+    let mut applied = applied.lock().unwrap();
+    while applied.flushes < flush {
+      applied = condvar.wait(applied).unwrap();
+    }
+  }
+
+  ///
+  /// The number of times the event thread has called `invalidate`.
+  ///
+  pub fn invalidation_count(&self) -> u64 {
+    let &(ref applied, _) = &*self.applied;
+    applied.lock().unwrap().invalidations
+  }
+
+  ///
+  /// Waits (for at most the given timeout) until `invalidation_count` exceeds the given count,
+  /// and returns whether it did.
+  ///
+  pub fn await_invalidation(&self, count: u64, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let &(ref applied, ref condvar) = &*self.applied;
+    let mut applied = applied.lock().unwrap();
+    while applied.invalidations <= count {
+      let now = Instant::now();
+      if now >= deadline {
+        return false;
+      }
+      applied = condvar.wait_timeout(applied, deadline - now).unwrap().0;
+    }
+    true
+  }
+
+  fn mark_unwatched(&self) {
+    self.unwatched.store(true, Ordering::SeqCst);
+  }
+
+  fn is_owner(&self) -> bool {
+    unsafe { libc::getpid() == self.pid }
+  }
+
+  fn process_events<F>(
+    root: &Path,
+    ignore: &GitignoreStyleExcludes,
+    watched: &Mutex<HashSet<PathBuf>>,
+    listed_dirs: &Mutex<HashSet<PathBuf>>,
+    pause: &(Mutex<bool>, Condvar),
+    applied: &(Mutex<Applied>, Condvar),
+    receiver: Receiver<DebouncedEvent>,
+    invalidate: F,
+  ) where
+    F: Fn(Invalidation),
+  {
+    let &(ref applied, ref applied_condvar) = applied;
+    let invalidate = |invalidation| {
+      invalidate(invalidation);
+      applied.lock().unwrap().invalidations += 1;
+      applied_condvar.notify_all();
+    };
+
+    for event in receiver {
+      let changed = match event {
+        // Events are processed in order, so every event delivered before the marker was applied.
+        DebouncedEvent::NoticeWrite(ref path) if path.as_os_str().is_empty() => {
+          applied.lock().unwrap().flushes += 1;
+          applied_condvar.notify_all();
+          continue;
+        }
+        // Notices are sent immediately, before the corresponding debounced event.
+        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => continue,
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path)
+        | DebouncedEvent::Error(_, Some(path)) => vec![path],
+        DebouncedEvent::Rename(src, dst) => vec![src, dst],
+        DebouncedEvent::Rescan | DebouncedEvent::Error(_, None) => vec![],
+      };
+
+      // Hold the pause lock while invalidating, so that `pause_prefork` waits for us.
+      let &(ref paused, ref condvar) = pause;
+      let mut is_paused = paused.lock().unwrap();
+      while *is_paused {
+        is_paused = condvar.wait(is_paused).unwrap();
+      }
+
+      if changed.is_empty() {
+        // Events may have been lost, so everything beneath the watched directories might have
+        // changed.
+        invalidate(Invalidation::All);
+        continue;
+      }
+
+      let mut paths = HashSet::new();
+      for path in changed {
+        let relative_path = match path.strip_prefix(root) {
+          Ok(relative_path) => relative_path.to_owned(),
+          Err(_) => continue,
+        };
+        let is_dir = match fs::symlink_metadata(&path) {
+          Ok(metadata) => metadata.is_dir(),
+          Err(_) => {
+            // A deleted directory is no longer watched by the OS, so it must be watched again if
+            // it is recreated and re-scanned.
+            let was_watched = watched.lock().unwrap().remove(&relative_path);
+            let was_listed = listed_dirs.lock().unwrap().remove(&relative_path);
+            was_watched || was_listed
+          }
+        };
+        if ignore.is_ignored_path(&relative_path, is_dir) {
+          continue;
+        }
+        if let Some(parent) = relative_path.parent() {
+          paths.insert(parent.to_owned());
+        }
+        paths.insert(relative_path);
+      }
+
+      if !paths.is_empty() {
+        invalidate(Invalidation::Paths(paths));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+  extern crate testutil;
+
+  use std::path::PathBuf;
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use self::testutil::make_file;
+  use super::{Invalidation, InvalidationWatcher};
+  use {Dir, GitignoreStyleExcludes};
+
+  #[test]
+  fn await_invalidation() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let (watcher, invalidations) = new_watcher(dir.path().canonicalize().unwrap());
+    watcher.watch(&Dir(PathBuf::from(""))).unwrap();
+
+    let count = watcher.invalidation_count();
+    make_file(&dir.path().join("marmoset"), &[], 0o600);
+    assert!(watcher.await_invalidation(count, Duration::from_secs(10)));
+    let invalidations = invalidations.lock().unwrap();
+    assert!(invalidations.iter().any(|invalidation| match invalidation {
+      &Invalidation::Paths(ref paths) => paths.contains(&PathBuf::from("marmoset")),
+      &Invalidation::All => false,
+    }));
+  }
+
+  #[test]
+  fn flush_when_unwatched_invalidates_everything() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let (watcher, invalidations) = new_watcher(dir.path().canonicalize().unwrap());
+
+    watcher.flush();
+    assert_eq!(Vec::<Invalidation>::new(), *invalidations.lock().unwrap());
+
+    // Once some directory could not be watched, each flush invalidates everything before it
+    // returns.
+    watcher.mark_unwatched();
+    watcher.flush();
+    assert_eq!(vec![Invalidation::All], *invalidations.lock().unwrap());
+    watcher.flush();
+    assert_eq!(
+      vec![Invalidation::All, Invalidation::All],
+      *invalidations.lock().unwrap()
+    );
+  }
+
+  fn new_watcher(root: PathBuf) -> (InvalidationWatcher, Arc<Mutex<Vec<Invalidation>>>) {
+    let invalidations = Arc::new(Mutex::new(vec![]));
+    let recorded = invalidations.clone();
+    let watcher = InvalidationWatcher::new(
+      &root,
+      GitignoreStyleExcludes::create(&[]).unwrap(),
+      Duration::from_millis(10),
+      move |invalidation| recorded.lock().unwrap().push(invalidation),
+    ).unwrap();
+    (watcher, invalidations)
+  }
+}
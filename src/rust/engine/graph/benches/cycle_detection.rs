@@ -0,0 +1,179 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! Measures the cost of cycle detection while building a synthetic deep graph, in which each
+//! Node depends on the two Nodes below it. Requesting the second dependency of each Node adds an
+//! edge into the middle of an existing deep subgraph, which is the worst case for a search-based
+//! cycle check. The graph is built both "top down" (by requesting only the deepest Node) and
+//! "bottom up" (by requesting each Node as a root in turn, so that every root depends on existing
+//! Nodes).
+//!
+//! Each build is measured with both the incremental cycle detection that the Graph uses, and the
+//! search-based cycle detection that it replaced. Run with
+//! `cargo bench --features bfs_cycle_detection`: the time per edge for incremental detection
+//! should stay roughly flat as the depth grows.
+//!
+
+extern crate boxfuture;
+extern crate futures;
+extern crate graph;
+extern crate hashing;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future::{self, Future};
+use graph::{EntryId, Graph, Node, NodeContext, NodeError};
+use hashing::Digest;
+
+const DEPTHS: &[usize] = &[1000, 2000, 4000, 8000];
+
+fn main() {
+  // Completing a deep chain of Nodes recurses through their futures, so use a large stack.
+  thread::Builder::new()
+    .stack_size(1 << 30)
+    .spawn(|| {
+      let builds: &[(&str, fn(usize) -> Vec<BNode>)] =
+        &[("top down", top_down), ("bottom up", bottom_up)];
+      let detections: &[(&str, fn() -> Graph<BNode>)] = &[
+        ("incremental", Graph::new),
+        ("bfs", Graph::with_bfs_cycle_detection),
+      ];
+      for &(name, roots) in builds {
+        for &(detection, new_graph) in detections {
+          for &depth in DEPTHS {
+            let elapsed = build(new_graph(), roots(depth));
+            let edges = 2 * depth as u64;
+            println!(
+              "{:>9}, {:>11}, depth {:>6}: {:>10.3}ms total, {:>8}ns per edge",
+              name,
+              detection,
+              depth,
+              as_millis(elapsed),
+              as_nanos(elapsed) / edges
+            );
+          }
+        }
+      }
+    })
+    .unwrap()
+    .join()
+    .unwrap();
+}
+
+fn top_down(depth: usize) -> Vec<BNode> {
+  vec![BNode(depth)]
+}
+
+fn bottom_up(depth: usize) -> Vec<BNode> {
+  (0..depth + 1).map(BNode).collect()
+}
+
+fn build(graph: Graph<BNode>, roots: Vec<BNode>) -> Duration {
+  let graph = Arc::new(graph);
+  let context = BContext {
+    graph: graph.clone(),
+    entry_id: None,
+  };
+  let start = Instant::now();
+  for root in roots {
+    graph.create(root, &context).wait().unwrap();
+  }
+  start.elapsed()
+}
+
+fn as_nanos(d: Duration) -> u64 {
+  d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn as_millis(d: Duration) -> f64 {
+  as_nanos(d) as f64 / 1_000_000.0
+}
+
+///
+/// A Node which depends on the two Nodes below it (like a naive fibonacci).
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct BNode(usize);
+
+impl Node for BNode {
+  type Context = BContext;
+  type Item = usize;
+  type Error = BError;
+
+  fn run(self, context: BContext) -> BoxFuture<usize, BError> {
+    if self.0 < 2 {
+      return future::ok(self.0).to_boxed();
+    }
+    // Request the dependencies sequentially, so that the second is already complete (and has its
+    // own deep dependencies) by the time that it is requested.
+    let second = BNode(self.0 - 2);
+    let second_context = context.clone();
+    context
+      .get(BNode(self.0 - 1))
+      .and_then(move |first| {
+        second_context
+          .get(second)
+          .map(move |second| (first + second) % 1_000_000_007)
+      })
+      .to_boxed()
+  }
+
+  fn format(&self) -> String {
+    format!("{:?}", self)
+  }
+
+  fn type_name(&self) -> &'static str {
+    "BNode"
+  }
+
+  fn digest(_result: usize) -> Option<Digest> {
+    None
+  }
+}
+
+#[derive(Clone)]
+struct BContext {
+  graph: Arc<Graph<BNode>>,
+  entry_id: Option<EntryId>,
+}
+
+impl BContext {
+  fn get(&self, dst: BNode) -> BoxFuture<usize, BError> {
+    self.graph.get(self.entry_id.unwrap(), self, dst)
+  }
+}
+
+impl NodeContext for BContext {
+  type Node = BNode;
+
+  fn clone_for(&self, entry_id: EntryId) -> BContext {
+    BContext {
+      graph: self.graph.clone(),
+      entry_id: Some(entry_id),
+    }
+  }
+
+  fn graph(&self) -> &Graph<BNode> {
+    &self.graph
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum BError {
+  Cyclic(Vec<String>),
+  Invalidated,
+}
+
+impl NodeError for BError {
+  fn invalidated() -> BError {
+    BError::Invalidated
+  }
+
+  fn cyclic(path: Vec<String>) -> BError {
+    BError::Cyclic(path)
+  }
+}
@@ -0,0 +1,195 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use json;
+
+///
+/// A span of time during which a Node ran, to be rendered as a Chrome trace "complete" event.
+///
+pub struct Span {
+  pub name: String,
+  pub category: &'static str,
+  // If set, the span is rendered in a lane of its own with this name, rather than in a shared
+  // lane with its dependents and dependencies.
+  pub lane: Option<&'static str>,
+  pub start: Instant,
+  // The time at which the span completed, or the time of the export if it is still running.
+  pub end: Instant,
+  pub completed: bool,
+  pub runs: u64,
+}
+
+///
+/// Writes the given spans as a JSON object in the Chrome trace-event format, which can be opened
+/// in `chrome://tracing` (among other viewers). Timestamps are relative to the earliest span.
+///
+/// Trace viewers render the events within a lane (a "thread") as a stack, so overlapping events
+/// in a lane must be properly nested. Spans are assigned to the first lane in which they nest,
+/// which places dependencies below the dependents that requested them.
+///
+pub fn write<W: Write>(spans: &mut [Span], f: &mut W) -> io::Result<()> {
+  // Sort parents before the children that they contain.
+  spans.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+  let (assignments, lanes) = assign_lanes(spans);
+  let origin = spans.first().map(|span| span.start);
+
+  writeln!(f, "{{\"traceEvents\": [")?;
+  let mut first = true;
+  let mut lane_counts: Vec<(Option<&'static str>, usize)> = Vec::new();
+  for (tid, lane) in lanes.iter().enumerate() {
+    let index = match lane_counts.iter().position(|&(name, _)| name == *lane) {
+      Some(position) => {
+        lane_counts[position].1 += 1;
+        lane_counts[position].1
+      }
+      None => {
+        lane_counts.push((*lane, 0));
+        0
+      }
+    };
+    let name = format!("{} {}", lane.unwrap_or("Nodes"), index);
+    separate(f, &mut first)?;
+    write!(
+      f,
+      "{{\"name\": \"thread_name\", \"ph\": \"M\", \"pid\": 1, \"tid\": {}, \
+       \"args\": {{\"name\": {}}}}}",
+      tid,
+      json::string(&name)
+    )?;
+  }
+  for (span, tid) in spans.iter().zip(assignments) {
+    let origin = origin.expect("There is at least one span.");
+    separate(f, &mut first)?;
+    write!(
+      f,
+      "{{\"name\": {}, \"cat\": {}, \"ph\": \"X\", \"ts\": {}, \"dur\": {}, \"pid\": 1, \
+       \"tid\": {}, \"args\": {{\"completed\": {}, \"runs\": {}}}}}",
+      json::string(&span.name),
+      json::string(span.category),
+      micros(span.start.duration_since(origin)),
+      micros(span.end.duration_since(span.start)),
+      tid,
+      span.completed,
+      span.runs
+    )?;
+  }
+  writeln!(f, "\n], \"displayTimeUnit\": \"ms\"}}")
+}
+
+fn separate<W: Write>(f: &mut W, first: &mut bool) -> io::Result<()> {
+  if *first {
+    *first = false;
+    Ok(())
+  } else {
+    writeln!(f, ",")
+  }
+}
+
+fn micros(d: Duration) -> String {
+  let nanos = d.subsec_nanos();
+  format!(
+    "{}.{:03}",
+    d.as_secs() * 1_000_000 + u64::from(nanos / 1_000),
+    nanos % 1_000
+  )
+}
+
+///
+/// Assigns each of the given spans (which must be sorted by start time, and then by descending end
+/// time) to a lane, such that the spans within a lane are either disjoint or properly nested.
+/// Spans with a named lane are only assigned to lanes with that name, in which spans never
+/// overlap. Returns the lane of each span, and the name of each lane.
+///
+fn assign_lanes(spans: &[Span]) -> (Vec<usize>, Vec<Option<&'static str>>) {
+  // For each lane, its name and the end times of its open spans, innermost last.
+  let mut lanes: Vec<(Option<&'static str>, Vec<Instant>)> = Vec::new();
+  let assignments = spans
+    .iter()
+    .map(|span| {
+      let position = lanes.iter_mut().position(|&mut (name, ref mut open)| {
+        if name != span.lane {
+          return false;
+        }
+        // Because spans are sorted by start time, spans that have ended will not contain any
+        // later span either.
+        while open.last().map(|&end| end <= span.start).unwrap_or(false) {
+          open.pop();
+        }
+        match open.last() {
+          None => true,
+          Some(&end) => name.is_none() && span.end <= end,
+        }
+      });
+      let lane = position.unwrap_or_else(|| {
+        lanes.push((span.lane, Vec::new()));
+        lanes.len() - 1
+      });
+      lanes[lane].1.push(span.end);
+      lane
+    })
+    .collect();
+  (assignments, lanes.into_iter().map(|(name, _)| name).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use super::{assign_lanes, write, Span};
+
+  fn span(origin: Instant, start: u64, end: u64, lane: Option<&'static str>) -> Span {
+    Span {
+      name: format!("{}..{}", start, end),
+      category: "Test",
+      lane: lane,
+      start: origin + Duration::from_millis(start),
+      end: origin + Duration::from_millis(end),
+      completed: true,
+      runs: 1,
+    }
+  }
+
+  #[test]
+  fn lanes() {
+    let origin = Instant::now();
+    let spans = vec![
+      span(origin, 0, 10, None),
+      span(origin, 1, 5, None),
+      // Overlaps the previous span without nesting in it.
+      span(origin, 4, 8, None),
+      span(origin, 5, 7, Some("Processes")),
+      span(origin, 6, 9, Some("Processes")),
+      span(origin, 8, 9, Some("Processes")),
+    ];
+    let (assignments, lanes) = assign_lanes(&spans);
+    assert_eq!(assignments, vec![0, 0, 1, 2, 3, 2]);
+    assert_eq!(lanes, vec![None, None, Some("Processes"), Some("Processes")]);
+  }
+
+  #[test]
+  fn trace_events() {
+    let origin = Instant::now();
+    let mut spans = vec![
+      span(origin, 1, 2, Some("Processes")),
+      span(origin, 0, 3, None),
+    ];
+    let mut out = Vec::new();
+    write(&mut spans, &mut out).unwrap();
+    assert_eq!(
+      String::from_utf8(out).unwrap(),
+      "{\"traceEvents\": [\n\
+       {\"name\": \"thread_name\", \"ph\": \"M\", \"pid\": 1, \"tid\": 0, \
+       \"args\": {\"name\": \"Nodes 0\"}},\n\
+       {\"name\": \"thread_name\", \"ph\": \"M\", \"pid\": 1, \"tid\": 1, \
+       \"args\": {\"name\": \"Processes 0\"}},\n\
+       {\"name\": \"0..3\", \"cat\": \"Test\", \"ph\": \"X\", \"ts\": 0.000, \"dur\": 3000.000, \
+       \"pid\": 1, \"tid\": 0, \"args\": {\"completed\": true, \"runs\": 1}},\n\
+       {\"name\": \"1..2\", \"cat\": \"Test\", \"ph\": \"X\", \"ts\": 1000.000, \
+       \"dur\": 1000.000, \"pid\": 1, \"tid\": 1, \"args\": {\"completed\": true, \"runs\": 1}}\n\
+       ], \"displayTimeUnit\": \"ms\"}\n"
+    );
+  }
+}
@@ -5,16 +5,24 @@ extern crate boxfuture;
 extern crate fnv;
 extern crate futures;
 extern crate hashing;
+extern crate json_encoding as json;
 extern crate petgraph;
+#[cfg(test)]
+extern crate tempfile;
 
+mod chrome_trace;
 mod node;
+pub mod persistence;
 
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::hash::BuildHasherDefault;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasherDefault, Hash};
 use std::fs::{File, OpenOptions};
 use std::io::{self, BufWriter, Write};
+use std::mem;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::collections::binary_heap::BinaryHeap;
 
@@ -22,20 +30,260 @@ use fnv::FnvHasher;
 
 use petgraph::Direction;
 use petgraph::stable_graph::{StableDiGraph, StableGraph};
+use petgraph::visit::EdgeRef;
 use futures::future::{self, Future};
 
 use boxfuture::{BoxFuture, Boxable};
 pub use node::{EntryId, Node, NodeContext, NodeError, NodeTracer, NodeVisualizer};
+use persistence::PersistedEntry;
 
 type FNV = BuildHasherDefault<FnvHasher>;
 
+// The number of times in a row that a loaded persisted entry is persisted again without its Node
+// being requested, before it is dropped: see `Graph::persist`.
+const MAX_PERSISTED_AGE: u64 = 5;
+
 type PGraph<N> = StableDiGraph<Entry<N>, (), u32>;
 
-type EntryStateField<Item, Error> = future::Shared<BoxFuture<Item, Error>>;
+// When a Graph exceeds its EvictionLimits, Entries are evicted until it is this percentage below
+// them.
+const EVICTION_HEADROOM_PERCENT: usize = 10;
+
+///
+/// A counter that is incremented each time an Entry completes with a result that differs from
+/// its previous result. Dependents record the Generations of their dependencies in order to
+/// determine whether they need to re-run when they are dirtied.
+///
+type Generation = u64;
+
+///
+/// Identifies a particular run of an Entry, to allow for detecting whether an Entry was restarted
+/// while some asynchronous operation was in flight.
+///
+type RunToken = u32;
+
+///
+/// A logical clock which is incremented each time an Entry is requested, in order to determine
+/// which Entries were least recently used.
+///
+type Tick = u64;
+
+///
+/// The position of an Entry in the topological order of a Graph.
+///
+type TopoOrder = i64;
+
+type EntryResult<N> = (Result<<N as Node>::Item, <N as Node>::Error>, Generation);
+
+type EntryStateField<Item, Error> =
+  future::Shared<BoxFuture<(Item, Generation), (Error, Generation)>>;
 
 struct EntryState<N: Node> {
   field: EntryStateField<N::Item, N::Error>,
   start_time: Instant,
+  // Set when the field completes.
+  end_time: Arc<Mutex<Option<Instant>>>,
+  // Recorded when the field completes.
+  result_size: Arc<ResultSize>,
+}
+
+///
+/// The estimated size of the result of a run of an Entry, which is counted towards the estimated
+/// size of its Graph from when the run completes until the ResultSize is dropped (along with the
+/// state of the Entry that held it).
+///
+struct ResultSize {
+  bytes: AtomicUsize,
+  graph_bytes: Arc<AtomicUsize>,
+}
+
+impl ResultSize {
+  fn new(graph_bytes: Arc<AtomicUsize>) -> ResultSize {
+    ResultSize {
+      bytes: AtomicUsize::new(0),
+      graph_bytes: graph_bytes,
+    }
+  }
+
+  fn record(&self, bytes: usize) {
+    self.bytes.store(bytes, Ordering::SeqCst);
+    self.graph_bytes.fetch_add(bytes, Ordering::SeqCst);
+  }
+}
+
+impl Drop for ResultSize {
+  fn drop(&mut self) {
+    self
+      .graph_bytes
+      .fetch_sub(self.bytes.load(Ordering::SeqCst), Ordering::SeqCst);
+  }
+}
+
+///
+/// Counts of the runs of the Entries of a Graph by state, which are updated as each run begins
+/// and completes (see `RunTracker`), so that `Graph::progress` need not walk the Graph.
+///
+struct RunCounts<N> {
+  queued: usize,
+  running: usize,
+  completed: usize,
+  next_id: u64,
+  // The Nodes of the running runs (by id), and the times at which they began.
+  running_nodes: HashMap<u64, (N, Instant)>,
+}
+
+impl<N> RunCounts<N> {
+  fn new() -> RunCounts<N> {
+    RunCounts {
+      queued: 0,
+      running: 0,
+      completed: 0,
+      next_id: 0,
+      running_nodes: HashMap::new(),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RunState {
+  // Requested, but not yet polled.
+  Queued,
+  Running,
+  Completed,
+}
+
+///
+/// Tracks one run of an Entry in the RunCounts of its Graph. A run which is dropped before it
+/// completes (because its Entry was cleared, for example) is no longer counted.
+///
+struct RunTracker<N: Node> {
+  id: u64,
+  state: Mutex<RunState>,
+  counts: Arc<Mutex<RunCounts<N>>>,
+}
+
+impl<N: Node> RunTracker<N> {
+  fn new(counts: &Arc<Mutex<RunCounts<N>>>) -> RunTracker<N> {
+    let id = {
+      let mut counts = counts.lock().unwrap();
+      counts.queued += 1;
+      counts.next_id += 1;
+      counts.next_id
+    };
+    RunTracker {
+      id: id,
+      state: Mutex::new(RunState::Queued),
+      counts: counts.clone(),
+    }
+  }
+
+  fn begin(&self, node: N) {
+    let mut state = self.state.lock().unwrap();
+    let mut counts = self.counts.lock().unwrap();
+    counts.queued -= 1;
+    counts.running += 1;
+    counts.running_nodes.insert(self.id, (node, Instant::now()));
+    *state = RunState::Running;
+  }
+
+  fn complete(&self) {
+    let mut state = self.state.lock().unwrap();
+    let mut counts = self.counts.lock().unwrap();
+    counts.running -= 1;
+    counts.completed += 1;
+    counts.running_nodes.remove(&self.id);
+    *state = RunState::Completed;
+  }
+}
+
+impl<N: Node> Drop for RunTracker<N> {
+  fn drop(&mut self) {
+    let state = *self.state.get_mut().unwrap();
+    let mut counts = self.counts.lock().unwrap();
+    match state {
+      RunState::Queued => counts.queued -= 1,
+      RunState::Running => {
+        counts.running -= 1;
+        counts.running_nodes.remove(&self.id);
+      }
+      RunState::Completed => {}
+    }
+  }
+}
+
+///
+/// The number of Entries that an invalidation cleared (because they matched as invalidation
+/// roots) and dirtied (because they transitively depended on a root).
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InvalidationCounts {
+  pub cleared: usize,
+  pub dirtied: usize,
+}
+
+impl InvalidationCounts {
+  pub fn total(&self) -> usize {
+    self.cleared + self.dirtied
+  }
+}
+
+///
+/// The progress of the execution of the Entries in a Graph (see `Graph::progress`).
+///
+#[derive(Clone, Debug)]
+pub struct Progress<N> {
+  // Runs of Entries which have been requested, but have not yet begun.
+  pub queued: usize,
+  pub running: usize,
+  // The total number of runs of Entries that have completed in the lifetime of the Graph.
+  pub completed: usize,
+  // The running Nodes which were selected to be reported, and how long they have been running
+  // for, longest first.
+  pub running_nodes: Vec<(N, Duration)>,
+}
+
+///
+/// The timing of the most recent run of an Entry.
+///
+#[derive(Clone, Debug)]
+pub struct EntryTiming<N> {
+  pub node: N,
+  pub start: Instant,
+  // None if the Entry is still running.
+  pub end: Option<Instant>,
+  // The number of times that the Entry has been started (including to be cleaned).
+  pub runs: u64,
+}
+
+///
+/// Bounds on the size of a Graph: when either is exceeded, least recently used Entries are
+/// evicted by `Graph::evict`. The estimated size of a Graph accounts for the fixed size of its
+/// Entries, edges and results, and for the heap allocations of its Nodes and their results as
+/// estimated by `Node::heap_size` and `Node::result_heap_size`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EvictionLimits {
+  pub max_entries: Option<usize>,
+  pub max_bytes: Option<usize>,
+}
+
+impl EvictionLimits {
+  pub fn unlimited() -> EvictionLimits {
+    EvictionLimits::default()
+  }
+
+  ///
+  /// Returns true if the given size exceeds these limits, which are first reduced by the given
+  /// percentage (so that eviction can free some headroom, rather than running on every request).
+  ///
+  fn exceeded_by(&self, entries: usize, bytes: usize, headroom_percent: usize) -> bool {
+    let exceeds = |size: usize, limit: Option<usize>| {
+      limit
+        .map(|limit| size > limit - (limit * headroom_percent / 100))
+        .unwrap_or(false)
+    };
+    exceeds(entries, self.max_entries) || exceeds(bytes, self.max_bytes)
+  }
 }
 
 ///
@@ -59,23 +307,55 @@ impl<N: Node> EntryKey<N> {
 }
 
 fn unwrap_entry_res<N: Node>(
-  res: Result<future::SharedItem<N::Item>, future::SharedError<N::Error>>,
-) -> Result<N::Item, N::Error> {
+  res: Result<
+    future::SharedItem<(N::Item, Generation)>,
+    future::SharedError<(N::Error, Generation)>,
+  >,
+) -> EntryResult<N> {
   match res {
-    Ok(nr) => Ok((*nr).clone()),
-    Err(failure) => Err((*failure).clone()),
+    Ok(nr) => (Ok(nr.0.clone()), nr.1),
+    Err(failure) => (Err(failure.0.clone()), failure.1),
+  }
+}
+
+fn entry_result_future<N: Node>(
+  (result, generation): EntryResult<N>,
+) -> BoxFuture<(N::Item, Generation), (N::Error, Generation)> {
+  match result {
+    Ok(item) => future::ok((item, generation)).to_boxed(),
+    Err(err) => future::err((err, generation)).to_boxed(),
   }
 }
 
 ///
 /// An Entry and its adjacencies.
 ///
+/// When an Entry is invalidated, it is either cleared (if it is an invalidation root, or still
+/// running) in which case it will re-run when it is next requested, or dirtied (if it is a
+/// completed dependent of an invalidation root). A dirty Entry is "cleaned" when it is next
+/// requested by requesting its dependencies: if none of their Generations have changed, its
+/// previous result is reused, and otherwise it re-runs.
+///
+/// If an Entry re-runs and produces a result equal to its previous result (see
+/// `Node::result_eq` and `Node::error_eq`), its Generation does not change, and so its dirty
+/// dependents will not need to re-run ("early cutoff").
+///
 pub struct Entry<N: Node> {
   // TODO: This is a clone of the Node, which is also kept in the `nodes` map. It would be
   // nice to avoid keeping two copies of each Node, but tracking references between the two
   // maps is painful.
   node: EntryKey<N>,
   state: Option<EntryState<N>>,
+  run_token: RunToken,
+  // If the Entry has been cleared, the result of its last completed run, and its size.
+  previous_result: Option<EntryResult<N>>,
+  previous_result_size: Option<Arc<ResultSize>>,
+  // If the Entry is dirty, the Generations of its dependencies when it was dirtied.
+  dirty_dependencies: Option<Vec<(EntryId, Option<Generation>)>>,
+  // The Tick at which the Entry was last requested, for eviction.
+  last_used: Tick,
+  // The number of times that the Entry has been started (including to be cleaned).
+  runs: u64,
 }
 
 impl<N: Node> Entry<N> {
@@ -88,35 +368,222 @@ impl<N: Node> Entry<N> {
     Entry {
       node: node,
       state: None,
+      run_token: 0,
+      previous_result: None,
+      previous_result_size: None,
+      dirty_dependencies: None,
+      last_used: 0,
+      runs: 0,
     }
   }
 
   ///
-  /// Returns a reference to the Node's Future, starting it if need be.
+  /// Returns a reference to the Node's Future, starting (or cleaning) it if need be.
   ///
-  fn state<C>(&mut self, context: &C, entry_id: EntryId) -> EntryStateField<N::Item, N::Error>
+  fn state<C>(
+    &mut self,
+    context: &C,
+    entry_id: EntryId,
+    graph_bytes: &Arc<AtomicUsize>,
+    run_counts: &Arc<Mutex<RunCounts<N>>>,
+  ) -> EntryStateField<N::Item, N::Error>
   where
     C: NodeContext<Node = N>,
   {
     if let Some(ref state) = self.state {
-      state.field.clone()
-    } else {
-      let start_time = Instant::now();
-      let state = match &self.node {
-        &EntryKey::Valid(ref n) => {
-          // Wrap the launch in future::lazy to defer it until after we're outside the Graph lock.
-          let context = context.clone_for(entry_id);
-          let node = n.clone();
-          future::lazy(move || node.run(context)).to_boxed()
+      if self.dirty_dependencies.is_none() {
+        return state.field.clone();
+      }
+    }
+
+    let start_time = Instant::now();
+    self.runs += 1;
+    self.run_token = self.run_token.wrapping_add(1);
+    let run_token = self.run_token;
+    let state = match &self.node {
+      &EntryKey::Valid(ref n) => {
+        // Wrap the launch in future::lazy to defer it until after we're outside the Graph lock.
+        let context = context.clone_for(entry_id);
+        let node = n.clone();
+        match (self.dirty_dependencies.take(), self.peek_result()) {
+          (Some(dependencies), Some(previous_result)) => future::lazy(move || {
+            Entry::clean(
+              node,
+              context,
+              entry_id,
+              run_token,
+              dependencies,
+              previous_result,
+            )
+          }).to_boxed(),
+          _ => {
+            let previous_result = self.previous_result.take();
+            self.previous_result_size = None;
+            future::lazy(move || {
+              let result = match context.graph().take_persisted(&node) {
+                Some(persisted) => Entry::restore(node, context, entry_id, persisted),
+                None => node.run(context),
+              };
+              Entry::<N>::with_generation(result, previous_result)
+            }).to_boxed()
+          }
         }
-        &EntryKey::Cyclic(_) => future::err(N::Error::cyclic()).to_boxed(),
-      };
+      }
+      // The path of a cycle is only reported to the requester which detected it (see `get`).
+      &EntryKey::Cyclic(_) => future::err((N::Error::cyclic(vec![]), 0)).to_boxed(),
+    };
 
-      self.state = Some(EntryState {
-        field: state.shared(),
-        start_time,
+    let end_time = Arc::new(Mutex::new(None));
+    let completed_end_time = end_time.clone();
+    let result_size = Arc::new(ResultSize::new(graph_bytes.clone()));
+    let completed_result_size = result_size.clone();
+    let run_tracker = Arc::new(RunTracker::new(run_counts));
+    let completed_run_tracker = run_tracker.clone();
+    let running_node = self.node.content().clone();
+    let state = future::lazy(move || {
+      run_tracker.begin(running_node);
+      state
+    }).then(move |res| {
+      *completed_end_time.lock().unwrap() = Some(Instant::now());
+      completed_result_size.record(match res {
+        Ok((ref item, _)) => mem::size_of::<N::Item>() + N::result_heap_size(item),
+        Err(_) => mem::size_of::<N::Error>(),
       });
-      self.state(context, entry_id)
+      completed_run_tracker.complete();
+      res
+    });
+    self.state = Some(EntryState {
+      field: state.to_boxed().shared(),
+      start_time,
+      end_time,
+      result_size,
+    });
+    self.state(context, entry_id, graph_bytes, run_counts)
+  }
+
+  ///
+  /// Requests the given dependencies of a dirty Entry in the order in which they were recorded to
+  /// determine whether any of them have changed, stopping at the first that has. If none have, the
+  /// previous result is still valid: otherwise, re-runs the Node.
+  ///
+  /// Dependencies are checked sequentially because a Node's later dependencies are generally
+  /// computed from its earlier ones: once an earlier dependency has changed, a later one may no
+  /// longer be requested at all, and cleaning it would be wasted (or even failing) work.
+  ///
+  fn clean(
+    node: N,
+    context: N::Context,
+    entry_id: EntryId,
+    run_token: RunToken,
+    dependencies: Vec<(EntryId, Option<Generation>)>,
+    previous_result: EntryResult<N>,
+  ) -> BoxFuture<(N::Item, Generation), (N::Error, Generation)> {
+    let check_context = context.clone();
+    let unchanged = future::loop_fn(dependencies.into_iter(), move |mut remaining| {
+      match remaining.next() {
+        Some((dep_id, previous_generation)) => check_context
+          .graph()
+          .get_generation(&check_context, dep_id)
+          .map(move |generation| {
+            if generation == previous_generation {
+              future::Loop::Continue(remaining)
+            } else {
+              future::Loop::Break(false)
+            }
+          })
+          .to_boxed(),
+        None => future::ok(future::Loop::Break(true)).to_boxed(),
+      }
+    });
+    unchanged
+      .and_then(move |unchanged| {
+        if unchanged {
+          entry_result_future::<N>(previous_result)
+        } else {
+          // The Node's dependencies might change when it re-runs.
+          context.graph().clear_dependencies(entry_id, run_token);
+          let result = node.run(context);
+          Entry::<N>::with_generation(result, Some(previous_result))
+        }
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Computes the Generation of the given result: the previous Generation if the result is equal
+  /// to the previous result, and the next Generation otherwise.
+  ///
+  fn with_generation(
+    result: BoxFuture<N::Item, N::Error>,
+    previous_result: Option<EntryResult<N>>,
+  ) -> BoxFuture<(N::Item, Generation), (N::Error, Generation)> {
+    result
+      .then(move |result| {
+        let generation = match previous_result {
+          Some((Ok(ref previous), generation)) => match result {
+            Ok(ref item) if N::result_eq(previous, item) => generation,
+            _ => generation + 1,
+          },
+          Some((Err(ref previous), generation)) => match result {
+            Err(ref error) if N::error_eq(previous, error) => generation,
+            _ => generation + 1,
+          },
+          None => 0,
+        };
+        entry_result_future::<N>((result, generation))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Attempts to reuse a persisted result for the given Node, which is only valid if each of the
+  /// Node's persisted dependencies still produces the same result, and the result is still
+  /// restorable (see `Node::is_restorable`). Otherwise, runs the Node.
+  ///
+  /// Requesting the dependencies also records them as dependencies of this Node, so that it
+  /// will be invalidated if they are.
+  ///
+  fn restore(
+    node: N,
+    context: N::Context,
+    entry_id: EntryId,
+    persisted: PersistedEntry,
+  ) -> BoxFuture<N::Item, N::Error> {
+    let deps: Option<Vec<(N, Vec<u8>)>> = persisted
+      .deps
+      .into_iter()
+      .map(|(key, result)| N::from_persistent_key(&key).map(|dep| (dep, result)))
+      .collect();
+    match (deps, N::from_persistent_result(&persisted.result)) {
+      (Some(deps), Some(item)) => {
+        let validations = deps
+          .into_iter()
+          .map(|(dep, expected)| {
+            context
+              .graph()
+              .get(entry_id, &context, dep)
+              .then(move |res| -> Result<bool, N::Error> {
+                Ok(match res {
+                  Ok(ref actual) => N::persistent_result(actual).map_or(false, |a| a == expected),
+                  Err(_) => false,
+                })
+              })
+          })
+          .collect::<Vec<_>>();
+        let restorable = N::is_restorable(&context, &item);
+        future::join_all(validations)
+          .join(restorable)
+          .and_then(move |(valid, restorable)| {
+            if restorable && valid.into_iter().all(|v| v) {
+              context.graph().restored_count.fetch_add(1, Ordering::SeqCst);
+              future::ok(item).to_boxed()
+            } else {
+              node.run(context)
+            }
+          })
+          .to_boxed()
+      }
+      _ => node.run(context),
     }
   }
 
@@ -124,12 +591,44 @@ impl<N: Node> Entry<N> {
   /// If the Future for this Node has already completed, returns a clone of its result.
   ///
   fn peek(&self) -> Option<Result<N::Item, N::Error>> {
+    self.peek_result().map(|(result, _)| result)
+  }
+
+  fn peek_result(&self) -> Option<EntryResult<N>> {
     self
       .state
       .as_ref()
       .and_then(|state| state.field.peek().map(unwrap_entry_res::<N>))
   }
 
+  ///
+  /// Returns the Generation of the most recently completed run of this Entry, if any.
+  ///
+  fn generation(&self) -> Option<Generation> {
+    self
+      .peek_result()
+      .or_else(|| self.previous_result.clone())
+      .map(|(_, generation)| generation)
+  }
+
+  ///
+  /// Returns true if this Entry has started and has not yet completed.
+  ///
+  fn is_running(&self) -> bool {
+    self
+      .state
+      .as_ref()
+      .map(|state| state.field.peek().is_none())
+      .unwrap_or(false)
+  }
+
+  ///
+  /// Returns true if this Entry has no current state, and so will run when next requested.
+  ///
+  fn needs_run(&self) -> bool {
+    self.state.is_none()
+  }
+
   ///
   /// If the Node has started and has not yet completed, returns its runtime.
   ///
@@ -144,8 +643,71 @@ impl<N: Node> Entry<N> {
     })
   }
 
+  ///
+  /// If the Node has started, returns the time at which it started, and the time at which it
+  /// completed (if it has).
+  ///
+  fn timing(&self) -> Option<(Instant, Option<Instant>)> {
+    self
+      .state
+      .as_ref()
+      .map(|state| (state.start_time, *state.end_time.lock().unwrap()))
+  }
+
+  ///
+  /// If the Node has started, returns its runtime: either until it completed, or until now if it
+  /// is still running.
+  ///
+  fn duration(&self, now: &Instant) -> Option<Duration> {
+    self
+      .timing()
+      .map(|(start_time, end_time)| end_time.unwrap_or(*now).duration_since(start_time))
+  }
+
+  ///
+  /// Describes the current state of this Entry (see `Entry` for the lifecycle of an Entry).
+  ///
+  fn state_str(&self) -> &'static str {
+    match (&self.state, &self.previous_result) {
+      (&Some(_), _) if self.is_running() => "running",
+      (&Some(_), _) if self.dirty_dependencies.is_some() => "dirty",
+      (&Some(_), _) => "completed",
+      (&None, &Some(_)) => "cleared",
+      (&None, &None) => "not_started",
+    }
+  }
+
+  ///
+  /// Clears the state of this Entry, so that it will re-run when it is next requested. Its
+  /// previous result is preserved so that the re-run can compute its Generation.
+  ///
+  fn invalidate(&mut self) {
+    if let Some(previous_result) = self.peek_result() {
+      self.previous_result = Some(previous_result);
+      self.previous_result_size = self.state.as_ref().map(|state| state.result_size.clone());
+    }
+    self.state = None;
+    self.dirty_dependencies = None;
+  }
+
+  ///
+  /// Marks this Entry dirty, so that it will be cleaned when it is next requested. If the Entry
+  /// is still running, it is cleared instead (since its dependencies are not yet known).
+  ///
+  fn dirty(&mut self, dependencies: Vec<(EntryId, Option<Generation>)>) {
+    if self.peek_result().is_none() {
+      self.invalidate();
+    } else if self.dirty_dependencies.is_none() {
+      // If the Entry was already dirty, the Generations that it observed were recorded then.
+      self.dirty_dependencies = Some(dependencies);
+    }
+  }
+
   fn clear(&mut self) {
     self.state = None;
+    self.previous_result = None;
+    self.previous_result_size = None;
+    self.dirty_dependencies = None;
   }
 
   fn format(&self) -> String {
@@ -163,6 +725,23 @@ type Nodes<N> = HashMap<EntryKey<N>, EntryId>;
 struct InnerGraph<N: Node> {
   nodes: Nodes<N>,
   pg: PGraph<N>,
+  // A topological order of the Entries (indexed by EntryId) in which each Entry precedes its
+  // dependencies: see `InnerGraph::add_edge`.
+  orders: Vec<TopoOrder>,
+  first_order: TopoOrder,
+  last_order: TopoOrder,
+  limits: EvictionLimits,
+  clock: Tick,
+  // The Entries in order of the Tick at which they were last used.
+  lru: BTreeMap<Tick, EntryId>,
+  // The estimated heap sizes of the Nodes of the Entries, and of their results.
+  node_bytes: usize,
+  result_bytes: Arc<AtomicUsize>,
+  run_counts: Arc<Mutex<RunCounts<N>>>,
+  evicted_count: usize,
+  // Whether edges are added by `add_edge_bfs`: see `Graph::with_bfs_cycle_detection`.
+  #[cfg(feature = "bfs_cycle_detection")]
+  bfs_cycle_detection: bool,
 }
 
 impl<N: Node> InnerGraph<N> {
@@ -185,31 +764,176 @@ impl<N: Node> InnerGraph<N> {
       .expect("The unsafe_entry_for_id method should only be used in read-only methods!")
   }
 
+  ///
+  /// Returns the EntryId for the given Node, creating an Entry if need be.
+  ///
+  /// A new Entry has no edges, so it can be placed anywhere in the topological order. Because it
+  /// is being created as the dependency of an existing Entry, it is placed last.
+  ///
   fn ensure_entry(&mut self, node: EntryKey<N>) -> EntryId {
-    InnerGraph::ensure_entry_internal(&mut self.pg, &mut self.nodes, node)
+    self.ensure_entry_at(node, false)
+  }
+
+  ///
+  /// Like `ensure_entry`, but places a new Entry first in the topological order, because it is
+  /// being created as a root, which might depend on any existing Entry.
+  ///
+  fn ensure_root_entry(&mut self, node: EntryKey<N>) -> EntryId {
+    self.ensure_entry_at(node, true)
   }
 
-  fn ensure_entry_internal<'a>(
-    pg: &mut PGraph<N>,
-    nodes: &mut Nodes<N>,
-    node: EntryKey<N>,
-  ) -> EntryId {
-    if let Some(&id) = nodes.get(&node) {
+  fn ensure_entry_at(&mut self, node: EntryKey<N>, first: bool) -> EntryId {
+    if let Some(&id) = self.nodes.get(&node) {
       return id;
     }
 
-    // New entry.
-    let id = pg.add_node(Entry::new(node.clone()));
-    nodes.insert(node, id);
+    // The Node is held both by the Entry and as its key.
+    self.node_bytes += 2 * node.content().heap_size();
+    let id = self.pg.add_node(Entry::new(node.clone()));
+    self.nodes.insert(node, id);
+    self.touch(id);
+    let order = if first {
+      self.first_order -= 1;
+      self.first_order
+    } else {
+      self.last_order += 1;
+      self.last_order
+    };
+    if self.orders.len() <= id.index() {
+      self.orders.resize(id.index() + 1, 0);
+    }
+    self.orders[id.index()] = order;
     id
   }
 
+  fn order(&self, id: EntryId) -> TopoOrder {
+    self.orders[id.index()]
+  }
+
+  ///
+  /// Marks the given Entry as the most recently used.
+  ///
+  fn touch(&mut self, id: EntryId) {
+    self.clock += 1;
+    if let Some(entry) = self.pg.node_weight_mut(id) {
+      self.lru.remove(&entry.last_used);
+      entry.last_used = self.clock;
+      self.lru.insert(self.clock, id);
+    }
+  }
+
+  fn remove_entry(&mut self, id: EntryId) {
+    if let Some(entry) = self.pg.remove_node(id) {
+      self.lru.remove(&entry.last_used);
+      self.nodes.remove(&entry.node);
+      self.node_bytes -= 2 * entry.node.content().heap_size();
+    }
+  }
+
+  ///
+  /// Returns the state of the given Entry, starting it if need be. If the Entry will run from
+  /// scratch, its dependencies from any previous run are first removed.
+  ///
+  fn entry_state<C>(
+    &mut self,
+    context: &C,
+    entry_id: EntryId,
+  ) -> Option<EntryStateField<N::Item, N::Error>>
+  where
+    C: NodeContext<Node = N>,
+  {
+    if self.entry_for_id(entry_id)?.needs_run() {
+      self.clear_dependencies(entry_id);
+    }
+    self.touch(entry_id);
+    let result_bytes = self.result_bytes.clone();
+    let run_counts = self.run_counts.clone();
+    self
+      .entry_for_id_mut(entry_id)
+      .map(|entry| entry.state(context, entry_id, &result_bytes, &run_counts))
+  }
+
+  ///
+  /// Returns the dependencies of the given Entry in the order in which they were first requested.
+  ///
+  fn dependencies_in_order(&self, entry_id: EntryId) -> Vec<EntryId> {
+    // The edges of an Entry are iterated newest first.
+    let mut dep_ids: Vec<EntryId> = self.pg.neighbors(entry_id).collect();
+    dep_ids.reverse();
+    let mut seen: HashSet<EntryId, FNV> = HashSet::default();
+    dep_ids.retain(|&dep_id| seen.insert(dep_id));
+    dep_ids
+  }
+
+  fn clear_dependencies(&mut self, entry_id: EntryId) {
+    let edges: Vec<_> = self.pg.edges(entry_id).map(|edge| edge.id()).collect();
+    for edge in edges {
+      self.pg.remove_edge(edge);
+    }
+  }
+
+  ///
+  /// Adds an edge from src to dst (ie, declares that src depends on dst), unless doing so would
+  /// create a cycle, in which case returns the path of the cycle: starting at src, through dst,
+  /// and back to src.
+  ///
+  /// Rather than searching for an existing path from dst to src for each new edge, this maintains
+  /// a topological order of the Entries in which each Entry precedes its dependencies (see
+  /// Pearce and Kelly, "A Dynamic Topological Sort Algorithm for Directed Acyclic Graphs"). An
+  /// edge that agrees with the order cannot create a cycle. For an edge that disagrees, only the
+  /// Entries between dst and src in the order need to be searched, and are then reordered.
   ///
-  /// Detect whether adding an edge from src to dst would create a cycle.
+  fn add_edge(&mut self, src_id: EntryId, dst_id: EntryId) -> Result<(), Vec<EntryId>> {
+    #[cfg(feature = "bfs_cycle_detection")]
+    {
+      if self.bfs_cycle_detection {
+        return self.add_edge_bfs(src_id, dst_id);
+      }
+    }
+    if src_id == dst_id {
+      return Err(vec![src_id, dst_id]);
+    }
+    let src_order = self.order(src_id);
+    let dst_order = self.order(dst_id);
+    if src_order > dst_order {
+      // Search the dependencies of dst which precede src: if src is among them, there is a cycle.
+      let forward = self
+        .affected_region(
+          dst_id,
+          Direction::Outgoing,
+          |order| order < src_order,
+          Some(src_id),
+        )
+        .map_err(|path| {
+          let mut cycle = Vec::with_capacity(path.len() + 1);
+          cycle.push(src_id);
+          cycle.extend(path);
+          cycle
+        })?;
+      // And the dependents of src which follow dst. Then move all of the dependents before all
+      // of the dependencies, reusing the positions that they occupied in the order.
+      let backward = self
+        .affected_region(
+          src_id,
+          Direction::Incoming,
+          |order| order > dst_order,
+          None,
+        )
+        .expect("A region without a needle cannot contain it.");
+      self.reorder(backward, forward);
+    }
+    self.pg.add_edge(src_id, dst_id, ());
+    Ok(())
+  }
+
   ///
-  /// Returns true if a cycle would be created by adding an edge from src->dst.
+  /// Adds an edge like `add_edge`, but detects cycles by searching for an existing path from dst
+  /// to src, as the Graph did before it maintained a topological order. Only exists so that the
+  /// two can be compared by the cycle_detection benchmark: the topological order is not
+  /// maintained, and the path of a detected cycle is not computed.
   ///
-  fn detect_cycle(&self, src_id: EntryId, dst_id: EntryId) -> bool {
+  #[cfg(feature = "bfs_cycle_detection")]
+  fn add_edge_bfs(&mut self, src_id: EntryId, dst_id: EntryId) -> Result<(), Vec<EntryId>> {
     // Search either forward from the dst, or backward from the src.
     let (root, needle, direction) = {
       let out_from_dst = self.pg.neighbors(dst_id).count();
@@ -224,10 +948,70 @@ impl<N: Node> InnerGraph<N> {
       }
     };
 
-    // Search for an existing path from dst to src.
     let mut roots = VecDeque::new();
     roots.push_back(root);
-    self.walk(roots, direction).any(|eid| eid == needle)
+    if src_id == dst_id || self.walk(roots, direction).any(|eid| eid == needle) {
+      return Err(vec![src_id, dst_id]);
+    }
+    self.pg.add_edge(src_id, dst_id, ());
+    Ok(())
+  }
+
+  ///
+  /// Returns the Entries reachable from the given root in the given direction (including the
+  /// root) via Entries whose order satisfies the given predicate, or if the needle was reachable,
+  /// the path from the root to the needle.
+  ///
+  fn affected_region<P: Fn(TopoOrder) -> bool>(
+    &self,
+    root: EntryId,
+    direction: Direction,
+    in_region: P,
+    needle: Option<EntryId>,
+  ) -> Result<Vec<EntryId>, Vec<EntryId>> {
+    // Records the Entry from which each visited Entry was first reached.
+    let mut parents: HashMap<EntryId, EntryId, FNV> = HashMap::default();
+    parents.insert(root, root);
+    let mut stack = vec![root];
+    let mut region = Vec::new();
+    while let Some(id) = stack.pop() {
+      region.push(id);
+      for neighbor in self.pg.neighbors_directed(id, direction) {
+        if Some(neighbor) == needle {
+          let mut path = vec![neighbor, id];
+          let mut current = id;
+          while current != root {
+            current = parents[&current];
+            path.push(current);
+          }
+          path.reverse();
+          return Err(path);
+        }
+        if in_region(self.order(neighbor)) && !parents.contains_key(&neighbor) {
+          parents.insert(neighbor, id);
+          stack.push(neighbor);
+        }
+      }
+    }
+    Ok(region)
+  }
+
+  ///
+  /// Assigns the orders currently held by the given Entries such that all of the `before` Entries
+  /// precede all of the `after` Entries, while preserving the relative order within each.
+  ///
+  fn reorder(&mut self, mut before: Vec<EntryId>, mut after: Vec<EntryId>) {
+    before.sort_by_key(|&id| self.order(id));
+    after.sort_by_key(|&id| self.order(id));
+    let mut orders: Vec<TopoOrder> = before
+      .iter()
+      .chain(after.iter())
+      .map(|&id| self.order(id))
+      .collect();
+    orders.sort();
+    for (id, order) in before.into_iter().chain(after.into_iter()).zip(orders) {
+      self.orders[id.index()] = order;
+    }
   }
 
   ///
@@ -265,6 +1049,68 @@ impl<N: Node> InnerGraph<N> {
     }
   }
 
+  ///
+  /// Estimates the size of this Graph in bytes: see `EvictionLimits`.
+  ///
+  fn estimated_bytes(&self) -> usize {
+    let entry_size = mem::size_of::<Entry<N>>()
+      + mem::size_of::<(EntryKey<N>, EntryId)>()
+      + mem::size_of::<TopoOrder>();
+    let edge_size = mem::size_of::<petgraph::graph::Edge<(), u32>>();
+    self.nodes.len() * entry_size
+      + self.pg.edge_count() * edge_size
+      + self.node_bytes
+      + self.result_bytes.load(Ordering::SeqCst)
+  }
+
+  fn exceeds_limits(&self, headroom_percent: usize) -> bool {
+    self
+      .limits
+      .exceeded_by(self.nodes.len(), self.estimated_bytes(), headroom_percent)
+  }
+
+  ///
+  /// If this Graph exceeds its EvictionLimits, removes least recently used Entries until it is
+  /// below them (with some headroom), and returns the number of Entries that were removed.
+  ///
+  /// Only Entries which are not running and which have no dependents are eligible for eviction,
+  /// because the dependents of an Entry refer to it (by EntryId) in order to be cleaned. Evicting
+  /// an Entry may make its dependencies eligible. Since a dependency is generally used after its
+  /// dependent, it is usually visited later in the same pass over the Entries in least recently
+  /// used order: otherwise, another pass is made.
+  ///
+  fn evict(&mut self) -> usize {
+    if !self.exceeds_limits(0) {
+      return 0;
+    }
+    let mut evicted = 0;
+    loop {
+      let evicted_before = evicted;
+      let mut next_tick = 0;
+      while self.exceeds_limits(EVICTION_HEADROOM_PERCENT) {
+        let (tick, id) = match self.lru.range(next_tick..).next() {
+          Some((&tick, &id)) => (tick, id),
+          None => break,
+        };
+        next_tick = tick + 1;
+        let has_dependents = self
+          .pg
+          .neighbors_directed(id, Direction::Incoming)
+          .next()
+          .is_some();
+        if !has_dependents && !self.unsafe_entry_for_id(id).is_running() {
+          self.remove_entry(id);
+          evicted += 1;
+        }
+      }
+      if evicted == evicted_before || !self.exceeds_limits(EVICTION_HEADROOM_PERCENT) {
+        break;
+      }
+    }
+    self.evicted_count += evicted;
+    evicted
+  }
+
   fn clear(&mut self) {
     for eid in self.nodes.values() {
       self.pg.node_weight_mut(*eid).map(|entry| entry.clear());
@@ -276,66 +1122,201 @@ impl<N: Node> InnerGraph<N> {
   /// their transitive dependents.
   ///
   /// An "invalidation root" is a Node in the graph which can be invalidated for a reason other
-  /// than having had its dependencies changed. When an invalidation root Node is invalidated,
-  /// its dependencies are invalidated as well (regardless of whether they are also roots).
-  ///
-  fn invalidate_from_roots<P: Fn(&N) -> bool>(&mut self, predicate: P) -> usize {
-    // Collect all entries that will be deleted.
-    let ids: HashSet<EntryId, FNV> = {
-      let root_ids = self
-        .nodes
-        .iter()
-        .filter_map(|(entry, &entry_id)| {
-          if predicate(entry.content()) {
-            Some(entry_id)
-          } else {
-            None
-          }
-        })
-        .collect();
-      self
-        .walk(root_ids, Direction::Incoming)
-        .map(|eid| eid)
-        .collect()
-    };
+  /// than having had its dependencies changed. Invalidation roots are cleared, so that they will
+  /// re-run when they are next requested, while their transitive dependents are marked dirty
+  /// (see `Entry`), so that they will only re-run if their dependencies have changed.
+  ///
+  /// Returns the number of Entries that were cleared and dirtied, grouped by the key that
+  /// `classify` returns for their Nodes.
+  ///
+  fn invalidate_from_roots<P, C, K>(
+    &mut self,
+    predicate: P,
+    classify: C,
+  ) -> HashMap<K, InvalidationCounts>
+  where
+    P: Fn(&N) -> bool,
+    C: Fn(&N) -> K,
+    K: Eq + Hash,
+  {
+    let root_ids: HashSet<EntryId, FNV> = self
+      .nodes
+      .iter()
+      .filter_map(|(entry, &entry_id)| {
+        if predicate(entry.content()) {
+          Some(entry_id)
+        } else {
+          None
+        }
+      })
+      .collect();
 
-    // Then remove all entries in one shot.
-    let result = ids.len();
-    InnerGraph::invalidate_internal(&mut self.pg, &mut self.nodes, ids);
+    // Record the current Generations of the dependencies of each dirtied Entry.
+    let dirtied: Vec<(EntryId, Vec<(EntryId, Option<Generation>)>)> = self
+      .walk(root_ids.iter().cloned().collect(), Direction::Incoming)
+      .filter(|eid| !root_ids.contains(eid))
+      .map(|eid| {
+        let dependencies = self
+          .dependencies_in_order(eid)
+          .into_iter()
+          .map(|dep_id| (dep_id, self.unsafe_entry_for_id(dep_id).generation()))
+          .collect();
+        (eid, dependencies)
+      })
+      .collect();
 
-    // And return the removed count.
-    result
+    let mut counts: HashMap<K, InvalidationCounts> = HashMap::new();
+    for root_id in root_ids {
+      if let Some(entry) = self.entry_for_id_mut(root_id) {
+        counts
+          .entry(classify(entry.node.content()))
+          .or_insert_with(InvalidationCounts::default)
+          .cleared += 1;
+        entry.invalidate();
+      }
+    }
+    for (eid, dependencies) in dirtied {
+      if let Some(entry) = self.entry_for_id_mut(eid) {
+        counts
+          .entry(classify(entry.node.content()))
+          .or_insert_with(InvalidationCounts::default)
+          .dirtied += 1;
+        entry.dirty(dependencies);
+      }
+    }
+    counts
   }
 
-  fn invalidate_internal(pg: &mut PGraph<N>, nodes: &mut Nodes<N>, ids: HashSet<EntryId, FNV>) {
-    if ids.is_empty() {
-      return;
+  ///
+  /// Writes one JSON object per line for each Entry reachable from the given roots (or for all
+  /// Entries, if no roots are given), and returns the number of Entries written.
+  ///
+  fn export_json(&self, roots: Option<&[N]>, path: &Path) -> io::Result<usize> {
+    let mut f = BufWriter::new(File::create(path)?);
+    let entry_ids = self.reachable_or_all(roots);
+
+    let now = Instant::now();
+    for &eid in &entry_ids {
+      let entry = self.unsafe_entry_for_id(eid);
+      let (outcome, result) = match entry.peek() {
+        Some(Ok(ref item)) => (Some("success"), Some(format!("{:?}", item))),
+        Some(Err(ref err)) => (Some("failure"), Some(format!("{:?}", err))),
+        None => (None, None),
+      };
+      let dependencies = self
+        .pg
+        .neighbors(eid)
+        .map(|dep_id| dep_id.index().to_string())
+        .collect::<Vec<_>>();
+      writeln!(
+        f,
+        "{{\"id\": {}, \"type\": {}, \"node\": {}, \"cyclic\": {}, \"state\": {}, \
+         \"outcome\": {}, \"result\": {}, \"generation\": {}, \"duration_secs\": {}, \
+         \"dependencies\": [{}]}}",
+        eid.index(),
+        json::string(entry.node.content().type_name()),
+        json::string(&entry.node.content().format()),
+        match entry.node {
+          EntryKey::Valid(_) => false,
+          EntryKey::Cyclic(_) => true,
+        },
+        json::string(entry.state_str()),
+        json::option(outcome, json::string),
+        json::option(result, |r| json::string(&r)),
+        json::option(entry.generation(), |g| g.to_string()),
+        json::option(entry.duration(&now), |d| {
+          format!("{}.{:09}", d.as_secs(), d.subsec_nanos())
+        }),
+        dependencies.join(", ")
+      )?;
     }
+    f.flush()?;
+    Ok(entry_ids.len())
+  }
 
-    for &id in &ids {
-      // Validate that all dependents of the id are also scheduled for removal.
-      assert!(
-        pg.neighbors_directed(id, Direction::Incoming)
-          .all(|dep| ids.contains(&dep))
-      );
+  ///
+  /// Returns the timings of the most recent runs of the Entries reachable from the given roots
+  /// (or of all Entries, if no roots are given) that have started.
+  ///
+  fn timings(&self, roots: Option<&[N]>) -> Vec<EntryTiming<N>> {
+    self
+      .reachable_or_all(roots)
+      .into_iter()
+      .filter_map(|eid| {
+        let entry = self.unsafe_entry_for_id(eid);
+        match entry.node {
+          EntryKey::Valid(ref node) => entry.timing().map(|(start, end)| EntryTiming {
+            node: node.clone(),
+            start: start,
+            end: end,
+            runs: entry.runs,
+          }),
+          EntryKey::Cyclic(_) => None,
+        }
+      })
+      .collect()
+  }
 
-      // Remove the entry from the graph (which will also remove dependent edges).
-      pg.remove_node(id);
-    }
+  ///
+  /// Returns the critical path through the completed Entries reachable from the given roots, as
+  /// a chain of Nodes and their self-times.
+  ///
+  /// The path begins at the root that ran for the longest, and then repeatedly follows the
+  /// dependency that completed last while its dependent was running: that is, the dependency
+  /// that the dependent was waiting on. A dependency may have started before its dependent (if
+  /// another Node requested it first), or completed after it (if the dependent failed early), so
+  /// each step of the path is clipped to the window of the step before it. The self-time of each
+  /// Node is the duration of its window, less the window of the next Node in the path, so the
+  /// self-times in the path sum to the duration of the root.
+  ///
+  fn critical_path(&self, roots: &[N]) -> Vec<(N, Duration)> {
+    let completed = |eid: EntryId| match self.unsafe_entry_for_id(eid).timing() {
+      Some((start, Some(end))) => Some((eid, start, end)),
+      _ => None,
+    };
+    let mut current = roots
+      .iter()
+      .filter_map(|n| self.entry_id(&EntryKey::Valid(n.clone())))
+      .filter_map(|&eid| completed(eid))
+      .max_by_key(|&(_, start, end)| end.duration_since(start));
 
-    // Filter the Nodes to delete any with matching ids.
-    let filtered: Vec<(EntryKey<N>, EntryId)> = nodes
-      .drain()
-      .filter(|&(_, id)| !ids.contains(&id))
-      .collect();
-    nodes.extend(filtered);
+    let mut path = Vec::new();
+    while let Some((eid, start, end)) = current {
+      let next = self
+        .pg
+        .neighbors_directed(eid, Direction::Outgoing)
+        .filter_map(|dep_id| completed(dep_id))
+        .filter(|&(_, dep_start, dep_end)| dep_end > start && dep_start < end)
+        .max_by_key(|&(_, _, dep_end)| dep_end)
+        .map(|(dep_id, dep_start, dep_end)| (dep_id, max(dep_start, start), min(dep_end, end)));
+      let waiting = next
+        .map(|(_, next_start, next_end)| next_end.duration_since(next_start))
+        .unwrap_or_else(|| Duration::from_secs(0));
+      path.push((
+        self.unsafe_entry_for_id(eid).node.content().clone(),
+        end.duration_since(start) - waiting,
+      ));
+      current = next;
+    }
+    path
+  }
 
-    assert!(
-      nodes.len() == pg.node_count(),
-      "The Nodes and Entries maps are mismatched: {} vs {}",
-      nodes.len(),
-      pg.node_count()
-    );
+  ///
+  /// Returns the ids of the Entries reachable from the given roots, or of all Entries if no roots
+  /// are given.
+  ///
+  fn reachable_or_all(&self, roots: Option<&[N]>) -> Vec<EntryId> {
+    match roots {
+      Some(roots) => {
+        let root_entries = roots
+          .iter()
+          .filter_map(|n| self.entry_id(&EntryKey::Valid(n.clone())))
+          .cloned()
+          .collect();
+        self.walk(root_entries, Direction::Outgoing).collect()
+      }
+      None => self.pg.node_indices().collect(),
+    }
   }
 
   fn visualize<V: NodeVisualizer<N>>(
@@ -526,24 +1507,86 @@ impl<N: Node> InnerGraph<N> {
 ///
 pub struct Graph<N: Node> {
   inner: Mutex<InnerGraph<N>>,
+  // Shared with the InnerGraph, but updated outside of its lock.
+  run_counts: Arc<Mutex<RunCounts<N>>>,
+  // Persisted entries which have been loaded, but not yet requested: see `Graph::load_persisted`.
+  persisted: Mutex<HashMap<Vec<u8>, PersistedEntry>>,
+  // The number of Nodes whose persisted results were reused rather than re-running them.
+  restored_count: AtomicUsize,
 }
 
 impl<N: Node> Graph<N> {
   pub fn new() -> Graph<N> {
+    Graph::with_limits(EvictionLimits::unlimited())
+  }
+
+  pub fn with_limits(limits: EvictionLimits) -> Graph<N> {
+    let run_counts = Arc::new(Mutex::new(RunCounts::new()));
     let inner = InnerGraph {
       nodes: HashMap::default(),
       pg: StableGraph::new(),
+      orders: Vec::new(),
+      first_order: 0,
+      last_order: 0,
+      limits: limits,
+      clock: 0,
+      lru: BTreeMap::new(),
+      node_bytes: 0,
+      result_bytes: Arc::new(AtomicUsize::new(0)),
+      run_counts: run_counts.clone(),
+      evicted_count: 0,
+      #[cfg(feature = "bfs_cycle_detection")]
+      bfs_cycle_detection: false,
     };
     Graph {
       inner: Mutex::new(inner),
+      run_counts: run_counts,
+      persisted: Mutex::new(HashMap::default()),
+      restored_count: AtomicUsize::new(0),
     }
   }
 
+  ///
+  /// Creates a Graph which detects cycles by searching for each new edge (see
+  /// `InnerGraph::add_edge_bfs`), for comparison in benchmarks.
+  ///
+  #[cfg(feature = "bfs_cycle_detection")]
+  pub fn with_bfs_cycle_detection() -> Graph<N> {
+    let graph = Graph::new();
+    graph.inner.lock().unwrap().bfs_cycle_detection = true;
+    graph
+  }
+
   pub fn len(&self) -> usize {
     let inner = self.inner.lock().unwrap();
     inner.nodes.len()
   }
 
+  ///
+  /// Returns the estimated size of the Graph in bytes: see `EvictionLimits`.
+  ///
+  pub fn estimated_bytes(&self) -> usize {
+    let inner = self.inner.lock().unwrap();
+    inner.estimated_bytes()
+  }
+
+  ///
+  /// If the Graph exceeds its EvictionLimits, evicts least recently used Entries which are not
+  /// running and have no dependents, and returns the number that were evicted.
+  ///
+  pub fn evict(&self) -> usize {
+    let mut inner = self.inner.lock().unwrap();
+    inner.evict()
+  }
+
+  ///
+  /// Returns the total number of Entries that have been evicted from this Graph.
+  ///
+  pub fn evicted_count(&self) -> usize {
+    let inner = self.inner.lock().unwrap();
+    inner.evicted_count
+  }
+
   ///
   /// In the context of the given src Node, declare a dependency on the given dst Node and
   /// begin its execution if it has not already started.
@@ -553,32 +1596,82 @@ impl<N: Node> Graph<N> {
     C: NodeContext<Node = N>,
   {
     // Get or create the destination, and then insert the dep and return its state.
-    let dst_state = {
+    let (dst_state, cycle) = {
       let mut inner = self.inner.lock().unwrap();
-      let dst_id = {
-        // TODO: doing cycle detection under the lock... unfortunate, but probably unavoidable
-        // without a much more complicated algorithm.
-        let potential_dst_id = inner.ensure_entry(EntryKey::Valid(dst_node.clone()));
-        if inner.detect_cycle(src_id, potential_dst_id) {
-          // Cyclic dependency: declare a dependency on a copy of the Node that is marked Cyclic.
-          inner.ensure_entry(EntryKey::Cyclic(dst_node.clone()))
-        } else {
-          // Valid dependency.
-          potential_dst_id
+      // Declare the dep, and return the state of the destination.
+      let potential_dst_id = inner.ensure_entry(EntryKey::Valid(dst_node.clone()));
+      let (dst_id, cycle) = match inner.add_edge(src_id, potential_dst_id) {
+        // Valid dependency.
+        Ok(()) => (potential_dst_id, None),
+        Err(path) => {
+          // Cyclic dependency: declare a dependency on a copy of the Node that is marked Cyclic,
+          // which has no dependencies of its own, and so cannot itself create a cycle.
+          let cyclic_id = inner.ensure_entry(EntryKey::Cyclic(dst_node.clone()));
+          inner
+            .add_edge(src_id, cyclic_id)
+            .expect("An Entry without dependencies cannot create a cycle.");
+          let path: Vec<N> = path
+            .into_iter()
+            .map(|id| inner.unsafe_entry_for_id(id).node.content().clone())
+            .collect();
+          (cyclic_id, Some(path))
         }
       };
-
-      // Declare the dep, and return the state of the destination.
-      inner.pg.add_edge(src_id, dst_id, ());
-      inner
-        .entry_for_id_mut(dst_id)
-        .map(|entry| entry.state(context, dst_id))
-        .unwrap_or_else(|| future::err(N::Error::invalidated()).to_boxed().shared())
+      let dst_state = inner
+        .entry_state(context, dst_id)
+        .unwrap_or_else(|| future::err((N::Error::invalidated(), 0)).to_boxed().shared());
+      (dst_state, cycle)
     };
 
+    if let Some(path) = cycle {
+      // Report the path of the cycle to the requester, formatting it outside the graph lock.
+      return future::err(N::Error::cyclic(path.iter().map(|n| n.format()).collect())).to_boxed();
+    }
+
     // Got the destination's state. Now that we're outside the graph locks, we can safely
     // retrieve it.
-    dst_state.then(unwrap_entry_res::<N>).to_boxed()
+    dst_state
+      .then(|res| unwrap_entry_res::<N>(res).0)
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the Generation of the given existing dependency (which must already be recorded as a
+  /// dependency of the requester), starting (or cleaning) it if need be. Returns None if the
+  /// dependency no longer exists.
+  ///
+  fn get_generation<C>(
+    &self,
+    context: &C,
+    dst_id: EntryId,
+  ) -> BoxFuture<Option<Generation>, (N::Error, Generation)>
+  where
+    C: NodeContext<Node = N>,
+  {
+    let dst_state = {
+      let mut inner = self.inner.lock().unwrap();
+      inner.entry_state(context, dst_id)
+    };
+    match dst_state {
+      Some(dst_state) => dst_state
+        .then(|res| Ok(Some(unwrap_entry_res::<N>(res).1)))
+        .to_boxed(),
+      None => future::ok(None).to_boxed(),
+    }
+  }
+
+  ///
+  /// Removes the dependencies of the given Entry if it is still executing the given run.
+  ///
+  fn clear_dependencies(&self, entry_id: EntryId, run_token: RunToken) {
+    let mut inner = self.inner.lock().unwrap();
+    let matches = inner
+      .entry_for_id(entry_id)
+      .map(|entry| entry.run_token == run_token)
+      .unwrap_or(false);
+    if matches {
+      inner.clear_dependencies(entry_id);
+    }
   }
 
   ///
@@ -591,14 +1684,13 @@ impl<N: Node> Graph<N> {
     // Initialize the state while under the lock...
     let state = {
       let mut inner = self.inner.lock().unwrap();
-      let id = inner.ensure_entry(EntryKey::Valid(node.into()));
+      let id = inner.ensure_root_entry(EntryKey::Valid(node.into()));
       inner
-        .entry_for_id_mut(id)
-        .map(|entry| entry.state(context, id))
-        .unwrap_or_else(|| future::err(N::Error::invalidated()).to_boxed().shared())
+        .entry_state(context, id)
+        .unwrap_or_else(|| future::err((N::Error::invalidated(), 0)).to_boxed().shared())
     };
     // ...but only `get` it outside the lock.
-    state.then(unwrap_entry_res::<N>).to_boxed()
+    state.then(|res| unwrap_entry_res::<N>(res).0).to_boxed()
   }
 
   ///
@@ -609,9 +1701,34 @@ impl<N: Node> Graph<N> {
     inner.clear()
   }
 
+  ///
+  /// Invalidates the Nodes matching the given predicate (see `InnerGraph::invalidate_from_roots`),
+  /// and returns the total number of Entries that were cleared or dirtied.
+  ///
   pub fn invalidate_from_roots<P: Fn(&N) -> bool>(&self, predicate: P) -> usize {
+    self
+      .invalidate_from_roots_by(predicate, |_| ())
+      .values()
+      .map(InvalidationCounts::total)
+      .sum()
+  }
+
+  ///
+  /// Like `invalidate_from_roots`, but returns the number of Entries that were cleared and
+  /// dirtied grouped by the key that `classify` returns for their Nodes (such as their type).
+  ///
+  pub fn invalidate_from_roots_by<P, C, K>(
+    &self,
+    predicate: P,
+    classify: C,
+  ) -> HashMap<K, InvalidationCounts>
+  where
+    P: Fn(&N) -> bool,
+    C: Fn(&N) -> K,
+    K: Eq + Hash,
+  {
     let mut inner = self.inner.lock().unwrap();
-    inner.invalidate_from_roots(predicate)
+    inner.invalidate_from_roots(predicate, classify)
   }
 
   pub fn trace<T: NodeTracer<N>>(&self, root: &N, path: &Path) -> io::Result<()> {
@@ -619,6 +1736,100 @@ impl<N: Node> Graph<N> {
     inner.trace::<T>(root, path)
   }
 
+  ///
+  /// Writes the Entries reachable from the given roots (or all Entries, if no roots are given) to
+  /// the given path as JSON lines, and returns the number of Entries written.
+  ///
+  /// Each line is a JSON object describing an Entry's Node, its state, the outcome, Generation
+  /// and duration of its most recent run (if any), and the ids of its dependencies.
+  ///
+  pub fn export_json(&self, roots: Option<&[N]>, path: &Path) -> io::Result<usize> {
+    let inner = self.inner.lock().unwrap();
+    inner.export_json(roots, path)
+  }
+
+  ///
+  /// Returns the timings of the most recent runs of the Entries reachable from the given roots (or
+  /// of all Entries, if no roots are given).
+  ///
+  pub fn timings(&self, roots: Option<&[N]>) -> Vec<EntryTiming<N>> {
+    let inner = self.inner.lock().unwrap();
+    inner.timings(roots)
+  }
+
+  ///
+  /// Writes the timings of the Entries reachable from the given roots (or of all Entries, if no
+  /// roots are given) to the given path in the Chrome trace-event format, and returns the number
+  /// of Entries written.
+  ///
+  /// Each Entry is rendered as a span, nested below the spans of its dependents. Entries for which
+  /// `lane` returns a name are instead rendered in lanes of their own with that name.
+  ///
+  pub fn export_chrome_trace<F>(
+    &self,
+    roots: Option<&[N]>,
+    lane: F,
+    path: &Path,
+  ) -> io::Result<usize>
+  where
+    F: Fn(&N) -> Option<&'static str>,
+  {
+    let (timings, now) = {
+      let inner = self.inner.lock().unwrap();
+      (inner.timings(roots), Instant::now())
+    };
+    // Now that we're outside the graph lock, format the Nodes.
+    let mut spans: Vec<_> = timings
+      .into_iter()
+      .map(|timing| chrome_trace::Span {
+        name: timing.node.format(),
+        category: timing.node.type_name(),
+        lane: lane(&timing.node),
+        start: timing.start,
+        end: timing.end.unwrap_or(now),
+        completed: timing.end.is_some(),
+        runs: timing.runs,
+      })
+      .collect();
+    let mut f = BufWriter::new(File::create(path)?);
+    chrome_trace::write(&mut spans, &mut f)?;
+    f.flush()?;
+    Ok(spans.len())
+  }
+
+  ///
+  /// Returns the progress of the execution of the Entries in the Graph, including those running
+  /// Nodes for which `report_running` returns true. Intended to be polled while roots are
+  /// executing: the counts are maintained as runs begin and complete, so this does not walk the
+  /// Graph (or acquire its lock).
+  ///
+  pub fn progress<F: Fn(&N) -> bool>(&self, report_running: F) -> Progress<N> {
+    let now = Instant::now();
+    let counts = self.run_counts.lock().unwrap();
+    let mut running_nodes: Vec<(N, Duration)> = counts
+      .running_nodes
+      .values()
+      .filter(|&&(ref node, _)| report_running(node))
+      .map(|&(ref node, began_time)| (node.clone(), now.duration_since(began_time)))
+      .collect();
+    running_nodes.sort_by(|&(_, a), &(_, b)| b.cmp(&a));
+    Progress {
+      queued: counts.queued,
+      running: counts.running,
+      completed: counts.completed,
+      running_nodes: running_nodes,
+    }
+  }
+
+  ///
+  /// Returns the critical path through the completed Entries reachable from the given roots, as a
+  /// chain of Nodes and their self-times (see `InnerGraph::critical_path`).
+  ///
+  pub fn critical_path(&self, roots: &[N]) -> Vec<(N, Duration)> {
+    let inner = self.inner.lock().unwrap();
+    inner.critical_path(roots)
+  }
+
   pub fn visualize<V: NodeVisualizer<N>>(
     &self,
     visualizer: V,
@@ -643,6 +1854,132 @@ impl<N: Node> Graph<N> {
     let inner = self.inner.lock().unwrap();
     inner.all_digests()
   }
+
+  ///
+  /// Writes the results of all completed Nodes which support persistence (see
+  /// `Node::persistent_key`) to the given path, along with the results of their dependencies.
+  ///
+  /// Entries which were loaded but not requested are written as well, so that Nodes which are
+  /// only requested by some runs are not forgotten by the others. But they are dropped once they
+  /// have been carried over for MAX_PERSISTED_AGE persists in a row, so that Nodes which are
+  /// never requested again do not remain in the file forever.
+  ///
+  /// Returns the number of entries written.
+  ///
+  pub fn persist(&self, path: &Path) -> io::Result<usize> {
+    // Collect candidates under the lock, but compute their keys outside of it.
+    let candidates: Vec<(N, N::Item, Vec<Option<(N, N::Item)>>)> = {
+      let inner = self.inner.lock().unwrap();
+      inner
+        .pg
+        .node_indices()
+        .filter_map(|id| {
+          let entry = inner.unsafe_entry_for_id(id);
+          match (&entry.node, entry.peek()) {
+            (&EntryKey::Valid(ref node), Some(Ok(item))) if !node.is_volatile() => {
+              let dep_ids: HashSet<EntryId, FNV> = inner.pg.neighbors(id).collect();
+              let deps = dep_ids
+                .into_iter()
+                .map(|dep_id| {
+                  let dep_entry = inner.unsafe_entry_for_id(dep_id);
+                  match (&dep_entry.node, dep_entry.peek()) {
+                    (&EntryKey::Valid(ref dep), Some(Ok(dep_item))) => {
+                      Some((dep.clone(), dep_item))
+                    }
+                    _ => None,
+                  }
+                })
+                .collect();
+              Some((node.clone(), item, deps))
+            }
+            _ => None,
+          }
+        })
+        .collect()
+    };
+
+    let mut entries = self
+      .persisted
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|&(_, entry)| entry.age < MAX_PERSISTED_AGE)
+      .map(|(key, entry)| {
+        let entry = PersistedEntry {
+          age: entry.age + 1,
+          ..entry.clone()
+        };
+        (key.clone(), entry)
+      })
+      .collect::<HashMap<_, _>>();
+    for (node, item, deps) in candidates {
+      if let Some((key, entry)) = Self::persisted_entry(&node, &item, deps) {
+        entries.insert(key, entry);
+      }
+    }
+    persistence::write(path, entries.iter())
+  }
+
+  fn persisted_entry(
+    node: &N,
+    item: &N::Item,
+    deps: Vec<Option<(N, N::Item)>>,
+  ) -> Option<(Vec<u8>, PersistedEntry)> {
+    let key = node.persistent_key()?;
+    let result = N::persistent_result(item)?;
+    let deps = deps
+      .into_iter()
+      .map(|dep| -> Option<(Vec<u8>, Vec<u8>)> {
+        let (dep, dep_item) = dep?;
+        Some((dep.persistent_key()?, N::persistent_result(&dep_item)?))
+      })
+      .collect::<Option<Vec<_>>>()?;
+    Some((
+      key,
+      PersistedEntry {
+        result,
+        deps,
+        age: 0,
+      },
+    ))
+  }
+
+  ///
+  /// Loads entries that were written by `Graph::persist`. When a matching Node is next requested,
+  /// its persisted result will be used if the persisted results of its dependencies are still
+  /// valid.
+  ///
+  /// Validating a dependency means re-running it, because dependencies are volatile. Restoring
+  /// thus only saves time for Nodes which are more expensive than their dependencies: in the
+  /// engine, for processes and Snapshots rather than for the files that they consume.
+  ///
+  /// Returns the number of entries loaded.
+  ///
+  pub fn load_persisted(&self, path: &Path) -> io::Result<usize> {
+    let entries = persistence::read(path)?;
+    let count = entries.len();
+    self.persisted.lock().unwrap().extend(entries);
+    Ok(count)
+  }
+
+  ///
+  /// The number of Nodes whose persisted results have been reused (see `Graph::load_persisted`)
+  /// rather than re-running them.
+  ///
+  pub fn restored_count(&self) -> usize {
+    self.restored_count.load(Ordering::SeqCst)
+  }
+
+  ///
+  /// Removes and returns the loaded persisted entry for the given Node, if any.
+  ///
+  fn take_persisted(&self, node: &N) -> Option<PersistedEntry> {
+    if node.is_volatile() || self.persisted.lock().unwrap().is_empty() {
+      return None;
+    }
+    let key = node.persistent_key()?;
+    self.persisted.lock().unwrap().remove(&key)
+  }
 }
 
 ///
@@ -722,13 +2059,20 @@ impl<'a, N: Node + 'a, P: Fn(EntryId, Level) -> bool> Iterator for LeveledWalk<'
 
 #[cfg(test)]
 mod tests {
-  use std::sync::Arc;
+  use std::fs::File;
+  use std::io::Read;
+  use std::sync::{Arc, Barrier, Mutex};
+  use std::thread;
+  use std::time::Duration;
 
   use boxfuture::{BoxFuture, Boxable};
   use futures::future::{self, Future};
   use hashing::Digest;
+  use tempfile::TempDir;
 
-  use super::{EntryId, Graph, Node, NodeContext, NodeError};
+  use super::{EntryId, EntryKey, EvictionLimits, Graph, InvalidationCounts, Node, NodeContext,
+              NodeError, Progress, MAX_PERSISTED_AGE};
+  use persistence::{Decoder, Encoder};
 
   #[test]
   fn create() {
@@ -740,9 +2084,537 @@ mod tests {
     );
   }
 
+  #[test]
+  fn add_edge_detects_cycles() {
+    let graph: Graph<TNode> = Graph::new();
+    let mut inner = graph.inner.lock().unwrap();
+    let ids: Vec<EntryId> = (0..6)
+      .map(|i| inner.ensure_entry(EntryKey::Valid(TNode(i))))
+      .collect();
+
+    // Each of these edges disagrees with the order in which the Entries were created.
+    for &(src, dst) in &[(5, 4), (4, 3), (3, 2), (1, 0), (2, 1)] {
+      assert_eq!(inner.add_edge(ids[src], ids[dst]), Ok(()));
+    }
+    // The graph is now a chain from 5 to 0, so any edge against the chain creates a cycle, which
+    // is reported as a path back to the source.
+    let path = |is: &[usize]| Err(is.iter().map(|&i| ids[i]).collect::<Vec<_>>());
+    assert_eq!(inner.add_edge(ids[0], ids[5]), path(&[0, 5, 4, 3, 2, 1, 0]));
+    assert_eq!(inner.add_edge(ids[2], ids[4]), path(&[2, 4, 3, 2]));
+    assert_eq!(inner.add_edge(ids[3], ids[3]), path(&[3, 3]));
+    assert_eq!(inner.add_edge(ids[5], ids[0]), Ok(()));
+
+    for edge in inner.pg.edge_indices() {
+      let (src, dst) = inner.pg.edge_endpoints(edge).unwrap();
+      assert!(inner.order(src) < inner.order(dst));
+    }
+  }
+
+  #[test]
+  fn cyclic_dependency_reports_path() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    let ids: Vec<EntryId> = {
+      let mut inner = graph.inner.lock().unwrap();
+      let ids: Vec<EntryId> = (0..3)
+        .map(|i| inner.ensure_entry(EntryKey::Valid(TNode(i))))
+        .collect();
+      inner.add_edge(ids[2], ids[1]).unwrap();
+      inner.add_edge(ids[1], ids[0]).unwrap();
+      ids
+    };
+
+    let cycle = |is: &[usize]| is.iter().map(|i| format!("TNode({})", i)).collect();
+    assert_eq!(
+      graph.get(ids[0], &context, TNode(2)).wait(),
+      Err(TError::Cyclic(cycle(&[0, 2, 1, 0])))
+    );
+    // The cyclic dependency is recorded, and later requests report the same path.
+    assert_eq!(
+      graph.get(ids[0], &context, TNode(2)).wait(),
+      Err(TError::Cyclic(cycle(&[0, 2, 1, 0])))
+    );
+  }
+
+  #[test]
+  fn timings_and_chrome_trace() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    graph.invalidate_from_roots(|n| n == &TNode(0));
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+
+    // Every Entry has completed, and has been started twice: the leaf to re-run, and its
+    // dependents to be cleaned.
+    let mut timings = graph.timings(Some(&[TNode(2)]));
+    timings.sort_by_key(|timing| timing.node.0);
+    assert_eq!(
+      timings
+        .iter()
+        .map(|timing| (timing.node.clone(), timing.runs))
+        .collect::<Vec<_>>(),
+      vec![(TNode(0), 2), (TNode(1), 2), (TNode(2), 2)]
+    );
+    for timing in &timings {
+      assert!(timing.end.expect("Should have completed.") >= timing.start);
+    }
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("trace.json");
+    let count = graph
+      .export_chrome_trace(None, |n| if n.0 == 0 { Some("Leaves") } else { None }, &path)
+      .unwrap();
+    assert_eq!(count, 3);
+    let mut contents = String::new();
+    File::open(&path)
+      .unwrap()
+      .read_to_string(&mut contents)
+      .unwrap();
+    assert!(contents.contains("\"name\": \"Leaves 0\""));
+    assert!(contents.contains("\"name\": \"TNode(2)\", \"cat\": \"TNode\", \"ph\": \"X\""));
+  }
+
+  #[test]
+  fn progress() {
+    let graph = Arc::new(Graph::new());
+    let barrier = Arc::new(Barrier::new(2));
+    let context = TContext::new(graph.clone()).with_leaf_barrier(barrier.clone());
+    let counts = |progress: Progress<TNode>| {
+      (
+        progress.queued,
+        progress.running,
+        progress.completed,
+        progress.running_nodes.len(),
+      )
+    };
+
+    // The root is queued until it is polled.
+    let root = graph.create(TNode(2), &context);
+    assert_eq!(counts(graph.progress(|_| true)), (1, 0, 0, 0));
+
+    // While the leaf runs, its dependents are running too.
+    let root = thread::spawn(move || root.wait());
+    barrier.wait();
+    assert_eq!(counts(graph.progress(|_| true)), (0, 3, 0, 3));
+    assert_eq!(counts(graph.progress(|n| n == &TNode(0))), (0, 3, 0, 1));
+    barrier.wait();
+    assert_eq!(root.join().unwrap(), Ok("2/1/0".to_string()));
+    assert_eq!(counts(graph.progress(|_| true)), (0, 0, 3, 0));
+
+    // A run which is cleared before it begins is no longer queued.
+    graph.invalidate_from_roots(|n| n == &TNode(2));
+    let root = graph.create(TNode(2), &TContext::new(graph.clone()));
+    assert_eq!(counts(graph.progress(|_| true)), (1, 0, 3, 0));
+    drop(root);
+    graph.invalidate_from_roots(|n| n == &TNode(2));
+    assert_eq!(counts(graph.progress(|_| true)), (0, 0, 3, 0));
+  }
+
+  #[test]
+  fn critical_path() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+
+    // The chain of Nodes is the critical path, and its self-times sum to the root's duration.
+    let path = graph.critical_path(&[TNode(2)]);
+    assert_eq!(
+      path.iter().map(|&(ref n, _)| n.clone()).collect::<Vec<_>>(),
+      vec![TNode(2), TNode(1), TNode(0)]
+    );
+    let root_timing = graph
+      .timings(Some(&[TNode(2)]))
+      .into_iter()
+      .find(|timing| timing.node == TNode(2))
+      .unwrap();
+    let root_duration = root_timing.end.unwrap().duration_since(root_timing.start);
+    let total = path
+      .iter()
+      .fold(Duration::from_secs(0), |total, &(_, self_time)| total + self_time);
+    assert_eq!(total, root_duration);
+  }
+
+  #[test]
+  fn critical_path_with_overlapping_dependencies() {
+    let graph = Arc::new(Graph::new());
+    let barrier = Arc::new(Barrier::new(2));
+    let context = TContext::new(graph.clone()).with_leaf_barrier(barrier.clone());
+
+    // TNode(1) (and the leaf that it depends on) start before TNode(2), which depends on it.
+    let (dep_graph, dep_context) = (graph.clone(), context.clone());
+    let dep = thread::spawn(move || dep_graph.create(TNode(1), &dep_context).wait());
+    barrier.wait();
+    let root = graph.create(TNode(2), &context);
+    barrier.wait();
+    assert_eq!(root.wait(), Ok("2/1/0".to_string()));
+    assert_eq!(dep.join().unwrap(), Ok("1/0".to_string()));
+
+    // Only the portions of the dependencies' runs during which the root was running are
+    // attributed to the path, so its self-times still sum to the root's duration.
+    let path = graph.critical_path(&[TNode(2)]);
+    assert_eq!(
+      path.iter().map(|&(ref n, _)| n.clone()).collect::<Vec<_>>(),
+      vec![TNode(2), TNode(1), TNode(0)]
+    );
+    let root_timing = graph
+      .timings(Some(&[TNode(2)]))
+      .into_iter()
+      .find(|timing| timing.node == TNode(2))
+      .unwrap();
+    let root_duration = root_timing.end.unwrap().duration_since(root_timing.start);
+    let total = path
+      .iter()
+      .fold(Duration::from_secs(0), |total, &(_, self_time)| total + self_time);
+    assert_eq!(total, root_duration);
+  }
+
+  #[test]
+  fn invalidate_and_clean() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+
+    // Invalidating the leaf dirties its dependents, but does not remove them.
+    assert_eq!(graph.invalidate_from_roots(|n| n == &TNode(0)), 3);
+    assert_eq!(graph.len(), 3);
+
+    // The leaf re-runs and produces the same value, so its dependents are cleaned.
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    assert_eq!(context.runs(), vec![TNode(0)]);
+  }
+
+  #[test]
+  fn invalidate_by_classification() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+
+    let counts = graph.invalidate_from_roots_by(|n| n == &TNode(0), |n| n.0 == 0);
+    assert_eq!(
+      counts.get(&true),
+      Some(&InvalidationCounts {
+        cleared: 1,
+        dirtied: 0,
+      })
+    );
+    assert_eq!(
+      counts.get(&false),
+      Some(&InvalidationCounts {
+        cleared: 0,
+        dirtied: 2,
+      })
+    );
+  }
+
+  #[test]
+  fn evict_entries_without_dependents() {
+    let graph = Arc::new(Graph::with_limits(EvictionLimits {
+      max_entries: Some(2),
+      max_bytes: None,
+    }));
+    let context = TContext::new(graph.clone());
+    assert_eq!(graph.create(TNode(1), &context).wait(), Ok("1/0".to_string()));
+    assert_eq!(graph.evict(), 0);
+
+    // Only TNode(2) has no dependents, so it is the only Entry that can be evicted.
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    assert_eq!(graph.evict(), 1);
+    assert_eq!(graph.len(), 2);
+    assert_eq!(graph.evicted_count(), 1);
+
+    // Its dependencies were not evicted, so only TNode(2) re-runs.
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    assert_eq!(context.runs(), vec![TNode(2)]);
+  }
+
+  #[test]
+  fn estimated_bytes_include_results() {
+    let estimated_bytes = |leaf: &str| {
+      let graph = Arc::new(Graph::new());
+      let context = TContext::new(graph.clone()).with_leaf(leaf);
+      graph.create(TNode(2), &context).wait().unwrap();
+      graph.estimated_bytes()
+    };
+    // Each of the three results contains the leaf.
+    let leaf = "0".repeat(1000);
+    assert!(estimated_bytes(&leaf) > estimated_bytes("0") + 3 * 900);
+  }
+
+  #[test]
+  fn evict_in_least_recently_used_order() {
+    let graph = Arc::new(Graph::with_limits(EvictionLimits {
+      max_entries: Some(0),
+      max_bytes: None,
+    }));
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    assert!(graph.estimated_bytes() > 0);
+
+    // Each dependency was used after its dependent, and so becomes eligible before it is visited.
+    assert_eq!(graph.evict(), 3);
+    assert_eq!(graph.len(), 0);
+    // The sizes of the evicted Entries and their results are no longer counted.
+    assert_eq!(graph.estimated_bytes(), 0);
+  }
+
+  #[test]
+  fn invalidate_and_rerun() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+
+    // The leaf re-runs and produces a different value, so its dependents re-run.
+    graph.invalidate_from_roots(|n| n == &TNode(0));
+    let context = TContext::new(graph.clone()).with_leaf("changed");
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/changed".to_string())
+    );
+    assert_eq!(context.runs(), vec![TNode(0), TNode(1), TNode(2)]);
+  }
+
+  #[test]
+  fn invalidate_with_early_cutoff() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(3), &context).wait(),
+      Ok("3/2/1/0".to_string())
+    );
+
+    // TNode(1) re-runs and produces the same value (because it is not the leaf that was
+    // invalidated), so TNode(2) and TNode(3) do not need to re-run.
+    graph.invalidate_from_roots(|n| n == &TNode(1));
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(3), &context).wait(),
+      Ok("3/2/1/0".to_string())
+    );
+    assert_eq!(context.runs(), vec![TNode(1)]);
+  }
+
+  #[test]
+  fn invalidate_and_clean_dependencies_in_order() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone()).with_guard(3);
+    assert_eq!(
+      graph.create(TNode(3), &context).wait(),
+      Ok("3/2/1/0".to_string())
+    );
+
+    // TNode(3) requested the leaf before TNode(2), so the changed leaf is checked first, and
+    // TNode(3) re-runs without requesting TNode(2) (which would otherwise have been cleaned).
+    graph.invalidate_from_roots(|n| n == &TNode(0));
+    let context = TContext::new(graph.clone())
+      .with_guard(3)
+      .with_leaf("changed");
+    assert_eq!(
+      graph.create(TNode(3), &context).wait(),
+      Ok("3/changed".to_string())
+    );
+    assert_eq!(context.runs(), vec![TNode(0), TNode(3)]);
+  }
+
+  #[test]
+  fn invalidate_with_repeated_error() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone()).with_leaf_error("boom");
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Err(TError::Failed("boom".to_string()))
+    );
+
+    // The leaf re-runs and fails in the same way, so its dependents are cleaned.
+    graph.invalidate_from_roots(|n| n == &TNode(0));
+    let context = TContext::new(graph.clone()).with_leaf_error("boom");
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Err(TError::Failed("boom".to_string()))
+    );
+    assert_eq!(context.runs(), vec![TNode(0)]);
+
+    // But if it fails differently, they re-run.
+    graph.invalidate_from_roots(|n| n == &TNode(0));
+    let context = TContext::new(graph.clone()).with_leaf_error("bang");
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Err(TError::Failed("bang".to_string()))
+    );
+    assert_eq!(context.runs(), vec![TNode(0), TNode(1), TNode(2)]);
+  }
+
+  #[test]
+  fn persist_and_restore() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph");
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    // The volatile TNode(0) is not persisted.
+    assert_eq!(graph.persist(&path).unwrap(), 2);
+
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(graph.load_persisted(&path).unwrap(), 2);
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    // Only the volatile Node should have run: the others were restored.
+    assert_eq!(context.runs(), vec![TNode(0)]);
+    assert_eq!(graph.restored_count(), 2);
+    // And restored Nodes are persisted again.
+    assert_eq!(graph.persist(&path).unwrap(), 2);
+  }
+
+  #[test]
+  fn unrequested_persisted_entries_age_out() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph");
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    assert_eq!(graph.persist(&path).unwrap(), 2);
+
+    // Entries which are loaded but not requested are carried over a limited number of times.
+    for _ in 0..MAX_PERSISTED_AGE {
+      let graph = Arc::new(Graph::<TNode>::new());
+      assert_eq!(graph.load_persisted(&path).unwrap(), 2);
+      assert_eq!(graph.persist(&path).unwrap(), 2);
+    }
+    let graph = Arc::new(Graph::<TNode>::new());
+    assert_eq!(graph.load_persisted(&path).unwrap(), 2);
+    assert_eq!(graph.persist(&path).unwrap(), 0);
+  }
+
+  #[test]
+  fn export_json() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph.json");
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    graph.invalidate_from_roots(|n| n == &TNode(0));
+
+    assert_eq!(graph.export_json(Some(&[TNode(1)]), &path).unwrap(), 2);
+    assert_eq!(graph.export_json(None, &path).unwrap(), 3);
+    let mut contents = String::new();
+    File::open(&path)
+      .and_then(|mut f| f.read_to_string(&mut contents))
+      .unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines.iter().all(|line| line.contains("\"type\": \"TNode\"")));
+    assert!(
+      lines
+        .iter()
+        .any(|line| line.contains("\"node\": \"TNode(0)\"") && line.contains("\"cleared\""))
+    );
+    assert!(
+      lines
+        .iter()
+        .any(|line| line.contains("\"result\": \"\\\"2/1/0\\\"\"") && line.contains("\"dirty\""))
+    );
+  }
+
+  #[test]
+  fn restore_invalid() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph");
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    graph.persist(&path).unwrap();
+
+    // The volatile leaf produces a different value in the new Graph, so nothing is restored.
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone()).with_leaf("changed");
+    graph.load_persisted(&path).unwrap();
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/changed".to_string())
+    );
+    let mut runs = context.runs();
+    runs.sort_by_key(|n| n.0);
+    assert_eq!(runs, vec![TNode(0), TNode(1), TNode(2)]);
+  }
+
+  #[test]
+  fn restore_unrestorable() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph");
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    graph.persist(&path).unwrap();
+
+    // The dependencies are unchanged, but the persisted results can't be used.
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(graph.clone()).with_restorable(false);
+    graph.load_persisted(&path).unwrap();
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok("2/1/0".to_string())
+    );
+    let mut runs = context.runs();
+    runs.sort_by_key(|n| n.0);
+    assert_eq!(runs, vec![TNode(0), TNode(1), TNode(2)]);
+  }
+
   ///
   /// A node that builds a string by recursively requesting itself and prepending its value
-  /// to the result.
+  /// to the result. The leaf TNode(0) is volatile, and returns the leaf value of its context.
+  ///
+  /// The guard Node of the context first requests the leaf, and only recurses if the leaf has its
+  /// default value.
   ///
   #[derive(Clone, Debug, Eq, Hash, PartialEq)]
   struct TNode(usize);
@@ -752,14 +2624,37 @@ mod tests {
     type Error = TError;
 
     fn run(self, context: TContext) -> BoxFuture<String, TError> {
+      context.runs.lock().unwrap().push(self.clone());
       let depth = self.0;
-      if depth > 0 {
+      if context.guard == Some(depth) {
+        let guard_context = context.clone();
+        context
+          .get(TNode(0))
+          .and_then(move |leaf| {
+            if leaf == "0" {
+              guard_context
+                .get(TNode(depth - 1))
+                .map(move |v| format!("{}/{}", depth, v))
+                .to_boxed()
+            } else {
+              future::ok(format!("{}/{}", depth, leaf)).to_boxed()
+            }
+          })
+          .to_boxed()
+      } else if depth > 0 {
         context
           .get(TNode(depth - 1))
           .map(move |v| format!("{}/{}", depth, v))
           .to_boxed()
       } else {
-        future::ok(format!("{}", depth)).to_boxed()
+        if let Some(ref barrier) = context.leaf_barrier {
+          barrier.wait();
+          barrier.wait();
+        }
+        match context.leaf_error {
+          Some(ref error) => future::err(TError::Failed(error.clone())).to_boxed(),
+          None => future::ok(context.leaf.clone()).to_boxed(),
+        }
       }
     }
 
@@ -767,15 +2662,61 @@ mod tests {
       format!("{:?}", self)
     }
 
+    fn type_name(&self) -> &'static str {
+      "TNode"
+    }
+
     fn digest(_result: Self::Item) -> Option<Digest> {
       None
     }
+
+    fn result_heap_size(result: &String) -> usize {
+      result.capacity()
+    }
+
+    fn result_eq(a: &String, b: &String) -> bool {
+      a == b
+    }
+
+    fn error_eq(a: &TError, b: &TError) -> bool {
+      a == b
+    }
+
+    fn persistent_key(&self) -> Option<Vec<u8>> {
+      Some(Encoder::new().u64(self.0 as u64).finish())
+    }
+
+    fn from_persistent_key(key: &[u8]) -> Option<TNode> {
+      Decoder::new(key).u64().map(|depth| TNode(depth as usize))
+    }
+
+    fn persistent_result(result: &String) -> Option<Vec<u8>> {
+      Some(result.as_bytes().to_vec())
+    }
+
+    fn from_persistent_result(bytes: &[u8]) -> Option<String> {
+      String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    fn is_restorable(context: &TContext, _result: &String) -> BoxFuture<bool, TError> {
+      future::ok(context.restorable).to_boxed()
+    }
+
+    fn is_volatile(&self) -> bool {
+      self.0 == 0
+    }
   }
 
   #[derive(Clone)]
   struct TContext {
     graph: Arc<Graph<TNode>>,
     entry_id: Option<EntryId>,
+    leaf: String,
+    leaf_error: Option<String>,
+    guard: Option<usize>,
+    restorable: bool,
+    leaf_barrier: Option<Arc<Barrier>>,
+    runs: Arc<Mutex<Vec<TNode>>>,
   }
   impl NodeContext for TContext {
     type Node = TNode;
@@ -783,6 +2724,12 @@ mod tests {
       TContext {
         graph: self.graph.clone(),
         entry_id: Some(entry_id),
+        leaf: self.leaf.clone(),
+        leaf_error: self.leaf_error.clone(),
+        guard: self.guard,
+        restorable: self.restorable,
+        leaf_barrier: self.leaf_barrier.clone(),
+        runs: self.runs.clone(),
       }
     }
 
@@ -796,9 +2743,48 @@ mod tests {
       TContext {
         graph,
         entry_id: None,
+        leaf: "0".to_string(),
+        leaf_error: None,
+        guard: None,
+        restorable: true,
+        leaf_barrier: None,
+        runs: Arc::new(Mutex::new(Vec::new())),
       }
     }
 
+    fn with_leaf(mut self, leaf: &str) -> TContext {
+      self.leaf = leaf.to_string();
+      self
+    }
+
+    fn with_leaf_error(mut self, error: &str) -> TContext {
+      self.leaf_error = Some(error.to_string());
+      self
+    }
+
+    fn with_guard(mut self, depth: usize) -> TContext {
+      self.guard = Some(depth);
+      self
+    }
+
+    fn with_restorable(mut self, restorable: bool) -> TContext {
+      self.restorable = restorable;
+      self
+    }
+
+    ///
+    /// Makes the leaf wait on the given Barrier twice when it runs: once to signal that it has
+    /// started, and once to be released.
+    ///
+    fn with_leaf_barrier(mut self, barrier: Arc<Barrier>) -> TContext {
+      self.leaf_barrier = Some(barrier);
+      self
+    }
+
+    fn runs(&self) -> Vec<TNode> {
+      self.runs.lock().unwrap().clone()
+    }
+
     fn get(&self, dst: TNode) -> BoxFuture<String, TError> {
       self.graph.get(self.entry_id.unwrap(), self, dst)
     }
@@ -806,7 +2792,8 @@ mod tests {
 
   #[derive(Clone, Debug, Eq, PartialEq)]
   enum TError {
-    Cyclic,
+    Cyclic(Vec<String>),
+    Failed(String),
     Invalidated,
   }
   impl NodeError for TError {
@@ -814,8 +2801,8 @@ mod tests {
       TError::Invalidated
     }
 
-    fn cyclic() -> Self {
-      TError::Cyclic
+    fn cyclic(path: Vec<String>) -> Self {
+      TError::Cyclic(path)
     }
   }
 }
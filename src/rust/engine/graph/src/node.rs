@@ -4,7 +4,8 @@
 use std::fmt::Debug;
 use std::hash::Hash;
 
-use boxfuture::BoxFuture;
+use boxfuture::{BoxFuture, Boxable};
+use futures::future;
 use hashing::Digest;
 
 use petgraph::stable_graph;
@@ -28,10 +29,102 @@ pub trait Node: Clone + Eq + Hash + Send + 'static {
   // TODO: Use a `Display` bound instead.
   fn format(&self) -> String;
 
+  ///
+  /// The name of the kind of this Node, for use in reporting on Nodes by kind.
+  ///
+  fn type_name(&self) -> &'static str;
+
   ///
   /// If the given Node output represents an FS operation, returns its Digest.
   ///
   fn digest(result: Self::Item) -> Option<Digest>;
+
+  ///
+  /// Estimates the number of bytes of heap memory owned by this Node, for the estimated size of
+  /// the Graph (see `EvictionLimits`). The default implementation assumes that it owns none.
+  ///
+  fn heap_size(&self) -> usize {
+    0
+  }
+
+  ///
+  /// Like `Node::heap_size`, but for a Node output.
+  ///
+  fn result_heap_size(_result: &Self::Item) -> usize {
+    0
+  }
+
+  ///
+  /// Returns true if the given Node outputs are equal. When an invalidated Node re-runs and
+  /// produces an output equal to its previous output, the Nodes that depend on it do not need to
+  /// re-run.
+  ///
+  /// The default implementation conservatively assumes that outputs are never equal.
+  ///
+  fn result_eq(_a: &Self::Item, _b: &Self::Item) -> bool {
+    false
+  }
+
+  ///
+  /// Returns true if the given Node failures are equal. Like `result_eq`, this allows the
+  /// dependents of a Node which fails in the same way after being invalidated to avoid re-running.
+  ///
+  /// The default implementation conservatively assumes that failures are never equal.
+  ///
+  fn error_eq(_a: &Self::Error, _b: &Self::Error) -> bool {
+    false
+  }
+
+  ///
+  /// If this Node has an identity that is stable across processes, returns it encoded as bytes.
+  /// Only Nodes with a persistent key (and a persistent result) will be persisted by
+  /// `Graph::persist`.
+  ///
+  fn persistent_key(&self) -> Option<Vec<u8>> {
+    None
+  }
+
+  ///
+  /// Recreates a Node from a key that was returned by `Node::persistent_key`. This is required
+  /// for any Node which might be the dependency of a persisted Node, in order to validate it.
+  ///
+  fn from_persistent_key(_key: &[u8]) -> Option<Self> {
+    None
+  }
+
+  ///
+  /// If the given Node output can be persisted, returns it encoded as bytes.
+  ///
+  fn persistent_result(_result: &Self::Item) -> Option<Vec<u8>> {
+    None
+  }
+
+  ///
+  /// Recreates a Node output from bytes that were returned by `Node::persistent_result`.
+  ///
+  fn from_persistent_result(_bytes: &[u8]) -> Option<Self::Item> {
+    None
+  }
+
+  ///
+  /// Returns true if a result that was recreated by `Node::from_persistent_result` can still be
+  /// used, e.g. because any content that it refers to by Digest still exists. The default
+  /// implementation assumes that it can.
+  ///
+  fn is_restorable(_context: &Self::Context, _result: &Self::Item) -> BoxFuture<bool, Self::Error> {
+    future::ok(true).to_boxed()
+  }
+
+  ///
+  /// Returns true if this Node observes state outside of the Graph (such as the filesystem),
+  /// and thus might produce a different result across processes for the same key.
+  ///
+  /// Volatile Nodes are always re-run rather than restored from a persisted result: instead,
+  /// their results are used to validate the persisted results of the Nodes that depend on them.
+  ///
+  fn is_volatile(&self) -> bool {
+    false
+  }
 }
 
 pub trait NodeError: Clone + Debug + Send {
@@ -42,9 +135,11 @@ pub trait NodeError: Clone + Debug + Send {
   fn invalidated() -> Self;
 
   ///
-  /// Creates an instance that represents that a Node dependency was cyclic.
+  /// Creates an instance that represents that a Node dependency was cyclic along the given path
+  /// of formatted Nodes, which begins and ends with the Node that requested the dependency. The
+  /// path is empty if it is not known.
   ///
-  fn cyclic() -> Self;
+  fn cyclic(path: Vec<String>) -> Self;
 }
 
 ///
@@ -0,0 +1,254 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+// Identifies (and versions) the format of a persisted Graph file.
+const MAGIC: &[u8] = b"pants-graph-v2\n";
+
+///
+/// Encodes values into a stable, length-prefixed binary format, for use in Node
+/// implementations of persistent keys and results.
+///
+#[derive(Default)]
+pub struct Encoder {
+  bytes: Vec<u8>,
+}
+
+impl Encoder {
+  pub fn new() -> Encoder {
+    Encoder::default()
+  }
+
+  pub fn u64(&mut self, value: u64) -> &mut Encoder {
+    for shift in (0..8).rev() {
+      self.bytes.push((value >> (shift * 8)) as u8);
+    }
+    self
+  }
+
+  pub fn bool(&mut self, value: bool) -> &mut Encoder {
+    self.bytes.push(value as u8);
+    self
+  }
+
+  pub fn bytes(&mut self, value: &[u8]) -> &mut Encoder {
+    self.u64(value.len() as u64);
+    self.bytes.extend_from_slice(value);
+    self
+  }
+
+  pub fn str(&mut self, value: &str) -> &mut Encoder {
+    self.bytes(value.as_bytes())
+  }
+
+  pub fn finish(&mut self) -> Vec<u8> {
+    ::std::mem::replace(&mut self.bytes, Vec::new())
+  }
+}
+
+///
+/// Decodes values that were encoded by an Encoder. Each method returns None if the input was
+/// truncated or malformed.
+///
+pub struct Decoder<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+  pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+    Decoder { bytes }
+  }
+
+  pub fn u64(&mut self) -> Option<u64> {
+    if self.bytes.len() < 8 {
+      return None;
+    }
+    let value = self.bytes[0..8]
+      .iter()
+      .fold(0, |acc, &byte| (acc << 8) | u64::from(byte));
+    self.bytes = &self.bytes[8..];
+    Some(value)
+  }
+
+  pub fn bool(&mut self) -> Option<bool> {
+    match self.bytes.first() {
+      Some(&byte) if byte <= 1 => {
+        self.bytes = &self.bytes[1..];
+        Some(byte == 1)
+      }
+      _ => None,
+    }
+  }
+
+  pub fn bytes(&mut self) -> Option<&'a [u8]> {
+    let len = self.u64()? as usize;
+    if self.bytes.len() < len {
+      return None;
+    }
+    let (value, rest) = self.bytes.split_at(len);
+    self.bytes = rest;
+    Some(value)
+  }
+
+  pub fn string(&mut self) -> Option<String> {
+    self
+      .bytes()
+      .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+  }
+
+  ///
+  /// Returns true if all input has been consumed.
+  ///
+  pub fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+}
+
+///
+/// The persisted result of a Node, along with the persisted results of each of its dependencies
+/// at the time that it ran. The Node's result may only be reused if all of its dependencies
+/// produce identical results.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PersistedEntry {
+  pub result: Vec<u8>,
+  pub deps: Vec<(Vec<u8>, Vec<u8>)>,
+  // The number of times in a row that this entry has been persisted again without its Node being
+  // requested.
+  pub age: u64,
+}
+
+///
+/// Writes the given (key, entry) pairs to the given path, replacing it atomically.
+///
+pub fn write<'a, I>(path: &Path, entries: I) -> io::Result<usize>
+where
+  I: Iterator<Item = (&'a Vec<u8>, &'a PersistedEntry)>,
+{
+  let tmp_path = path.with_extension("tmp");
+  let mut count = 0;
+  {
+    let mut f = BufWriter::new(File::create(&tmp_path)?);
+    f.write_all(MAGIC)?;
+    for (key, entry) in entries {
+      let mut encoder = Encoder::new();
+      encoder
+        .bytes(key)
+        .bytes(&entry.result)
+        .u64(entry.age)
+        .u64(entry.deps.len() as u64);
+      for &(ref dep_key, ref dep_result) in &entry.deps {
+        encoder.bytes(dep_key).bytes(dep_result);
+      }
+      f.write_all(&Encoder::new().bytes(&encoder.finish()).finish())?;
+      count += 1;
+    }
+    f.flush()?;
+  }
+  fs::rename(&tmp_path, path)?;
+  Ok(count)
+}
+
+///
+/// Reads the (key, entry) pairs that were written to the given path by `write`.
+///
+pub fn read(path: &Path) -> io::Result<Vec<(Vec<u8>, PersistedEntry)>> {
+  let mut contents = Vec::new();
+  File::open(path)?.read_to_end(&mut contents)?;
+  if !contents.starts_with(MAGIC) {
+    return Err(invalid_data(path));
+  }
+
+  let mut entries = Vec::new();
+  let mut decoder = Decoder::new(&contents[MAGIC.len()..]);
+  while !decoder.is_empty() {
+    let entry = decoder
+      .bytes()
+      .and_then(decode_entry)
+      .ok_or_else(|| invalid_data(path))?;
+    entries.push(entry);
+  }
+  Ok(entries)
+}
+
+fn decode_entry(bytes: &[u8]) -> Option<(Vec<u8>, PersistedEntry)> {
+  let mut decoder = Decoder::new(bytes);
+  let key = decoder.bytes()?.to_vec();
+  let result = decoder.bytes()?.to_vec();
+  let age = decoder.u64()?;
+  let dep_count = decoder.u64()?;
+  let mut deps = Vec::new();
+  for _ in 0..dep_count {
+    let dep_key = decoder.bytes()?.to_vec();
+    let dep_result = decoder.bytes()?.to_vec();
+    deps.push((dep_key, dep_result));
+  }
+  if decoder.is_empty() {
+    Some((key, PersistedEntry { result, deps, age }))
+  } else {
+    None
+  }
+}
+
+fn invalid_data(path: &Path) -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!("{} is not a valid persisted Graph.", path.display()),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{read, write, Decoder, Encoder, PersistedEntry};
+
+  use std::fs::File;
+  use std::io::Write;
+  use tempfile::TempDir;
+
+  #[test]
+  fn roundtrip_values() {
+    let bytes = Encoder::new()
+      .u64(0x0102_0304_0506_0708)
+      .bool(true)
+      .str("roland")
+      .bytes(&[])
+      .finish();
+    let mut decoder = Decoder::new(&bytes);
+    assert_eq!(decoder.u64(), Some(0x0102_0304_0506_0708));
+    assert_eq!(decoder.bool(), Some(true));
+    assert_eq!(decoder.string(), Some("roland".to_string()));
+    assert_eq!(decoder.bytes(), Some(&[][..]));
+    assert!(decoder.is_empty());
+    assert_eq!(decoder.u64(), None);
+  }
+
+  #[test]
+  fn roundtrip_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph");
+    let key = b"key".to_vec();
+    let entry = PersistedEntry {
+      result: b"result".to_vec(),
+      deps: vec![(b"dep".to_vec(), b"dep_result".to_vec())],
+      age: 3,
+    };
+    assert_eq!(
+      write(&path, vec![(&key, &entry)].into_iter()).unwrap(),
+      1
+    );
+    assert_eq!(read(&path).unwrap(), vec![(key, entry)]);
+  }
+
+  #[test]
+  fn read_invalid_file() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("graph");
+    File::create(&path)
+      .and_then(|mut f| f.write_all(b"pants-graph-v2\n\x00\x01"))
+      .unwrap();
+    assert!(read(&path).is_err());
+  }
+}
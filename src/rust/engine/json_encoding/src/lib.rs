@@ -0,0 +1,56 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+// Helpers for hand-writing JSON, shared by the exports of the Graph and the RuleGraph.
+
+use std::fmt::Write;
+
+///
+/// Encodes the given value as a JSON string literal (including quotes).
+///
+pub fn string(value: &str) -> String {
+  let mut res = String::with_capacity(value.len() + 2);
+  res.push('"');
+  for c in value.chars() {
+    match c {
+      '"' => res.push_str("\\\""),
+      '\\' => res.push_str("\\\\"),
+      '\n' => res.push_str("\\n"),
+      '\r' => res.push_str("\\r"),
+      '\t' => res.push_str("\\t"),
+      c if (c as u32) < 0x20 => {
+        write!(res, "\\u{:04x}", c as u32).unwrap();
+      }
+      c => res.push(c),
+    }
+  }
+  res.push('"');
+  res
+}
+
+///
+/// Encodes the given optional value as JSON, using `null` for None.
+///
+pub fn option<T, F: FnOnce(T) -> String>(value: Option<T>, encode: F) -> String {
+  value.map(encode).unwrap_or_else(|| "null".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{option, string};
+
+  #[test]
+  fn strings() {
+    assert_eq!(string("plain"), "\"plain\"");
+    assert_eq!(
+      string("\"quoted\"\\\n\u{1}"),
+      "\"\\\"quoted\\\"\\\\\\n\\u0001\""
+    );
+  }
+
+  #[test]
+  fn options() {
+    assert_eq!(option(Some(1), |v| v.to_string()), "1");
+    assert_eq!(option(None::<u32>, |v| v.to_string()), "null");
+  }
+}
@@ -11,21 +11,60 @@ pub fn verify_directory_canonical(directory: &remote_execution::Directory) -> Re
     |n| n.get_name(),
     |n| n.get_digest(),
   )?;
+  verify_symlink_nodes(directory.get_symlinks())?;
   let file_names: HashSet<&str> = directory
     .get_files()
     .iter()
     .map(|file| file.get_name())
     .chain(directory.get_directories().iter().map(|dir| dir.get_name()))
+    .chain(directory.get_symlinks().iter().map(|link| link.get_name()))
     .collect();
-  if file_names.len() != directory.get_files().len() + directory.get_directories().len() {
+  if file_names.len()
+    != directory.get_files().len() + directory.get_directories().len()
+      + directory.get_symlinks().len()
+  {
     return Err(format!(
-      "Children must be unique, but a path was both a file and a directory: {:?}",
+      "Children must be unique, but a path was more than one of a file, a directory, or a \
+       symlink: {:?}",
       directory
     ));
   }
   Ok(())
 }
 
+fn verify_symlink_nodes(nodes: &[remote_execution::SymlinkNode]) -> Result<(), String> {
+  let mut prev: Option<&remote_execution::SymlinkNode> = None;
+  for node in nodes {
+    verify_no_unknown_fields(node)?;
+    if node.get_name().contains("/") {
+      return Err(format!(
+        "All children must have one path segment, but found {}",
+        node.get_name()
+      ));
+    }
+    if node.get_target().is_empty() {
+      return Err(format!(
+        "Symlinks must have a target, but {} did not",
+        node.get_name()
+      ));
+    }
+    match prev {
+      Some(p) => {
+        if node.get_name() <= p.get_name() {
+          return Err(format!(
+            "Children must be sorted and unique, but {} was before {}",
+            p.get_name(),
+            node.get_name()
+          ));
+        }
+      }
+      None => {}
+    }
+    prev = Some(node);
+  }
+  Ok(())
+}
+
 fn verify_nodes<Node, GetName, GetDigest>(
   nodes: &[Node],
   get_name: GetName,
@@ -75,7 +114,7 @@ fn verify_no_unknown_fields(message: &protobuf::Message) -> Result<(), String> {
 
 #[cfg(test)]
 mod canonical_directory_tests {
-  use super::remote_execution::{Digest, Directory, DirectoryNode, FileNode};
+  use super::remote_execution::{Digest, Directory, DirectoryNode, FileNode, SymlinkNode};
   use super::verify_directory_canonical;
   use protobuf::Message;
 
@@ -369,4 +408,69 @@ mod canonical_directory_tests {
       file
     });
   }
+
+  #[test]
+  fn canonical_directory_with_symlinks() {
+    let mut directory = Directory::new();
+    directory.mut_symlinks().push({
+      let mut link = SymlinkNode::new();
+      link.set_name("roland".to_owned());
+      link.set_target("cats/roland".to_owned());
+      link
+    });
+    directory.mut_symlinks().push({
+      let mut link = SymlinkNode::new();
+      link.set_name("simba".to_owned());
+      link.set_target("../simba".to_owned());
+      link
+    });
+    assert_eq!(Ok(()), verify_directory_canonical(&directory));
+  }
+
+  #[test]
+  fn unsorted_path_in_symlink() {
+    let mut directory = Directory::new();
+    directory.mut_symlinks().push({
+      let mut link = SymlinkNode::new();
+      link.set_name("simba".to_owned());
+      link.set_target("../simba".to_owned());
+      link
+    });
+    directory.mut_symlinks().push({
+      let mut link = SymlinkNode::new();
+      link.set_name("roland".to_owned());
+      link.set_target("cats/roland".to_owned());
+      link
+    });
+
+    let error = verify_directory_canonical(&directory).expect_err("Want error");
+    assert!(
+      error.contains("simba was before roland"),
+      format!("Bad error message: {}", error)
+    );
+  }
+
+  #[test]
+  fn duplicate_path_in_file_and_symlink() {
+    let mut directory = Directory::new();
+    directory.mut_files().push({
+      let mut file = FileNode::new();
+      file.set_name("roland".to_owned());
+      file.set_digest({
+        let mut digest = Digest::new();
+        digest.set_size_bytes(FILE_SIZE);
+        digest.set_hash(HASH.to_owned());
+        digest
+      });
+      file
+    });
+    directory.mut_symlinks().push({
+      let mut link = SymlinkNode::new();
+      link.set_name("roland".to_owned());
+      link.set_target("cats/roland".to_owned());
+      link
+    });
+
+    verify_directory_canonical(&directory).expect_err("Want error");
+  }
 }
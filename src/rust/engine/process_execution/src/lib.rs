@@ -9,6 +9,7 @@ extern crate futures;
 extern crate futures_timer;
 extern crate grpcio;
 extern crate hashing;
+extern crate libc;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
@@ -69,7 +70,7 @@ pub struct ExecuteProcessRequest {
 ///
 /// The result of running a process.
 ///
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq)]
 pub struct FallibleExecuteProcessResult {
   pub stdout: Bytes,
   pub stderr: Bytes,
@@ -78,6 +79,20 @@ pub struct FallibleExecuteProcessResult {
   // It's unclear whether this should be a Snapshot or a digest of a Directory. A Directory digest
   // is handy, so let's try that out for now.
   pub output_directory: hashing::Digest,
+
+  // The sandbox of a locally executed process, if it was kept (see `local::KeepSandboxes`). This
+  // is a side effect of one particular run of the process rather than a part of its result, so it
+  // is ignored by equality (and is not persisted).
+  pub kept_sandbox: Option<PathBuf>,
+}
+
+impl PartialEq for FallibleExecuteProcessResult {
+  fn eq(&self, other: &FallibleExecuteProcessResult) -> bool {
+    self.stdout == other.stdout
+      && self.stderr == other.stderr
+      && self.exit_code == other.exit_code
+      && self.output_directory == other.output_directory
+  }
 }
 
 pub trait CommandRunner: Send + Sync {
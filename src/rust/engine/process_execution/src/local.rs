@@ -1,12 +1,19 @@
 extern crate tempfile;
 
 use boxfuture::{BoxFuture, Boxable};
-use fs::{self, GlobMatching, PathGlobs, PathStatGetter, Snapshot, Store, StrictGlobMatching};
+use fs::{self, PathStat, Snapshot, Store};
 use futures::{future, Future};
-use std::collections::BTreeSet;
-use std::path::PathBuf;
+use hashing::{Digest, WriterHasher};
+use libc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tokio_process::CommandExt;
 
@@ -14,56 +21,328 @@ use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
 
 use bytes::Bytes;
 
+///
+/// Which sandboxes (i.e. workdirs) of finished processes to keep on disk for debugging. A kept
+/// sandbox contains a `__run.sh` script which re-runs the process in it.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeepSandboxes {
+  Never,
+  OnFailure,
+  Always,
+}
+
+impl KeepSandboxes {
+  pub fn create(keep_sandboxes: &str) -> Result<KeepSandboxes, String> {
+    match keep_sandboxes {
+      "never" => Ok(KeepSandboxes::Never),
+      "on_failure" => Ok(KeepSandboxes::OnFailure),
+      "always" => Ok(KeepSandboxes::Always),
+      _ => Err(format!("Unrecognized keep_sandboxes value: {}.", keep_sandboxes)),
+    }
+  }
+
+  fn should_keep(&self, succeeded: bool) -> bool {
+    match *self {
+      KeepSandboxes::Never => false,
+      KeepSandboxes::OnFailure => !succeeded,
+      KeepSandboxes::Always => true,
+    }
+  }
+}
+
+// The prefix of the directories which hold the workdirs of a WorkdirPool.
+const WORKDIR_POOL_PREFIX: &str = "process-workdirs";
+
+// The file in the directory of a WorkdirPool which is locked for as long as the pool is in use.
+const WORKDIR_POOL_LOCK: &str = ".lock";
+
+// The coarsest mtime granularity (in seconds) of the filesystems which workdirs might live on.
+const MTIME_GRANULARITY_SECS: u64 = 2;
+
 pub struct CommandRunner {
   store: fs::Store,
   fs_pool: Arc<fs::ResettablePool>,
+  workdirs: Option<Arc<WorkdirPool>>,
+  sandbox_base: Option<PathBuf>,
+  keep_sandboxes: KeepSandboxes,
 }
 
 impl CommandRunner {
-  pub fn new(store: fs::Store, fs_pool: Arc<fs::ResettablePool>) -> CommandRunner {
-    CommandRunner { store, fs_pool }
+  pub fn new(
+    store: fs::Store,
+    fs_pool: Arc<fs::ResettablePool>,
+    keep_sandboxes: KeepSandboxes,
+  ) -> CommandRunner {
+    CommandRunner {
+      store,
+      fs_pool,
+      workdirs: None,
+      sandbox_base: None,
+      keep_sandboxes,
+    }
+  }
+
+  ///
+  /// Make temporary sandboxes under `sandbox_base`, rather than under the system temp dir. Files
+  /// can only be linked from a FileCache into sandboxes on the same device as it, so this should
+  /// be a directory next to the cache.
+  ///
+  pub fn with_sandbox_base(self, sandbox_base: PathBuf) -> CommandRunner {
+    CommandRunner {
+      sandbox_base: Some(sandbox_base),
+      ..self
+    }
+  }
+
+  ///
+  /// Make a CommandRunner which keeps the working directories of finished processes, and reuses
+  /// them for later processes by syncing them to the new inputs rather than materializing every
+  /// input file again. The workdirs live in a fresh directory under `workdir_base`, which is
+  /// locked while the CommandRunner is in use, and removed when it is dropped. Any such
+  /// directories which are no longer locked (i.e. whose processes were killed) are removed when a
+  /// CommandRunner is created, so CommandRunners in concurrent processes may share a
+  /// `workdir_base`.
+  ///
+  /// A workdir in which a process modified its inputs, wrote undeclared files, or wrote outputs
+  /// which overlap its inputs, is discarded rather than reused.
+  ///
+  /// Kept sandboxes are moved out of the pool, into `workdir_base` itself.
+  ///
+  pub fn with_persistent_workdirs(
+    store: fs::Store,
+    fs_pool: Arc<fs::ResettablePool>,
+    keep_sandboxes: KeepSandboxes,
+    workdir_base: PathBuf,
+  ) -> Result<CommandRunner, String> {
+    fs::safe_create_dir_all_ioerror(&workdir_base)
+      .map_err(|e| format!("Error making workdir base {:?}: {:?}", workdir_base, e))?;
+    WorkdirPool::remove_stale(&workdir_base)?;
+    let base = tempfile::Builder::new()
+      .prefix(WORKDIR_POOL_PREFIX)
+      .tempdir_in(&workdir_base)
+      .map(|tempdir| tempdir.into_path())
+      .map_err(|e| format!("Error making workdirs in {:?}: {:?}", workdir_base, e))?;
+    let lock = WorkdirPool::lock(&base)?;
+    Ok(CommandRunner {
+      store,
+      fs_pool,
+      workdirs: Some(Arc::new(WorkdirPool {
+        base,
+        idle: Mutex::new(Vec::new()),
+        _lock: lock,
+      })),
+      sandbox_base: None,
+      keep_sandboxes,
+    })
+  }
+
+  fn prepare_workdir(&self, input_files: Digest) -> BoxFuture<Workdir, String> {
+    match self.workdirs {
+      Some(ref workdirs) => WorkdirPool::acquire(workdirs, self.store.clone(), input_files)
+        .map(Workdir::Persistent)
+        .to_boxed(),
+      None => {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("process-execution");
+        let workdir = try_future!(
+          match self.sandbox_base {
+            Some(ref sandbox_base) => builder.tempdir_in(sandbox_base),
+            None => builder.tempdir(),
+          }.map_err(|err| format!(
+            "Error making tempdir for local process execution: {:?}",
+            err
+          ))
+        );
+        self
+          .store
+          .materialize_directory(workdir.path().to_owned(), input_files)
+          .map(move |()| Workdir::Temporary(workdir))
+          .to_boxed()
+      }
+    }
+  }
+
+  ///
+  /// Writes a script into a kept sandbox which re-runs the process with the same argv and env.
+  ///
+  fn write_run_script(
+    sandbox: &Path,
+    argv: &[String],
+    env: &BTreeMap<String, String>,
+  ) -> Result<(), String> {
+    let mut env_args = vec!["PATH=".to_owned()];
+    env_args.extend(
+      env
+        .iter()
+        .map(|(key, value)| shell_quote(&format!("{}={}", key, value))),
+    );
+    let script = format!(
+      "#!/bin/bash\n\
+       # Re-runs the process which was executed in this sandbox.\n\
+       cd {}\n\
+       env -i {} {}\n",
+      shell_quote(&sandbox.to_string_lossy()),
+      env_args.join(" "),
+      argv
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+    );
+    let path = sandbox.join("__run.sh");
+    OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(true)
+      .mode(0o755)
+      .open(&path)
+      .and_then(|mut f| f.write_all(script.as_bytes()))
+      .map_err(|e| format!("Error writing {:?}: {:?}", path, e))
+  }
+
+  ///
+  /// Captures the requested outputs of a process which ran in `workdir` as a Snapshot.
+  ///
+  fn capture_outputs(
+    store: Store,
+    fs_pool: Arc<fs::ResettablePool>,
+    workdir: &Path,
+    output_file_paths: BTreeSet<PathBuf>,
+    output_dir_paths: BTreeSet<PathBuf>,
+  ) -> BoxFuture<Snapshot, String> {
+    if output_file_paths.is_empty() && output_dir_paths.is_empty() {
+      return future::ok(fs::Snapshot::empty()).to_boxed();
+    }
+    // Use no ignore patterns, because we are looking for explicitly listed paths.
+    let posix_fs = try_future!(fs::PosixFS::new(workdir, fs_pool, vec![]).map_err(|err| {
+      format!(
+        "Error making posix_fs to fetch local process execution output files: {}",
+        err
+      )
+    }));
+    CommandRunner::construct_output_snapshot(
+      store,
+      Arc::new(posix_fs),
+      output_file_paths,
+      output_dir_paths,
+    )
+  }
+
+  ///
+  /// Keeps the workdir of a finished process if its result calls for it, and otherwise releases
+  /// it. Processes which could not be run, or whose outputs could not be captured, count as
+  /// failed: the path of a sandbox kept for one of them is added to the error.
+  ///
+  fn finish_workdir(
+    workdir: Workdir,
+    result: Result<FallibleExecuteProcessResult, String>,
+    keep_sandboxes: KeepSandboxes,
+    argv: &[String],
+    env: &BTreeMap<String, String>,
+    store: &Store,
+    output_paths: Vec<PathBuf>,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let succeeded = match result {
+      Ok(ref result) => result.exit_code == 0,
+      Err(_) => false,
+    };
+    if keep_sandboxes.should_keep(succeeded) {
+      let kept = workdir.keep().and_then(|sandbox| {
+        CommandRunner::write_run_script(&sandbox, argv, env)?;
+        if succeeded {
+          info!("Kept the sandbox of process {:?} at {:?}", argv, sandbox);
+        } else {
+          warn!(
+            "Kept the sandbox of failed process {:?} at {:?}. It can be re-run with {:?}",
+            argv,
+            sandbox,
+            sandbox.join("__run.sh")
+          );
+        }
+        Ok(sandbox)
+      });
+      let result = match (kept, result) {
+        (Ok(sandbox), Ok(result)) => Ok(FallibleExecuteProcessResult {
+          kept_sandbox: Some(sandbox),
+          ..result
+        }),
+        (Ok(sandbox), Err(err)) => Err(format!(
+          "{} (the sandbox was kept at {:?}, and can be re-run with {:?})",
+          err,
+          sandbox,
+          sandbox.join("__run.sh")
+        )),
+        (Err(err), _) => Err(err),
+      };
+      return future::done(result).to_boxed();
+    }
+    match result {
+      Ok(result) => workdir
+        .finish(store, output_paths)
+        .map(move |()| result)
+        .to_boxed(),
+      // A workdir which is dropped without being finished is deleted (or discarded).
+      Err(err) => future::err(err).to_boxed(),
+    }
   }
 
+  ///
+  /// Captures the requested outputs of a process without following symlinks, so that symlinked
+  /// layouts (and empty directories) round-trip through the Store exactly as the process left them.
+  ///
   fn construct_output_snapshot(
     store: Store,
     posix_fs: Arc<fs::PosixFS>,
     output_file_paths: BTreeSet<PathBuf>,
     output_dir_paths: BTreeSet<PathBuf>,
   ) -> BoxFuture<Snapshot, String> {
-    let output_dirs_glob_strings: Result<Vec<String>, String> = output_dir_paths
-      .into_iter()
-      .map(|p| {
-        p.into_os_string()
-          .into_string()
-          .map_err(|e| format!("Error stringifying output_directories: {:?}", e))
-          .map(|s| format!("{}/**", s))
-      })
-      .collect();
-
-    let output_dirs_future = posix_fs
-      .expand(try_future!(PathGlobs::create(
-        &try_future!(output_dirs_glob_strings),
-        &[],
-        StrictGlobMatching::Ignore,
-      )))
-      .map_err(|e| format!("Error stating output dirs: {}", e));
+    let output_files_future = future::join_all(
+      output_file_paths
+        .into_iter()
+        .map(|path| {
+          posix_fs
+            .walk_preserving_links(path)
+            .map(|maybe_path_stats| {
+              // Output files which turned out to be directories are ignored.
+              maybe_path_stats
+                .into_iter()
+                .flat_map(|path_stats| path_stats.into_iter())
+                .filter(|path_stat| match path_stat {
+                  &PathStat::Dir { .. } => false,
+                  &PathStat::File { .. } | &PathStat::Link { .. } => true,
+                })
+                .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>(),
+    ).map_err(|e| format!("Error stating output files: {}", e));
 
-    let output_files_future = posix_fs
-      .path_stats(output_file_paths.into_iter().collect())
-      .map_err(|e| format!("Error stating output files: {}", e));
+    let output_dirs_future = future::join_all(
+      output_dir_paths
+        .into_iter()
+        .map(|path| posix_fs.walk_preserving_links(path))
+        .collect::<Vec<_>>(),
+    ).map_err(|e| format!("Error stating output dirs: {}", e));
 
     output_files_future
       .join(output_dirs_future)
       .and_then(|(output_files_stats, output_dirs_stats)| {
         let paths: Vec<_> = output_files_stats
           .into_iter()
-          .chain(output_dirs_stats.into_iter().map(|p| Some(p)))
+          .flat_map(|path_stats| path_stats.into_iter())
+          .chain(
+            output_dirs_stats
+              .into_iter()
+              .flat_map(|maybe_path_stats| maybe_path_stats.into_iter())
+              .flat_map(|path_stats| path_stats.into_iter()),
+          )
           .collect();
 
         fs::Snapshot::from_path_stats(
           store.clone(),
           fs::OneOffStoreFileByDigest::new(store, posix_fs),
-          paths.into_iter().filter_map(|v| v).collect(),
+          paths,
         )
       })
       .to_boxed()
@@ -75,79 +354,60 @@ impl super::CommandRunner for CommandRunner {
   /// Runs a command on this machine in the passed working directory.
   ///
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let workdir = try_future!(
-      tempfile::Builder::new()
-        .prefix("process-execution")
-        .tempdir()
-        .map_err(|err| format!(
-          "Error making tempdir for local process execution: {:?}",
-          err
-        ))
-    );
-
     let store = self.store.clone();
+    let workdir_store = self.store.clone();
     let fs_pool = self.fs_pool.clone();
     let env = req.env;
     let output_file_paths = req.output_files;
     let output_dir_paths = req.output_directories;
+    let output_paths: Vec<PathBuf> = output_file_paths
+      .iter()
+      .chain(output_dir_paths.iter())
+      .cloned()
+      .collect();
     let argv = req.argv;
+    let keep_sandboxes = self.keep_sandboxes;
     self
-      .store
-      .materialize_directory(workdir.path().to_owned(), req.input_files)
-      .and_then(move |()| {
-        Command::new(&argv[0])
+      .prepare_workdir(req.input_files)
+      .and_then(move |workdir| {
+        let workdir_path = workdir.path().to_owned();
+        let result = Command::new(&argv[0])
                   .args(&argv[1..])
                   .current_dir(workdir.path())
                   .env_clear()
                   // It would be really nice not to have to manually set PATH but this is sadly the only way
                   // to stop automatic PATH searching.
                   .env("PATH", "")
-                  .envs(env)
+                  .envs(&env)
                   .output_async()
                   .map_err(|e| format!("Error executing process: {:?}", e))
-                  .map(|output| (output, workdir))
-      })
-      .and_then(|(output, workdir)| {
-        let output_snapshot = if output_file_paths.is_empty() && output_dir_paths.is_empty() {
-          future::ok(fs::Snapshot::empty()).to_boxed()
-        } else {
-          // Use no ignore patterns, because we are looking for explicitly listed paths.
-          future::done(
-            fs::PosixFS::new(
-              workdir.path(),
-              fs_pool,
-              vec![],
-            )
+                  .and_then(move |output| {
+                    CommandRunner::capture_outputs(
+                      store,
+                      fs_pool,
+                      &workdir_path,
+                      output_file_paths,
+                      output_dir_paths,
+                    ).map(move |snapshot| FallibleExecuteProcessResult {
+                      stdout: Bytes::from(output.stdout),
+                      stderr: Bytes::from(output.stderr),
+                      exit_code: output.status.code().unwrap(),
+                      output_directory: snapshot.digest,
+                      kept_sandbox: None,
+                    })
+                  });
+        // Force workdir not to get dropped (or reused) until after we've ingested the outputs.
+        result.then(move |result| {
+          CommandRunner::finish_workdir(
+            workdir,
+            result,
+            keep_sandboxes,
+            &argv,
+            &env,
+            &workdir_store,
+            output_paths,
           )
-          .map_err(|err| {
-            format!(
-              "Error making posix_fs to fetch local process execution output files: {}",
-              err
-            )
-          })
-          .map(|posix_fs| Arc::new(posix_fs))
-          .and_then(|posix_fs| {
-            CommandRunner::construct_output_snapshot(
-              store,
-              posix_fs,
-              output_file_paths,
-              output_dir_paths
-            )
-          })
-          // Force workdir not to get dropped until after we've ingested the outputs
-          .map(|result| (result, workdir))
-          .map(|(result, _workdir)| result)
-          .to_boxed()
-        };
-
-        output_snapshot
-          .map(|snapshot| FallibleExecuteProcessResult {
-            stdout: Bytes::from(output.stdout),
-            stderr: Bytes::from(output.stderr),
-            exit_code: output.status.code().unwrap(),
-            output_directory: snapshot.digest,
-          })
-          .to_boxed()
+        })
       })
       .to_boxed()
   }
@@ -158,6 +418,465 @@ impl super::CommandRunner for CommandRunner {
   }
 }
 
+///
+/// Quotes a string for use as a single word in a bash script.
+///
+fn shell_quote(s: &str) -> String {
+  format!("'{}'", s.replace("'", "'\\''"))
+}
+
+///
+/// The directory a local process runs in.
+///
+enum Workdir {
+  // Deleted when dropped.
+  Temporary(tempfile::TempDir),
+  Persistent(PersistentWorkdir),
+}
+
+impl Workdir {
+  fn path(&self) -> &Path {
+    match *self {
+      Workdir::Temporary(ref tempdir) => tempdir.path(),
+      Workdir::Persistent(ref workdir) => &workdir.path,
+    }
+  }
+
+  ///
+  /// Called once a process has finished and its outputs have been captured.
+  ///
+  fn finish(self, store: &Store, output_paths: Vec<PathBuf>) -> BoxFuture<(), String> {
+    match self {
+      Workdir::Temporary(_) => future::ok(()).to_boxed(),
+      Workdir::Persistent(workdir) => workdir.release(store, output_paths),
+    }
+  }
+
+  ///
+  /// Called instead of `finish` to keep the workdir on disk, rather than deleting or reusing it.
+  ///
+  fn keep(self) -> Result<PathBuf, String> {
+    match self {
+      Workdir::Temporary(tempdir) => Ok(tempdir.into_path()),
+      Workdir::Persistent(workdir) => workdir.detach(),
+    }
+  }
+}
+
+///
+/// A pool of working directories which outlive the processes run in them. Each idle workdir is
+/// tagged with the digest of the inputs it currently contains, so that it can be synced to the
+/// inputs of the next process by touching only the entries which differ.
+///
+struct WorkdirPool {
+  base: PathBuf,
+  idle: Mutex<Vec<(PathBuf, Digest)>>,
+  // Held (and locked) for as long as the pool is in use, to show that it isn't stale.
+  _lock: File,
+}
+
+impl WorkdirPool {
+  fn acquire(
+    workdirs: &Arc<WorkdirPool>,
+    store: Store,
+    input_files: Digest,
+  ) -> BoxFuture<PersistentWorkdir, String> {
+    let reusable = {
+      let mut idle = workdirs.idle.lock().unwrap();
+      // Prefer a workdir which already contains exactly these inputs, and otherwise whichever was
+      // most recently released.
+      let index = idle
+        .iter()
+        .position(|&(_, digest)| digest == input_files)
+        .or_else(|| idle.len().checked_sub(1));
+      index.map(|index| idle.remove(index))
+    };
+    let (path, sync) = match reusable {
+      Some((path, current)) => {
+        let sync = store.sync_directory(path.clone(), current, input_files);
+        (path, sync)
+      }
+      None => {
+        let path = try_future!(
+          tempfile::Builder::new()
+            .prefix("process-execution")
+            .tempdir_in(&workdirs.base)
+            .map(|tempdir| tempdir.into_path())
+            .map_err(|err| format!(
+              "Error making workdir in {:?} for local process execution: {:?}",
+              workdirs.base, err
+            ))
+        );
+        let sync = store.materialize_directory(path.clone(), input_files);
+        (path, sync)
+      }
+    };
+    // From here on the workdir is owned by a PersistentWorkdir, which discards it on failure.
+    let mut workdir = PersistentWorkdir {
+      path,
+      input_files,
+      stamps: BTreeMap::new(),
+      stamped_at: UNIX_EPOCH,
+      workdirs: workdirs.clone(),
+      released: false,
+    };
+    sync
+      .and_then(move |()| {
+        workdir.stamped_at = SystemTime::now();
+        stamp_paths(&workdir.path, Path::new(""), &mut workdir.stamps)
+          .map_err(|e| format!("Error listing workdir {:?}: {:?}", workdir.path, e))?;
+        Ok(workdir)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Creates the lock file of the pool at `base`, and locks it. The file is locked before it is
+  /// moved into place, so that the pool is never mistaken for a stale one.
+  ///
+  fn lock(base: &Path) -> Result<File, String> {
+    let lock_path = base.join(WORKDIR_POOL_LOCK);
+    let temp_lock = tempfile::NamedTempFile::new_in(base)
+      .map_err(|e| format!("Error creating lock file in {:?}: {:?}", base, e))?;
+    match try_lock(temp_lock.as_file()) {
+      Ok(true) => (),
+      Ok(false) => return Err(format!("Lock file {:?} was already locked", temp_lock.path())),
+      Err(e) => return Err(format!("Error locking {:?}: {:?}", temp_lock.path(), e)),
+    }
+    temp_lock
+      .persist(&lock_path)
+      .map_err(|e| format!("Error persisting lock file {:?}: {:?}", lock_path, e))
+  }
+
+  ///
+  /// Removes the pools under `workdir_base` which were left behind by CommandRunners which were
+  /// never dropped: i.e., those whose lock files are no longer locked. Pools without a lock file
+  /// can't be proven to be stale, so they are left alone.
+  ///
+  fn remove_stale(workdir_base: &Path) -> Result<(), String> {
+    let entries = ::std::fs::read_dir(workdir_base)
+      .map_err(|e| format!("Error listing workdir base {:?}: {:?}", workdir_base, e))?;
+    for entry in entries {
+      let entry =
+        entry.map_err(|e| format!("Error listing workdir base {:?}: {:?}", workdir_base, e))?;
+      if !entry.file_name().to_string_lossy().starts_with(WORKDIR_POOL_PREFIX) {
+        continue;
+      }
+      let lock_path = entry.path().join(WORKDIR_POOL_LOCK);
+      let lock = match File::open(&lock_path) {
+        Ok(lock) => lock,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(format!("Error opening lock file {:?}: {:?}", lock_path, e)),
+      };
+      // Holding the lock for the removal ensures that no other process removes the pool too.
+      match try_lock(&lock) {
+        Ok(true) => (),
+        Ok(false) => continue,
+        Err(e) => return Err(format!("Error locking {:?}: {:?}", lock_path, e)),
+      }
+      match ::std::fs::remove_dir_all(entry.path()) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => {
+          return Err(format!(
+            "Error removing stale workdirs {:?}: {:?}",
+            entry.path(),
+            e
+          ))
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+///
+/// Takes an exclusive lock on the given file without blocking, returning false if another open
+/// file (in this process or any other) already holds it. The lock is released when the file is
+/// closed, including when its process dies.
+///
+fn try_lock(file: &File) -> io::Result<bool> {
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+    return Ok(true);
+  }
+  let err = io::Error::last_os_error();
+  if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+    Ok(false)
+  } else {
+    Err(err)
+  }
+}
+
+impl Drop for WorkdirPool {
+  fn drop(&mut self) {
+    let _ = ::std::fs::remove_dir_all(&self.base);
+  }
+}
+
+struct PersistentWorkdir {
+  path: PathBuf,
+  input_files: Digest,
+  // The stamps of the inputs once they were synced, to detect whether the process modified them.
+  stamps: BTreeMap<PathBuf, PathStamp>,
+  // When the stamps were taken.
+  stamped_at: SystemTime,
+  workdirs: Arc<WorkdirPool>,
+  released: bool,
+}
+
+impl PersistentWorkdir {
+  ///
+  /// Removes the outputs of the process which ran in this workdir, and returns it to the pool if
+  /// it then contains exactly its inputs again, as they were synced. Otherwise (if the process
+  /// modified its inputs, wrote undeclared files, or wrote outputs which replaced or are nested
+  /// inside its inputs) the workdir is discarded, because it can't be tagged with the digest of
+  /// its inputs.
+  ///
+  fn release(self, store: &Store, output_paths: Vec<PathBuf>) -> BoxFuture<(), String> {
+    input_paths(store.clone(), self.input_files, PathBuf::new())
+      .and_then(move |inputs| -> Result<(), String> {
+        let overlaps_inputs = output_paths.iter().any(|output_path| {
+          inputs.iter().any(|(input_path, input)| {
+            input_path.starts_with(output_path)
+              || (*input != InputPath::Dir && output_path.starts_with(input_path))
+          })
+        });
+        if overlaps_inputs {
+          return Ok(());
+        }
+        self.remove_outputs(&output_paths, &inputs)?;
+        let mut stamps = BTreeMap::new();
+        stamp_paths(&self.path, Path::new(""), &mut stamps)
+          .map_err(|e| format!("Error listing workdir {:?}: {:?}", self.path, e))?;
+        if self.inputs_unchanged(&stamps, &inputs) {
+          self.return_to_pool();
+        }
+        Ok(())
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Whether the current `stamps` of this workdir show that it contains exactly its inputs, as
+  /// they were synced. An equal stamp can't rule out that a path which was synced shortly before
+  /// it was stamped was rewritten within the same mtime tick, so the content of such (racily
+  /// stamped) paths is compared to the inputs instead.
+  ///
+  fn inputs_unchanged(
+    &self,
+    stamps: &BTreeMap<PathBuf, PathStamp>,
+    inputs: &BTreeMap<PathBuf, InputPath>,
+  ) -> bool {
+    if !stamps.keys().eq(inputs.keys()) || *stamps != self.stamps {
+      return false;
+    }
+    stamps
+      .iter()
+      .filter(|&(_, stamp)| stamp.is_racy(self.stamped_at))
+      .all(|(path, _)| {
+        let path_in_workdir = self.path.join(path);
+        match inputs[path] {
+          InputPath::Dir => true,
+          InputPath::File(digest) => digest_file(&path_in_workdir)
+            .map(|actual| actual == digest)
+            .unwrap_or(false),
+          InputPath::Symlink(ref target) => ::std::fs::read_link(&path_in_workdir)
+            .map(|actual| actual == *target)
+            .unwrap_or(false),
+        }
+      })
+  }
+
+  fn remove_outputs(
+    &self,
+    output_paths: &[PathBuf],
+    inputs: &BTreeMap<PathBuf, InputPath>,
+  ) -> Result<(), String> {
+    for output_path in output_paths {
+      let path = self.path.join(output_path);
+      let removed = match ::std::fs::symlink_metadata(&path) {
+        Ok(ref metadata) if metadata.is_dir() => ::std::fs::remove_dir_all(&path),
+        Ok(_) => ::std::fs::remove_file(&path),
+        Err(_) => Ok(()),
+      };
+      removed.map_err(|e| format!("Error removing output {:?} from workdir: {:?}", path, e))?;
+      // Also remove any (now empty) parent directories which the process created for the output.
+      let mut parent = output_path.parent();
+      while let Some(dir) = parent {
+        if dir == Path::new("") || inputs.contains_key(dir) {
+          break;
+        }
+        if ::std::fs::remove_dir(self.path.join(dir)).is_err() {
+          break;
+        }
+        parent = dir.parent();
+      }
+    }
+    Ok(())
+  }
+
+  fn return_to_pool(mut self) {
+    self
+      .workdirs
+      .idle
+      .lock()
+      .unwrap()
+      .push((self.path.clone(), self.input_files));
+    self.released = true;
+  }
+
+  ///
+  /// Moves this workdir out of the pool (whose directory is deleted when the pool is dropped), so
+  /// that it is never reused or deleted.
+  ///
+  fn detach(mut self) -> Result<PathBuf, String> {
+    let destination = {
+      let pool_base = &self.workdirs.base;
+      let parent = pool_base
+        .parent()
+        .ok_or_else(|| format!("Workdir pool {:?} has no parent directory", pool_base))?;
+      parent.join(self.path.file_name().unwrap())
+    };
+    ::std::fs::rename(&self.path, &destination).map_err(|e| {
+      format!(
+        "Error moving workdir {:?} to {:?}: {:?}",
+        self.path, destination, e
+      )
+    })?;
+    self.released = true;
+    Ok(destination)
+  }
+}
+
+impl Drop for PersistentWorkdir {
+  fn drop(&mut self) {
+    // A workdir which was not released is in an unknown state, so it can't be reused.
+    if !self.released {
+      let _ = ::std::fs::remove_dir_all(&self.path);
+    }
+  }
+}
+
+///
+/// An entry of the inputs of a process.
+///
+#[derive(Debug, Eq, PartialEq)]
+enum InputPath {
+  Dir,
+  File(Digest),
+  Symlink(PathBuf),
+}
+
+///
+/// Lists every path in the Directory with the given digest.
+///
+fn input_paths(
+  store: Store,
+  digest: Digest,
+  prefix: PathBuf,
+) -> BoxFuture<BTreeMap<PathBuf, InputPath>, String> {
+  store
+    .load_directory(digest)
+    .and_then(move |maybe_directory| {
+      let directory = try_future!(
+        maybe_directory.ok_or_else(|| format!("Could not find directory with digest {:?}", digest))
+      );
+      let mut paths = BTreeMap::new();
+      for file_node in directory.get_files() {
+        let digest: Result<Digest, String> = file_node.get_digest().into();
+        paths.insert(
+          prefix.join(file_node.get_name()),
+          InputPath::File(try_future!(digest)),
+        );
+      }
+      for symlink_node in directory.get_symlinks() {
+        paths.insert(
+          prefix.join(symlink_node.get_name()),
+          InputPath::Symlink(PathBuf::from(symlink_node.get_target())),
+        );
+      }
+      let mut subdirectory_futures = vec![];
+      for dir_node in directory.get_directories() {
+        let path = prefix.join(dir_node.get_name());
+        let digest: Result<Digest, String> = dir_node.get_digest().into();
+        paths.insert(path.clone(), InputPath::Dir);
+        subdirectory_futures.push(input_paths(store.clone(), try_future!(digest), path));
+      }
+      future::join_all(subdirectory_futures)
+        .map(move |subdirectory_paths| {
+          for subdirectory_path in subdirectory_paths {
+            paths.extend(subdirectory_path);
+          }
+          paths
+        })
+        .to_boxed()
+    })
+    .to_boxed()
+}
+
+///
+/// The metadata of a path in a workdir which changes if a process modifies it.
+///
+#[derive(Debug, Eq, PartialEq)]
+struct PathStamp {
+  mode: u32,
+  // The size and mtime of files and symlinks. Not recorded for directories, whose mtimes change
+  // as outputs are written into and removed from them.
+  contents: Option<(u64, i64, i64)>,
+}
+
+impl PathStamp {
+  ///
+  /// Whether this stamp might equal that of a different file which was written in the same mtime
+  /// tick, after the stamp was taken (at `stamped_at`).
+  ///
+  fn is_racy(&self, stamped_at: SystemTime) -> bool {
+    match self.contents {
+      Some((_, mtime, mtime_nsec)) if mtime >= 0 => {
+        let mtime = UNIX_EPOCH + Duration::new(mtime as u64, mtime_nsec as u32);
+        mtime + Duration::from_secs(MTIME_GRANULARITY_SECS) > stamped_at
+      }
+      _ => false,
+    }
+  }
+}
+
+fn digest_file(path: &Path) -> io::Result<Digest> {
+  let mut hasher = WriterHasher::new(io::sink());
+  let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+  Ok(Digest(hasher.finish(), size as usize))
+}
+
+///
+/// Adds every path under `root.join(dir)` to `stamps`, relative to `root` and without following
+/// symlinks.
+///
+fn stamp_paths(
+  root: &Path,
+  dir: &Path,
+  stamps: &mut BTreeMap<PathBuf, PathStamp>,
+) -> io::Result<()> {
+  for entry in ::std::fs::read_dir(root.join(dir))? {
+    let entry = entry?;
+    let path = dir.join(entry.file_name());
+    let metadata = ::std::fs::symlink_metadata(root.join(&path))?;
+    let contents = if metadata.is_dir() {
+      stamp_paths(root, &path, stamps)?;
+      None
+    } else {
+      Some((metadata.size(), metadata.mtime(), metadata.mtime_nsec()))
+    };
+    stamps.insert(
+      path,
+      PathStamp {
+        mode: metadata.mode(),
+        contents,
+      },
+    );
+  }
+  Ok(())
+}
+
 #[cfg(test)]
 mod tests {
   extern crate tempfile;
@@ -198,6 +917,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       }
     )
   }
@@ -222,6 +942,7 @@ mod tests {
         stderr: as_bytes("bar"),
         exit_code: 1,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       }
     )
   }
@@ -320,6 +1041,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       }
     )
   }
@@ -347,6 +1069,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
+        kept_sandbox: None,
       }
     )
   }
@@ -379,6 +1102,40 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
+        kept_sandbox: None,
+      }
+    )
+  }
+
+  #[test]
+  fn output_symlinks_and_empty_dirs() {
+    let result = run_command_locally_in_dir(ExecuteProcessRequest {
+      argv: vec![
+        find_bash(),
+        "-c".to_owned(),
+        format!(
+          "/bin/mkdir cats dogs && echo -n {} > cats/roland && /bin/ln -s cats/roland roland",
+          TestData::roland().string(),
+        ),
+      ],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: vec![PathBuf::from("roland")].into_iter().collect(),
+      output_directories: vec![PathBuf::from("cats"), PathBuf::from("dogs")]
+        .into_iter()
+        .collect(),
+      timeout: Duration::from_millis(1000),
+      description: "bash".to_string(),
+    });
+
+    assert_eq!(
+      result.unwrap(),
+      FallibleExecuteProcessResult {
+        stdout: as_bytes(""),
+        stderr: as_bytes(""),
+        exit_code: 0,
+        output_directory: TestDirectory::with_symlink_and_empty_directory().digest(),
+        kept_sandbox: None,
       }
     )
   }
@@ -412,6 +1169,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
+        kept_sandbox: None,
       }
     )
   }
@@ -443,6 +1201,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 1,
         output_directory: TestDirectory::containing_roland().digest(),
+        kept_sandbox: None,
       }
     )
   }
@@ -472,10 +1231,344 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
+        kept_sandbox: None,
       }
     )
   }
 
+  #[test]
+  fn persistent_workdirs_are_synced_between_runs() {
+    let store_dir = TempDir::new().unwrap();
+    let workdir_base = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    for directory in vec![
+      TestDirectory::containing_roland(),
+      TestDirectory::recursive(),
+      TestDirectory::containing_roland_and_treats(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .expect("Error saving Directory");
+    }
+    for file in vec![TestData::roland(), TestData::catnip()] {
+      store
+        .store_file_bytes(file.bytes(), false)
+        .wait()
+        .expect("Error saving file bytes");
+    }
+    let runner = super::CommandRunner::with_persistent_workdirs(
+      store,
+      pool,
+      super::KeepSandboxes::Never,
+      workdir_base.path().join("workdirs"),
+    ).unwrap();
+
+    let first = runner
+      .run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "echo -n * && echo -n foo > out".to_owned(),
+        ],
+        env: BTreeMap::new(),
+        input_files: TestDirectory::recursive().digest(),
+        output_files: vec![PathBuf::from("out")].into_iter().collect(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "list inputs".to_string(),
+      })
+      .wait()
+      .unwrap();
+    assert_eq!(first.stdout, as_bytes("cats treats"));
+
+    // The second process sees its own inputs, and not the outputs of the first.
+    let second = runner
+      .run(ExecuteProcessRequest {
+        argv: vec![find_bash(), "-c".to_owned(), "echo -n *".to_owned()],
+        env: BTreeMap::new(),
+        input_files: TestDirectory::containing_roland_and_treats().digest(),
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "list inputs".to_string(),
+      })
+      .wait()
+      .unwrap();
+    assert_eq!(second.stdout, as_bytes("roland treats"));
+
+    let bases = std::fs::read_dir(workdir_base.path().join("workdirs"))
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .collect::<Vec<_>>();
+    assert_eq!(bases.len(), 1);
+    let workdirs = std::fs::read_dir(&bases[0])
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .filter(|name| name.to_string_lossy() != super::WORKDIR_POOL_LOCK)
+      .count();
+    assert_eq!(workdirs, 1);
+  }
+
+  #[test]
+  fn persistent_workdirs_with_undeclared_files_are_discarded() {
+    let store_dir = TempDir::new().unwrap();
+    let workdir_base = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+    let runner = super::CommandRunner::with_persistent_workdirs(
+      store,
+      pool,
+      super::KeepSandboxes::Never,
+      workdir_base.path().to_owned(),
+    ).unwrap();
+
+    let run = |script: &str, output_file: &str| {
+      runner
+        .run(ExecuteProcessRequest {
+          argv: vec![find_bash(), "-c".to_owned(), script.to_owned()],
+          env: BTreeMap::new(),
+          input_files: TestDirectory::containing_roland().digest(),
+          output_files: vec![PathBuf::from(output_file)].into_iter().collect(),
+          output_directories: BTreeSet::new(),
+          timeout: Duration::from_millis(1000),
+          description: "write files".to_string(),
+        })
+        .wait()
+        .unwrap()
+    };
+
+    // Declared outputs (and the directories created for them) are removed from the workdir, but
+    // an undeclared file means the workdir can't be reused.
+    run(
+      "mkdir out && echo -n foo > out/file && echo -n bar > stray",
+      "out/file",
+    );
+    let second = run("echo -n * && echo -n foo > roland", "roland");
+    assert_eq!(second.stdout, as_bytes("roland"));
+
+    // An output which overwrote an input discards the workdir too.
+    let third = run("cat roland", "out/file");
+    assert_eq!(third.stdout, TestData::roland().bytes());
+  }
+
+  #[test]
+  fn persistent_workdirs_with_modified_inputs_are_discarded() {
+    let store_dir = TempDir::new().unwrap();
+    let workdir_base = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+    let runner = super::CommandRunner::with_persistent_workdirs(
+      store,
+      pool,
+      super::KeepSandboxes::Never,
+      workdir_base.path().to_owned(),
+    ).unwrap();
+
+    let run = |script: &str| {
+      runner
+        .run(ExecuteProcessRequest {
+          argv: vec![find_bash(), "-c".to_owned(), script.to_owned()],
+          env: BTreeMap::new(),
+          input_files: TestDirectory::containing_roland().digest(),
+          output_files: BTreeSet::new(),
+          output_directories: BTreeSet::new(),
+          timeout: Duration::from_millis(1000),
+          description: "modify inputs".to_string(),
+        })
+        .wait()
+        .unwrap()
+    };
+
+    // Later processes see the original inputs, whether they were rewritten or chmod'd in place.
+    run("echo -n European > roland");
+    // Even a rewrite which preserves the size and mtime of a freshly synced input is detected.
+    run(
+      "touch -r roland ../reference && echo -n 'Scottish Burmese' > roland \
+       && touch -r ../reference roland && rm ../reference",
+    );
+    let second = run("cat roland && chmod +x roland");
+    assert_eq!(second.stdout, TestData::roland().bytes());
+    let third = run("test -x roland && echo -n executable");
+    assert_eq!(third.stdout, as_bytes(""));
+  }
+
+  #[test]
+  fn stale_workdir_pools_are_removed() {
+    let store_dir = TempDir::new().unwrap();
+    let workdir_base = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let new_runner = || {
+      super::CommandRunner::with_persistent_workdirs(
+        store.clone(),
+        pool.clone(),
+        super::KeepSandboxes::Never,
+        workdir_base.path().to_owned(),
+      ).unwrap()
+    };
+    let pools = || {
+      std::fs::read_dir(workdir_base.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<BTreeSet<_>>()
+    };
+
+    // As if left behind by a process which was killed: its lock file is no longer locked.
+    let stale = workdir_base.path().join("process-workdirs-stale");
+    std::fs::create_dir_all(stale.join("process-execution-workdir")).unwrap();
+    std::fs::File::create(stale.join(".lock")).unwrap();
+    // As if being created by another process, which hasn't locked it yet.
+    let unlocked = workdir_base.path().join("process-workdirs-unlocked");
+    std::fs::create_dir(&unlocked).unwrap();
+
+    let first = new_runner();
+    let live = pools();
+    assert!(!live.contains(&stale));
+    assert!(live.contains(&unlocked));
+    assert_eq!(live.len(), 2);
+
+    // The pool of a CommandRunner which is still in use is locked, and so isn't removed.
+    let second = new_runner();
+    assert!(pools().is_superset(&live));
+    assert_eq!(pools().len(), 3);
+
+    drop(first);
+    drop(second);
+    assert_eq!(pools(), vec![unlocked].into_iter().collect());
+  }
+
+  #[test]
+  fn keep_sandbox_on_failure() {
+    let store_dir = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let runner = super::CommandRunner::new(store, pool, super::KeepSandboxes::OnFailure);
+
+    let mut env = BTreeMap::new();
+    env.insert("GREETING".to_owned(), "it's me".to_owned());
+    let result = runner
+      .run(ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "echo -n \"$GREETING\" && echo -n \"$PWD\" >&2 && touch leftover && exit 1".to_owned(),
+        ],
+        env: env,
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "fail".to_string(),
+      })
+      .wait()
+      .unwrap();
+    assert_eq!(result.exit_code, 1);
+    assert_eq!(result.stdout, as_bytes("it's me"));
+
+    // The process ran in the kept sandbox, and its run script reproduces the process.
+    let sandbox = PathBuf::from(String::from_utf8(result.stderr.to_vec()).unwrap());
+    assert_eq!(result.kept_sandbox, Some(sandbox.clone()));
+    // Where the sandbox was kept doesn't distinguish this result from that of another run.
+    assert_eq!(
+      result,
+      FallibleExecuteProcessResult {
+        kept_sandbox: None,
+        ..result.clone()
+      }
+    );
+    let run_script = sandbox.join("__run.sh");
+    assert!(sandbox.join("leftover").exists());
+    let rerun = std::process::Command::new(find_bash())
+      .arg(&run_script)
+      .output()
+      .unwrap();
+    std::fs::remove_dir_all(&sandbox).unwrap();
+    assert_eq!(rerun.status.code(), Some(1));
+    assert_eq!(rerun.stdout, b"it's me".to_vec());
+  }
+
+  #[test]
+  fn keep_sandbox_of_process_which_could_not_run() {
+    let store_dir = TempDir::new().unwrap();
+    let workdir_base = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let runner = super::CommandRunner::with_persistent_workdirs(
+      store,
+      pool,
+      super::KeepSandboxes::OnFailure,
+      workdir_base.path().to_owned(),
+    ).unwrap();
+
+    let err = runner
+      .run(ExecuteProcessRequest {
+        argv: owned_string_vec(&["echo", "-n", "foo"]),
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "echo foo".to_string(),
+      })
+      .wait()
+      .expect_err("Want Err");
+
+    let kept = std::fs::read_dir(workdir_base.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().path())
+      .filter(|path| path.join("__run.sh").exists())
+      .collect::<Vec<_>>();
+    assert_eq!(kept.len(), 1);
+    // The error reports where the sandbox was kept.
+    assert!(err.contains(&format!("{:?}", kept[0].join("__run.sh"))));
+  }
+
+  #[test]
+  fn temporary_sandboxes_in_sandbox_base() {
+    let store_dir = TempDir::new().unwrap();
+    let sandbox_base = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let runner = super::CommandRunner::new(store, pool, super::KeepSandboxes::Never)
+      .with_sandbox_base(sandbox_base.path().to_owned());
+
+    let result = runner
+      .run(ExecuteProcessRequest {
+        argv: vec![find_bash(), "-c".to_owned(), "echo -n \"$PWD\"".to_owned()],
+        env: BTreeMap::new(),
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout: Duration::from_millis(1000),
+        description: "pwd".to_string(),
+      })
+      .wait()
+      .unwrap();
+
+    let sandbox = PathBuf::from(String::from_utf8(result.stdout.to_vec()).unwrap());
+    assert_eq!(sandbox.parent(), Some(sandbox_base.path()));
+    // The sandbox is still deleted once the process has finished.
+    assert!(!sandbox.exists());
+  }
+
   fn run_command_locally(
     req: ExecuteProcessRequest,
   ) -> Result<FallibleExecuteProcessResult, String> {
@@ -488,10 +1581,7 @@ mod tests {
     let store_dir = TempDir::new().unwrap();
     let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
     let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
-    let runner = super::CommandRunner {
-      store: store,
-      fs_pool: pool,
-    };
+    let runner = super::CommandRunner::new(store, pool, super::KeepSandboxes::Never);
     runner.run(req).wait()
   }
 
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::iter;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -8,7 +9,7 @@ use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
 use bytes::Bytes;
 use digest::{Digest as DigestTrait, FixedOutput};
-use fs::{self, File, PathStat, Store};
+use fs::{self, File, Link, PathStat, Store};
 use futures::{future, Future};
 use futures_timer::Delay;
 use hashing::{Digest, Fingerprint};
@@ -26,7 +27,9 @@ pub struct CommandRunner {
   env: Resettable<Arc<grpcio::Environment>>,
   execution_client: Resettable<Arc<bazel_protos::remote_execution_grpc::ExecutionClient>>,
   operations_client: Resettable<Arc<bazel_protos::operations_grpc::OperationsClient>>,
+  action_cache_client: Resettable<Arc<bazel_protos::remote_execution_grpc::ActionCacheClient>>,
   store: Store,
+  skip_cache_lookup: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -58,109 +61,9 @@ impl super::CommandRunner for CommandRunner {
   /// timeout: polls in a tight loop.
   ///
   fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let execution_client = self.execution_client.clone();
-    let execution_client2 = execution_client.clone();
-    let operations_client = self.operations_client.clone();
-
-    let store = self.store.clone();
-    let execute_request_result = make_execute_request(&req);
-
-    let req_description = req.description;
-    let req_timeout = req.timeout;
-
-    match execute_request_result {
+    match make_execute_request(&req) {
       Ok((command, execute_request)) => {
-        let command_runner = self.clone();
-        let command_digest = try_future!(execute_request.get_action().get_command_digest().into());
-        self
-          .upload_command(&command, command_digest)
-          .and_then(move |_| {
-            debug!(
-              "Executing remotely request: {:?} (command: {:?})",
-              execute_request, command
-            );
-
-            map_grpc_result(execution_client.get().execute(&execute_request))
-              .map(|result| (Arc::new(execute_request), result))
-          })
-          .and_then(move |(execute_request, operation)| {
-            let start_time = Instant::now();
-
-            future::loop_fn((operation, 0), move |(operation, iter_num)| {
-              let req_description = req_description.clone();
-
-              let execute_request = execute_request.clone();
-              let execution_client2 = execution_client2.clone();
-              let store = store.clone();
-              let operations_client = operations_client.clone();
-              command_runner
-                .extract_execute_response(operation)
-                .map(|value| future::Loop::Break(value))
-                .or_else(move |value| {
-                  match value {
-                    ExecutionError::Fatal(err) => future::err(err).to_boxed(),
-                    ExecutionError::MissingDigests(missing_digests) => {
-                      debug!(
-                        "Server reported missing digests; trying to upload: {:?}",
-                        missing_digests
-                      );
-                      let execute_request = execute_request.clone();
-                      let execution_client2 = execution_client2.clone();
-                      store.ensure_remote_has_recursive(missing_digests)
-                              .and_then(move |()| {
-                                map_grpc_result(
-                                  execution_client2.get().execute(
-                                    &execute_request.clone()
-                                  )
-                                )
-                              })
-                              // Reset `iter_num` on `MissingDigests`
-                              .map(|operation| future::Loop::Continue((operation, 0)))
-                              .to_boxed()
-                    }
-                    ExecutionError::NotFinished(operation_name) => {
-                      let mut operation_request =
-                        bazel_protos::operations::GetOperationRequest::new();
-                      operation_request.set_name(operation_name.clone());
-
-                      let backoff_period = min(
-                        CommandRunner::BACKOFF_MAX_WAIT_MILLIS,
-                        (1 + iter_num) * CommandRunner::BACKOFF_INCR_WAIT_MILLIS,
-                      );
-
-                      // take the grpc result and cancel the op if too much time has passed.
-                      let elapsed = start_time.elapsed();
-
-                      if elapsed > req_timeout {
-                        future::err(format!(
-                          "Exceeded time out of {:?} with {:?} for operation {}, {}",
-                          req_timeout, elapsed, operation_name, req_description
-                        )).to_boxed()
-                      } else {
-                        // maybe the delay here should be the min of remaining time and the backoff period
-                        Delay::new(Duration::from_millis(backoff_period))
-                          .map_err(move |e| {
-                            format!(
-                              "Future-Delay errored at operation result polling for {}, {}: {}",
-                              operation_name, req_description, e
-                            )
-                          })
-                          .and_then(move |_| {
-                            future::done(map_grpc_result(
-                              operations_client.get().get_operation(&operation_request),
-                            )).map(move |operation| {
-                              future::Loop::Continue((operation, iter_num + 1))
-                            })
-                              .to_boxed()
-                          })
-                          .to_boxed()
-                      }
-                    }
-                  }
-                })
-            })
-          })
-          .to_boxed()
+        self.execute(command, execute_request, req.description, req.timeout)
       }
       Err(err) => future::err(err).to_boxed(),
     }
@@ -171,6 +74,7 @@ impl super::CommandRunner for CommandRunner {
     self.env.reset();
     self.execution_client.reset();
     self.operations_client.reset();
+    self.action_cache_client.reset();
   }
 }
 
@@ -185,6 +89,7 @@ impl CommandRunner {
       Resettable::new(move || grpcio::ChannelBuilder::new(env2.get()).connect(&address));
     let channel2 = channel.clone();
     let channel3 = channel.clone();
+    let channel4 = channel.clone();
     let execution_client = Resettable::new(move || {
       Arc::new(bazel_protos::remote_execution_grpc::ExecutionClient::new(
         channel2.get(),
@@ -195,16 +100,193 @@ impl CommandRunner {
         channel3.get(),
       ))
     });
+    let action_cache_client = Resettable::new(move || {
+      Arc::new(bazel_protos::remote_execution_grpc::ActionCacheClient::new(
+        channel4.get(),
+      ))
+    });
 
     CommandRunner {
       channel,
       env,
       execution_client,
       operations_client,
+      action_cache_client,
       store,
+      skip_cache_lookup: false,
     }
   }
 
+  ///
+  /// Make this CommandRunner ask the server to execute every request, even if it has a cached
+  /// result for it (e.g. to check whether an action is deterministic).
+  ///
+  pub fn skipping_cache_lookup(mut self) -> CommandRunner {
+    self.skip_cache_lookup = true;
+    self
+  }
+
+  ///
+  /// Fetches the cached ActionResult for the Action with the given digest from the server's
+  /// ActionCache, if there is one. Outputs of the result are fetched into the Store.
+  ///
+  pub fn get_cached_action_result(
+    &self,
+    action_digest: Digest,
+  ) -> BoxFuture<Option<FallibleExecuteProcessResult>, String> {
+    let mut request = bazel_protos::remote_execution::GetActionResultRequest::new();
+    request.set_action_digest((&action_digest).into());
+    let action_result = match self.action_cache_client.get().get_action_result(&request) {
+      Err(grpcio::Error::RpcFailure(ref status))
+        if status.status == grpcio::RpcStatusCode::NotFound =>
+      {
+        return future::ok(None).to_boxed()
+      }
+      result => try_future!(map_grpc_result(result)),
+    };
+    let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
+    execute_response.set_result(action_result);
+    let exit_code = execute_response.get_result().get_exit_code();
+    self
+      .extract_stdout(&execute_response)
+      .join(self.extract_stderr(&execute_response))
+      .join(self.extract_output_files(&execute_response))
+      .map(move |((stdout, stderr), output_directory)| {
+        Some(FallibleExecuteProcessResult {
+          stdout,
+          stderr,
+          exit_code,
+          output_directory,
+          kept_sandbox: None,
+        })
+      })
+      .map_err(|e| format!("Error extracting cached ActionResult: {:?}", e))
+      .to_boxed()
+  }
+
+  ///
+  /// Executes a stored Action (see `load_action`) exactly as it was stored, so that its digest,
+  /// platform properties and caching policy are preserved.
+  ///
+  pub fn run_action(
+    &self,
+    action: bazel_protos::remote_execution::Action,
+    command: bazel_protos::remote_execution::Command,
+    description: String,
+    timeout: Duration,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let mut execute_request = bazel_protos::remote_execution::ExecuteRequest::new();
+    execute_request.set_action(action);
+    self.execute(command, execute_request, description, timeout)
+  }
+
+  fn execute(
+    &self,
+    command: bazel_protos::remote_execution::Command,
+    mut execute_request: bazel_protos::remote_execution::ExecuteRequest,
+    req_description: String,
+    req_timeout: Duration,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let execution_client = self.execution_client.clone();
+    let execution_client2 = execution_client.clone();
+    let operations_client = self.operations_client.clone();
+
+    let store = self.store.clone();
+    execute_request.set_skip_cache_lookup(self.skip_cache_lookup);
+
+    let command_runner = self.clone();
+    let command_digest = try_future!(execute_request.get_action().get_command_digest().into());
+    self
+      .upload_command(&command, command_digest)
+      .and_then(move |_| {
+        debug!(
+          "Executing remotely request: {:?} (command: {:?})",
+          execute_request, command
+        );
+
+        map_grpc_result(execution_client.get().execute(&execute_request))
+          .map(|result| (Arc::new(execute_request), result))
+      })
+      .and_then(move |(execute_request, operation)| {
+        let start_time = Instant::now();
+
+        future::loop_fn((operation, 0), move |(operation, iter_num)| {
+          let req_description = req_description.clone();
+
+          let execute_request = execute_request.clone();
+          let execution_client2 = execution_client2.clone();
+          let store = store.clone();
+          let operations_client = operations_client.clone();
+          command_runner
+            .extract_execute_response(operation)
+            .map(|value| future::Loop::Break(value))
+            .or_else(move |value| {
+              match value {
+                ExecutionError::Fatal(err) => future::err(err).to_boxed(),
+                ExecutionError::MissingDigests(missing_digests) => {
+                  debug!(
+                    "Server reported missing digests; trying to upload: {:?}",
+                    missing_digests
+                  );
+                  let execute_request = execute_request.clone();
+                  let execution_client2 = execution_client2.clone();
+                  store.ensure_remote_has_recursive(missing_digests)
+                          .and_then(move |()| {
+                            map_grpc_result(
+                              execution_client2.get().execute(
+                                &execute_request.clone()
+                              )
+                            )
+                          })
+                          // Reset `iter_num` on `MissingDigests`
+                          .map(|operation| future::Loop::Continue((operation, 0)))
+                          .to_boxed()
+                }
+                ExecutionError::NotFinished(operation_name) => {
+                  let mut operation_request =
+                    bazel_protos::operations::GetOperationRequest::new();
+                  operation_request.set_name(operation_name.clone());
+
+                  let backoff_period = min(
+                    CommandRunner::BACKOFF_MAX_WAIT_MILLIS,
+                    (1 + iter_num) * CommandRunner::BACKOFF_INCR_WAIT_MILLIS,
+                  );
+
+                  // take the grpc result and cancel the op if too much time has passed.
+                  let elapsed = start_time.elapsed();
+
+                  if elapsed > req_timeout {
+                    future::err(format!(
+                      "Exceeded time out of {:?} with {:?} for operation {}, {}",
+                      req_timeout, elapsed, operation_name, req_description
+                    )).to_boxed()
+                  } else {
+                    // maybe the delay here should be the min of remaining time and the backoff period
+                    Delay::new(Duration::from_millis(backoff_period))
+                      .map_err(move |e| {
+                        format!(
+                          "Future-Delay errored at operation result polling for {}, {}: {}",
+                          operation_name, req_description, e
+                        )
+                      })
+                      .and_then(move |_| {
+                        future::done(map_grpc_result(
+                          operations_client.get().get_operation(&operation_request),
+                        )).map(move |operation| {
+                          future::Loop::Continue((operation, iter_num + 1))
+                        })
+                          .to_boxed()
+                      })
+                      .to_boxed()
+                  }
+                }
+              }
+            })
+        })
+      })
+      .to_boxed()
+  }
+
   fn upload_command(
     &self,
     command: &bazel_protos::remote_execution::Command,
@@ -265,6 +347,7 @@ impl CommandRunner {
             stderr: stderr,
             exit_code: execute_response.get_result().get_exit_code(),
             output_directory: output_directory,
+            kept_sandbox: None,
           }).to_boxed(),
           grpcio::RpcStatusCode::FailedPrecondition => {
             if execute_response.get_status().get_details().len() != 1 {
@@ -488,7 +571,29 @@ impl CommandRunner {
       })
       .collect();
 
-    let path_stats = try_future!(path_stats_result.map_err(|err| ExecutionError::Fatal(err)));
+    let mut path_stats = try_future!(path_stats_result.map_err(|err| ExecutionError::Fatal(err)));
+
+    // Symlinks carry no content, so they can be added to the output directory directly.
+    path_stats.extend(
+      execute_response
+        .get_result()
+        .get_output_file_symlinks()
+        .iter()
+        .chain(
+          execute_response
+            .get_result()
+            .get_output_directory_symlinks()
+            .iter(),
+        )
+        .map(|output_symlink| {
+          let path = PathBuf::from(output_symlink.get_path());
+          PathStat::link(
+            path.clone(),
+            Link(path),
+            PathBuf::from(output_symlink.get_target()),
+          )
+        }),
+    );
 
     #[derive(Clone)]
     struct StoreOneOffRemoteDigest {
@@ -515,7 +620,15 @@ impl CommandRunner {
       }
     }
 
+    let output_directory_futures = execute_response
+      .get_result()
+      .get_output_directories()
+      .iter()
+      .map(|output_directory| self.extract_output_directory(output_directory))
+      .collect::<Vec<_>>();
+
     let store = self.store.clone();
+    let store_2 = self.store.clone();
     future::join_all(futures)
       .and_then(|_| {
         // The unwrap() below is safe because we have joined any futures that had references to the Arc
@@ -532,6 +645,91 @@ impl CommandRunner {
           ))
         })
       })
+      .join(future::join_all(output_directory_futures))
+      .and_then(move |(files_digest, mut directory_digests)| {
+        if directory_digests.is_empty() {
+          return future::ok(files_digest).to_boxed();
+        }
+        directory_digests.push(files_digest);
+        fs::Snapshot::merge_directories(store_2, directory_digests)
+          .map_err(|error| {
+            ExecutionError::Fatal(format!(
+              "Error when merging output directories into output files: {}",
+              error
+            ))
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Fetches the Tree proto for an OutputDirectory, stores all of the Directory protos it contains
+  /// locally, and returns the digest of a Directory which contains the output directory at its
+  /// path relative to the root of the action.
+  ///
+  fn extract_output_directory(
+    &self,
+    output_directory: &bazel_protos::remote_execution::OutputDirectory,
+  ) -> BoxFuture<Digest, ExecutionError> {
+    let path = output_directory.get_path().to_owned();
+    let output_directory_path = path.clone();
+    if !output_directory.has_tree_digest() {
+      return future::err(ExecutionError::Fatal(format!(
+        "Output directory {:?} did not have a tree_digest",
+        path
+      ))).to_boxed();
+    }
+    let tree_digest_result: Result<Digest, String> = output_directory.get_tree_digest().into();
+    let tree_digest = try_future!(tree_digest_result.map_err(ExecutionError::Fatal));
+
+    let store = self.store.clone();
+    let store_2 = self.store.clone();
+    let path_2 = path.clone();
+    self
+      .store
+      .load_file_bytes_with(tree_digest, |bytes| {
+        let mut tree = bazel_protos::remote_execution::Tree::new();
+        tree
+          .merge_from_bytes(&bytes)
+          .map(|()| tree)
+          .map_err(|e| format!("Tree proto was not valid: {:?}", e))
+      })
+      .and_then(move |maybe_tree| {
+        maybe_tree.unwrap_or_else(|| {
+          Err(format!(
+            "Tree {:?} for output directory {:?} did not exist in the Store",
+            tree_digest, path
+          ))
+        })
+      })
+      .and_then(move |tree| {
+        try_future!(
+          tree
+            .get_children()
+            .iter()
+            .chain(iter::once(tree.get_root()))
+            .map(bazel_protos::verify_directory_canonical)
+            .collect::<Result<Vec<()>, String>>()
+        );
+        let child_futures = tree
+          .get_children()
+          .iter()
+          .map(|child| store.record_directory(child, true))
+          .collect::<Vec<_>>();
+        future::join_all(child_futures)
+          .and_then(move |_| store.record_directory(tree.get_root(), true))
+          .to_boxed()
+      })
+      .and_then(move |root_digest| {
+        fs::Snapshot::add_prefix(store_2, root_digest, PathBuf::from(path_2))
+      })
+      .map_err(move |error| {
+        ExecutionError::Fatal(format!(
+          "Error extracting output directory {:?}: {}",
+          output_directory_path, error
+        ))
+      })
       .to_boxed()
   }
 }
@@ -568,6 +766,19 @@ fn make_execute_request(
     .collect::<Result<Vec<String>, String>>()?;
   output_files.sort();
   action.set_output_files(protobuf::repeated::RepeatedField::from_vec(output_files));
+  let mut output_directories = req
+    .output_directories
+    .iter()
+    .map(|p| {
+      p.to_str()
+        .map(|s| s.to_owned())
+        .ok_or_else(|| format!("Non-UTF8 output directory path: {:?}", p))
+    })
+    .collect::<Result<Vec<String>, String>>()?;
+  output_directories.sort();
+  action.set_output_directories(protobuf::repeated::RepeatedField::from_vec(
+    output_directories,
+  ));
 
   let mut execute_request = bazel_protos::remote_execution::ExecuteRequest::new();
   execute_request.set_action(action);
@@ -575,6 +786,93 @@ fn make_execute_request(
   Ok((command, execute_request))
 }
 
+///
+/// Loads an Action which has already been stored (for example by an earlier remote execution),
+/// and its Command, from the Store.
+///
+pub fn load_action(
+  store: Store,
+  action_digest: Digest,
+) -> BoxFuture<
+  (
+    bazel_protos::remote_execution::Action,
+    bazel_protos::remote_execution::Command,
+  ),
+  String,
+> {
+  let store2 = store.clone();
+  load_proto::<bazel_protos::remote_execution::Action>(&store, action_digest, "Action")
+    .and_then(move |action| {
+      let command_digest = try_future!(action.get_command_digest().into());
+      load_proto::<bazel_protos::remote_execution::Command>(&store2, command_digest, "Command")
+        .map(move |command| (action, command))
+        .to_boxed()
+    })
+    .to_boxed()
+}
+
+///
+/// Reconstructs the ExecuteProcessRequest for a stored Action (see `load_action`), e.g. to run it
+/// locally. Fields of the Action which an ExecuteProcessRequest can't represent (such as its
+/// platform properties) are dropped, so to execute it remotely, use `CommandRunner::run_action`.
+///
+/// `default_timeout` is used if the Action doesn't specify a timeout.
+///
+pub fn execute_process_request_from_action(
+  action: &bazel_protos::remote_execution::Action,
+  command: &bazel_protos::remote_execution::Command,
+  action_digest: Digest,
+  default_timeout: Duration,
+) -> Result<ExecuteProcessRequest, String> {
+  let input_files: Result<Digest, String> = action.get_input_root_digest().into();
+  let timeout = if action.has_timeout() {
+    Duration::new(
+      action.get_timeout().get_seconds() as u64,
+      action.get_timeout().get_nanos() as u32,
+    )
+  } else {
+    default_timeout
+  };
+  Ok(ExecuteProcessRequest {
+    argv: command.get_arguments().to_vec(),
+    env: command
+      .get_environment_variables()
+      .iter()
+      .map(|env| (env.get_name().to_owned(), env.get_value().to_owned()))
+      .collect(),
+    input_files: input_files?,
+    output_files: action
+      .get_output_files()
+      .iter()
+      .map(|path| PathBuf::from(path))
+      .collect(),
+    output_directories: action
+      .get_output_directories()
+      .iter()
+      .map(|path| PathBuf::from(path))
+      .collect(),
+    timeout: timeout,
+    description: format!("action {:?}", action_digest),
+  })
+}
+
+fn load_proto<T: Message>(
+  store: &Store,
+  digest: Digest,
+  kind: &'static str,
+) -> BoxFuture<T, String> {
+  store
+    .load_file_bytes_with(digest, move |bytes| {
+      protobuf::parse_from_bytes::<T>(&bytes)
+        .map_err(|e| format!("Error parsing {} {:?}: {:?}", kind, digest, e))
+    })
+    .and_then(move |maybe_proto| match maybe_proto {
+      Some(proto) => proto,
+      None => Err(format!("{} {:?} did not exist in the store", kind, digest)),
+    })
+    .to_boxed()
+}
+
 fn format_error(error: &bazel_protos::status::Status) -> String {
   let error_code_enum = bazel_protos::code::Code::from_i32(error.get_code());
   let error_code = match error_code_enum {
@@ -628,7 +926,7 @@ mod tests {
 
   use super::{CommandRunner, ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult};
   use super::super::CommandRunner as CommandRunnerTrait;
-  use std::collections::{BTreeMap, BTreeSet};
+  use std::collections::{BTreeMap, BTreeSet, HashMap};
   use std::iter::{self, FromIterator};
   use std::path::PathBuf;
   use std::sync::Arc;
@@ -696,6 +994,65 @@ mod tests {
     assert_eq!(result, Ok((want_command, want_execute_request)));
   }
 
+  #[test]
+  fn execute_process_request_from_action() {
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "yo"]),
+      env: vec![("SOME".to_owned(), "value".to_owned())]
+        .into_iter()
+        .collect(),
+      input_files: TestDirectory::containing_roland().digest(),
+      output_files: vec![PathBuf::from("path/to/file")].into_iter().collect(),
+      output_directories: vec![PathBuf::from("some/dir")].into_iter().collect(),
+      timeout: Duration::from_secs(60),
+      description: "some description".to_owned(),
+    };
+    let (command, execute_request) = super::make_execute_request(&req).unwrap();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::local_only(
+      store_dir.path(),
+      Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
+    ).unwrap();
+    store
+      .store_file_bytes(Bytes::from(command.write_to_bytes().unwrap()), false)
+      .wait()
+      .unwrap();
+    let action_digest = store
+      .store_file_bytes(
+        Bytes::from(execute_request.get_action().write_to_bytes().unwrap()),
+        false,
+      )
+      .wait()
+      .unwrap();
+
+    let default_timeout = Duration::from_secs(15 * 60);
+    let (action, command) = super::load_action(store.clone(), action_digest)
+      .wait()
+      .unwrap();
+    assert_eq!(&action, execute_request.get_action());
+    let replayed =
+      super::execute_process_request_from_action(&action, &command, action_digest, default_timeout)
+        .unwrap();
+    assert_eq!(
+      replayed,
+      ExecuteProcessRequest {
+        timeout: default_timeout,
+        description: format!("action {:?}", action_digest),
+        ..req
+      }
+    );
+
+    let missing = super::load_action(store, TestData::roland().digest()).wait();
+    assert_eq!(
+      missing,
+      Err(format!(
+        "Action {:?} did not exist in the store",
+        TestData::roland().digest()
+      ))
+    );
+  }
+
   #[test]
   fn server_rejecting_execute_request_gives_error() {
     let execute_request = echo_foo_request();
@@ -755,10 +1112,54 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       }
     );
   }
 
+  #[test]
+  fn run_action_preserves_the_whole_action() {
+    let (command, mut execute_request) = super::make_execute_request(&echo_foo_request()).unwrap();
+    {
+      let action = execute_request.mut_action();
+      action.set_do_not_cache(true);
+      let mut property = bazel_protos::remote_execution::Platform_Property::new();
+      property.set_name("OSFamily".to_owned());
+      property.set_value("linux".to_owned());
+      action.mut_platform().mut_properties().push(property);
+    }
+
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        execute_request.clone(),
+        vec![
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+
+    let cas = mock::StubCAS::with_roland_and_directory(1024);
+    let result = create_command_runner(mock_server.address(), &cas)
+      .run_action(
+        execute_request.take_action(),
+        command,
+        "echo a foo".to_owned(),
+        Duration::from_millis(5000),
+      )
+      .wait()
+      .unwrap();
+
+    assert_eq!(result.stdout, as_bytes("foo"));
+  }
+
   #[test]
   fn extract_response_with_digest_stdout() {
     let op_name = "gimme-foo".to_string();
@@ -778,6 +1179,7 @@ mod tests {
         stderr: testdata_empty.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       })
     );
   }
@@ -801,6 +1203,7 @@ mod tests {
         stderr: testdata.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       })
     );
   }
@@ -851,6 +1254,7 @@ mod tests {
         stderr: test_stderr.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       })
     );
 
@@ -908,6 +1312,7 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       }
     );
   }
@@ -1152,6 +1557,7 @@ mod tests {
         stderr: Bytes::from(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        kept_sandbox: None,
       })
     );
     {
@@ -1228,6 +1634,7 @@ mod tests {
       stderr: Bytes::from("simba"),
       exit_code: 17,
       output_directory: TestDirectory::nested().digest(),
+      kept_sandbox: None,
     };
 
     let mut output_file = bazel_protos::remote_execution::OutputFile::new();
@@ -1455,6 +1862,60 @@ mod tests {
     }
   }
 
+  #[test]
+  fn get_cached_action_result_hit() {
+    let cas = mock::StubCAS::with_roland_and_directory(1024);
+    let action_digest = TestData::catnip().digest();
+    cas.action_results.lock().unwrap().insert(action_digest.0, {
+      let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+      output_file.set_path("roland".into());
+      output_file.set_digest((&TestData::roland().digest()).into());
+      let mut result = bazel_protos::remote_execution::ActionResult::new();
+      result.set_stdout_raw(as_bytes("foo"));
+      result.set_exit_code(1);
+      result.mut_output_files().push(output_file);
+      result
+    });
+    let command_runner = create_command_runner(cas.address(), &cas);
+
+    assert_eq!(
+      command_runner
+        .get_cached_action_result(action_digest)
+        .wait(),
+      Ok(Some(FallibleExecuteProcessResult {
+        stdout: as_bytes("foo"),
+        stderr: as_bytes(""),
+        exit_code: 1,
+        output_directory: TestDirectory::containing_roland().digest(),
+        kept_sandbox: None,
+      }))
+    );
+  }
+
+  #[test]
+  fn get_cached_action_result_miss() {
+    let cas = mock::StubCAS::with_roland_and_directory(1024);
+    let command_runner = create_command_runner(cas.address(), &cas);
+
+    assert_eq!(
+      command_runner
+        .get_cached_action_result(TestData::catnip().digest())
+        .wait(),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn get_cached_action_result_error() {
+    let cas = mock::StubCAS::always_errors();
+    let command_runner = create_command_runner(cas.address(), &cas);
+
+    command_runner
+      .get_cached_action_result(TestData::catnip().digest())
+      .wait()
+      .expect_err("Want Err");
+  }
+
   #[test]
   fn extract_output_files_from_response_one_file() {
     let mut output_file = bazel_protos::remote_execution::OutputFile::new();
@@ -1536,6 +1997,127 @@ mod tests {
     )
   }
 
+  #[test]
+  fn extract_output_files_from_response_with_symlink() {
+    let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+    output_file.set_path("cats/roland".into());
+    output_file.set_digest((&TestData::roland().digest()).into());
+    output_file.set_is_executable(false);
+    let mut output_files = protobuf::RepeatedField::new();
+    output_files.push(output_file);
+
+    let mut output_symlink = bazel_protos::remote_execution::OutputSymlink::new();
+    output_symlink.set_path("roland".into());
+    output_symlink.set_target("cats/roland".into());
+    let mut output_symlinks = protobuf::RepeatedField::new();
+    output_symlinks.push(output_symlink);
+
+    let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
+    execute_response.set_result({
+      let mut result = bazel_protos::remote_execution::ActionResult::new();
+      result.set_exit_code(0);
+      result.set_output_files(output_files);
+      result.set_output_file_symlinks(output_symlinks);
+      result
+    });
+
+    assert_eq!(
+      extract_output_files_from_response(&execute_response),
+      Ok(TestDirectory::with_symlink().digest())
+    )
+  }
+
+  #[test]
+  fn extract_output_files_from_response_output_directory() {
+    let (cas, tree_digest) = cas_containing_tree({
+      let mut tree = bazel_protos::remote_execution::Tree::new();
+      tree.set_root(TestDirectory::containing_roland().directory());
+      tree
+    });
+
+    let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
+    output_directory.set_path("cats".into());
+    output_directory.set_tree_digest(tree_digest);
+    let mut output_directories = protobuf::RepeatedField::new();
+    output_directories.push(output_directory);
+
+    let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
+    execute_response.set_result({
+      let mut result = bazel_protos::remote_execution::ActionResult::new();
+      result.set_exit_code(0);
+      result.set_output_directories(output_directories);
+      result
+    });
+
+    assert_eq!(
+      extract_output_files_from_response_with_cas(&cas, &execute_response),
+      Ok(TestDirectory::nested().digest())
+    )
+  }
+
+  #[test]
+  fn extract_output_files_from_response_output_directory_and_file() {
+    let (cas, tree_digest) = cas_containing_tree({
+      let mut tree = bazel_protos::remote_execution::Tree::new();
+      tree.set_root(TestDirectory::nested().directory());
+      tree.mut_children().push(TestDirectory::containing_roland().directory());
+      tree
+    });
+
+    let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+    output_file.set_path("treats".into());
+    output_file.set_digest((&TestData::catnip().digest()).into());
+    output_file.set_is_executable(false);
+    let mut output_files = protobuf::RepeatedField::new();
+    output_files.push(output_file);
+
+    // The Tree's root is the output directory itself, which here is the root of the action.
+    let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
+    output_directory.set_path("".into());
+    output_directory.set_tree_digest(tree_digest);
+    let mut output_directories = protobuf::RepeatedField::new();
+    output_directories.push(output_directory);
+
+    let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
+    execute_response.set_result({
+      let mut result = bazel_protos::remote_execution::ActionResult::new();
+      result.set_exit_code(0);
+      result.set_output_files(output_files);
+      result.set_output_directories(output_directories);
+      result
+    });
+
+    assert_eq!(
+      extract_output_files_from_response_with_cas(&cas, &execute_response),
+      Ok(TestDirectory::recursive().digest())
+    )
+  }
+
+  #[test]
+  fn extract_output_files_from_response_output_directory_missing_tree() {
+    let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
+    output_directory.set_path("cats".into());
+    output_directory.set_tree_digest((&TestDirectory::containing_roland().digest()).into());
+    let mut output_directories = protobuf::RepeatedField::new();
+    output_directories.push(output_directory);
+
+    let mut execute_response = bazel_protos::remote_execution::ExecuteResponse::new();
+    execute_response.set_result({
+      let mut result = bazel_protos::remote_execution::ActionResult::new();
+      result.set_exit_code(0);
+      result.set_output_directories(output_directories);
+      result
+    });
+
+    let cas = mock::StubCAS::empty();
+    let error = extract_output_files_from_response_with_cas(&cas, &execute_response)
+      .expect_err("Want error");
+    match error {
+      ExecutionError::Fatal(message) => assert_contains(&message, "did not exist"),
+      other => panic!("Want fatal error, got {:?}", other),
+    }
+  }
+
   fn echo_foo_request() -> ExecuteProcessRequest {
     ExecuteProcessRequest {
       argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
@@ -1677,12 +2259,34 @@ mod tests {
     execute_response: &bazel_protos::remote_execution::ExecuteResponse,
   ) -> Result<Digest, ExecutionError> {
     let cas = mock::StubCAS::with_roland_and_directory(1024);
-    let command_runner = create_command_runner("".to_owned(), &cas);
+    extract_output_files_from_response_with_cas(&cas, execute_response)
+  }
+
+  fn extract_output_files_from_response_with_cas(
+    cas: &mock::StubCAS,
+    execute_response: &bazel_protos::remote_execution::ExecuteResponse,
+  ) -> Result<Digest, ExecutionError> {
+    let command_runner = create_command_runner("".to_owned(), cas);
     command_runner
       .extract_output_files(&execute_response)
       .wait()
   }
 
+  fn cas_containing_tree(
+    tree: bazel_protos::remote_execution::Tree,
+  ) -> (mock::StubCAS, bazel_protos::remote_execution::Digest) {
+    let tree_digest = super::digest(&tree).expect("Error digesting tree");
+    let tree_bytes = Bytes::from(tree.write_to_bytes().expect("Error serializing tree"));
+    let mut blobs = HashMap::new();
+    blobs.insert(
+      Fingerprint::from_hex_string(tree_digest.get_hash()).unwrap(),
+      tree_bytes,
+    );
+    blobs.insert(TestData::roland().fingerprint(), TestData::roland().bytes());
+    blobs.insert(TestData::catnip().fingerprint(), TestData::catnip().bytes());
+    (mock::StubCAS::with_unverified_content(1024, blobs), tree_digest)
+  }
+
   fn make_any_proto(message: &protobuf::Message) -> protobuf::well_known_types::Any {
     let mut any = protobuf::well_known_types::Any::new();
     any.set_type_url(format!(
@@ -9,6 +9,7 @@ extern crate process_execution;
 use clap::{App, AppSettings, Arg};
 use futures::future::Future;
 use hashing::{Digest, Fingerprint};
+use process_execution::CommandRunner;
 use std::collections::{BTreeMap, BTreeSet};
 use std::iter::Iterator;
 use std::process::exit;
@@ -22,6 +23,12 @@ use std::time::Duration;
 /// It outputs its output/err to stdout/err, and exits with its exit code.
 ///
 /// It does not perform $PATH lookup or shell expansion.
+///
+/// Alternatively, an Action which has already been stored can be replayed with:
+///  process_executor --action-digest abc123 80
+/// in which case the outputs of the run (local, or remote if --server is set) are compared against
+/// the Action's cached ActionResult in --action-cache-server (or --cas-server) if it has one, and
+/// the differences are written to stderr.
 fn main() {
   env_logger::init();
 
@@ -37,16 +44,28 @@ fn main() {
       Arg::with_name("input-digest")
         .long("input-digest")
         .takes_value(true)
-        .required(true)
+        .required_unless("action-digest")
         .help("Fingerprint (hex string) of the digest to use as the input file tree."),
     )
     .arg(
       Arg::with_name("input-digest-length")
         .long("input-digest-length")
         .takes_value(true)
-        .required(true)
+        .required_unless("action-digest")
         .help("Length of the proto-bytes whose digest to use as the input file tree."),
     )
+    .arg(
+      Arg::with_name("action-digest")
+        .long("action-digest")
+        .takes_value(true)
+        .number_of_values(2)
+        .value_names(&["fingerprint", "size_bytes"])
+        .conflicts_with_all(&["input-digest", "input-digest-length", "env", "argv"])
+        .help(
+          "Digest of a stored Action to replay, rather than running argv. The Action and its \
+           Command are loaded from the local store (or --cas-server).",
+        ),
+    )
     .arg(
       Arg::with_name("server")
         .long("server")
@@ -62,6 +81,24 @@ fn main() {
         .takes_value(true)
         .help("The host:port of the gRPC CAS server to connect to."),
     )
+    .arg(
+      Arg::with_name("action-cache-server")
+        .long("action-cache-server")
+        .takes_value(true)
+        .requires("action-digest")
+        .help(
+          "The host:port of the gRPC ActionCache server from which to fetch the cached \
+           ActionResult of a replayed Action. Defaults to --cas-server.",
+        ),
+    )
+    .arg(
+      Arg::with_name("keep-sandboxes")
+        .long("keep-sandboxes")
+        .takes_value(true)
+        .possible_values(&["never", "on_failure", "always"])
+        .default_value("never")
+        .help("Which local execution sandboxes to keep on disk for debugging."),
+    )
     .arg(
       Arg::with_name("env")
         .long("env")
@@ -74,32 +111,15 @@ fn main() {
       Arg::with_name("argv")
         .multiple(true)
         .last(true)
-        .required(true),
+        .required_unless("action-digest"),
     )
     .get_matches();
 
-  let argv: Vec<String> = args
-    .values_of("argv")
-    .unwrap()
-    .map(|v| v.to_string())
-    .collect();
-  let env: BTreeMap<String, String> = match args.values_of("env") {
-    Some(values) => values
-      .map(|v| {
-        let mut parts = v.splitn(2, "=");
-        (
-          parts.next().unwrap().to_string(),
-          parts.next().unwrap_or_default().to_string(),
-        )
-      })
-      .collect(),
-    None => BTreeMap::new(),
-  };
   let local_store_path = args.value_of("local-store-path").unwrap();
   let pool = Arc::new(fs::ResettablePool::new("process-executor-".to_owned()));
   let server_arg = args.value_of("server");
   let store = match (server_arg, args.value_of("cas-server")) {
-    (Some(_server), Some(cas_server)) => fs::Store::with_remote(
+    (_, Some(cas_server)) => fs::Store::with_remote(
       local_store_path,
       pool.clone(),
       cas_server.to_owned(),
@@ -108,42 +128,288 @@ fn main() {
       Duration::from_secs(30),
     ),
     (None, None) => fs::Store::local_only(local_store_path, pool.clone()),
-    _ => panic!("Must specify either both --server and --cas-server or neither."),
+    (Some(_server), None) => panic!("Must specify --cas-server when specifying --server."),
   }.expect("Error making store");
 
-  let input_files = {
-    let fingerprint = Fingerprint::from_hex_string(args.value_of("input-digest").unwrap())
-      .expect("Bad input-digest");
-    let length = args
-      .value_of("input-digest-length")
-      .unwrap()
-      .parse::<usize>()
-      .expect("input-digest-length must be a non-negative number");
-    Digest(fingerprint, length)
-  };
+  let action_digest = args.values_of("action-digest").map(|mut values| {
+    parse_digest(values.next().unwrap(), values.next().unwrap(), "action-digest")
+  });
 
-  let request = process_execution::ExecuteProcessRequest {
-    argv,
-    env,
-    input_files,
-    output_files: BTreeSet::new(),
-    output_directories: BTreeSet::new(),
-    timeout: Duration::new(15 * 60, 0),
-    description: "process_executor".to_string(),
-  };
+  let stored_action = action_digest.map(|action_digest| {
+    process_execution::remote::load_action(store.clone(), action_digest)
+      .wait()
+      .expect("Error loading action")
+  });
 
-  let runner: Box<process_execution::CommandRunner> = match server_arg {
-    Some(address) => Box::new(process_execution::remote::CommandRunner::new(
-      address.to_owned(),
-      1,
-      store,
-    )),
-    None => Box::new(process_execution::local::CommandRunner::new(store, pool)),
+  let timeout = Duration::new(15 * 60, 0);
+  let request = match (action_digest, &stored_action) {
+    (Some(action_digest), &Some((ref action, ref command))) => {
+      process_execution::remote::execute_process_request_from_action(
+        action,
+        command,
+        action_digest,
+        timeout,
+      ).expect("Error loading action")
+    }
+    _ => {
+      let argv: Vec<String> = args
+        .values_of("argv")
+        .unwrap()
+        .map(|v| v.to_string())
+        .collect();
+      let env: BTreeMap<String, String> = match args.values_of("env") {
+        Some(values) => values
+          .map(|v| {
+            let mut parts = v.splitn(2, "=");
+            (
+              parts.next().unwrap().to_string(),
+              parts.next().unwrap_or_default().to_string(),
+            )
+          })
+          .collect(),
+        None => BTreeMap::new(),
+      };
+      let input_files = parse_digest(
+        args.value_of("input-digest").unwrap(),
+        args.value_of("input-digest-length").unwrap(),
+        "input-digest",
+      );
+      process_execution::ExecuteProcessRequest {
+        argv,
+        env,
+        input_files,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        timeout,
+        description: "process_executor".to_string(),
+      }
+    }
   };
 
-  let result = runner.run(request).wait().expect("Error executing");
+  let remote_runner = server_arg.map(|address| {
+    process_execution::remote::CommandRunner::new(address.to_owned(), 1, store.clone())
+  });
+  let result = match (remote_runner, stored_action) {
+    // When replaying an action, execute the stored Action itself (so that its digest and platform
+    // are unchanged), rather than fetching its cached result.
+    (Some(remote_runner), Some((action, command))) => remote_runner
+      .skipping_cache_lookup()
+      .run_action(action, command, request.description, request.timeout),
+    (Some(remote_runner), None) => remote_runner.run(request),
+    (None, _) => process_execution::local::CommandRunner::new(
+      store.clone(),
+      pool,
+      process_execution::local::KeepSandboxes::create(args.value_of("keep-sandboxes").unwrap())
+        .expect("Bad keep-sandboxes"),
+    ).run(request),
+  }.wait()
+    .expect("Error executing");
 
   print!("{}", String::from_utf8(result.stdout.to_vec()).unwrap());
   eprint!("{}", String::from_utf8(result.stderr.to_vec()).unwrap());
+
+  if let Some(action_digest) = action_digest {
+    match args
+      .value_of("action-cache-server")
+      .or_else(|| args.value_of("cas-server"))
+    {
+      Some(address) => match compare_with_cached_result(&store, address, action_digest, &result)
+        .expect("Error comparing against cached ActionResult")
+      {
+        Some(differences) => for difference in differences {
+          eprintln!("{}", difference);
+        },
+        None => eprintln!("No cached ActionResult for action {:?}", action_digest),
+      },
+      None => eprintln!(
+        "Specify --action-cache-server or --cas-server to compare against a cached ActionResult."
+      ),
+    }
+  }
   exit(result.exit_code);
 }
+
+fn parse_digest(fingerprint: &str, size_bytes: &str, name: &str) -> Digest {
+  let fingerprint =
+    Fingerprint::from_hex_string(fingerprint).unwrap_or_else(|e| panic!("Bad {}: {}", name, e));
+  let size_bytes = size_bytes
+    .parse::<usize>()
+    .unwrap_or_else(|_| panic!("{} length must be a non-negative number", name));
+  Digest(fingerprint, size_bytes)
+}
+
+///
+/// Compares a result against the cached ActionResult of the same action in the ActionCache at
+/// `address`, returning their differences, or None if the action has no cached result.
+///
+fn compare_with_cached_result(
+  store: &fs::Store,
+  address: &str,
+  action_digest: Digest,
+  result: &process_execution::FallibleExecuteProcessResult,
+) -> Result<Option<Vec<String>>, String> {
+  let action_cache =
+    process_execution::remote::CommandRunner::new(address.to_owned(), 1, store.clone());
+  match action_cache.get_cached_action_result(action_digest).wait()? {
+    Some(cached) => result_diff(store, &cached, result).map(Some),
+    None => Ok(None),
+  }
+}
+
+///
+/// Lists the differences between a cached result and a new result for the same action. Only the
+/// exit codes, stdout, stderr and outputs are compared: not where the new result's sandbox was
+/// kept, for example.
+///
+fn result_diff(
+  store: &fs::Store,
+  cached: &process_execution::FallibleExecuteProcessResult,
+  result: &process_execution::FallibleExecuteProcessResult,
+) -> Result<Vec<String>, String> {
+  let mut differences = vec![];
+  if cached.exit_code != result.exit_code {
+    differences.push(format!(
+      "exit code differs: cached {} new {}",
+      cached.exit_code, result.exit_code
+    ));
+  }
+  if cached.stdout != result.stdout {
+    differences.push(format!("stdout differs: cached {:?}", cached.stdout));
+  }
+  if cached.stderr != result.stderr {
+    differences.push(format!("stderr differs: cached {:?}", cached.stderr));
+  }
+  if cached.output_directory != result.output_directory {
+    let diff = store
+      .diff_directories(cached.output_directory, result.output_directory)
+      .wait()?
+      .ok_or_else(|| {
+        format!(
+          "Output directories {:?} or {:?} were not found in the Store",
+          cached.output_directory, result.output_directory
+        )
+      })?;
+    for (path, entry) in diff.added {
+      differences.push(format!("output added {} {}", path.display(), entry));
+    }
+    for (path, entry) in diff.removed {
+      differences.push(format!("output removed {} {}", path.display(), entry));
+    }
+    for (path, before, after) in diff.modified {
+      differences.push(format!(
+        "output modified {} {} {}",
+        path.display(),
+        before,
+        after
+      ));
+    }
+  }
+  if differences.is_empty() {
+    differences.push("Outputs are identical to the cached ActionResult.".to_owned());
+  }
+  Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate bazel_protos;
+  extern crate mock;
+  extern crate tempfile;
+  extern crate testutil;
+
+  use self::tempfile::TempDir;
+  use self::testutil::as_bytes;
+  use self::testutil::data::{TestData, TestDirectory};
+  use super::compare_with_cached_result;
+  use fs;
+  use process_execution::FallibleExecuteProcessResult;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use std::time::Duration;
+
+  fn cas_with_cached_result() -> mock::StubCAS {
+    let cas = mock::StubCAS::with_roland_and_directory(1024);
+    cas
+      .action_results
+      .lock()
+      .unwrap()
+      .insert(TestData::catnip().fingerprint(), {
+        let mut output_file = bazel_protos::remote_execution::OutputFile::new();
+        output_file.set_path("roland".into());
+        output_file.set_digest((&TestData::roland().digest()).into());
+        let mut result = bazel_protos::remote_execution::ActionResult::new();
+        result.set_stdout_raw(as_bytes("foo"));
+        result.mut_output_files().push(output_file);
+        result
+      });
+    cas
+  }
+
+  fn compare(
+    cas: &mock::StubCAS,
+    result: FallibleExecuteProcessResult,
+  ) -> Result<Option<Vec<String>>, String> {
+    let store_dir = TempDir::new().unwrap();
+    let store = fs::Store::with_remote(
+      store_dir.path(),
+      Arc::new(fs::ResettablePool::new("test-pool-".to_owned())),
+      cas.address(),
+      1,
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+    ).expect("Failed to make store");
+    compare_with_cached_result(&store, &cas.address(), TestData::catnip().digest(), &result)
+  }
+
+  #[test]
+  fn identical_to_cached_result() {
+    let result = FallibleExecuteProcessResult {
+      stdout: as_bytes("foo"),
+      stderr: as_bytes(""),
+      exit_code: 0,
+      output_directory: TestDirectory::containing_roland().digest(),
+      kept_sandbox: Some(PathBuf::from("/sandbox")),
+    };
+    assert_eq!(
+      compare(&cas_with_cached_result(), result),
+      Ok(Some(vec![
+        "Outputs are identical to the cached ActionResult.".to_owned(),
+      ]))
+    );
+  }
+
+  #[test]
+  fn different_from_cached_result() {
+    let result = FallibleExecuteProcessResult {
+      stdout: as_bytes("bar"),
+      stderr: as_bytes(""),
+      exit_code: 1,
+      output_directory: fs::EMPTY_DIGEST,
+      kept_sandbox: None,
+    };
+    let roland = TestData::roland().digest();
+    assert_eq!(
+      compare(&cas_with_cached_result(), result),
+      Ok(Some(vec![
+        "exit code differs: cached 0 new 1".to_owned(),
+        "stdout differs: cached b\"foo\"".to_owned(),
+        format!("output removed roland file {} {} false", roland.0, roland.1),
+      ]))
+    );
+  }
+
+  #[test]
+  fn no_cached_result() {
+    let result = FallibleExecuteProcessResult {
+      stdout: as_bytes("foo"),
+      stderr: as_bytes(""),
+      exit_code: 0,
+      output_directory: fs::EMPTY_DIGEST,
+      kept_sandbox: None,
+    };
+    assert_eq!(
+      compare(&mock::StubCAS::with_roland_and_directory(1024), result),
+      Ok(None)
+    );
+  }
+}
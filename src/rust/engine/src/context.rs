@@ -13,16 +13,22 @@ use futures::Future;
 use boxfuture::{BoxFuture, Boxable};
 use core::{Failure, TypeId};
 use externs;
-use fs::{safe_create_dir_all_ioerror, PosixFS, ResettablePool, Store};
-use graph::{EntryId, Graph, NodeContext};
+use fs::{safe_create_dir_all_ioerror, FileCacheMode, Invalidation, PosixFS, ResettablePool,
+         Store};
+use graph::{EntryId, EvictionLimits, Graph, NodeContext};
 use handles::maybe_drain_handles;
 use nodes::{NodeKey, TryInto, WrappedNode};
 use process_execution::{self, BoundedCommandRunner, CommandRunner};
+use process_execution::local::KeepSandboxes;
 use resettable::Resettable;
 use rule_graph::RuleGraph;
+use scheduler::{invalidate_all_paths, invalidate_paths};
 use tasks::Tasks;
 use types::Types;
 
+// How long the filesystem watcher waits for a burst of changes to a path to settle.
+const WATCHER_DEBOUNCE_MILLIS: u64 = 100;
+
 ///
 /// The core context shared (via Arc) between the Scheduler and the Context objects of
 /// all running Nodes.
@@ -32,9 +38,10 @@ use types::Types;
 /// https://github.com/tokio-rs/tokio/issues/369 is resolved.
 ///
 pub struct Core {
-  pub graph: Graph<NodeKey>,
-  pub tasks: Tasks,
+  pub graph: Arc<Graph<NodeKey>>,
+  pub tasks: Arc<Tasks>,
   pub rule_graph: RuleGraph,
+  pub rule_graph_parallelism: usize,
   pub types: Types,
   pub fs_pool: Arc<ResettablePool>,
   pub runtime: Resettable<Arc<Runtime>>,
@@ -57,6 +64,12 @@ impl Core {
     remote_store_chunk_bytes: usize,
     remote_store_chunk_upload_timeout: Duration,
     process_execution_parallelism: usize,
+    process_execution_reuse_workdirs: bool,
+    process_execution_keep_sandboxes: KeepSandboxes,
+    process_execution_file_cache: FileCacheMode,
+    watch_filesystem: bool,
+    graph_limits: EvictionLimits,
+    rule_graph_parallelism: usize,
   ) -> Core {
     let mut snapshots_dir = PathBuf::from(work_dir);
     snapshots_dir.push("snapshots");
@@ -71,18 +84,35 @@ impl Core {
       None => panic!("Could not find home dir"),
     };
 
+    // Local processes run in sandboxes under this directory, and files are linked into them from
+    // a FileCache alongside them, because links can't cross devices.
+    let process_execution_dir = work_dir.join("process_execution");
+
     let store = safe_create_dir_all_ioerror(&store_path)
-      .map_err(|e| format!("Error making directory {:?}: {:?}", store_path, e))
+      .and_then(|()| safe_create_dir_all_ioerror(&process_execution_dir))
+      .map_err(|e| {
+        format!(
+          "Error making directories {:?} and {:?}: {:?}",
+          store_path, process_execution_dir, e
+        )
+      })
       .and_then(|()| match remote_store_server {
         Some(address) => Store::with_remote(
-          store_path,
+          &store_path,
           fs_pool.clone(),
           address,
           remote_store_thread_count,
           remote_store_chunk_bytes,
           remote_store_chunk_upload_timeout,
         ),
-        None => Store::local_only(store_path, fs_pool.clone()),
+        None => Store::local_only(&store_path, fs_pool.clone()),
+      })
+      .and_then(|store| {
+        store.with_file_cache(
+          process_execution_dir.join("immutable_files"),
+          fs_pool.clone(),
+          process_execution_file_cache,
+        )
       })
       .unwrap_or_else(|e| panic!("Could not initialize Store: {:?}", e));
 
@@ -94,35 +124,81 @@ impl Core {
           process_execution_parallelism + 2,
           store.clone(),
         )),
-        None => Box::new(process_execution::local::CommandRunner::new(
-          store.clone(),
-          fs_pool.clone(),
-        )),
+        None if process_execution_reuse_workdirs => Box::new(
+          process_execution::local::CommandRunner::with_persistent_workdirs(
+            store.clone(),
+            fs_pool.clone(),
+            process_execution_keep_sandboxes,
+            process_execution_dir,
+          ).unwrap_or_else(|e| panic!("Could not initialize local CommandRunner: {:?}", e)),
+        ),
+        None => Box::new(
+          process_execution::local::CommandRunner::new(
+            store.clone(),
+            fs_pool.clone(),
+            process_execution_keep_sandboxes,
+          ).with_sandbox_base(process_execution_dir),
+        ),
       };
 
     let command_runner =
       BoundedCommandRunner::new(underlying_command_runner, process_execution_parallelism);
 
-    let rule_graph = RuleGraph::new(&tasks, root_subject_types);
+    let tasks = Arc::new(tasks);
+    let rule_graph = RuleGraph::new(tasks.clone(), root_subject_types, rule_graph_parallelism);
+
+    let graph = Arc::new(Graph::with_limits(graph_limits));
+
+    // FIXME: Errors in initialization should definitely be exposed as python
+    // exceptions, rather than as panics.
+    let vfs = PosixFS::new(build_root, fs_pool.clone(), ignore_patterns)
+      .and_then(|vfs| {
+        if watch_filesystem {
+          let graph = graph.clone();
+          vfs.with_watcher(
+            Duration::from_millis(WATCHER_DEBOUNCE_MILLIS),
+            move |invalidation| match invalidation {
+              Invalidation::Paths(paths) => {
+                let invalidated = invalidate_paths(&graph, &paths);
+                debug!(
+                  "Filesystem watcher invalidated {} nodes for {} paths.",
+                  invalidated,
+                  paths.len()
+                );
+              }
+              Invalidation::All => {
+                let invalidated = invalidate_all_paths(&graph);
+                warn!(
+                  "Filesystem watcher may have missed events: invalidated all {} filesystem nodes.",
+                  invalidated
+                );
+              }
+            },
+          )
+        } else {
+          Ok(vfs)
+        }
+      })
+      .unwrap_or_else(|e| {
+        panic!("Could not initialize VFS: {:?}", e);
+      });
 
     Core {
-      graph: Graph::new(),
+      graph: graph,
       tasks: tasks,
       rule_graph: rule_graph,
+      rule_graph_parallelism: rule_graph_parallelism,
       types: types,
-      fs_pool: fs_pool.clone(),
+      fs_pool: fs_pool,
       runtime: runtime,
       store: store,
-      // FIXME: Errors in initialization should definitely be exposed as python
-      // exceptions, rather than as panics.
-      vfs: PosixFS::new(build_root, fs_pool, ignore_patterns).unwrap_or_else(|e| {
-        panic!("Could not initialize VFS: {:?}", e);
-      }),
+      vfs: vfs,
       command_runner: command_runner,
     }
   }
 
   pub fn pre_fork(&self) {
+    self.vfs.pause_watcher_prefork();
     self.fs_pool.reset();
     self.store.reset_prefork();
     self.runtime.reset();
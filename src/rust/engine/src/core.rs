@@ -169,16 +169,22 @@ pub enum Failure {
 pub enum Noop {
   NoTask,
   NoVariant,
-  Cycle,
+  // The formatted path of Nodes which formed the cycle, if known.
+  Cycle(Vec<String>),
 }
 
 impl fmt::Debug for Noop {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    f.write_str(match self {
-      &Noop::Cycle => "Dep graph contained a cycle.",
-      &Noop::NoTask => "No task was available to compute the value.",
-      &Noop::NoVariant => "A matching variant key was not configured in variants.",
-    })
+    match self {
+      &Noop::Cycle(ref path) if !path.is_empty() => write!(
+        f,
+        "Dep graph contained a cycle:\n  {}",
+        path.join("\n  -> ")
+      ),
+      &Noop::Cycle(_) => f.write_str("Dep graph contained a cycle."),
+      &Noop::NoTask => f.write_str("No task was available to compute the value."),
+      &Noop::NoVariant => f.write_str("A matching variant key was not configured in variants."),
+    }
   }
 }
 
@@ -23,6 +23,7 @@ extern crate fs;
 extern crate futures;
 extern crate graph;
 extern crate hashing;
+extern crate json_encoding;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -31,6 +32,7 @@ extern crate process_execution;
 extern crate resettable;
 extern crate tokio;
 
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::fs::File;
 use std::io;
@@ -48,7 +50,10 @@ use externs::{Buffer, BufferBuffer, CallExtern, CloneValExtern, CreateExceptionE
               ProjectMultiExtern, PyResult, SatisfiedByExtern, SatisfiedByTypeExtern,
               StoreBytesExtern, StoreI64Extern, StoreTupleExtern, TypeIdBuffer, TypeToStrExtern,
               ValToStrExtern};
+use fs::FileCacheMode;
 use futures::Future;
+use graph::{EvictionLimits, InvalidationCounts};
+use process_execution::local::KeepSandboxes;
 use rule_graph::{GraphMaker, RuleGraph};
 use scheduler::{ExecutionRequest, RootResult, Scheduler, Session};
 use tasks::Tasks;
@@ -203,6 +208,9 @@ pub extern "C" fn scheduler_create(
   type_path_globs: TypeConstraint,
   type_directory_digest: TypeConstraint,
   type_snapshot: TypeConstraint,
+  type_snapshot_subset: TypeConstraint,
+  type_directory_with_prefix_to_strip: TypeConstraint,
+  type_directory_with_prefix_to_add: TypeConstraint,
   type_files_content: TypeConstraint,
   type_dir: TypeConstraint,
   type_file: TypeConstraint,
@@ -222,6 +230,13 @@ pub extern "C" fn scheduler_create(
   remote_store_chunk_bytes: u64,
   remote_store_chunk_upload_timeout_seconds: u64,
   process_execution_parallelism: u64,
+  process_execution_reuse_workdirs: bool,
+  process_execution_keep_sandboxes: Buffer,
+  process_execution_file_cache: Buffer,
+  watch_filesystem: bool,
+  graph_max_entries: u64,
+  graph_max_memory_mb: u64,
+  rule_graph_parallelism: u64,
 ) -> *const Scheduler {
   let root_type_ids = root_type_ids.to_vec();
   let ignore_patterns = ignore_patterns_buf
@@ -243,6 +258,9 @@ pub extern "C" fn scheduler_create(
     path_globs: type_path_globs,
     directory_digest: type_directory_digest,
     snapshot: type_snapshot,
+    snapshot_subset: type_snapshot_subset,
+    directory_with_prefix_to_strip: type_directory_with_prefix_to_strip,
+    directory_with_prefix_to_add: type_directory_with_prefix_to_add,
     files_content: type_files_content,
     dir: type_dir,
     file: type_file,
@@ -262,6 +280,16 @@ pub extern "C" fn scheduler_create(
   let remote_execution_server_string = remote_execution_server
     .to_string()
     .expect("remote_execution_server was not valid UTF8");
+  let keep_sandboxes = process_execution_keep_sandboxes
+    .to_string()
+    .expect("process_execution_keep_sandboxes was not valid UTF8");
+  let keep_sandboxes = KeepSandboxes::create(&keep_sandboxes)
+    .unwrap_or_else(|e| panic!("Invalid process_execution_keep_sandboxes: {}", e));
+  let file_cache_mode = process_execution_file_cache
+    .to_string()
+    .expect("process_execution_file_cache was not valid UTF8");
+  let file_cache_mode = FileCacheMode::create(&file_cache_mode)
+    .unwrap_or_else(|e| panic!("Invalid process_execution_file_cache: {}", e));
   Box::into_raw(Box::new(Scheduler::new(Core::new(
     root_type_ids.clone(),
     tasks,
@@ -283,6 +311,23 @@ pub extern "C" fn scheduler_create(
     remote_store_chunk_bytes as usize,
     Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
     process_execution_parallelism as usize,
+    process_execution_reuse_workdirs,
+    keep_sandboxes,
+    file_cache_mode,
+    watch_filesystem,
+    EvictionLimits {
+      max_entries: if graph_max_entries == 0 {
+        None
+      } else {
+        Some(graph_max_entries as usize)
+      },
+      max_bytes: if graph_max_memory_mb == 0 {
+        None
+      } else {
+        Some(graph_max_memory_mb as usize * 1024 * 1024)
+      },
+    },
+    rule_graph_parallelism as usize,
   ))))
 }
 
@@ -311,6 +356,78 @@ pub extern "C" fn scheduler_metrics(
   })
 }
 
+///
+/// Returns a Value representing a tuple of tuples of node description string and self-time in
+/// microseconds, for each node on the critical path of the Session (see
+/// `Scheduler::critical_path`).
+///
+#[no_mangle]
+pub extern "C" fn scheduler_critical_path(
+  scheduler_ptr: *mut Scheduler,
+  session_ptr: *mut Session,
+) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_session(session_ptr, |session| {
+      let values = scheduler
+        .critical_path(session)
+        .into_iter()
+        .map(|(node, self_time)| {
+          externs::store_tuple(&[
+            externs::store_bytes(node.as_bytes()),
+            externs::store_i64(duration_micros(self_time)),
+          ])
+        })
+        .collect::<Vec<_>>();
+      externs::store_tuple(&values)
+    })
+  })
+}
+
+///
+/// Returns a Value representing a tuple of a tuple of tuples of count name string and count int,
+/// and a tuple of tuples of process description string and elapsed time in microseconds for each
+/// running process (see `Scheduler::progress`).
+///
+#[no_mangle]
+pub extern "C" fn scheduler_progress(
+  scheduler_ptr: *mut Scheduler,
+  session_ptr: *mut Session,
+) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_session(session_ptr, |session| {
+      let progress = scheduler.progress(session);
+      let counts = vec![
+        ("queued", progress.queued),
+        ("running", progress.running),
+        ("completed", progress.completed),
+        ("bytes_uploaded", progress.transfer_counts.uploaded),
+        ("bytes_downloaded", progress.transfer_counts.downloaded),
+      ].into_iter()
+        .map(|(name, count)| {
+          externs::store_tuple(&[
+            externs::store_bytes(name.as_bytes()),
+            externs::store_i64(count as i64),
+          ])
+        })
+        .collect::<Vec<_>>();
+      let running_processes = progress
+        .running_processes
+        .into_iter()
+        .map(|(description, elapsed)| {
+          externs::store_tuple(&[
+            externs::store_bytes(description.as_bytes()),
+            externs::store_i64(duration_micros(elapsed)),
+          ])
+        })
+        .collect::<Vec<_>>();
+      externs::store_tuple(&[
+        externs::store_tuple(&counts),
+        externs::store_tuple(&running_processes),
+      ])
+    })
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn scheduler_pre_fork(scheduler_ptr: *mut Scheduler) {
   with_scheduler(scheduler_ptr, |scheduler| {
@@ -414,6 +531,27 @@ pub extern "C" fn tasks_add_select_variant(
   })
 }
 
+#[no_mangle]
+pub extern "C" fn tasks_add_select_union(tasks_ptr: *mut Tasks, product: TypeConstraint) {
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.add_select_union(product);
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_set_priority(tasks_ptr: *mut Tasks, priority: i64) {
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.set_priority(priority);
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn tasks_set_declares_other_products(tasks_ptr: *mut Tasks) {
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.set_declares_other_products();
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn tasks_task_end(tasks_ptr: *mut Tasks) {
   with_tasks(tasks_ptr, |tasks| {
@@ -438,11 +576,110 @@ pub extern "C" fn graph_invalidate(scheduler_ptr: *mut Scheduler, paths_buf: Buf
   })
 }
 
+///
+/// Invalidates the filesystem Nodes whose paths are matched by the given PathGlobs value, and
+/// returns a Value representing a tuple of tuples of node type string, cleared count int, and
+/// dirtied count int.
+///
+#[no_mangle]
+pub extern "C" fn graph_invalidate_globs(
+  scheduler_ptr: *mut Scheduler,
+  path_globs_val: Value,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    nodes::Snapshot::lift_path_globs(&path_globs_val)
+      .map(|path_globs| store_invalidation_counts(scheduler.invalidate_globs(&path_globs)))
+      .into()
+  })
+}
+
+///
+/// Invalidates all Nodes of the given type, and returns a Value like `graph_invalidate_globs`.
+///
+#[no_mangle]
+pub extern "C" fn graph_invalidate_node_type(
+  scheduler_ptr: *mut Scheduler,
+  type_name_buf: Buffer,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    type_name_buf
+      .to_string()
+      .map_err(|e| format!("Failed to decode node type name as UTF8: {:?}", e))
+      .and_then(|type_name| scheduler.invalidate_node_type(&type_name))
+      .map(store_invalidation_counts)
+      .into()
+  })
+}
+
+fn store_invalidation_counts(counts: HashMap<&'static str, InvalidationCounts>) -> Value {
+  let values = counts
+    .into_iter()
+    .map(|(type_name, counts)| {
+      externs::store_tuple(&[
+        externs::store_bytes(type_name.as_bytes()),
+        externs::store_i64(counts.cleared as i64),
+        externs::store_i64(counts.dirtied as i64),
+      ])
+    })
+    .collect::<Vec<_>>();
+  externs::store_tuple(&values)
+}
+
 #[no_mangle]
 pub extern "C" fn graph_len(scheduler_ptr: *mut Scheduler) -> u64 {
   with_scheduler(scheduler_ptr, |scheduler| scheduler.core.graph.len() as u64)
 }
 
+#[no_mangle]
+pub extern "C" fn graph_await_watcher_invalidation(
+  scheduler_ptr: *mut Scheduler,
+  count: u64,
+  timeout_millis: u64,
+) -> bool {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .vfs
+      .await_watcher_invalidation(count, Duration::from_millis(timeout_millis))
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn graph_persist(
+  scheduler_ptr: *mut Scheduler,
+  path_ptr: *const raw::c_char,
+) -> PyResult {
+  let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
+  let path = PathBuf::from(path_str);
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .graph
+      .persist(path.as_path())
+      .map(|count| externs::store_i64(count as i64))
+      .map_err(|e| format!("Failed to persist graph to {}: {:?}", path.display(), e))
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn graph_load_persisted(
+  scheduler_ptr: *mut Scheduler,
+  path_ptr: *const raw::c_char,
+) -> PyResult {
+  let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
+  let path = PathBuf::from(path_str);
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .graph
+      .load_persisted(path.as_path())
+      .map(|count| externs::store_i64(count as i64))
+      .map_err(|e| format!("Failed to load persisted graph from {}: {:?}", path.display(), e))
+      .into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn graph_visualize(
   scheduler_ptr: *mut Scheduler,
@@ -461,6 +698,53 @@ pub extern "C" fn graph_visualize(
   })
 }
 
+///
+/// Exports the graph as JSON lines, and returns the number of nodes that were written.
+///
+#[no_mangle]
+pub extern "C" fn graph_export_json(
+  scheduler_ptr: *mut Scheduler,
+  session_ptr: *mut Session,
+  path_ptr: *const raw::c_char,
+  all: bool,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_session(session_ptr, |session| {
+      let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
+      let path = PathBuf::from(path_str);
+      scheduler
+        .export_json(session, all, path.as_path())
+        .map(|count| externs::store_i64(count as i64))
+        .map_err(|e| format!("Failed to export graph to {}: {:?}", path.display(), e))
+        .into()
+    })
+  })
+}
+
+///
+/// Exports the timings of graph nodes as a Chrome trace, and returns the number of nodes that were
+/// written.
+///
+#[no_mangle]
+pub extern "C" fn graph_export_chrome_trace(
+  scheduler_ptr: *mut Scheduler,
+  session_ptr: *mut Session,
+  path_ptr: *const raw::c_char,
+  all: bool,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_session(session_ptr, |session| {
+      let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
+      let path = PathBuf::from(path_str);
+      scheduler
+        .export_chrome_trace(session, all, path.as_path())
+        .map(|count| externs::store_i64(count as i64))
+        .map_err(|e| format!("Failed to export trace to {}: {:?}", path.display(), e))
+        .into()
+    })
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn graph_trace(
   scheduler_ptr: *mut Scheduler,
@@ -517,6 +801,75 @@ pub extern "C" fn validator_run(scheduler_ptr: *mut Scheduler) -> Value {
   })
 }
 
+///
+/// Returns a Value representing a tuple of the errors for each unusable rule (see
+/// `RuleGraph::diagnose`). Each error is a tuple of the rule string and a tuple of diagnostics,
+/// each of which is a tuple of:
+///   subject type string, product string (empty if there was none), reason string,
+///   tuple of (candidate string, tuple of reason strings), and tuple of suggestion strings.
+///
+#[no_mangle]
+pub extern "C" fn validator_diagnostics(scheduler_ptr: *mut Scheduler) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let errors = scheduler
+      .core
+      .rule_graph
+      .diagnose()
+      .into_iter()
+      .map(|error| {
+        let diagnostics = error
+          .diagnostics
+          .into_iter()
+          .map(|d| {
+            let rejected_candidates = d
+              .rejected_candidates
+              .iter()
+              .map(|&(ref candidate, ref reasons)| {
+                externs::store_tuple(&[
+                  externs::store_bytes(candidate.as_bytes()),
+                  store_strs(reasons),
+                ])
+              })
+              .collect::<Vec<_>>();
+            externs::store_tuple(&[
+              externs::store_bytes(d.subject_type.as_bytes()),
+              externs::store_bytes(d.product.unwrap_or_default().as_bytes()),
+              externs::store_bytes(d.reason.as_bytes()),
+              externs::store_tuple(&rejected_candidates),
+              store_strs(&d.suggestions),
+            ])
+          })
+          .collect::<Vec<_>>();
+        externs::store_tuple(&[
+          externs::store_bytes(error.rule.as_bytes()),
+          externs::store_tuple(&diagnostics),
+        ])
+      })
+      .collect::<Vec<_>>();
+    externs::store_tuple(&errors)
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn validator_ambiguities(scheduler_ptr: *mut Scheduler) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let ambiguities = scheduler
+      .core
+      .rule_graph
+      .ambiguities()
+      .into_iter()
+      .map(|ambiguity| {
+        externs::store_tuple(&[
+          externs::store_bytes(ambiguity.subject_type.as_bytes()),
+          externs::store_bytes(ambiguity.product.as_bytes()),
+          store_strs(&ambiguity.candidates),
+        ])
+      })
+      .collect::<Vec<_>>();
+    externs::store_tuple(&ambiguities)
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn rule_graph_visualize(
   scheduler_ptr: *mut Scheduler,
@@ -552,6 +905,76 @@ pub extern "C" fn rule_subgraph_visualize(
   })
 }
 
+///
+/// Writes the rule graph for the given subject types to the given path as JSON (see
+/// `RuleGraph::to_json`).
+///
+#[no_mangle]
+pub extern "C" fn rule_graph_export_json(
+  scheduler_ptr: *mut Scheduler,
+  subject_types: TypeIdBuffer,
+  path_ptr: *const raw::c_char,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
+    let path = PathBuf::from(path_str);
+
+    let graph = graph_full(scheduler, subject_types.to_vec());
+    File::create(path.as_path())
+      .and_then(|file| graph.to_json(&mut io::BufWriter::new(file)))
+      .map_err(|e| format!("Failed to export rule graph to {}: {:?}", path.display(), e))
+      .into()
+  })
+}
+
+///
+/// Returns a Value representing a tuple of: a tuple of (at most `max_paths`) rule paths by which
+/// the given product could be computed for the given subject type, each of which is a tuple of
+/// entry strings, and an int which is 1 if the paths were truncated (see `RuleGraph::rule_paths`).
+///
+#[no_mangle]
+pub extern "C" fn rule_graph_rule_paths(
+  scheduler_ptr: *mut Scheduler,
+  subject_type: TypeId,
+  product_type: TypeConstraint,
+  max_paths: u64,
+) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let (paths, truncated) = scheduler.core.rule_graph.rule_paths(
+      subject_type,
+      product_type,
+      max_paths as usize,
+    );
+    let paths = paths.iter().map(|path| store_strs(path)).collect::<Vec<_>>();
+    externs::store_tuple(&[
+      externs::store_tuple(&paths),
+      externs::store_i64(truncated as i64),
+    ])
+  })
+}
+
+///
+/// Returns a Value representing a tuple of the strings of the rules that are unused in the rule
+/// graph.
+///
+#[no_mangle]
+pub extern "C" fn rule_graph_unused_rules(scheduler_ptr: *mut Scheduler) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    store_strs(&scheduler.core.rule_graph.unused_rules())
+  })
+}
+
+///
+/// Returns a Value representing a tuple of the strings of the intrinsic entries that are reached
+/// in the rule graph.
+///
+#[no_mangle]
+pub extern "C" fn rule_graph_reached_intrinsics(scheduler_ptr: *mut Scheduler) -> Value {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    store_strs(&scheduler.core.rule_graph.reached_intrinsics())
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn set_panic_handler() {
   panic::set_hook(Box::new(|panic_info| {
@@ -639,7 +1062,20 @@ pub extern "C" fn capture_snapshots(
 pub extern "C" fn merge_directories(
   scheduler_ptr: *mut Scheduler,
   directories_value: Value,
+  merge_policy_buf: Buffer,
 ) -> PyResult {
+  let merge_policy_result = merge_policy_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode merge policy as UTF8: {:?}", e))
+    .and_then(|merge_policy| fs::MergePolicy::create(&merge_policy));
+  let merge_policy = match merge_policy_result {
+    Ok(p) => p,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+
   let digests_result: Result<Vec<hashing::Digest>, String> =
     externs::project_multi(&directories_value, "dependencies")
       .iter()
@@ -654,7 +1090,11 @@ pub extern "C" fn merge_directories(
   };
 
   with_scheduler(scheduler_ptr, |scheduler| {
-    fs::Snapshot::merge_directories(scheduler.core.store.clone(), digests)
+    fs::Snapshot::merge_directories_with_policy(
+      scheduler.core.store.clone(),
+      digests,
+      merge_policy,
+    )
       .wait()
       .map(|dir| nodes::Snapshot::store_directory(&scheduler.core, &dir))
       .into()
@@ -662,7 +1102,11 @@ pub extern "C" fn merge_directories(
 }
 
 fn graph_full(scheduler: &Scheduler, subject_types: Vec<TypeId>) -> RuleGraph {
-  let graph_maker = GraphMaker::new(&scheduler.core.tasks, subject_types);
+  let graph_maker = GraphMaker::new(
+    scheduler.core.tasks.clone(),
+    subject_types,
+    scheduler.core.rule_graph_parallelism,
+  );
   graph_maker.full_graph()
 }
 
@@ -671,10 +1115,26 @@ fn graph_sub(
   subject_type: TypeId,
   product_type: TypeConstraint,
 ) -> RuleGraph {
-  let graph_maker = GraphMaker::new(&scheduler.core.tasks, vec![subject_type.clone()]);
+  let graph_maker = GraphMaker::new(
+    scheduler.core.tasks.clone(),
+    vec![subject_type.clone()],
+    scheduler.core.rule_graph_parallelism,
+  );
   graph_maker.sub_graph(&subject_type, &product_type)
 }
 
+fn duration_micros(duration: Duration) -> i64 {
+  (duration.as_secs() * 1_000_000 + u64::from(duration.subsec_nanos() / 1_000)) as i64
+}
+
+fn store_strs(strs: &[String]) -> Value {
+  let values = strs
+    .iter()
+    .map(|s| externs::store_bytes(s.as_bytes()))
+    .collect::<Vec<_>>();
+  externs::store_tuple(&values)
+}
+
 fn write_to_file(path: &Path, graph: &RuleGraph) -> io::Result<()> {
   let file = File::create(path)?;
   let mut f = io::BufWriter::new(file);
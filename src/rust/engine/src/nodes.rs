@@ -5,6 +5,8 @@ extern crate bazel_protos;
 
 use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::ffi::OsStr;
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -25,6 +27,7 @@ use selectors;
 use tasks::{self, Intrinsic, IntrinsicKind};
 
 use graph::{Node, NodeError, NodeTracer, NodeVisualizer};
+use graph::persistence::{Decoder, Encoder};
 
 pub type NodeFuture<T> = BoxFuture<T, Failure>;
 
@@ -93,7 +96,8 @@ pub trait WrappedNode: Into<NodeKey> {
 ///
 /// A Node that selects a product for a subject.
 ///
-/// A Select can be satisfied by multiple sources, but fails if multiple sources produce a value.
+/// A Select can be satisfied by multiple sources, but fails if multiple sources produce a value,
+/// unless it is a union, in which case it produces a tuple of the values of all sources.
 /// The 'variants' field represents variant configuration that is propagated to dependencies. When
 /// a task needs to consume a product as configured by the variants map, it can pass variant_key,
 /// which matches a 'variant' value to restrict the names of values selected by a SelectNode.
@@ -106,6 +110,15 @@ pub struct Select {
   entries: rule_graph::Entries,
 }
 
+///
+/// Whether an intrinsic removes a prefix from, or adds a prefix to, the paths in a Directory.
+///
+#[derive(Clone, Copy, Debug)]
+enum PrefixChange {
+  Strip,
+  Add,
+}
+
 impl Select {
   pub fn new(
     product: TypeConstraint,
@@ -258,6 +271,31 @@ impl Select {
     }
   }
 
+  ///
+  /// Given the results of configured Task nodes for a union, merge the successful values (after
+  /// the subject itself, if it matched) into a tuple. Noops are ignored, so the tuple may be empty.
+  ///
+  fn merge_task_results(
+    &self,
+    context: Context,
+    literal_value: Option<Value>,
+    results: Vec<Result<Value, Failure>>,
+  ) -> Result<Value, Failure> {
+    let mut matches: Vec<Value> = literal_value.into_iter().collect();
+    for result in results {
+      match result {
+        Ok(value) => {
+          if let Some(v) = self.select_literal(&context, value, &None) {
+            matches.push(v);
+          }
+        }
+        Err(Failure::Noop(_)) => continue,
+        Err(failure) => return Err(failure),
+      }
+    }
+    Ok(externs::store_tuple(&matches))
+  }
+
   fn snapshot(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<fs::Snapshot> {
     let ref edges = context
       .core
@@ -276,6 +314,79 @@ impl Select {
       .to_boxed()
   }
 
+  ///
+  /// Selects the input of an intrinsic which operates on a Python request object for the subject.
+  ///
+  fn select_intrinsic_input(
+    &self,
+    context: &Context,
+    entry: &rule_graph::Entry,
+    input: TypeConstraint,
+  ) -> NodeFuture<Value> {
+    let ref edges = context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for intrinsic.");
+    Select::new(input, self.subject.clone(), self.variants.clone(), edges).run(context.clone())
+  }
+
+  fn snapshot_subset(
+    &self,
+    context: &Context,
+    entry: &rule_graph::Entry,
+  ) -> NodeFuture<fs::Snapshot> {
+    let store = context.core.store.clone();
+    self
+      .select_intrinsic_input(context, entry, context.core.types.snapshot_subset.clone())
+      .and_then(move |snapshot_subset_val| {
+        let digest = try_future!(
+          lift_digest(&externs::project_ignoring_type(
+            &snapshot_subset_val,
+            "directory_digest"
+          )).map_err(|str| throw(&str))
+        );
+        let path_globs = try_future!(
+          Snapshot::lift_path_globs(&externs::project_ignoring_type(&snapshot_subset_val, "globs"))
+            .map_err(|str| throw(&str))
+        );
+        fs::Snapshot::subset(store, digest, path_globs)
+          .map_err(|str| throw(&str))
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn reprefix_directory(
+    &self,
+    context: &Context,
+    entry: &rule_graph::Entry,
+    change: PrefixChange,
+  ) -> NodeFuture<hashing::Digest> {
+    let store = context.core.store.clone();
+    let input = match change {
+      PrefixChange::Strip => context.core.types.directory_with_prefix_to_strip.clone(),
+      PrefixChange::Add => context.core.types.directory_with_prefix_to_add.clone(),
+    };
+    self
+      .select_intrinsic_input(context, entry, input)
+      .and_then(move |request_val| {
+        let digest = try_future!(
+          lift_digest(&externs::project_ignoring_type(
+            &request_val,
+            "directory_digest"
+          )).map_err(|str| throw(&str))
+        );
+        let prefix = PathBuf::from(externs::project_str(&request_val, "prefix"));
+        let result = match change {
+          PrefixChange::Strip => fs::Snapshot::strip_prefix(store, digest, prefix),
+          PrefixChange::Add => fs::Snapshot::add_prefix(store, digest, prefix),
+        };
+        result.map_err(|str| throw(&str)).to_boxed()
+      })
+      .to_boxed()
+  }
+
   fn execute_process(
     &self,
     context: &Context,
@@ -314,6 +425,11 @@ impl Select {
     self
       .entries
       .iter()
+      // A union may select the subject itself, which is handled by `run` rather than by a node.
+      .filter(|entry| match entry {
+        &&rule_graph::Entry::SubjectIsProduct { .. } => false,
+        _ => true,
+      })
       .map(
         |entry| match context.core.rule_graph.rule_for_inner(entry) {
           &rule_graph::Rule::Task(ref task) => context.get(Task {
@@ -333,6 +449,36 @@ impl Select {
               .map(move |snapshot| Snapshot::store_snapshot(&context.core, &snapshot))
               .to_boxed()
           }
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::SnapshotSubset,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .snapshot_subset(&context, &entry)
+              .map(move |snapshot| Snapshot::store_snapshot(&context.core, &snapshot))
+              .to_boxed()
+          }
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::StripPrefix,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .reprefix_directory(&context, &entry, PrefixChange::Strip)
+              .map(move |digest| Snapshot::store_directory(&context.core, &digest))
+              .to_boxed()
+          }
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::AddPrefix,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .reprefix_directory(&context, &entry, PrefixChange::Add)
+              .map(move |digest| Snapshot::store_directory(&context.core, &digest))
+              .to_boxed()
+          }
           &rule_graph::Rule::Intrinsic(Intrinsic {
             kind: IntrinsicKind::FilesContent,
             ..
@@ -388,6 +534,14 @@ impl Select {
                     externs::store_bytes(&result.0.stderr),
                     externs::store_i64(result.0.exit_code as i64),
                     Snapshot::store_directory(&context.core, &result.0.output_directory),
+                    externs::store_bytes(
+                      result
+                        .0
+                        .kept_sandbox
+                        .as_ref()
+                        .map(|sandbox| sandbox.as_os_str().as_bytes())
+                        .unwrap_or(&[]),
+                    ),
                   ],
                 )
               })
@@ -419,11 +573,14 @@ impl WrappedNode for Select {
       None => None,
     };
 
-    // If the Subject "is a" or "has a" Product, then we're done.
-    if let Some(literal_value) =
-      self.select_literal(&context, externs::val_for(&self.subject), &variant_value)
-    {
-      return ok(literal_value);
+    // If the Subject "is a" or "has a" Product, then we're done (unless this is a union, which
+    // also includes the products of the configured tasks).
+    let literal_value =
+      self.select_literal(&context, externs::val_for(&self.subject), &variant_value);
+    if !self.selector.union {
+      if let Some(ref literal_value) = literal_value {
+        return ok(literal_value.clone());
+      }
     }
 
     // Else, attempt to use the configured tasks to compute the value.
@@ -441,7 +598,11 @@ impl WrappedNode for Select {
     let variant_value = variant_value.map(|s| s.to_string());
     deps_future
       .and_then(move |dep_results| {
-        future::result(self.choose_task_result(context, dep_results, &variant_value))
+        if self.selector.union {
+          future::result(self.merge_task_results(context, literal_value, dep_results))
+        } else {
+          future::result(self.choose_task_result(context, dep_results, &variant_value))
+        }
       })
       .to_boxed()
   }
@@ -517,6 +678,41 @@ impl ExecuteProcess {
       description: description,
     }))
   }
+
+  ///
+  /// The human-readable description of the process.
+  ///
+  pub fn description(&self) -> &str {
+    &self.0.description
+  }
+
+  ///
+  /// Encodes every field of the request, since each of them may affect the result.
+  ///
+  fn encode(&self, encoder: &mut Encoder) {
+    let request = &self.0;
+    encoder.u64(request.argv.len() as u64);
+    for arg in &request.argv {
+      encoder.str(arg);
+    }
+    encoder.u64(request.env.len() as u64);
+    for (key, value) in &request.env {
+      encoder.str(key).str(value);
+    }
+    encode_digest(encoder, &request.input_files);
+    encoder.u64(request.output_files.len() as u64);
+    for path in &request.output_files {
+      encode_path(encoder, path);
+    }
+    encoder.u64(request.output_directories.len() as u64);
+    for path in &request.output_directories {
+      encode_path(encoder, path);
+    }
+    encoder
+      .u64(request.timeout.as_secs())
+      .u64(u64::from(request.timeout.subsec_nanos()))
+      .str(&request.description);
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -668,6 +864,26 @@ impl Snapshot {
       .to_boxed()
   }
 
+  ///
+  /// Encodes the fields of the PathGlobs subject that `lift_path_globs` consumes: unlike the
+  /// subject's Key, these are stable across processes.
+  ///
+  fn encode(&self, encoder: &mut Encoder) {
+    let item = externs::val_for(&self.0);
+    let include = externs::project_multi_strs(&item, "include");
+    let exclude = externs::project_multi_strs(&item, "exclude");
+    let glob_match_error_behavior =
+      externs::project_ignoring_type(&item, "glob_match_error_behavior");
+    let failure_behavior = externs::project_str(&glob_match_error_behavior, "failure_behavior");
+    for globs in &[include, exclude] {
+      encoder.u64(globs.len() as u64);
+      for glob in globs {
+        encoder.str(glob);
+      }
+    }
+    encoder.str(&failure_behavior);
+  }
+
   pub fn lift_path_globs(item: &Value) -> Result<PathGlobs, String> {
     let include = externs::project_multi_strs(item, "include");
     let exclude = externs::project_multi_strs(item, "exclude");
@@ -722,6 +938,11 @@ impl Snapshot {
     externs::unsafe_call(&core.types.construct_file, &args)
   }
 
+  fn store_link(core: &Arc<Core>, item: &Link) -> Value {
+    let args = [Self::store_path(item.0.as_path())];
+    externs::unsafe_call(&core.types.construct_link, &args)
+  }
+
   fn store_path_stat(core: &Arc<Core>, item: &PathStat) -> Value {
     let args = match item {
       &PathStat::Dir { ref path, ref stat } => {
@@ -730,6 +951,9 @@ impl Snapshot {
       &PathStat::File { ref path, ref stat } => {
         vec![Self::store_path(path), Self::store_file(core, stat)]
       }
+      &PathStat::Link { ref path, ref stat, .. } => {
+        vec![Self::store_path(path), Self::store_link(core, stat)]
+      }
     };
     externs::unsafe_call(&core.types.construct_path_stat, &args)
   }
@@ -954,17 +1178,42 @@ impl NodeTracer<NodeKey> for Tracer {
   }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub enum NodeKey {
-  DigestFile(DigestFile),
-  ExecuteProcess(ExecuteProcess),
-  ReadLink(ReadLink),
-  Scandir(Scandir),
-  Select(Select),
-  Snapshot(Snapshot),
-  Task(Task),
+///
+/// Defines NodeKey with a variant per given Node type (named after the type), along with the
+/// names of the variants, so that `NodeKey::TYPE_NAMES` and `Node::type_name` cannot disagree.
+///
+macro_rules! node_keys {
+  ($($variant:ident),*) => {
+    #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+    pub enum NodeKey {
+      $($variant($variant),)*
+    }
+
+    impl NodeKey {
+      ///
+      /// The names of each of the types of NodeKey: see `Node::type_name`.
+      ///
+      pub const TYPE_NAMES: &'static [&'static str] = &[$(stringify!($variant)),*];
+
+      fn variant_name(&self) -> &'static str {
+        match self {
+          $(&NodeKey::$variant(..) => stringify!($variant),)*
+        }
+      }
+    }
+  };
 }
 
+node_keys!(
+  DigestFile,
+  ExecuteProcess,
+  ReadLink,
+  Scandir,
+  Select,
+  Snapshot,
+  Task
+);
+
 impl NodeKey {
   fn product_str(&self) -> String {
     fn typstr(tc: &TypeConstraint) -> String {
@@ -1045,6 +1294,9 @@ impl Node for NodeKey {
       &NodeKey::Snapshot(ref s) => format!("Snapshot({})", keystr(&s.0)),
     }
   }
+  fn type_name(&self) -> &'static str {
+    self.variant_name()
+  }
 
   fn digest(res: NodeResult) -> Option<hashing::Digest> {
     match res {
@@ -1056,6 +1308,278 @@ impl Node for NodeKey {
       | NodeResult::Value(_) => None,
     }
   }
+
+  fn result_eq(a: &NodeResult, b: &NodeResult) -> bool {
+    match (a, b) {
+      (&NodeResult::Digest(ref a), &NodeResult::Digest(ref b)) => a == b,
+      (&NodeResult::DirectoryListing(ref a), &NodeResult::DirectoryListing(ref b)) => a.0 == b.0,
+      (&NodeResult::LinkDest(ref a), &NodeResult::LinkDest(ref b)) => a.0 == b.0,
+      (&NodeResult::ProcessResult(ref a), &NodeResult::ProcessResult(ref b)) => a.0 == b.0,
+      (&NodeResult::Snapshot(ref a), &NodeResult::Snapshot(ref b)) => a == b,
+      (&NodeResult::Value(ref a), &NodeResult::Value(ref b)) => externs::equals(a, b),
+      _ => false,
+    }
+  }
+
+  fn error_eq(a: &Failure, b: &Failure) -> bool {
+    match (a, b) {
+      (&Failure::Noop(ref a), &Failure::Noop(ref b)) => a == b,
+      // Exceptions rarely define equality, so compare their types and messages instead.
+      (&Failure::Throw(ref a, _), &Failure::Throw(ref b, _)) => {
+        externs::identify(a).type_id == externs::identify(b).type_id
+          && externs::val_to_str(a) == externs::val_to_str(b)
+      }
+      // An invalidated Node did not complete, so its dependents must always re-run.
+      _ => false,
+    }
+  }
+
+  fn heap_size(&self) -> usize {
+    match self {
+      &NodeKey::DigestFile(ref s) => path_heap_size(&s.0.path),
+      &NodeKey::ReadLink(ref s) => path_heap_size(&(s.0).0),
+      &NodeKey::Scandir(ref s) => path_heap_size(&(s.0).0),
+      // The remaining Nodes are mostly keyed by python values, whose sizes are not known.
+      &NodeKey::ExecuteProcess(_)
+      | &NodeKey::Select(_)
+      | &NodeKey::Snapshot(_)
+      | &NodeKey::Task(_) => 0,
+    }
+  }
+
+  fn result_heap_size(result: &NodeResult) -> usize {
+    match result {
+      &NodeResult::DirectoryListing(ref l) => l
+        .0
+        .iter()
+        .map(|stat| mem::size_of::<fs::Stat>() + path_heap_size(stat.path()))
+        .sum(),
+      &NodeResult::LinkDest(ref l) => path_heap_size(&l.0),
+      &NodeResult::ProcessResult(ref p) => p.0.stdout.len() + p.0.stderr.len(),
+      // Each PathStat holds both its symbolic path and the path of its Stat.
+      &NodeResult::Snapshot(ref s) => s
+        .path_stats
+        .iter()
+        .map(|path_stat| mem::size_of::<PathStat>() + 2 * path_heap_size(path_stat.path()))
+        .sum(),
+      // A Value is a handle to a python object, whose size is not known.
+      &NodeResult::Digest(_) | &NodeResult::Value(_) => 0,
+    }
+  }
+
+  fn persistent_key(&self) -> Option<Vec<u8>> {
+    let mut encoder = Encoder::new();
+    match self {
+      &NodeKey::DigestFile(ref s) => {
+        encode_path(encoder.str("DigestFile"), &s.0.path);
+        encoder.bool(s.0.is_executable);
+      }
+      &NodeKey::ReadLink(ref s) => encode_path(encoder.str("ReadLink"), &(s.0).0),
+      &NodeKey::Scandir(ref s) => encode_path(encoder.str("Scandir"), &(s.0).0),
+      &NodeKey::ExecuteProcess(ref s) => s.encode(encoder.str("ExecuteProcess")),
+      &NodeKey::Snapshot(ref s) => s.encode(encoder.str("Snapshot")),
+      // Keyed by (and producing) python values, which are not stable across processes.
+      &NodeKey::Select(_) | &NodeKey::Task(_) => return None,
+    }
+    Some(encoder.finish())
+  }
+
+  fn from_persistent_key(key: &[u8]) -> Option<NodeKey> {
+    // Only the filesystem Nodes are ever dependencies of persisted Nodes, so only they need to
+    // be recreated.
+    let mut decoder = Decoder::new(key);
+    let node = match decoder.string()?.as_str() {
+      "DigestFile" => NodeKey::DigestFile(DigestFile(File {
+        path: decode_path(&mut decoder)?,
+        is_executable: decoder.bool()?,
+      })),
+      "ReadLink" => NodeKey::ReadLink(ReadLink(Link(decode_path(&mut decoder)?))),
+      "Scandir" => NodeKey::Scandir(Scandir(Dir(decode_path(&mut decoder)?))),
+      _ => return None,
+    };
+    if decoder.is_empty() {
+      Some(node)
+    } else {
+      None
+    }
+  }
+
+  fn persistent_result(result: &NodeResult) -> Option<Vec<u8>> {
+    let mut encoder = Encoder::new();
+    match result {
+      &NodeResult::Digest(ref d) => encode_digest(encoder.str("Digest"), d),
+      &NodeResult::DirectoryListing(ref l) => {
+        encoder.str("DirectoryListing").u64(l.0.len() as u64);
+        for stat in &l.0 {
+          encode_stat(&mut encoder, stat);
+        }
+      }
+      &NodeResult::LinkDest(ref l) => encode_path(encoder.str("LinkDest"), &l.0),
+      &NodeResult::ProcessResult(ref p) => {
+        let result = &p.0;
+        encoder
+          .str("ProcessResult")
+          .bytes(&result.stdout)
+          .bytes(&result.stderr)
+          .u64(i64::from(result.exit_code) as u64);
+        encode_digest(&mut encoder, &result.output_directory);
+      }
+      &NodeResult::Snapshot(ref s) => {
+        encode_digest(encoder.str("Snapshot"), &s.digest);
+        encoder.u64(s.path_stats.len() as u64);
+        for path_stat in &s.path_stats {
+          encode_path_stat(&mut encoder, path_stat);
+        }
+      }
+      &NodeResult::Value(_) => return None,
+    }
+    Some(encoder.finish())
+  }
+
+  fn from_persistent_result(bytes: &[u8]) -> Option<NodeResult> {
+    let mut decoder = Decoder::new(bytes);
+    let result = match decoder.string()?.as_str() {
+      "Digest" => NodeResult::Digest(decode_digest(&mut decoder)?),
+      "DirectoryListing" => {
+        let count = decoder.u64()?;
+        let stats = (0..count)
+          .map(|_| decode_stat(&mut decoder))
+          .collect::<Option<Vec<_>>>()?;
+        NodeResult::DirectoryListing(DirectoryListing(stats))
+      }
+      "LinkDest" => NodeResult::LinkDest(LinkDest(decode_path(&mut decoder)?)),
+      "ProcessResult" => NodeResult::ProcessResult(ProcessResult(
+        process_execution::FallibleExecuteProcessResult {
+          stdout: decoder.bytes()?.to_vec().into(),
+          stderr: decoder.bytes()?.to_vec().into(),
+          exit_code: decoder.u64()? as i64 as i32,
+          output_directory: decode_digest(&mut decoder)?,
+          kept_sandbox: None,
+        },
+      )),
+      "Snapshot" => {
+        let digest = decode_digest(&mut decoder)?;
+        let count = decoder.u64()?;
+        let path_stats = (0..count)
+          .map(|_| decode_path_stat(&mut decoder))
+          .collect::<Option<Vec<_>>>()?;
+        NodeResult::Snapshot(fs::Snapshot { digest, path_stats })
+      }
+      _ => return None,
+    };
+    if decoder.is_empty() {
+      Some(result)
+    } else {
+      None
+    }
+  }
+
+  fn is_restorable(context: &Context, result: &NodeResult) -> NodeFuture<bool> {
+    // Process outputs and Snapshots refer to content in the Store, which might have been garbage
+    // collected since they were persisted.
+    let digest = match result {
+      &NodeResult::ProcessResult(ref p) => (p.0).output_directory,
+      &NodeResult::Snapshot(ref s) => s.digest,
+      _ => return future::ok(true).to_boxed(),
+    };
+    context
+      .core
+      .store
+      .contains_directory_recursive(digest)
+      .map_err(|e| throw(&e))
+      .to_boxed()
+  }
+
+  fn is_volatile(&self) -> bool {
+    self.fs_subject().is_some()
+  }
+}
+
+fn path_heap_size(path: &Path) -> usize {
+  path.as_os_str().len()
+}
+
+fn encode_path(encoder: &mut Encoder, path: &Path) {
+  encoder.bytes(path.as_os_str().as_bytes());
+}
+
+fn decode_path(decoder: &mut Decoder) -> Option<PathBuf> {
+  decoder
+    .bytes()
+    .map(|bytes| PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+fn encode_digest(encoder: &mut Encoder, digest: &hashing::Digest) {
+  encoder.bytes(digest.0.as_bytes()).u64(digest.1 as u64);
+}
+
+fn decode_digest(decoder: &mut Decoder) -> Option<hashing::Digest> {
+  let fingerprint = decoder.bytes()?;
+  if fingerprint.len() != mem::size_of::<hashing::Fingerprint>() {
+    return None;
+  }
+  Some(hashing::Digest(
+    hashing::Fingerprint::from_bytes_unsafe(fingerprint),
+    decoder.u64()? as usize,
+  ))
+}
+
+fn encode_stat(encoder: &mut Encoder, stat: &fs::Stat) {
+  match stat {
+    &fs::Stat::Dir(Dir(ref path)) => encode_path(encoder.str("Dir"), path),
+    &fs::Stat::File(ref file) => {
+      encode_path(encoder.str("File"), &file.path);
+      encoder.bool(file.is_executable);
+    }
+    &fs::Stat::Link(Link(ref path)) => encode_path(encoder.str("Link"), path),
+  }
+}
+
+fn decode_stat(decoder: &mut Decoder) -> Option<fs::Stat> {
+  match decoder.string()?.as_str() {
+    "Dir" => Some(fs::Stat::Dir(Dir(decode_path(decoder)?))),
+    "File" => Some(fs::Stat::File(File {
+      path: decode_path(decoder)?,
+      is_executable: decoder.bool()?,
+    })),
+    "Link" => Some(fs::Stat::Link(Link(decode_path(decoder)?))),
+    _ => None,
+  }
+}
+
+fn encode_path_stat(encoder: &mut Encoder, path_stat: &PathStat) {
+  match path_stat {
+    &PathStat::Dir { ref path, ref stat } => {
+      encode_path(encoder, path);
+      encode_stat(encoder, &fs::Stat::Dir(stat.clone()));
+    }
+    &PathStat::File { ref path, ref stat } => {
+      encode_path(encoder, path);
+      encode_stat(encoder, &fs::Stat::File(stat.clone()));
+    }
+    &PathStat::Link {
+      ref path,
+      ref stat,
+      ref target,
+    } => {
+      encode_path(encoder, path);
+      encode_stat(encoder, &fs::Stat::Link(stat.clone()));
+      encode_path(encoder, target);
+    }
+  }
+}
+
+fn decode_path_stat(decoder: &mut Decoder) -> Option<PathStat> {
+  let path = decode_path(decoder)?;
+  match decode_stat(decoder)? {
+    fs::Stat::Dir(stat) => Some(PathStat::Dir { path, stat }),
+    fs::Stat::File(stat) => Some(PathStat::File { path, stat }),
+    fs::Stat::Link(stat) => Some(PathStat::Link {
+      path,
+      stat,
+      target: decode_path(decoder)?,
+    }),
+  }
 }
 
 impl NodeError for Failure {
@@ -1063,8 +1587,8 @@ impl NodeError for Failure {
     Failure::Invalidated
   }
 
-  fn cyclic() -> Failure {
-    Failure::Noop(Noop::Cycle)
+  fn cyclic(path: Vec<String>) -> Failure {
+    Failure::Noop(Noop::Cycle(path))
   }
 }
 
@@ -1,11 +1,16 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::{hash_map, HashMap, HashSet};
+use std::cmp;
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet};
 use std::io;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use core::{Function, Key, TypeConstraint, TypeId, Value, ANY_TYPE};
 use externs;
+use json_encoding as json;
 use selectors::{Get, Select};
 use tasks::{Intrinsic, Task, Tasks};
 
@@ -21,7 +26,9 @@ impl UnreachableError {
       task_rule: task_rule,
       diagnostic: Diagnostic {
         subject_type: ANY_TYPE,
+        product: None,
         reason: "Unreachable".to_string(),
+        rejected_candidates: vec![],
       },
     }
   }
@@ -41,6 +48,13 @@ impl EntryWithDeps {
     }
   }
 
+  fn is_root(&self) -> bool {
+    match self {
+      &EntryWithDeps::Root(_) => true,
+      &EntryWithDeps::Inner(_) => false,
+    }
+  }
+
   fn task_rule(&self) -> Option<&Task> {
     match self {
       &EntryWithDeps::Inner(InnerEntry {
@@ -164,25 +178,104 @@ pub type Entries = Vec<Entry>;
 type RuleDependencyEdges = HashMap<EntryWithDeps, RuleEdges>;
 type RuleDiagnostics = Vec<Diagnostic>;
 type UnfulfillableRuleMap = HashMap<EntryWithDeps, RuleDiagnostics>;
+type AmbiguousRuleMap = HashMap<EntryWithDeps, Vec<Ambiguity>>;
+
+// The maximum number of similarly named types to suggest for a product that no rule provides.
+const MAX_SUGGESTIONS: usize = 3;
 
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
 pub struct Diagnostic {
   subject_type: TypeId,
+  // The product that could not be computed, if any.
+  product: Option<TypeConstraint>,
   reason: String,
+  // Entries that could have computed the product, but which were themselves unfulfillable.
+  rejected_candidates: Entries,
+}
+
+///
+/// Multiple entries which could each provide a product for a subject type, none of which declared
+/// a higher priority than the others.
+///
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
+pub struct Ambiguity {
+  subject_type: TypeId,
+  product: TypeConstraint,
+  candidates: Entries,
+}
+
+///
+/// A displayable description of an Ambiguity.
+///
+#[derive(Clone, Debug)]
+pub struct AmbiguityReport {
+  pub subject_type: String,
+  pub product: String,
+  pub candidates: Vec<String>,
 }
 
+///
+/// The errors for a rule that cannot be used, in a form suitable for reporting to users.
+///
+#[derive(Clone, Debug)]
+pub struct RuleError {
+  pub rule: String,
+  pub diagnostics: Vec<DiagnosticReport>,
+}
+
+///
+/// A displayable description of a Diagnostic.
+///
+#[derive(Clone, Debug)]
+pub struct DiagnosticReport {
+  pub subject_type: String,
+  // The product that could not be computed, if any.
+  pub product: Option<String>,
+  pub reason: String,
+  // Rules that could have computed the product, and the reasons that each was rejected.
+  pub rejected_candidates: Vec<(String, Vec<String>)>,
+  // If no rule could compute the product, known types with similar names.
+  pub suggestions: Vec<String>,
+}
+
+// Construction recurses through the dependencies of each entry, so give its threads the stack size
+// of a typical main thread.
+const CONSTRUCTION_THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+// The candidate entries that could provide each (subject type, product), for a Select or a union.
+type CandidateCache = HashMap<(TypeId, TypeConstraint, bool), Entries>;
+
 // Given the task index and the root subjects, it produces a rule graph that allows dependency nodes
 // to be found statically rather than dynamically.
-pub struct GraphMaker<'t> {
-  tasks: &'t Tasks,
+//
+// Root entries are partitioned by subject type (because roots for a subject type share most of
+// their dependencies), and each partition is expanded on its own thread. The candidates for each
+// (subject type, product) are memoized and shared between the threads, because computing them
+// requires calls to python. A `parallelism` of 1 constructs the graph serially, on the calling
+// thread.
+#[derive(Clone)]
+pub struct GraphMaker {
+  tasks: Arc<Tasks>,
   root_subject_types: Vec<TypeId>,
+  parallelism: usize,
+  // Products which are only consumed via SelectUnion: see `_construct_graph_helper`.
+  union_only_products: Arc<HashSet<TypeConstraint>>,
+  candidates: Arc<Mutex<CandidateCache>>,
 }
 
-impl<'t> GraphMaker<'t> {
-  pub fn new(tasks: &'t Tasks, root_subject_types: Vec<TypeId>) -> GraphMaker<'t> {
+impl GraphMaker {
+  pub fn new(
+    tasks: Arc<Tasks>,
+    root_subject_types: Vec<TypeId>,
+    parallelism: usize,
+  ) -> GraphMaker {
+    let union_only_products = Arc::new(tasks.union_only_product_types());
     GraphMaker {
       tasks: tasks,
       root_subject_types: root_subject_types,
+      parallelism: parallelism,
+      union_only_products: union_only_products,
+      candidates: Arc::new(Mutex::new(HashMap::new())),
     }
   }
 
@@ -199,27 +292,162 @@ impl<'t> GraphMaker<'t> {
   }
 
   pub fn _construct_graph(&self, roots: Vec<RootEntry>) -> RuleGraph {
+    let thread_count = cmp::max(
+      1,
+      cmp::min(self.parallelism, self.root_subject_types.len()),
+    );
+    let mut partitions: Vec<Vec<RootEntry>> = (0..thread_count).map(|_| Vec::new()).collect();
+    for root in roots {
+      let index = self
+        .root_subject_types
+        .iter()
+        .position(|&subject_type| subject_type == root.subject_type)
+        .unwrap_or(0);
+      partitions[index % thread_count].push(root);
+    }
+    partitions.retain(|partition| !partition.is_empty());
+
+    let expanded = if partitions.len() <= 1 {
+      partitions
+        .into_iter()
+        .map(|partition| self.expand(partition))
+        .collect::<Vec<_>>()
+    } else {
+      let handles = partitions
+        .into_iter()
+        .map(|partition| {
+          let graph_maker = self.clone();
+          thread::Builder::new()
+            .name("rule-graph".to_string())
+            .stack_size(CONSTRUCTION_THREAD_STACK_SIZE)
+            .spawn(move || graph_maker.expand(partition))
+            .unwrap_or_else(|e| panic!("Could not spawn rule graph thread: {:?}", e))
+        })
+        .collect::<Vec<_>>();
+      handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+        .collect::<Vec<_>>()
+    };
+
+    // Merge the partitions. Entries which are reachable from roots in more than one partition are
+    // expanded by each of them, so their edges and ambiguities are unioned. An entry is only found
+    // to be fulfillable in one partition but not in another when it participates in a cycle (see
+    // `_construct_graph_helper`), in which case we keep it if any partition was able to use it.
     let mut dependency_edges: RuleDependencyEdges = HashMap::new();
     let mut unfulfillable_rules: UnfulfillableRuleMap = HashMap::new();
-
-    for beginning_root in roots.into_iter() {
-      self._construct_graph_helper(
-        &mut dependency_edges,
-        &mut unfulfillable_rules,
-        EntryWithDeps::Root(beginning_root),
-      );
+    let mut ambiguous_rules: AmbiguousRuleMap = HashMap::new();
+    for (edges, unfulfillable, ambiguous) in expanded {
+      for (entry, entry_edges) in edges {
+        match dependency_edges.entry(entry) {
+          hash_map::Entry::Occupied(mut o) => o.get_mut().merge(entry_edges),
+          hash_map::Entry::Vacant(v) => {
+            v.insert(entry_edges);
+          }
+        }
+      }
+      for (entry, diagnostics) in unfulfillable {
+        unfulfillable_rules.entry(entry).or_insert(diagnostics);
+      }
+      for (entry, ambiguities) in ambiguous {
+        let merged = ambiguous_rules.entry(entry).or_insert_with(Vec::new);
+        for ambiguity in ambiguities {
+          if !merged.contains(&ambiguity) {
+            merged.push(ambiguity);
+          }
+        }
+      }
     }
+    unfulfillable_rules.retain(|entry, _| !dependency_edges.contains_key(entry));
+
+    // Many entries might depend on the same ambiguous product for a subject type: report it once.
+    let ambiguities: HashSet<Ambiguity> = ambiguous_rules
+      .into_iter()
+      .filter(|&(ref entry, _)| dependency_edges.contains_key(entry))
+      .flat_map(|(_, ambiguities)| ambiguities)
+      .collect();
+    // Candidates which might not produce a value for a subject are chosen between at runtime, so
+    // only the candidates which always produce one can be ambiguous.
+    let mut may_noop_entries = HashMap::new();
+    let ambiguities: Vec<Ambiguity> = ambiguities
+      .into_iter()
+      .filter_map(|ambiguity| {
+        let candidates: Entries = ambiguity
+          .candidates
+          .into_iter()
+          .filter(|candidate| !may_noop(&dependency_edges, &mut may_noop_entries, candidate))
+          .collect();
+        if candidates.len() > 1 {
+          Some(Ambiguity {
+            subject_type: ambiguity.subject_type,
+            product: ambiguity.product,
+            candidates: candidates,
+          })
+        } else {
+          None
+        }
+      })
+      .collect();
 
     let unreachable_rules = self.unreachable_rules(&dependency_edges, &unfulfillable_rules);
 
     RuleGraph {
       root_subject_types: self.root_subject_types.clone(),
+      product_types: self.tasks.all_product_types().into_iter().collect(),
       rule_dependency_edges: dependency_edges,
       unfulfillable_rules: unfulfillable_rules,
       unreachable_rules: unreachable_rules,
+      ambiguities: ambiguities,
     }
   }
 
+  ///
+  /// Expands the given roots (and their dependencies), and returns the resulting entries.
+  ///
+  fn expand(
+    &self,
+    roots: Vec<RootEntry>,
+  ) -> (RuleDependencyEdges, UnfulfillableRuleMap, AmbiguousRuleMap) {
+    let mut dependency_edges: RuleDependencyEdges = HashMap::new();
+    let mut unfulfillable_rules: UnfulfillableRuleMap = HashMap::new();
+    let mut ambiguous_rules: AmbiguousRuleMap = HashMap::new();
+
+    for beginning_root in roots.into_iter() {
+      self._construct_graph_helper(
+        &mut dependency_edges,
+        &mut unfulfillable_rules,
+        &mut ambiguous_rules,
+        EntryWithDeps::Root(beginning_root),
+      );
+    }
+    (dependency_edges, unfulfillable_rules, ambiguous_rules)
+  }
+
+  ///
+  /// Returns the (memoized) candidate entries that could provide the given product for the given
+  /// subject type. For a union, a matching subject does not exclude the other candidates.
+  ///
+  fn candidates(
+    &self,
+    subject_type: TypeId,
+    product_type: TypeConstraint,
+    union: bool,
+  ) -> Entries {
+    let key = (subject_type, product_type, union);
+    if let Some(entries) = self.candidates.lock().unwrap().get(&key) {
+      return entries.clone();
+    }
+    // NB: The lock is not held while computing candidates, so that other threads do not block on
+    // it while we call into python.
+    let entries = rhs(&self.tasks, subject_type, &product_type, union);
+    self
+      .candidates
+      .lock()
+      .unwrap()
+      .insert(key, entries.clone());
+    entries
+  }
+
   fn unreachable_rules(
     &self,
     full_dependency_edges: &RuleDependencyEdges,
@@ -255,12 +483,14 @@ impl<'t> GraphMaker<'t> {
   /// `subject_type`.
   ///
   /// When a rule cannot be fulfilled, it is added to `unfulfillable_rules` rather than to
-  /// `rule_dependency_edges`.
+  /// `rule_dependency_edges`. When a fulfillable rule has a dependency which could be provided by
+  /// multiple entries (none of which has the highest priority), it is added to `ambiguous_rules`.
   ///
   fn _construct_graph_helper(
     &self,
     rule_dependency_edges: &mut RuleDependencyEdges,
     unfulfillable_rules: &mut UnfulfillableRuleMap,
+    ambiguous_rules: &mut AmbiguousRuleMap,
     entry: EntryWithDeps,
   ) -> bool {
     // If the entry has not been visited before, store a placeholder in the unfulfillable rules map
@@ -286,40 +516,63 @@ impl<'t> GraphMaker<'t> {
 
     // For each dependency of the rule, recurse for each potential match and collect RuleEdges.
     let mut edges = RuleEdges::new();
+    let mut ambiguities = Vec::new();
     let mut fulfillable = true;
     for select_key in entry.dependency_keys().into_iter() {
-      let (subject, product) = match &select_key {
-        &SelectKey::JustSelect(ref s) => (entry.subject_type(), s.product.clone()),
-        &SelectKey::JustGet(ref g) => (g.subject.clone(), g.product.clone()),
+      let (subject, product, union) = match &select_key {
+        &SelectKey::JustSelect(ref s) => (entry.subject_type(), s.product.clone(), s.union),
+        &SelectKey::JustGet(ref g) => (g.subject.clone(), g.product.clone(), false),
       };
 
       // Confirm that at least one candidate is fulfillable.
-      let fulfillable_candidates = rhs(&self.tasks, subject, &product)
-        .into_iter()
-        .filter(|candidate| match candidate {
-          &Entry::WithDeps(ref c) => {
-            self._construct_graph_helper(rule_dependency_edges, unfulfillable_rules, c.clone())
-          }
-          &Entry::SubjectIsProduct { .. } => true,
-          &Entry::Singleton { .. } => true,
-        })
-        .collect::<Vec<_>>();
+      let (fulfillable_candidates, rejected_candidates): (Vec<_>, Vec<_>) =
+        self
+          .candidates(subject, product, union)
+          .into_iter()
+          .partition(|candidate| match candidate {
+            &Entry::WithDeps(ref c) => self._construct_graph_helper(
+              rule_dependency_edges,
+              unfulfillable_rules,
+              ambiguous_rules,
+              c.clone(),
+            ),
+            &Entry::SubjectIsProduct { .. } => true,
+            &Entry::Singleton { .. } => true,
+          });
 
-      if fulfillable_candidates.is_empty() {
-        // If no candidates were fulfillable, this rule is not fulfillable.
+      if fulfillable_candidates.is_empty() && !union {
+        // If no candidates were fulfillable, this rule is not fulfillable (unless the selector is
+        // a union, which may select any number of products, including none).
         unfulfillable_rules
           .entry(entry.clone())
           .or_insert(vec![])
           .push(Diagnostic {
             subject_type: subject.clone(),
+            product: Some(product.clone()),
             reason: format!(
               "no rule was available to compute {} for subject type {}",
               type_constraint_str(product.clone()),
               type_str(subject.clone())
             ),
+            rejected_candidates: rejected_candidates,
           });
         fulfillable = false;
+      } else if union {
+        edges.add_edges_via(select_key, fulfillable_candidates);
       } else {
+        // Only the candidates with the highest priority are used: if there are multiple, then the
+        // dependency is ambiguous. Roots are generated for every product, so roots for products
+        // which are only consumed via SelectUnion (and so are expected to have many providers)
+        // are exempt.
+        let fulfillable_candidates = prioritize(fulfillable_candidates);
+        let exempt = entry.is_root() && self.union_only_products.contains(&product);
+        if fulfillable_candidates.len() > 1 && !exempt {
+          ambiguities.push(Ambiguity {
+            subject_type: subject,
+            product: product,
+            candidates: fulfillable_candidates.clone(),
+          });
+        }
         // Extend the RuleEdges for this SelectKey.
         edges.add_edges_via(select_key, fulfillable_candidates);
       }
@@ -327,6 +580,9 @@ impl<'t> GraphMaker<'t> {
 
     if fulfillable {
       // All depedendencies were fulfillable: replace the placeholder with the computed RuleEdges.
+      if !ambiguities.is_empty() {
+        ambiguous_rules.insert(entry.clone(), ambiguities);
+      }
       rule_dependency_edges.insert(entry, edges);
       true
     } else {
@@ -354,18 +610,13 @@ impl<'t> GraphMaker<'t> {
     subject_type: &TypeId,
     product_type: &TypeConstraint,
   ) -> Option<RootEntry> {
-    let candidates = rhs(&self.tasks, subject_type.clone(), product_type);
+    let candidates = self.candidates(subject_type.clone(), product_type.clone(), false);
     if candidates.is_empty() {
       None
     } else {
       Some(RootEntry {
         subject_type: subject_type.clone(),
-        clause: vec![
-          Select {
-            product: product_type.clone(),
-            variant_key: None,
-          },
-        ],
+        clause: vec![Select::without_variant(product_type.clone())],
         gets: vec![],
       })
     }
@@ -385,14 +636,18 @@ impl<'t> GraphMaker<'t> {
 ///   types for requests, to the rules that can fulfill them.
 /// `rule_dependency_edges` A map from rule entries to the rule entries they depend on.
 ///   The collections of dependencies are contained by RuleEdges objects.
+/// `product_types` The product types that were provided by rules when this graph was generated.
 /// `unfulfillable_rules` A map of rule entries to collections of Diagnostics
 ///   containing the reasons why they were eliminated from the graph.
+/// `ambiguities` The products for subject types that could be provided by multiple rules.
 #[derive(Debug, Default)]
 pub struct RuleGraph {
   root_subject_types: Vec<TypeId>,
+  product_types: Vec<TypeConstraint>,
   rule_dependency_edges: RuleDependencyEdges,
   unfulfillable_rules: UnfulfillableRuleMap,
   unreachable_rules: Vec<UnreachableError>,
+  ambiguities: Vec<Ambiguity>,
 }
 
 // TODO: Take by reference.
@@ -428,7 +683,8 @@ fn val_name(val: &Value) -> String {
 }
 
 pub fn select_str(select: &Select) -> String {
-  format!("Select({})", type_constraint_str(select.product)).to_string() // TODO variant key
+  let name = if select.union { "SelectUnion" } else { "Select" };
+  format!("{}({})", name, type_constraint_str(select.product)).to_string() // TODO variant key
 }
 
 fn get_str(get: &Get) -> String {
@@ -514,8 +770,12 @@ fn task_display(task: &Task) -> String {
 }
 
 impl RuleGraph {
-  pub fn new(tasks: &Tasks, root_subject_types: Vec<TypeId>) -> RuleGraph {
-    GraphMaker::new(tasks, root_subject_types).full_graph()
+  pub fn new(
+    tasks: Arc<Tasks>,
+    root_subject_types: Vec<TypeId>,
+    parallelism: usize,
+  ) -> RuleGraph {
+    GraphMaker::new(tasks, root_subject_types, parallelism).full_graph()
   }
 
   pub fn find_root_edges(&self, subject_type: TypeId, select: Select) -> Option<RuleEdges> {
@@ -554,8 +814,150 @@ impl RuleGraph {
     }
   }
 
-  pub fn validate(&self) -> Result<(), String> {
-    let mut collated_errors: HashMap<Task, Vec<String>> = HashMap::new();
+  ///
+  /// Returns the paths through the graph by which the given product could be computed for the
+  /// given subject type, sorted. Each path begins with an entry that provides the product, and
+  /// follows dependencies down to an entry that has none. Paths that would revisit an entry are
+  /// omitted.
+  ///
+  /// The number of paths can be exponential in the size of the graph, so at most `max_paths` paths
+  /// are explored (including those that are omitted). The returned bool is true if the search
+  /// was cut short, in which case the returned paths are incomplete.
+  ///
+  pub fn rule_paths(
+    &self,
+    subject_type: TypeId,
+    product: TypeConstraint,
+    max_paths: usize,
+  ) -> (Vec<Vec<String>>, bool) {
+    let select = Select::without_variant(product);
+    let mut paths = BTreeSet::new();
+    let mut remaining = max_paths;
+    let complete = match self.find_root_edges(subject_type, select) {
+      Some(edges) => edges.dependencies.iter().all(|entry| {
+        self.collect_rule_paths(entry, &mut Vec::new(), &mut paths, &mut remaining)
+      }),
+      None => true,
+    };
+    (paths.into_iter().collect(), !complete)
+  }
+
+  ///
+  /// Collects the paths that begin with the given path followed by the given entry, and returns
+  /// false if the search ran out of paths to explore before it was complete.
+  ///
+  fn collect_rule_paths(
+    &self,
+    entry: &Entry,
+    path: &mut Vec<Entry>,
+    paths: &mut BTreeSet<Vec<String>>,
+    remaining: &mut usize,
+  ) -> bool {
+    let dependencies = match entry {
+      &Entry::WithDeps(ref e) => self
+        .rule_dependency_edges
+        .get(e)
+        .map(|edges| edges.dependencies.clone())
+        .unwrap_or_else(|| vec![]),
+      _ => vec![],
+    };
+    let revisits = path.contains(entry);
+    if revisits || dependencies.is_empty() {
+      // This path ends here.
+      if *remaining == 0 {
+        return false;
+      }
+      *remaining -= 1;
+      if !revisits {
+        path.push(entry.clone());
+        paths.insert(path.iter().map(entry_str).collect());
+        path.pop();
+      }
+      return true;
+    }
+    path.push(entry.clone());
+    let complete = dependencies
+      .iter()
+      .all(|dependency| self.collect_rule_paths(dependency, path, paths, remaining));
+    path.pop();
+    complete
+  }
+
+  ///
+  /// Returns the rules that are not used in the graph, sorted.
+  ///
+  pub fn unused_rules(&self) -> Vec<String> {
+    let mut rules: Vec<String> = self
+      .unused_rule_diagnostics()
+      .keys()
+      .map(task_display)
+      .collect();
+    rules.sort();
+    rules
+  }
+
+  ///
+  /// Returns the intrinsic entries that are reachable in the graph, sorted.
+  ///
+  pub fn reached_intrinsics(&self) -> Vec<String> {
+    let mut intrinsics: Vec<String> = self
+      .rule_dependency_edges
+      .keys()
+      .filter(|entry| match entry {
+        &&EntryWithDeps::Inner(InnerEntry {
+          rule: Rule::Intrinsic(_),
+          ..
+        }) => true,
+        _ => false,
+      })
+      .map(entry_with_deps_str)
+      .collect();
+    intrinsics.sort();
+    intrinsics
+  }
+
+  ///
+  /// Returns the errors for each rule that cannot be used, sorted by rule.
+  ///
+  pub fn diagnose(&self) -> Vec<RuleError> {
+    let collated_diagnostics = self.unused_rule_diagnostics();
+    if collated_diagnostics.is_empty() {
+      return vec![];
+    }
+
+    // The names of all known types, from which to suggest alternatives for missing products.
+    let mut known_types: Vec<String> = self
+      .product_types
+      .iter()
+      .map(|&product| type_constraint_str(product))
+      .chain(self.root_subject_types.iter().map(|&t| type_str(t)))
+      .collect();
+    known_types.sort();
+    known_types.dedup();
+
+    let mut errors: Vec<RuleError> = collated_diagnostics
+      .into_iter()
+      .map(|(rule, diagnostics)| {
+        let mut reports: Vec<DiagnosticReport> = diagnostics
+          .iter()
+          .map(|d| self.diagnostic_report(d, &known_types))
+          .collect();
+        reports.sort_by(|a, b| a.reason.cmp(&b.reason));
+        RuleError {
+          rule: task_display(&rule),
+          diagnostics: reports,
+        }
+      })
+      .collect();
+    errors.sort_by(|a, b| a.rule.cmp(&b.rule));
+    errors
+  }
+
+  ///
+  /// Collates the Diagnostics for each rule that is not used anywhere in the graph.
+  ///
+  fn unused_rule_diagnostics(&self) -> HashMap<Task, Vec<Diagnostic>> {
+    let mut collated_diagnostics: HashMap<Task, Vec<Diagnostic>> = HashMap::new();
 
     let used_rules: HashSet<_> = self
       .rule_dependency_edges
@@ -592,28 +994,114 @@ impl RuleGraph {
       if used_rules.contains(&task_rule) {
         continue;
       }
-      for d in diagnostics {
-        collated_errors
-          .entry(task_rule.clone())
-          .or_insert(Vec::new())
-          .push(d.reason.clone());
-      }
+      collated_diagnostics
+        .entry(task_rule.clone())
+        .or_insert(Vec::new())
+        .extend(diagnostics);
     }
+    collated_diagnostics
+  }
 
-    if collated_errors.is_empty() {
-      return Ok(());
+  fn diagnostic_report(&self, diagnostic: &Diagnostic, known_types: &[String]) -> DiagnosticReport {
+    let product = diagnostic.product.map(type_constraint_str);
+    let suggestions = match product {
+      Some(ref product) if diagnostic.rejected_candidates.is_empty() => {
+        similar_names(product, known_types)
+      }
+      _ => vec![],
+    };
+    let rejected_candidates = diagnostic
+      .rejected_candidates
+      .iter()
+      .map(|candidate| {
+        let reasons = match candidate {
+          &Entry::WithDeps(ref c) => self
+            .unfulfillable_rules
+            .get(c)
+            .map(|ds| ds.iter().map(|d| d.reason.clone()).collect())
+            .unwrap_or_else(|| vec![]),
+          _ => vec![],
+        };
+        (entry_str(candidate), reasons)
+      })
+      .collect();
+    DiagnosticReport {
+      subject_type: type_str(diagnostic.subject_type),
+      product: product,
+      reason: diagnostic.reason.clone(),
+      rejected_candidates: rejected_candidates,
+      suggestions: suggestions,
     }
+  }
 
-    let mut msgs: Vec<String> = collated_errors
-      .into_iter()
-      .map(|(rule, mut errors)| {
-        errors.sort();
-        format!("{}:\n    {}", task_display(&rule), errors.join("\n    "))
+  ///
+  /// Returns the products for subject types that could be provided by multiple rules (none of
+  /// which declared a higher priority than the others), sorted by product and then subject type.
+  ///
+  pub fn ambiguities(&self) -> Vec<AmbiguityReport> {
+    let mut reports: Vec<AmbiguityReport> = self
+      .ambiguities
+      .iter()
+      .map(|ambiguity| {
+        let mut candidates: Vec<String> = ambiguity.candidates.iter().map(entry_str).collect();
+        candidates.sort();
+        AmbiguityReport {
+          subject_type: type_str(ambiguity.subject_type),
+          product: type_constraint_str(ambiguity.product),
+          candidates: candidates,
+        }
       })
       .collect();
-    msgs.sort();
+    reports.sort_by(|a, b| {
+      a.product
+        .cmp(&b.product)
+        .then(a.subject_type.cmp(&b.subject_type))
+    });
+    reports
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    let errors = self.diagnose();
+    let ambiguities = self.ambiguities();
+    if errors.is_empty() && ambiguities.is_empty() {
+      return Ok(());
+    }
 
-    Err(format!("Rules with errors: {}\n  {}", msgs.len(), msgs.join("\n  ")).to_string())
+    let mut sections = Vec::new();
+    if !errors.is_empty() {
+      let msgs: Vec<String> = errors
+        .into_iter()
+        .map(|error| {
+          let reasons: Vec<String> = error.diagnostics.into_iter().map(|d| d.reason).collect();
+          format!("{}:\n    {}", error.rule, reasons.join("\n    "))
+        })
+        .collect();
+      sections.push(format!(
+        "Rules with errors: {}\n  {}",
+        msgs.len(),
+        msgs.join("\n  ")
+      ));
+    }
+    if !ambiguities.is_empty() {
+      let msgs: Vec<String> = ambiguities
+        .into_iter()
+        .map(|ambiguity| {
+          format!(
+            "{} for subject type {} could be computed by:\n    {}",
+            ambiguity.product,
+            ambiguity.subject_type,
+            ambiguity.candidates.join("\n    ")
+          )
+        })
+        .collect();
+      sections.push(format!(
+        "Ambiguous rules (declare a priority to choose between them): {}\n  {}",
+        msgs.len(),
+        msgs.join("\n  ")
+      ));
+    }
+
+    Err(sections.join("\n"))
   }
 
   pub fn visualize(&self, f: &mut io::Write) -> io::Result<()> {
@@ -682,6 +1170,76 @@ impl RuleGraph {
     write!(f, "{}\n", internal_rule_strs.join("\n"))?;
     write!(f, "}}")
   }
+
+  ///
+  /// Writes the graph as a JSON object containing the root subject types, each entry in the graph
+  /// with its kind and dependencies, the intrinsics that were reached, and the unused rules. All
+  /// lists are sorted, so that the output is stable.
+  ///
+  pub fn to_json(&self, f: &mut io::Write) -> io::Result<()> {
+    fn list(strs: &[String]) -> String {
+      let values: Vec<String> = strs.iter().map(|s| json::string(s)).collect();
+      format!("[{}]", values.join(", "))
+    }
+
+    let mut root_subject_type_strs = self
+      .root_subject_types
+      .iter()
+      .map(|&t| type_str(t))
+      .collect::<Vec<String>>();
+    root_subject_type_strs.sort();
+
+    let mut entries = self
+      .rule_dependency_edges
+      .iter()
+      .map(|(k, deps)| {
+        let kind = match k {
+          &EntryWithDeps::Root(_) => "root",
+          &EntryWithDeps::Inner(InnerEntry {
+            rule: Rule::Task(_),
+            ..
+          }) => "task",
+          &EntryWithDeps::Inner(InnerEntry {
+            rule: Rule::Intrinsic(_),
+            ..
+          }) => "intrinsic",
+        };
+        let mut dependencies: Vec<String> = deps.dependencies.iter().map(entry_str).collect();
+        dependencies.sort();
+        dependencies.dedup();
+        (entry_with_deps_str(k), kind, dependencies)
+      })
+      .collect::<Vec<_>>();
+    entries.sort();
+
+    write!(f, "{{\n")?;
+    write!(
+      f,
+      "  \"root_subject_types\": {},\n",
+      list(&root_subject_type_strs)
+    )?;
+    write!(f, "  \"entries\": [")?;
+    for (i, &(ref entry, kind, ref dependencies)) in entries.iter().enumerate() {
+      if i > 0 {
+        write!(f, ",")?;
+      }
+      write!(
+        f,
+        "\n    {{\"entry\": {}, \"kind\": {}, \"dependencies\": {}}}",
+        json::string(entry),
+        json::string(kind),
+        list(dependencies)
+      )?;
+    }
+    write!(f, "\n  ],\n")?;
+    write!(
+      f,
+      "  \"reached_intrinsics\": {},\n",
+      list(&self.reached_intrinsics())
+    )?;
+    write!(f, "  \"unused_rules\": {}\n", list(&self.unused_rules()))?;
+    write!(f, "}}\n")
+  }
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, Default)]
@@ -724,21 +1282,173 @@ impl RuleEdges {
       }
     }
   }
+
+  ///
+  /// Adds the edges of another RuleEdges for the same entry (as computed by another partition of
+  /// the graph) to these.
+  ///
+  fn merge(&mut self, other: RuleEdges) {
+    for (select_key, dependencies) in other.dependencies_by_select_key {
+      self.add_edges_via(select_key, dependencies);
+    }
+  }
 }
 
-fn rhs(tasks: &Tasks, subject_type: TypeId, product_type: &TypeConstraint) -> Entries {
-  if externs::satisfied_by_type(product_type, &subject_type) {
+///
+/// Returns the names (most similar first) which are within a small edit distance of the given
+/// name, or which contain it (or are contained by it), ignoring case.
+///
+fn similar_names(name: &str, candidates: &[String]) -> Vec<String> {
+  let name = name.to_lowercase();
+  let max_distance = name.chars().count() / 3;
+  let mut similar: Vec<(usize, &String)> = candidates
+    .iter()
+    .filter_map(|candidate| {
+      let lowered = candidate.to_lowercase();
+      if lowered == name {
+        return None;
+      }
+      let distance = edit_distance(&name, &lowered);
+      let (shorter, longer) = if name.len() < lowered.len() {
+        (&name, &lowered)
+      } else {
+        (&lowered, &name)
+      };
+      let contains = shorter.chars().count() >= 3 && longer.contains(shorter.as_str());
+      if distance <= max_distance || contains {
+        Some((distance, candidate))
+      } else {
+        None
+      }
+    })
+    .collect();
+  similar.sort();
+  similar
+    .into_iter()
+    .take(MAX_SUGGESTIONS)
+    .map(|(_, candidate)| candidate.clone())
+    .collect()
+}
+
+///
+/// The Levenshtein distance between the given strings.
+///
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1];
+    for (j, &cb) in b.iter().enumerate() {
+      let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+      current.push(cmp::min(substitution, cmp::min(previous[j + 1], current[j]) + 1));
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+///
+/// Returns the given candidates which declared the highest priority, or all of them if none
+/// declared a priority. Tasks which did not declare a priority have a lower priority than any
+/// which did. Other candidates (such as intrinsics) cannot declare a priority, so they are never
+/// shadowed by a Task which did: choosing between them is left to the caller.
+///
+fn prioritize(candidates: Entries) -> Entries {
+  fn priority(entry: &Entry) -> Option<i64> {
+    match entry {
+      &Entry::WithDeps(ref e) => e.task_rule().and_then(|task| task.priority),
+      _ => None,
+    }
+  }
+
+  let highest = candidates.iter().map(priority).max().unwrap_or(None);
+  if highest.is_none() {
+    return candidates;
+  }
+  candidates
+    .into_iter()
+    .filter(|candidate| {
+      let is_task = match candidate {
+        &Entry::WithDeps(ref e) => e.task_rule().is_some(),
+        _ => false,
+      };
+      !is_task || priority(candidate) == highest
+    })
+    .collect()
+}
+
+///
+/// Returns true if the given candidate might not produce a value for a subject: if it is a Task
+/// which declares other products (see `Task::declares_other_products`), if it selects a variant,
+/// or if every candidate for one of its Selects might not produce a value. Entries are memoized in
+/// `may_noop_entries`, and an entry which is (recursively) being visited is assumed to produce a
+/// value.
+///
+fn may_noop(
+  dependency_edges: &RuleDependencyEdges,
+  may_noop_entries: &mut HashMap<EntryWithDeps, bool>,
+  candidate: &Entry,
+) -> bool {
+  let entry = match candidate {
+    &Entry::WithDeps(ref entry) => entry,
+    &Entry::SubjectIsProduct { .. } | &Entry::Singleton { .. } => return false,
+  };
+  if let Some(task) = entry.task_rule() {
+    if task.declares_other_products {
+      return true;
+    }
+  }
+  if let Some(&result) = may_noop_entries.get(entry) {
+    return result;
+  }
+
+  may_noop_entries.insert(entry.clone(), false);
+  let result = match dependency_edges.get(entry) {
+    Some(edges) => entry
+      .dependency_keys()
+      .into_iter()
+      .any(|select_key| match &select_key {
+        &SelectKey::JustSelect(ref select) if select.variant_key.is_some() => true,
+        &SelectKey::JustSelect(ref select) if !select.union => {
+          let dependencies = edges.entries_for(&select_key);
+          !dependencies.is_empty()
+            && dependencies
+              .iter()
+              .all(|dependency| may_noop(dependency_edges, may_noop_entries, dependency))
+        }
+        _ => false,
+      }),
+    None => false,
+  };
+  may_noop_entries.insert(entry.clone(), result);
+  result
+}
+
+fn rhs(
+  tasks: &Tasks,
+  subject_type: TypeId,
+  product_type: &TypeConstraint,
+  union: bool,
+) -> Entries {
+  let subject_is_product = externs::satisfied_by_type(product_type, &subject_type);
+  if subject_is_product && !union {
     // NB a matching subject is always picked first
     vec![Entry::new_subject_is_product(subject_type)]
   } else if let Some(&(ref key, _)) = tasks.gen_singleton(product_type) {
     vec![Entry::new_singleton(key.clone(), product_type.clone())]
   } else {
+    // A union selects a matching subject in addition to the products of every rule.
     let mut entries = Vec::new();
-    if let Some(matching_intrinsic) = tasks.gen_intrinsic(product_type) {
-      entries.push(Entry::WithDeps(EntryWithDeps::Inner(InnerEntry {
-        subject_type: subject_type,
-        rule: Rule::Intrinsic(matching_intrinsic.clone()),
-      })));
+    if subject_is_product {
+      entries.push(Entry::new_subject_is_product(subject_type));
+    }
+    if let Some(matching_intrinsics) = tasks.gen_intrinsics(product_type) {
+      entries.extend(matching_intrinsics.iter().map(|intrinsic| {
+        Entry::WithDeps(EntryWithDeps::Inner(InnerEntry {
+          subject_type: subject_type,
+          rule: Rule::Intrinsic(intrinsic.clone()),
+        }))
+      }));
     }
     if let Some(matching_tasks) = tasks.gen_tasks(product_type) {
       entries.extend(matching_tasks.iter().map(|task_rule| {
@@ -5,6 +5,7 @@ use std::collections::{HashMap, HashSet};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use futures::future::{self, Future};
 use futures::sync::oneshot;
@@ -12,8 +13,8 @@ use futures::sync::oneshot;
 use boxfuture::{BoxFuture, Boxable};
 use context::{Context, Core};
 use core::{Failure, Key, TypeConstraint, TypeId, Value};
-use fs::{self, GlobMatching, PosixFS};
-use graph::{EntryId, Graph, Node, NodeContext};
+use fs::{self, GlobMatching, PathGlobs, PosixFS, TransferCounts};
+use graph::{EntryId, Graph, InvalidationCounts, Node, NodeContext};
 use nodes::{NodeKey, Select, Tracer, TryInto, Visualizer};
 use rule_graph;
 use selectors;
@@ -28,6 +29,10 @@ use selectors;
 pub struct Session {
   // The total size of the graph at Session-creation time.
   preceding_graph_size: usize,
+  // The number of completed Node runs, and of bytes transferred, at Session-creation time: a
+  // Scheduler (in pantsd, for example) may outlive many Sessions.
+  preceding_completed: usize,
+  preceding_transfer_counts: TransferCounts,
   // The set of roots that have been requested within this session.
   roots: Mutex<HashSet<Root>>,
 }
@@ -36,6 +41,8 @@ impl Session {
   pub fn new(scheduler: &Scheduler) -> Session {
     Session {
       preceding_graph_size: scheduler.core.graph.len(),
+      preceding_completed: scheduler.core.graph.progress(|_| false).completed,
+      preceding_transfer_counts: scheduler.core.store.transfer_counts(),
       roots: Mutex::new(HashSet::new()),
     }
   }
@@ -51,6 +58,21 @@ impl Session {
   }
 }
 
+///
+/// A snapshot of the progress of the executions in a Session, for rendering to users.
+///
+pub struct ExecutionProgress {
+  // The numbers of Node runs which are queued and running (in the Scheduler as a whole), and which
+  // have completed during the Session.
+  pub queued: usize,
+  pub running: usize,
+  pub completed: usize,
+  // The descriptions of the running process executions, and how long they have been running for.
+  pub running_processes: Vec<(String, Duration)>,
+  // The bytes transferred to and from the remote store during the Session.
+  pub transfer_counts: TransferCounts,
+}
+
 pub struct ExecutionRequest {
   // Set of roots for an execution, in the order they were declared.
   pub roots: Vec<Root>,
@@ -91,6 +113,47 @@ impl Scheduler {
       .visualize(Visualizer::default(), &session.root_nodes(), path)
   }
 
+  ///
+  /// Exports the subgraph reachable from the roots of the given Session (or the entire graph, if
+  /// `all` is true) as JSON lines: see `Graph::export_json`.
+  ///
+  pub fn export_json(&self, session: &Session, all: bool, path: &Path) -> io::Result<usize> {
+    if all {
+      self.core.graph.export_json(None, path)
+    } else {
+      self
+        .core
+        .graph
+        .export_json(Some(&session.root_nodes()), path)
+    }
+  }
+
+  ///
+  /// Exports the timings of the subgraph reachable from the roots of the given Session (or of the
+  /// entire graph, if `all` is true) as a Chrome trace: see `Graph::export_chrome_trace`. Process
+  /// executions are rendered in lanes of their own.
+  ///
+  pub fn export_chrome_trace(
+    &self,
+    session: &Session,
+    all: bool,
+    path: &Path,
+  ) -> io::Result<usize> {
+    let roots = if all {
+      None
+    } else {
+      Some(session.root_nodes())
+    };
+    self.core.graph.export_chrome_trace(
+      roots.as_ref().map(|roots| roots.as_slice()),
+      |node| match node {
+        &NodeKey::ExecuteProcess(_) => Some("Process executions"),
+        _ => None,
+      },
+      path,
+    )
+  }
+
   pub fn trace(&self, request: &ExecutionRequest, path: &Path) -> io::Result<()> {
     for root in request.root_nodes() {
       self.core.graph.trace::<Tracer>(&root, path)?;
@@ -136,13 +199,50 @@ impl Scheduler {
   /// Invalidate the invalidation roots represented by the given Paths.
   ///
   pub fn invalidate(&self, paths: HashSet<PathBuf>) -> usize {
-    self.core.graph.invalidate_from_roots(move |node| {
-      if let Some(fs_subject) = node.fs_subject() {
-        paths.contains(fs_subject)
-      } else {
-        false
-      }
-    })
+    invalidate_paths(&self.core.graph, &paths)
+  }
+
+  ///
+  /// Invalidate the invalidation roots whose paths are matched by the given PathGlobs, along with
+  /// the listings of the directories that might contain matched paths (as `invalidate` expects
+  /// callers to do for changed paths), and return the number of Nodes that were invalidated per
+  /// type.
+  ///
+  pub fn invalidate_globs(
+    &self,
+    path_globs: &PathGlobs,
+  ) -> HashMap<&'static str, InvalidationCounts> {
+    self.core.graph.invalidate_from_roots_by(
+      |node| match (node, node.fs_subject()) {
+        (&NodeKey::Scandir(_), Some(dir)) => {
+          path_globs.matches(dir) || path_globs.matches_in_dir(dir)
+        }
+        (_, Some(path)) => path_globs.matches(path),
+        (_, None) => false,
+      },
+      NodeKey::type_name,
+    )
+  }
+
+  ///
+  /// Invalidate all Nodes of the given type (see `NodeKey::type_name`), and return the number of
+  /// Nodes that were invalidated per type.
+  ///
+  pub fn invalidate_node_type(
+    &self,
+    type_name: &str,
+  ) -> Result<HashMap<&'static str, InvalidationCounts>, String> {
+    if !NodeKey::TYPE_NAMES.iter().any(|&name| name == type_name) {
+      return Err(format!(
+        "Unrecognized node type {:?}: must be one of {:?}.",
+        type_name,
+        NodeKey::TYPE_NAMES
+      ));
+    }
+    Ok(self.core.graph.invalidate_from_roots_by(
+      |node| node.type_name() == type_name,
+      NodeKey::type_name,
+    ))
   }
 
   ///
@@ -159,9 +259,62 @@ impl Scheduler {
     );
     m.insert("preceding_graph_size", session.preceding_graph_size as i64);
     m.insert("resulting_graph_size", self.core.graph.len() as i64);
+    m.insert("resulting_graph_estimated_bytes", self.core.graph.estimated_bytes() as i64);
+    m.insert("graph_evicted_count", self.core.graph.evicted_count() as i64);
+    m.insert("graph_restored_count", self.core.graph.restored_count() as i64);
+    m.insert(
+      "watcher_invalidation_count",
+      self.core.vfs.watcher_invalidation_count() as i64,
+    );
     m
   }
 
+  ///
+  /// Returns the progress of the executions in the given Session. Safe to call while the Session
+  /// is executing.
+  ///
+  pub fn progress(&self, session: &Session) -> ExecutionProgress {
+    let progress = self.core.graph.progress(|node| match node {
+      &NodeKey::ExecuteProcess(_) => true,
+      _ => false,
+    });
+    let running_processes = progress
+      .running_nodes
+      .into_iter()
+      .filter_map(|(node, elapsed)| match node {
+        NodeKey::ExecuteProcess(process) => Some((process.description().to_string(), elapsed)),
+        _ => None,
+      })
+      .collect();
+    ExecutionProgress {
+      queued: progress.queued,
+      running: progress.running,
+      completed: progress
+        .completed
+        .saturating_sub(session.preceding_completed),
+      running_processes: running_processes,
+      transfer_counts: self
+        .core
+        .store
+        .transfer_counts()
+        .since(&session.preceding_transfer_counts),
+    }
+  }
+
+  ///
+  /// Returns the critical path through the subgraph executed for the roots of the given Session,
+  /// as a chain of formatted Nodes and their self-times: see `Graph::critical_path`.
+  ///
+  pub fn critical_path(&self, session: &Session) -> Vec<(String, Duration)> {
+    self
+      .core
+      .graph
+      .critical_path(&session.root_nodes())
+      .into_iter()
+      .map(|(node, self_time)| (node.format(), self_time))
+      .collect()
+  }
+
   ///
   /// Attempts to complete all of the given roots, retrying the entire set (up to `count`
   /// times) if any of them fail with `Failure::Invalidated`.
@@ -225,6 +378,11 @@ impl Scheduler {
     request: &'e ExecutionRequest,
     session: &Session,
   ) -> Vec<(&'e Key, &'e TypeConstraint, RootResult)> {
+    // If the process forked since the last execution, the filesystem watcher was paused. Apply
+    // the invalidations for any changes it was notified of meanwhile before running the roots.
+    self.core.vfs.resume_watcher();
+    self.core.vfs.flush_watcher();
+
     // Bootstrap tasks for the roots, and then wait for all of them.
     debug!("Launching {} roots.", request.roots.len());
 
@@ -239,6 +397,12 @@ impl Scheduler {
       .wait()
       .expect("Execution failed.");
 
+    // Now that the roots have completed, bound the size of the Graph.
+    let evicted = self.core.graph.evict();
+    if evicted > 0 {
+      debug!("Evicted {} least recently used nodes.", evicted);
+    }
+
     request
       .roots
       .iter()
@@ -315,3 +479,25 @@ impl NodeContext for RootContext {
     &self.core.graph
   }
 }
+
+///
+/// Invalidates the Nodes in the given Graph which represent the given (relative) filesystem
+/// paths, and returns the number of Nodes which were invalidated.
+///
+pub fn invalidate_paths(graph: &Graph<NodeKey>, paths: &HashSet<PathBuf>) -> usize {
+  graph.invalidate_from_roots(|node| {
+    if let Some(fs_subject) = node.fs_subject() {
+      paths.contains(fs_subject)
+    } else {
+      false
+    }
+  })
+}
+
+///
+/// Invalidates all of the Nodes in the given Graph which represent filesystem paths, and returns
+/// the number of Nodes which were invalidated.
+///
+pub fn invalidate_all_paths(graph: &Graph<NodeKey>) -> usize {
+  graph.invalidate_from_roots(|node| node.fs_subject().is_some())
+}
@@ -13,6 +13,8 @@ pub struct Get {
 pub struct Select {
   pub product: TypeConstraint,
   pub variant_key: Option<String>,
+  // If true, selects a tuple of the products of every matching rule, rather than exactly one.
+  pub union: bool,
 }
 
 impl Select {
@@ -20,6 +22,15 @@ impl Select {
     Select {
       product: product,
       variant_key: None,
+      union: false,
+    }
+  }
+
+  pub fn union(product: TypeConstraint) -> Select {
+    Select {
+      product: product,
+      variant_key: None,
+      union: true,
     }
   }
 }
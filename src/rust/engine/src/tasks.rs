@@ -15,6 +15,13 @@ pub struct Task {
   pub gets: Vec<Get>,
   pub func: Function,
   pub cacheable: bool,
+  // If set, this Task is preferred over Tasks with lower (or undeclared) priorities that provide
+  // the same product for a subject.
+  pub priority: Option<i64>,
+  // True if this Task was registered for one of several product types that it declares, and so
+  // might produce a value of another of them instead (as for the Structs hydrated for a symbol
+  // table).
+  pub declares_other_products: bool,
 }
 
 ///
@@ -22,8 +29,8 @@ pub struct Task {
 ///
 #[derive(Clone)]
 pub struct Tasks {
-  // output product type -> Intrinsic providing it
-  intrinsics: HashMap<TypeConstraint, Intrinsic, FNV>,
+  // output product type -> Intrinsics providing it
+  intrinsics: HashMap<TypeConstraint, Vec<Intrinsic>, FNV>,
   // Singleton Values to be returned for a given TypeConstraint.
   singletons: HashMap<TypeConstraint, (Key, Value), FNV>,
   // output product type -> list of tasks providing it
@@ -67,11 +74,38 @@ impl Tasks {
     self.tasks.values().flat_map(|tasks| tasks).collect()
   }
 
+  ///
+  /// Returns the product types which are selected via a SelectUnion, and which are not otherwise
+  /// selected or requested by any Task or Intrinsic.
+  ///
+  pub fn union_only_product_types(&self) -> HashSet<TypeConstraint> {
+    let mut unions = HashSet::new();
+    let mut others = HashSet::new();
+    for task in self.all_tasks() {
+      for select in &task.clause {
+        if select.union {
+          unions.insert(select.product);
+        } else {
+          others.insert(select.product);
+        }
+      }
+      others.extend(task.gets.iter().map(|get| get.product));
+    }
+    others.extend(
+      self
+        .intrinsics
+        .values()
+        .flat_map(|intrinsics| intrinsics)
+        .map(|intrinsic| intrinsic.input),
+    );
+    unions.difference(&others).cloned().collect()
+  }
+
   pub fn gen_singleton(&self, product: &TypeConstraint) -> Option<&(Key, Value)> {
     self.singletons.get(product)
   }
 
-  pub fn gen_intrinsic(&self, product: &TypeConstraint) -> Option<&Intrinsic> {
+  pub fn gen_intrinsics(&self, product: &TypeConstraint) -> Option<&Vec<Intrinsic>> {
     self.intrinsics.get(product)
   }
 
@@ -80,12 +114,27 @@ impl Tasks {
   }
 
   pub fn intrinsics_set(&mut self, types: &Types) {
-    self.intrinsics = vec![
+    let intrinsics = vec![
       Intrinsic {
         kind: IntrinsicKind::Snapshot,
         product: types.snapshot,
         input: types.path_globs,
       },
+      Intrinsic {
+        kind: IntrinsicKind::SnapshotSubset,
+        product: types.snapshot,
+        input: types.snapshot_subset,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::StripPrefix,
+        product: types.directory_digest,
+        input: types.directory_with_prefix_to_strip,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::AddPrefix,
+        product: types.directory_digest,
+        input: types.directory_with_prefix_to_add,
+      },
       Intrinsic {
         kind: IntrinsicKind::FilesContent,
         product: types.files_content,
@@ -96,9 +145,15 @@ impl Tasks {
         product: types.process_result,
         input: types.process_request,
       },
-    ].into_iter()
-      .map(|i| (i.product.clone(), i))
-      .collect();
+    ];
+    self.intrinsics = Default::default();
+    for intrinsic in intrinsics {
+      self
+        .intrinsics
+        .entry(intrinsic.product)
+        .or_insert_with(Vec::new)
+        .push(intrinsic);
+    }
   }
 
   pub fn singleton_add(&mut self, value: Value, product: TypeConstraint) {
@@ -128,9 +183,27 @@ impl Tasks {
       clause: Vec::new(),
       gets: Vec::new(),
       func: func,
+      priority: None,
+      declares_other_products: false,
     });
   }
 
+  pub fn set_priority(&mut self, priority: i64) {
+    self
+      .preparing
+      .as_mut()
+      .expect("Must `begin()` a task creation before setting its priority!")
+      .priority = Some(priority);
+  }
+
+  pub fn set_declares_other_products(&mut self) {
+    self
+      .preparing
+      .as_mut()
+      .expect("Must `begin()` a task creation before setting its products!")
+      .declares_other_products = true;
+  }
+
   pub fn add_get(&mut self, product: TypeConstraint, subject: TypeId) {
     self
       .preparing
@@ -152,9 +225,19 @@ impl Tasks {
       .push(Select {
         product: product,
         variant_key: variant_key,
+        union: false,
       });
   }
 
+  pub fn add_select_union(&mut self, product: TypeConstraint) {
+    self
+      .preparing
+      .as_mut()
+      .expect("Must `begin()` a task creation before adding clauses!")
+      .clause
+      .push(Select::union(product));
+  }
+
   pub fn task_end(&mut self) {
     // Move the task from `preparing` to the Tasks map
     let mut task = self
@@ -188,6 +271,9 @@ pub struct Intrinsic {
 #[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
 pub enum IntrinsicKind {
   Snapshot,
+  SnapshotSubset,
+  StripPrefix,
+  AddPrefix,
   FilesContent,
   ProcessExecution,
 }
@@ -16,6 +16,9 @@ pub struct Types {
   pub path_globs: TypeConstraint,
   pub directory_digest: TypeConstraint,
   pub snapshot: TypeConstraint,
+  pub snapshot_subset: TypeConstraint,
+  pub directory_with_prefix_to_strip: TypeConstraint,
+  pub directory_with_prefix_to_add: TypeConstraint,
   pub files_content: TypeConstraint,
   pub dir: TypeConstraint,
   pub file: TypeConstraint,
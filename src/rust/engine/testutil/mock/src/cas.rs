@@ -14,11 +14,16 @@ use testutil::data::{TestData, TestDirectory};
 /// Implements the ContentAddressableStorage gRPC API, answering read requests with either known
 /// content, NotFound for valid but unknown content, or InvalidArguments for bad arguments.
 ///
+/// Also implements the ActionCache gRPC API, answering requests for the ActionResults in
+/// `action_results` (keyed by the Fingerprint of their Action), and NotFound otherwise.
+///
 pub struct StubCAS {
   server_transport: grpcio::Server,
   read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
   pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  pub action_results:
+    Arc<Mutex<HashMap<Fingerprint, bazel_protos::remote_execution::ActionResult>>>,
 }
 
 impl StubCAS {
@@ -53,9 +58,11 @@ impl StubCAS {
     let read_request_count = Arc::new(Mutex::new(0));
     let write_message_sizes = Arc::new(Mutex::new(Vec::new()));
     let blobs = Arc::new(Mutex::new(blobs));
+    let action_results = Arc::new(Mutex::new(HashMap::new()));
     let responder = StubCASResponder {
       chunk_size_bytes: chunk_size_bytes,
       blobs: blobs.clone(),
+      action_results: action_results.clone(),
       read_request_count: read_request_count.clone(),
       write_message_sizes: write_message_sizes.clone(),
     };
@@ -66,6 +73,9 @@ impl StubCAS {
       .register_service(
         bazel_protos::remote_execution_grpc::create_content_addressable_storage(responder.clone()),
       )
+      .register_service(bazel_protos::remote_execution_grpc::create_action_cache(
+        responder.clone(),
+      ))
       .bind("localhost", 0)
       .build()
       .unwrap();
@@ -76,6 +86,7 @@ impl StubCAS {
       read_request_count,
       write_message_sizes,
       blobs,
+      action_results,
     }
   }
 
@@ -112,6 +123,7 @@ impl StubCAS {
 pub struct StubCASResponder {
   chunk_size_bytes: i64,
   blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
+  action_results: Arc<Mutex<HashMap<Fingerprint, bazel_protos::remote_execution::ActionResult>>>,
   pub read_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
 }
@@ -409,3 +421,50 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     ));
   }
 }
+
+impl bazel_protos::remote_execution_grpc::ActionCache for StubCASResponder {
+  fn get_action_result(
+    &self,
+    _ctx: grpcio::RpcContext,
+    req: bazel_protos::remote_execution::GetActionResultRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ActionResult>,
+  ) {
+    if self.should_always_fail() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let digest: Result<Digest, String> = req.get_action_digest().into();
+    let digest = match digest {
+      Ok(digest) => digest,
+      Err(err) => {
+        sink.fail(grpcio::RpcStatus::new(
+          grpcio::RpcStatusCode::InvalidArgument,
+          Some(format!("Bad action digest: {}", err)),
+        ));
+        return;
+      }
+    };
+    match self.action_results.lock().unwrap().get(&digest.0) {
+      Some(action_result) => sink.success(action_result.clone()),
+      None => sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::NotFound,
+        Some(format!("Did not find ActionResult for {}", digest.0)),
+      )),
+    };
+  }
+
+  fn update_action_result(
+    &self,
+    _ctx: grpcio::RpcContext,
+    _req: bazel_protos::remote_execution::UpdateActionResultRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::ActionResult>,
+  ) {
+    sink.fail(grpcio::RpcStatus::new(
+      grpcio::RpcStatusCode::Unimplemented,
+      None,
+    ));
+  }
+}
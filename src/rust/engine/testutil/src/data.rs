@@ -209,6 +209,49 @@ impl TestDirectory {
     TestDirectory { directory }
   }
 
+  // Directory structure:
+  //
+  // /cats/roland
+  // /roland -> cats/roland
+  pub fn with_symlink() -> TestDirectory {
+    let mut directory = TestDirectory::nested().directory();
+    directory.mut_symlinks().push({
+      let mut symlink = bazel_protos::remote_execution::SymlinkNode::new();
+      symlink.set_name("roland".to_string());
+      symlink.set_target("cats/roland".to_string());
+      symlink
+    });
+    TestDirectory { directory }
+  }
+
+  // Directory structure:
+  //
+  // /cats/roland
+  // /dogs (empty)
+  // /roland -> cats/roland
+  pub fn with_symlink_and_empty_directory() -> TestDirectory {
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_directories().push({
+      let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+      subdir.set_name("cats".to_string());
+      subdir.set_digest((&TestDirectory::containing_roland().digest()).into());
+      subdir
+    });
+    directory.mut_directories().push({
+      let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+      subdir.set_name("dogs".to_string());
+      subdir.set_digest((&TestDirectory::empty().digest()).into());
+      subdir
+    });
+    directory.mut_symlinks().push({
+      let mut symlink = bazel_protos::remote_execution::SymlinkNode::new();
+      symlink.set_name("roland".to_string());
+      symlink.set_target("cats/roland".to_string());
+      symlink
+    });
+    TestDirectory { directory }
+  }
+
   pub fn directory(&self) -> bazel_protos::remote_execution::Directory {
     self.directory.clone()
   }
@@ -0,0 +1,85 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import (absolute_import, division, generators, nested_scopes, print_function,
+                        unicode_literals, with_statement)
+
+import json
+import sys
+import time
+
+from pants.engine.rules import RootRule, TaskRule
+from pants.engine.selectors import Get, Select
+from pants.option.global_options import DEFAULT_EXECUTION_OPTIONS
+from pants_test.engine.util import create_scheduler
+
+
+# (root subject types, product types) for each synthetic rule set.
+SIZES = [(2, 50), (4, 100), (8, 200), (16, 400)]
+
+# The maximum numbers of threads to construct each rule graph with: serially, and by default.
+PARALLELISMS = [1, DEFAULT_EXECUTION_OPTIONS.rule_graph_parallelism]
+
+
+def noop(*args):
+  pass
+
+
+def synthetic_rules(subject_count, product_count):
+  """Creates a set of rules over synthetic types.
+
+  Each subject type can produce the first product, and each following product has one rule that
+  selects two earlier products for the same subject, and one rule that requests an earlier product
+  for a different subject type. Every rule is reachable from every root subject type.
+  """
+  subjects = [type(str('Subject{}'.format(i)), (object,), {}) for i in range(subject_count)]
+  products = [type(str('Product{}'.format(i)), (object,), {}) for i in range(product_count)]
+
+  rules = [RootRule(subject) for subject in subjects]
+  rules.extend(TaskRule(products[0], [Select(subject)], noop) for subject in subjects)
+  for i, product in enumerate(products[1:], start=1):
+    rules.append(TaskRule(product, [Select(products[i - 1]), Select(products[i // 2])], noop))
+    rules.append(TaskRule(product, [], noop,
+                          input_gets=[Get(products[i - 1], subjects[i % subject_count])]))
+  return rules
+
+
+def time_construction(rules, parallelism):
+  """Returns the number of seconds taken to create a Scheduler for the rules."""
+  execution_options = DEFAULT_EXECUTION_OPTIONS._replace(rule_graph_parallelism=parallelism)
+  start = time.time()
+  create_scheduler(rules, validate=False, execution_options=execution_options)
+  return time.time() - start
+
+
+def main():
+  """Measures the time taken to construct the rule graph for increasingly large rule sets.
+
+  The rule graph is constructed when a Scheduler is created, so this includes its (small) fixed
+  cost. Each rule set is constructed both serially and in parallel on the same machine, and the
+  speedup of parallel construction is reported. If a path is given as the first argument, the
+  results are also recorded to it as JSON, so that runs can be compared over time.
+  """
+  results = []
+  for subject_count, product_count in SIZES:
+    rules = synthetic_rules(subject_count, product_count)
+    seconds = {parallelism: time_construction(rules, parallelism) for parallelism in PARALLELISMS}
+    serial_seconds = seconds[PARALLELISMS[0]]
+    for parallelism in PARALLELISMS:
+      speedup = serial_seconds / seconds[parallelism]
+      print('{:>3} subject types, {:>4} product types, {:>5} rules, {:>2} threads: {:>8.3f}s '
+            '({:.2f}x)'.format(subject_count, product_count, len(rules), parallelism,
+                               seconds[parallelism], speedup))
+      results.append({
+        'subject_types': subject_count,
+        'product_types': product_count,
+        'rules': len(rules),
+        'threads': parallelism,
+        'seconds': seconds[parallelism],
+        'speedup': speedup,
+      })
+
+  if len(sys.argv) > 1:
+    with open(sys.argv[1], 'w') as f:
+      json.dump(results, f, indent=2, sort_keys=True)
@@ -147,7 +147,8 @@ class GraphInvalidationTest(GraphTestBase):
 
       invalidated_count = scheduler.invalidate_files(['3rdparty/python/BUILD'])
       self.assertGreater(invalidated_count, 0)
-      self.assertLess(scheduler.node_count(), initial_node_count)
+      # Invalidated Nodes are marked dirty rather than removed.
+      self.assertEquals(scheduler.node_count(), initial_node_count)
 
   def test_invalidate_fsnode_incremental(self):
     with self.open_scheduler(['//:', '3rdparty/::']) as (_, _, scheduler):
@@ -161,8 +162,7 @@ class GraphInvalidationTest(GraphTestBase):
         self.assertGreater(invalidated_count,
                            0,
                            'File {} did not invalidate any Nodes.'.format(filename))
-        node_count, last_node_count = scheduler.node_count(), node_count
-        self.assertLess(node_count, last_node_count)
+        self.assertEquals(scheduler.node_count(), node_count)
 
   def _ordering_test(self, spec, expected_sources=None):
     expected_sources = expected_sources or ['p', 'a', 'n', 't', 's', 'b', 'u', 'i', 'l', 'd']
@@ -48,16 +48,18 @@ class SchedulerTestBase(object):
                    rules=None,
                    project_tree=None,
                    work_dir=None,
-                   include_trace_on_error=True):
+                   include_trace_on_error=True,
+                   execution_options=None):
     """Creates a SchedulerSession for a Scheduler with the given Rules installed."""
     rules = rules or []
+    execution_options = execution_options or DEFAULT_EXECUTION_OPTIONS
     work_dir = work_dir or self._create_work_dir()
     project_tree = project_tree or self.mk_fs_tree(work_dir=work_dir)
     scheduler = Scheduler(self._native,
                           project_tree,
                           work_dir,
                           rules,
-                          DEFAULT_EXECUTION_OPTIONS,
+                          execution_options,
                           include_trace_on_error=include_trace_on_error)
     return scheduler.new_session()
 
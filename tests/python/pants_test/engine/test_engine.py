@@ -12,7 +12,7 @@ from textwrap import dedent
 from pants.build_graph.address import Address
 from pants.engine.nodes import Return
 from pants.engine.rules import RootRule, TaskRule, rule
-from pants.engine.selectors import Get, Select
+from pants.engine.selectors import Get, Select, SelectUnion
 from pants.util.contextutil import temporary_dir
 from pants.util.objects import datatype
 from pants_test.engine.examples.planners import Classpath, setup_json_scheduler
@@ -85,6 +85,28 @@ def fib(n):
   yield Fib(x.val + y.val)
 
 
+class Lint(datatype(['linter'])): pass
+
+
+class LintResults(datatype(['linters'])): pass
+
+
+def flake8(b):
+  return Lint('flake8')
+
+
+def pylint(b):
+  return Lint('pylint')
+
+
+def default_lint():
+  return Lint('pylint')
+
+
+def lint_results(lints):
+  return LintResults(tuple(sorted(lint.linter for lint in lints)))
+
+
 class EngineTest(unittest.TestCase, SchedulerTestBase):
 
   assert_equal_with_printing = assert_equal_with_printing
@@ -103,6 +125,42 @@ class EngineTest(unittest.TestCase, SchedulerTestBase):
 
     self.assertEqual(55, fib_10.val)
 
+  def test_union_merges_all_products(self):
+    rules = [
+      RootRule(B),
+      TaskRule(Lint, [Select(B)], flake8),
+      TaskRule(Lint, [Select(B)], pylint),
+      TaskRule(LintResults, [SelectUnion(Lint)], lint_results),
+    ]
+
+    results, = self.mk_scheduler(rules=rules).product_request(LintResults, subjects=[B()])
+
+    self.assertEqual(('flake8', 'pylint'), results.linters)
+
+  def test_union_includes_subject_and_products(self):
+    # When the subject is itself an instance of the product, it is merged with the products of the
+    # rules, rather than replacing them.
+    rules = [
+      RootRule(Lint),
+      TaskRule(Lint, [], default_lint),
+      TaskRule(LintResults, [SelectUnion(Lint)], lint_results),
+    ]
+
+    results, = self.mk_scheduler(rules=rules).product_request(LintResults,
+                                                              subjects=[Lint('custom')])
+
+    self.assertEqual(('custom', 'pylint'), results.linters)
+
+  def test_union_without_products(self):
+    rules = [
+      RootRule(B),
+      TaskRule(LintResults, [SelectUnion(Lint)], lint_results),
+    ]
+
+    results, = self.mk_scheduler(rules=rules).product_request(LintResults, subjects=[B()])
+
+    self.assertEqual((), results.linters)
+
   def test_no_include_trace_error_raises_boring_error(self):
     rules = [
       RootRule(B),
@@ -12,8 +12,9 @@ import unittest
 from contextlib import contextmanager
 
 from pants.base.project_tree import Dir, Link
-from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, FilesContent, PathGlobs,
-                             PathGlobsAndRoot, Snapshot, create_fs_rules)
+from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, DirectoryWithPrefixToAdd,
+                             DirectoryWithPrefixToStrip, FilesContent, PathGlobs,
+                             PathGlobsAndRoot, Snapshot, SnapshotSubset, create_fs_rules)
 from pants.util.contextutil import temporary_dir
 from pants.util.meta import AbstractClass
 from pants_test.engine.scheduler_test_base import SchedulerTestBase
@@ -357,6 +358,76 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
 
       self.assertEquals(both_snapshot.directory_digest, both_merged)
 
+  def test_merge_directories_with_policy(self):
+    with temporary_dir() as temp_dir:
+      os.mkdir(os.path.join(temp_dir, "first"))
+      os.mkdir(os.path.join(temp_dir, "second"))
+      with open(os.path.join(temp_dir, "first", "roland"), "w") as f:
+        f.write("European Burmese")
+      with open(os.path.join(temp_dir, "second", "roland"), "w") as f:
+        f.write("Not sure actually")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (first_snapshot, second_snapshot) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), os.path.join(temp_dir, "first")),
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), os.path.join(temp_dir, "second")),
+      ))
+      digests = (first_snapshot.directory_digest, second_snapshot.directory_digest)
+
+      with self.assertRaises(Exception) as cm:
+        scheduler.merge_directories(digests)
+      self.assertIn("roland", str(cm.exception))
+
+      self.assertEquals(
+        first_snapshot.directory_digest,
+        scheduler.merge_directories(digests, merge_policy='first_wins'),
+      )
+      self.assertEquals(
+        second_snapshot.directory_digest,
+        scheduler.merge_directories(digests, merge_policy='last_wins'),
+      )
+
+  def test_snapshot_subset(self):
+    with temporary_dir() as temp_dir:
+      with open(os.path.join(temp_dir, "roland"), "w") as f:
+        f.write("European Burmese")
+      with open(os.path.join(temp_dir, "susannah"), "w") as f:
+        f.write("Not sure actually")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (roland_snapshot, both_snapshot) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), temp_dir),
+        PathGlobsAndRoot(PathGlobs(("*",), ()), temp_dir),
+      ))
+
+      subset = self.execute(
+        scheduler,
+        Snapshot,
+        SnapshotSubset(both_snapshot.directory_digest, PathGlobs(("roland",), ())),
+      )[0]
+      self.assert_snapshot_equals(subset, ["roland"], roland_snapshot.directory_digest)
+
+  def test_add_and_strip_prefix(self):
+    with temporary_dir() as temp_dir:
+      with open(os.path.join(temp_dir, "roland"), "w") as f:
+        f.write("European Burmese")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (roland_snapshot,) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), temp_dir),
+      ))
+
+      prefixed = self.execute(
+        scheduler,
+        DirectoryDigest,
+        DirectoryWithPrefixToAdd(roland_snapshot.directory_digest, "cats/and"),
+      )[0]
+      self.assertNotEqual(roland_snapshot.directory_digest, prefixed)
+
+      stripped = self.execute(
+        scheduler,
+        DirectoryDigest,
+        DirectoryWithPrefixToStrip(prefixed, "cats/and"),
+      )[0]
+      self.assertEqual(roland_snapshot.directory_digest, stripped)
+
   def test_glob_match_error(self):
     with self.assertRaises(ValueError) as cm:
       self.assert_walk_files(PathGlobs(
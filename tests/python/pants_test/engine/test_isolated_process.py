@@ -6,6 +6,7 @@ from __future__ import (absolute_import, division, generators, nested_scopes, pr
                         unicode_literals, with_statement)
 
 import os
+import shutil
 import tarfile
 import unittest
 
@@ -16,6 +17,7 @@ from pants.engine.isolated_process import (ExecuteProcessRequest, ExecuteProcess
                                            create_process_rules)
 from pants.engine.rules import RootRule, rule
 from pants.engine.selectors import Get, Select
+from pants.option.global_options import DEFAULT_EXECUTION_OPTIONS
 from pants.util.objects import TypeCheckError, datatype
 from pants_test.engine.scheduler_test_base import SchedulerTestBase
 
@@ -402,6 +404,35 @@ class IsolatedProcessTest(SchedulerTestBase, unittest.TestCase):
       self.execute_raising_throw(scheduler, ExecuteProcessResult, request)
     self.assertIn("process 'one-cat' failed with exit code 1.", str(cm.exception))
 
+  def test_failing_command_reports_kept_sandbox(self):
+    execution_options = DEFAULT_EXECUTION_OPTIONS._replace(
+      process_execution_keep_sandboxes='on_failure')
+    scheduler = self.mk_scheduler_in_example_fs((), execution_options=execution_options)
+
+    request = ExecuteProcessRequest.create_with_empty_snapshot(
+      ("/bin/bash", "-c", "exit 1"),
+      dict(),
+      tuple(),
+      description='one-cat',
+    )
+
+    with self.assertRaises(ProcessExecutionFailure) as cm:
+      self.execute_raising_throw(scheduler, ExecuteProcessResult, request)
+    kept_sandbox = cm.exception.kept_sandbox
+    try:
+      self.assertTrue(os.path.isfile(os.path.join(kept_sandbox, '__run.sh')))
+      self.assertIn('{}/__run.sh'.format(kept_sandbox), str(cm.exception))
+    finally:
+      shutil.rmtree(kept_sandbox)
+
+  def test_kept_sandbox_is_ignored_by_equality(self):
+    result = FallibleExecuteProcessResult(b'out', b'err', 1, EMPTY_DIRECTORY_DIGEST, b'')
+    kept = FallibleExecuteProcessResult(b'out', b'err', 1, EMPTY_DIRECTORY_DIGEST, b'/sandbox')
+    self.assertEqual(result, kept)
+    self.assertEqual(hash(result), hash(kept))
+    self.assertNotEqual(
+      result, FallibleExecuteProcessResult(b'out', b'err', 0, EMPTY_DIRECTORY_DIGEST, b''))
+
   def mk_example_fs_tree(self):
     fs_tree = self.mk_fs_tree(os.path.join(os.path.dirname(__file__), 'examples'))
     test_fs = os.path.join(fs_tree.build_root, 'fs_test')
@@ -409,6 +440,8 @@ class IsolatedProcessTest(SchedulerTestBase, unittest.TestCase):
       tar.extractall(test_fs)
     return fs_tree
 
-  def mk_scheduler_in_example_fs(self, rules):
+  def mk_scheduler_in_example_fs(self, rules, execution_options=None):
     rules = list(rules) + create_fs_rules() + create_process_rules()
-    return self.mk_scheduler(rules=rules, project_tree=self.mk_example_fs_tree())
+    return self.mk_scheduler(rules=rules,
+                             project_tree=self.mk_example_fs_tree(),
+                             execution_options=execution_options)
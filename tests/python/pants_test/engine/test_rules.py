@@ -5,14 +5,19 @@
 from __future__ import (absolute_import, division, generators, nested_scopes, print_function,
                         unicode_literals, with_statement)
 
+import json
 import unittest
 from textwrap import dedent
 
 from pants.engine.build_files import create_graph_rules
-from pants.engine.fs import create_fs_rules
+from pants.engine.fs import PathGlobs, Snapshot, create_fs_rules
 from pants.engine.mapper import AddressMapper
 from pants.engine.rules import RootRule, RuleIndex, SingletonRule, TaskRule
-from pants.engine.selectors import Get, Select
+from pants.engine.scheduler import (RuleAmbiguity, RuleDiagnostic, RuleError,
+                                    RuleGraphValidationError, RulePaths)
+from pants.engine.selectors import Get, Select, SelectUnion, SelectVariant
+from pants.option.global_options import DEFAULT_EXECUTION_OPTIONS
+from pants.util.contextutil import temporary_file_path
 from pants.util.objects import Exactly
 from pants_test.engine.examples.parsers import JsonParser
 from pants_test.engine.examples.planners import Goal
@@ -60,6 +65,14 @@ class SubA(A):
     return 'SubA()'
 
 
+class Thing(object):
+  pass
+
+
+class Things(object):
+  pass
+
+
 _suba_root_rules = [RootRule(SubA)]
 
 
@@ -121,6 +134,137 @@ class RulesetValidatorTest(unittest.TestCase):
                                       """).strip(),
                                     str(cm.exception))
 
+  def test_diagnostics_with_rejected_candidates(self):
+    rules = [
+      RootRule(C),
+      TaskRule(A, [Select(B)], noop),
+      TaskRule(B, [Select(SubA)], noop)
+    ]
+
+    with self.assertRaises(RuleGraphValidationError) as cm:
+      create_scheduler(rules)
+    self.assertEquals(
+      (RuleError(rule='(A, (Select(B),), noop)',
+                 diagnostics=(RuleDiagnostic(
+                   subject_type='C',
+                   product='B',
+                   reason='no rule was available to compute B for subject type C',
+                   rejected_candidates=(
+                     ('(B, (Select(SubA),), noop) of C',
+                      ('no rule was available to compute SubA for subject type C',)),
+                   ),
+                   suggestions=()),)),
+       RuleError(rule='(B, (Select(SubA),), noop)',
+                 diagnostics=(RuleDiagnostic(
+                   subject_type='C',
+                   product='SubA',
+                   reason='no rule was available to compute SubA for subject type C',
+                   rejected_candidates=(),
+                   suggestions=()),))),
+      cm.exception.rule_errors)
+
+  def test_diagnostics_suggest_similar_types(self):
+    rules = [
+      RootRule(C),
+      TaskRule(A, [Select(Thing)], noop),
+      TaskRule(Things, [Select(C)], noop),
+    ]
+
+    scheduler = create_scheduler(rules, validate=False)
+    rule_errors = scheduler.rule_graph_diagnostics()
+    self.assertEquals(['(A, (Select(Thing),), noop)'], [e.rule for e in rule_errors])
+    diagnostic, = rule_errors[0].diagnostics
+    self.assertEquals('Thing', diagnostic.product)
+    self.assertEquals(('Things',), diagnostic.suggestions)
+
+  def test_ruleset_with_ambiguous_rules(self):
+    rules = _suba_root_rules + [
+      TaskRule(B, [Select(A)], noop),
+      TaskRule(A, [Select(SubA)], noop),
+      TaskRule(A, [], noop),
+    ]
+
+    with self.assertRaises(RuleGraphValidationError) as cm:
+      create_scheduler(rules)
+    self.assert_equal_with_printing(dedent("""
+                                      Ambiguous rules (declare a priority to choose between them): 1
+                                        A for subject type SubA could be computed by:
+                                          (A, (,), noop) of SubA
+                                          (A, (Select(SubA),), noop) of SubA
+                                      """).strip(),
+                                    str(cm.exception))
+    self.assertEquals((), cm.exception.rule_errors)
+    self.assertEquals(
+      (RuleAmbiguity(subject_type='SubA',
+                     product='A',
+                     candidates=('(A, (,), noop) of SubA', '(A, (Select(SubA),), noop) of SubA')),),
+      cm.exception.ambiguities)
+
+  def test_ruleset_with_ambiguous_rules_of_equal_priority(self):
+    rules = _suba_root_rules + [
+      TaskRule(B, [Select(A)], noop),
+      TaskRule(A, [Select(SubA)], noop, priority=1),
+      TaskRule(A, [], noop, priority=1),
+    ]
+
+    scheduler = create_scheduler(rules, validate=False)
+    ambiguity, = scheduler.rule_graph_ambiguities()
+    self.assertEquals(('(A, (,), noop) of SubA', '(A, (Select(SubA),), noop) of SubA'),
+                      ambiguity.candidates)
+
+  def test_ruleset_with_candidates_chosen_between_at_runtime(self):
+    # Rules which declare multiple products, or which select variants (or which select products
+    # that are only provided by such rules) might not produce a value for a subject, so they are
+    # chosen between at runtime rather than being ambiguous.
+    rules = _suba_root_rules + [
+      TaskRule(B, [Select(A)], noop),
+      TaskRule(Exactly(A, C), [Select(SubA)], noop),
+      TaskRule(A, [Select(SubA), SelectVariant(D, 'd')], noop),
+      TaskRule(A, [], noop),
+      TaskRule(D, [Select(SubA)], noop),
+      TaskRule(Things, [Select(C)], noop),
+      TaskRule(Things, [], noop),
+    ]
+
+    scheduler = create_scheduler(rules)
+    self.assertEquals((), scheduler.rule_graph_ambiguities())
+
+  def test_ruleset_with_prioritized_rule_and_intrinsic(self):
+    # An intrinsic cannot declare a priority, so it is not shadowed by a rule which did.
+    rules = create_fs_rules() + [
+      TaskRule(Snapshot, [Select(PathGlobs)], noop, priority=1),
+    ]
+
+    with self.assertRaises(RuleGraphValidationError) as cm:
+      create_scheduler(rules)
+    ambiguity, = cm.exception.ambiguities
+    self.assertEquals(('PathGlobs', 'Snapshot'), (ambiguity.subject_type, ambiguity.product))
+    self.assertEquals(2, len(ambiguity.candidates))
+    self.assertIn('(Snapshot, (Select(PathGlobs),), noop) of PathGlobs', ambiguity.candidates)
+
+  def test_ruleset_with_ambiguous_root(self):
+    # A product which is only requested by roots is still checked for ambiguity.
+    rules = _suba_root_rules + [
+      TaskRule(A, [Select(SubA)], noop),
+      TaskRule(A, [], noop),
+    ]
+
+    scheduler = create_scheduler(rules, validate=False)
+    ambiguity, = scheduler.rule_graph_ambiguities()
+    self.assertEquals('A', ambiguity.product)
+
+  def test_ruleset_with_union_only_product(self):
+    # A product which is only consumed via SelectUnion may have many providers, so its roots are
+    # not ambiguous.
+    rules = _suba_root_rules + [
+      TaskRule(Exactly(A), [SelectUnion(B)], noop),
+      TaskRule(B, [Select(SubA)], noop),
+      TaskRule(B, [], noop),
+    ]
+
+    scheduler = create_scheduler(rules, validate=False)
+    self.assertEquals((), scheduler.rule_graph_ambiguities())
+
   def test_ruleset_with_explicit_type_constraint(self):
     rules = _suba_root_rules + [
       TaskRule(Exactly(A), [Select(B)], noop),
@@ -431,6 +575,27 @@ class RuleGraphMakerTest(unittest.TestCase):
                      }""").strip(),
       subgraph)
 
+  def test_priority_chooses_between_rules(self):
+    rules = [
+      TaskRule(B, [Select(A)], noop),
+      TaskRule(A, [Select(SubA)], noop, priority=1),
+      TaskRule(A, [], noop)
+    ]
+
+    subgraph = self.create_subgraph(B, rules, SubA())
+
+    self.assert_equal_with_printing(dedent("""
+                     digraph {
+                       // root subject types: SubA
+                       // root entries
+                         "Select(B) for SubA" [color=blue]
+                         "Select(B) for SubA" -> {"(B, (Select(A),), noop) of SubA"}
+                       // internal entries
+                         "(A, (Select(SubA),), noop) of SubA" -> {"SubjectIsProduct(SubA)"}
+                         "(B, (Select(A),), noop) of SubA" -> {"(A, (Select(SubA),), noop) of SubA"}
+                     }""").strip(),
+      subgraph)
+
   def test_multiple_depend_on_same_rule(self):
     rules = _suba_root_rules + [
       TaskRule(B, [Select(A)], noop),
@@ -457,6 +622,42 @@ class RuleGraphMakerTest(unittest.TestCase):
                      }""").strip(),
       subgraph)
 
+  def test_union_without_providers(self):
+    rules = _suba_root_rules + [
+      TaskRule(Exactly(A), [SelectUnion(B)], noop),
+    ]
+    fullgraph = self.create_full_graph(rules)
+
+    self.assert_equal_with_printing(dedent("""
+                     digraph {
+                       // root subject types: SubA
+                       // root entries
+                         "Select(A) for SubA" [color=blue]
+                         "Select(A) for SubA" -> {"(A, (SelectUnion(B),), noop) of SubA"}
+                       // internal entries
+                         "(A, (SelectUnion(B),), noop) of SubA" -> {}
+                     }""").strip(), fullgraph)
+
+  def test_union_of_multiple_providers(self):
+    rules = _suba_root_rules + [
+      TaskRule(Exactly(A), [SelectUnion(B)], noop),
+      TaskRule(B, [Select(SubA)], noop),
+      TaskRule(B, [Select(C)], noop),
+    ]
+    subgraph = self.create_subgraph(A, rules, SubA(), validate=False)
+
+    self.assert_equal_with_printing(dedent("""
+                     digraph {
+                       // root subject types: SubA
+                       // root entries
+                         "Select(A) for SubA" [color=blue]
+                         "Select(A) for SubA" -> {"(A, (SelectUnion(B),), noop) of SubA"}
+                       // internal entries
+                         "(A, (SelectUnion(B),), noop) of SubA" -> {"(B, (Select(SubA),), noop) of SubA"}
+                         "(B, (Select(SubA),), noop) of SubA" -> {"SubjectIsProduct(SubA)"}
+                     }""").strip(),
+                                    subgraph)
+
   def test_get_simple(self):
     rules = [
       TaskRule(Exactly(A), [], noop, [Get(B, D)]),
@@ -477,6 +678,34 @@ class RuleGraphMakerTest(unittest.TestCase):
                      }""").strip(),
                                     subgraph)
 
+  def test_parallel_construction_matches_serial(self):
+    # Roots for each subject type are expanded on their own threads, so use several subject types,
+    # with a cycle, unfulfillable rules, ambiguities and Gets for other subject types between them.
+    rules = [
+      RootRule(SubA),
+      RootRule(C),
+      RootRule(D),
+      TaskRule(A, [Select(SubA)], noop),
+      TaskRule(A, [Select(B)], noop, priority=1),
+      TaskRule(B, [Select(A)], noop),
+      TaskRule(B, [Select(Thing)], noop, priority=1),
+      TaskRule(Things, [Select(D)], noop, input_gets=[Get(A, SubA)]),
+      TaskRule(Things, [], noop),
+    ]
+
+    def describe(parallelism):
+      execution_options = DEFAULT_EXECUTION_OPTIONS._replace(rule_graph_parallelism=parallelism)
+      scheduler = create_scheduler(rules, validate=False, execution_options=execution_options)
+      return (list(scheduler.rule_graph_visualization()),
+              scheduler.rule_graph_diagnostics(),
+              scheduler.rule_graph_ambiguities(),
+              scheduler.unused_rules())
+
+    serial = describe(1)
+    self.assertTrue(serial[1], 'Expected some rules to be unfulfillable.')
+    self.assertTrue(serial[2], 'Expected some products to be ambiguous.')
+    self.assertEquals(serial, describe(3))
+
   def create_full_graph(self, rules, validate=True):
     scheduler = create_scheduler(rules, validate=validate)
     return "\n".join(scheduler.rule_graph_visualization())
@@ -486,3 +715,57 @@ class RuleGraphMakerTest(unittest.TestCase):
     return "\n".join(scheduler.rule_subgraph_visualization(type(subject), requested_product))
 
   assert_equal_with_printing = assert_equal_with_printing
+
+
+class RuleGraphQueryTest(unittest.TestCase):
+  _rules = [
+    RootRule(SubA),
+    RootRule(A),
+    RootRule(PathGlobs),
+    TaskRule(A, [Select(SubA)], noop),
+    TaskRule(B, [Select(A)], noop),
+    TaskRule(C, [Select(D)], noop),
+  ]
+
+  def test_rule_paths(self):
+    scheduler = create_scheduler(self._rules, validate=False)
+    sub_a_paths = (('(B, (Select(A),), noop) of SubA',
+                    '(A, (Select(SubA),), noop) of SubA',
+                    'SubjectIsProduct(SubA)'),)
+    self.assertEquals(RulePaths(sub_a_paths, False), scheduler.rule_paths(SubA, B))
+    self.assertEquals(RulePaths((('(B, (Select(A),), noop) of A', 'SubjectIsProduct(A)'),), False),
+                      scheduler.rule_paths(A, B))
+    self.assertEquals(RulePaths((), False), scheduler.rule_paths(A, C))
+
+  def test_rule_paths_truncated(self):
+    scheduler = create_scheduler(self._rules, validate=False)
+    self.assertEquals(RulePaths((), True), scheduler.rule_paths(SubA, B, max_paths=0))
+    self.assertEquals(RulePaths((), False), scheduler.rule_paths(A, C, max_paths=0))
+
+  def test_unused_rules(self):
+    scheduler = create_scheduler(self._rules, validate=False)
+    self.assertEquals(('(C, (Select(D),), noop)',), scheduler.unused_rules())
+
+  def test_reached_intrinsics(self):
+    scheduler = create_scheduler(self._rules, validate=False)
+    intrinsics = scheduler.reached_intrinsics()
+    self.assertTrue(intrinsics)
+    self.assertTrue(all(i.endswith(' for PathGlobs') for i in intrinsics), intrinsics)
+
+  def test_export_json(self):
+    scheduler = create_scheduler(self._rules, validate=False)
+    with temporary_file_path() as path:
+      scheduler.export_rule_graph_json(path)
+      with open(path) as fd:
+        graph = json.load(fd)
+
+    self.assertEquals(['A', 'PathGlobs', 'SubA'], graph['root_subject_types'])
+    self.assertEquals(['(C, (Select(D),), noop)'], graph['unused_rules'])
+    self.assertEquals(list(scheduler.reached_intrinsics()), graph['reached_intrinsics'])
+    entries = {e['entry']: e for e in graph['entries']}
+    self.assertEquals('root', entries['Select(B) for SubA']['kind'])
+    self.assertEquals(['(B, (Select(A),), noop) of SubA'],
+                      entries['Select(B) for SubA']['dependencies'])
+    self.assertEquals('task', entries['(A, (Select(SubA),), noop) of SubA']['kind'])
+    self.assertEquals(set(['root', 'task', 'intrinsic']),
+                      set(e['kind'] for e in graph['entries']))
@@ -0,0 +1,164 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import (absolute_import, division, generators, nested_scopes, print_function,
+                        unicode_literals, with_statement)
+
+import json
+import os
+import tarfile
+import unittest
+
+from pants.engine.fs import PathGlobs, Snapshot, create_fs_rules
+from pants.option.global_options import DEFAULT_EXECUTION_OPTIONS
+from pants.util.contextutil import temporary_dir
+from pants_test.engine.scheduler_test_base import SchedulerTestBase
+
+
+class SchedulerGraphTest(SchedulerTestBase, unittest.TestCase):
+  """Tests of the scheduler's product graph, via Snapshots of the example fs_test project."""
+
+  _original_src = os.path.join(os.path.dirname(__file__), 'examples/fs_test/fs_test.tar')
+
+  def mk_project_tree(self):
+    project_tree = self.mk_fs_tree()
+    with tarfile.open(self._original_src) as tar:
+      tar.extractall(project_tree.build_root)
+    return project_tree
+
+  def mk_snapshot_scheduler(self, project_tree=None, execution_options=None):
+    """Creates a scheduler for the fs rules, in the given (or a new) example project tree."""
+    return self.mk_scheduler(rules=create_fs_rules(),
+                             project_tree=project_tree or self.mk_project_tree(),
+                             execution_options=execution_options)
+
+  def snapshot(self, scheduler, *include):
+    return self.execute_expecting_one_result(scheduler, Snapshot, PathGlobs(include=include)).value
+
+  def test_persisted_graph(self):
+    project_tree = self.mk_project_tree()
+    with temporary_dir() as persist_dir:
+      graph_file = os.path.join(persist_dir, 'graph')
+
+      def snapshot_digest_and_restored_count(load_persisted):
+        scheduler = self.mk_snapshot_scheduler(project_tree)
+        if load_persisted:
+          self.assertGreater(scheduler.load_persisted_graph(graph_file), 0)
+        snapshot = self.snapshot(scheduler, '4.txt')
+        self.assertGreater(scheduler.persist_graph(graph_file), 0)
+        return snapshot.directory_digest, scheduler.metrics()['graph_restored_count']
+
+      original, restored = snapshot_digest_and_restored_count(load_persisted=False)
+      self.assertEquals(0, restored)
+      # A persisted Snapshot is restored rather than re-run if the files it depends on have not
+      # changed...
+      self.assertEquals((original, 1), snapshot_digest_and_restored_count(load_persisted=True))
+      # ...but not otherwise.
+      with open(os.path.join(project_tree.build_root, '4.txt'), 'w') as f:
+        f.write('changed')
+      changed, restored = snapshot_digest_and_restored_count(load_persisted=True)
+      self.assertNotEquals(original, changed)
+      self.assertEquals(0, restored)
+
+  def test_watch_filesystem(self):
+    project_tree = self.mk_project_tree()
+    execution_options = DEFAULT_EXECUTION_OPTIONS._replace(watch_filesystem=True)
+    scheduler = self.mk_snapshot_scheduler(project_tree, execution_options)
+
+    def snapshot_files():
+      return sorted(f.path for f in self.snapshot(scheduler, 'a/b/*').files)
+
+    self.assertEquals(['a/b/1.txt', 'a/b/2'], snapshot_files())
+
+    # Writing a file beneath a scanned directory invalidates the directory, without any call to
+    # `invalidate_files`.
+    count = scheduler.metrics()['watcher_invalidation_count']
+    with open(os.path.join(project_tree.build_root, 'a/b/3.txt'), 'w') as f:
+      f.write('new')
+    self.assertTrue(scheduler.await_watcher_invalidation(count, timeout_secs=10),
+                    'The watcher did not invalidate a/b.')
+    self.assertEquals(['a/b/1.txt', 'a/b/2', 'a/b/3.txt'], snapshot_files())
+
+  def test_invalidate_globs_and_node_types(self):
+    scheduler = self.mk_snapshot_scheduler()
+    self.snapshot(scheduler, '4.txt')
+
+    # Invalidating a file clears its digest and the listing of its directory, and dirties the
+    # Snapshot which depends on them.
+    counts = scheduler.invalidate_globs(PathGlobs(include=['*.txt'], exclude=['1.txt']))
+    self.assertEquals((1, 0), counts['DigestFile'])
+    self.assertEquals((1, 0), counts['Scandir'])
+    self.assertEquals((0, 1), counts['Snapshot'])
+
+    counts = scheduler.invalidate_node_type('Snapshot')
+    self.assertEquals((1, 0), counts['Snapshot'])
+    self.assertNotIn('DigestFile', counts)
+
+    with self.assertRaises(Exception):
+      scheduler.invalidate_node_type('NotANodeType')
+
+  def test_export_graph_json(self):
+    scheduler = self.mk_snapshot_scheduler()
+    self.snapshot(scheduler, '4.txt')
+
+    with temporary_dir() as export_dir:
+      export_file = os.path.join(export_dir, 'graph.json')
+      count = scheduler.export_graph_json(export_file)
+      with open(export_file) as f:
+        nodes = [json.loads(line) for line in f]
+    self.assertEquals(count, len(nodes))
+
+    nodes_by_id = {node['id']: node for node in nodes}
+    snapshot, = [node for node in nodes if node['type'] == 'Snapshot']
+    self.assertEquals('completed', snapshot['state'])
+    self.assertEquals('success', snapshot['outcome'])
+    self.assertIn('DigestFile', [nodes_by_id[dep]['type'] for dep in snapshot['dependencies']])
+
+  def test_export_chrome_trace(self):
+    scheduler = self.mk_snapshot_scheduler()
+    self.snapshot(scheduler, '4.txt')
+
+    with temporary_dir() as export_dir:
+      export_file = os.path.join(export_dir, 'trace.json')
+      count = scheduler.export_chrome_trace(export_file)
+      with open(export_file) as f:
+        events = json.load(f)['traceEvents']
+    spans = [event for event in events if event['ph'] == 'X']
+    self.assertEquals(count, len(spans))
+
+    snapshot, = [span for span in spans if span['cat'] == 'Snapshot']
+    self.assertTrue(snapshot['args']['completed'])
+    self.assertEquals(1, snapshot['args']['runs'])
+    # Dependencies are nested within the spans of their dependents.
+    digest_file = [span for span in spans if span['cat'] == 'DigestFile'][0]
+    self.assertGreaterEqual(digest_file['ts'], snapshot['ts'])
+
+  def test_critical_path(self):
+    scheduler = self.mk_snapshot_scheduler()
+    self.snapshot(scheduler, '4.txt')
+
+    critical_path = scheduler.critical_path()
+    self.assertTrue(critical_path)
+    # The path passes through the Snapshot node that was requested (via a Select) by the root.
+    self.assertTrue(any(node.startswith('Snapshot(') for node, _ in critical_path))
+    for _, self_time in critical_path:
+      self.assertGreaterEqual(self_time, 0)
+
+  def test_progress(self):
+    scheduler = self.mk_snapshot_scheduler()
+    self.snapshot(scheduler, '4.txt')
+
+    # Once execution has finished, every node has completed.
+    progress = scheduler.progress()
+    self.assertEquals(0, progress.queued)
+    self.assertEquals(0, progress.running)
+    self.assertGreater(progress.completed, 0)
+    self.assertEquals((), progress.running_processes)
+    # Without a remote store, no bytes are transferred.
+    self.assertEquals(0, progress.bytes_uploaded)
+    self.assertEquals(0, progress.bytes_downloaded)
+    self.assertEquals(1, len(progress.lines()))
+
+    # A new session of the same scheduler only reports its own progress.
+    self.assertEquals(0, scheduler._scheduler.new_session().progress().completed)
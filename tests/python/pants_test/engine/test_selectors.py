@@ -7,7 +7,7 @@ from __future__ import (absolute_import, division, generators, nested_scopes, pr
 
 import unittest
 
-from pants.engine.selectors import Select, SelectVariant
+from pants.engine.selectors import Select, SelectUnion, SelectVariant
 
 
 class AClass(object):
@@ -19,6 +19,9 @@ class SelectorsTest(unittest.TestCase):
     self.assert_repr("Select(AClass)", Select(AClass))
     self.assert_repr("Select(AClass, optional=True)", Select(AClass, optional=True))
 
+  def test_union_repr(self):
+    self.assert_repr("SelectUnion(AClass)", SelectUnion(AClass))
+
   def test_variant_repr(self):
     self.assert_repr("SelectVariant(AClass, u'field')", SelectVariant(AClass, 'field'))
 
@@ -90,7 +90,7 @@ def init_native():
   return Native.create(opts.for_global_scope())
 
 
-def create_scheduler(rules, validate=True):
+def create_scheduler(rules, validate=True, execution_options=None):
   """Create a Scheduler."""
   native = init_native()
   return Scheduler(
@@ -98,7 +98,7 @@ def create_scheduler(rules, validate=True):
     FileSystemProjectTree(os.getcwd()),
     './.pants.d',
     rules,
-    execution_options=DEFAULT_EXECUTION_OPTIONS,
+    execution_options=execution_options or DEFAULT_EXECUTION_OPTIONS,
     validate=validate,
   )
 